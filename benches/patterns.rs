@@ -0,0 +1,66 @@
+//! Criterion benchmarks for the comparisons in `tokio_tutorial_patterns::bench`
+//!
+//! Run with `cargo bench --bench patterns`. Each group times the baseline
+//! and candidate variant of one workload separately.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::future::Future;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio_tutorial_patterns::bench::{self, BenchConfig, Measurement, Variant};
+
+/// Scales a measurement to `iters` operations, as criterion expects
+///
+/// A measurement may cover more or fewer operations than were asked for,
+/// e.g. the file workload makes one write per 100, so this goes by the
+/// operations it actually counted.
+fn per_iters(measurement: &Measurement, iters: u64) -> Duration {
+    let operations = measurement.operations.max(1) as f64;
+    measurement.elapsed.mul_f64(iters as f64 / operations)
+}
+
+/// Benchmarks the two variants of one workload, each run on its own
+fn compare<F, Fut>(c: &mut Criterion, rt: &Runtime, workload: &str, run: F)
+where
+    F: Fn(BenchConfig, Variant) -> Fut + Copy,
+    Fut: Future<Output = Measurement>,
+{
+    let mut group = c.benchmark_group(workload);
+    for (name, variant) in [
+        ("baseline", Variant::Baseline),
+        ("candidate", Variant::Candidate),
+    ] {
+        group.bench_function(name, |b| {
+            b.to_async(rt).iter_custom(|iters| async move {
+                let config = BenchConfig {
+                    operations: (iters as usize).max(1000),
+                    ..BenchConfig::default()
+                };
+                per_iters(&run(config, variant).await, iters)
+            });
+        });
+    }
+    group.finish();
+}
+
+fn patterns(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+
+    compare(c, &rt, "shared counter", |config, variant| async move {
+        bench::shared_counter(&config, variant).await
+    });
+    compare(c, &rt, "mpsc channel", |config, variant| async move {
+        bench::mpsc_channel(&config, variant).await
+    });
+    compare(c, &rt, "request handler", |config, variant| async move {
+        bench::request_handler(&config, variant).await
+    });
+    compare(c, &rt, "file writes", |config, variant| async move {
+        bench::file_writes(&config, variant)
+            .await
+            .expect("benchmark file could not be written")
+    });
+}
+
+criterion_group!(benches, patterns);
+criterion_main!(benches);
@@ -0,0 +1,380 @@
+//! Actors with mailboxes, and supervisors that restart them
+//!
+//! An [`Actor`] owns its state and handles one message at a time from its
+//! [`Mailbox`]; other tasks talk to it through a cloneable [`ActorRef`].
+//! Actors can run on their own with [`spawn`], or under a supervisor built
+//! from a [`SupervisorSpec`], which restarts them from a factory when they
+//! fail (return an error or panic). Because the mailbox outlives each
+//! incarnation of the actor, `ActorRef`s stay valid across restarts and
+//! messages sent in the meantime are handled by the new incarnation.
+//!
+//! Supervisors can supervise other supervisors. A supervisor that exceeds
+//! its restart intensity stops all of its children and fails itself, which
+//! its own supervisor then handles like any other failure.
+
+use crate::spawning::spawn_traced;
+use futures::future::BoxFuture;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Error type actors fail with
+pub type ActorError = Box<dyn std::error::Error + Send + Sync>;
+
+/// State plus the logic to handle messages sent to it
+pub trait Actor: Send + 'static {
+    type Message: Send + 'static;
+
+    /// Handles one message; an error stops this incarnation of the actor
+    fn handle(
+        &mut self,
+        message: Self::Message,
+    ) -> impl Future<Output = Result<(), ActorError>> + Send;
+}
+
+/// Returned by [`ActorRef::send`] with the message once nothing will ever
+/// receive it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActorStopped<M>(pub M);
+
+impl<M> fmt::Display for ActorStopped<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "actor has stopped")
+    }
+}
+
+impl<M: fmt::Debug> std::error::Error for ActorStopped<M> {}
+
+/// A handle for sending messages to an actor
+pub struct ActorRef<M> {
+    tx: mpsc::Sender<M>,
+}
+
+impl<M> Clone for ActorRef<M> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<M> ActorRef<M> {
+    /// Queues a message, waiting while the mailbox is full
+    pub async fn send(&self, message: M) -> Result<(), ActorStopped<M>> {
+        self.tx.send(message).await.map_err(|e| ActorStopped(e.0))
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// The receiving end of an actor's messages, kept across restarts
+pub struct Mailbox<M> {
+    rx: Arc<tokio::sync::Mutex<mpsc::Receiver<M>>>,
+}
+
+/// Creates a mailbox holding up to `capacity` messages
+pub fn mailbox<M>(capacity: usize) -> (ActorRef<M>, Mailbox<M>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let mailbox = Mailbox {
+        rx: Arc::new(tokio::sync::Mutex::new(rx)),
+    };
+    (ActorRef { tx }, mailbox)
+}
+
+/// How an actor or supervisor finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Exit {
+    /// Its mailbox closed, all of its children finished, or it was told to stop
+    Normal,
+    Failed(String),
+}
+
+/// Runs `actor` on its own task, unsupervised, until its mailbox closes or
+/// it fails
+pub fn spawn<A: Actor>(actor: A, capacity: usize) -> (ActorRef<A::Message>, JoinHandle<Exit>) {
+    let (actor_ref, mailbox) = mailbox(capacity);
+    let handle = spawn_traced(
+        "actor",
+        run_actor(actor, mailbox.rx, CancellationToken::new()),
+    );
+    (actor_ref, handle)
+}
+
+async fn run_actor<A: Actor>(
+    mut actor: A,
+    mailbox: Arc<tokio::sync::Mutex<mpsc::Receiver<A::Message>>>,
+    stop: CancellationToken,
+) -> Exit {
+    let mut mailbox = mailbox.lock().await;
+    loop {
+        // The current message is always finished before stopping
+        let message = tokio::select! {
+            biased;
+            _ = stop.cancelled() => return Exit::Normal,
+            message = mailbox.recv() => message,
+        };
+        let Some(message) = message else {
+            return Exit::Normal;
+        };
+        if let Err(e) = actor.handle(message).await {
+            return Exit::Failed(e.to_string());
+        }
+    }
+}
+
+/// Which children a supervisor restarts when one fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Only the child that failed
+    OneForOne,
+    /// Every running child, for children that depend on each other
+    OneForAll,
+}
+
+type StartFn = Box<dyn Fn(CancellationToken) -> BoxFuture<'static, Exit> + Send + Sync>;
+
+struct ChildSpec {
+    name: String,
+    start: StartFn,
+}
+
+/// Describes a supervisor and its children, in start order
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::actors::{self, Actor, ActorError, Strategy, SupervisorSpec};
+///
+/// struct Cache(Vec<String>);
+///
+/// impl Actor for Cache {
+///     type Message = String;
+///
+///     async fn handle(&mut self, key: String) -> Result<(), ActorError> {
+///         self.0.push(key);
+///         Ok(())
+///     }
+/// }
+///
+/// let (cache, cache_mailbox) = actors::mailbox(64);
+/// let supervisor = SupervisorSpec::new("app", Strategy::OneForOne)
+///     .intensity(3, Duration::from_secs(5))
+///     .actor("cache", cache_mailbox, || Cache(Vec::new()))
+///     .start();
+///
+/// cache.send("user:1".to_string()).await.unwrap();
+/// supervisor.shutdown().await;
+/// # }
+/// ```
+pub struct SupervisorSpec {
+    name: String,
+    strategy: Strategy,
+    max_restarts: usize,
+    within: Duration,
+    children: Vec<ChildSpec>,
+}
+
+impl SupervisorSpec {
+    /// A supervisor allowing 3 restarts in any 5 seconds
+    pub fn new(name: impl Into<String>, strategy: Strategy) -> Self {
+        Self {
+            name: name.into(),
+            strategy,
+            max_restarts: 3,
+            within: Duration::from_secs(5),
+            children: Vec::new(),
+        }
+    }
+
+    /// Gives up once more than `max_restarts` restarts would happen within
+    /// `within`
+    pub fn intensity(mut self, max_restarts: usize, within: Duration) -> Self {
+        self.max_restarts = max_restarts;
+        self.within = within;
+        self
+    }
+
+    /// Adds an actor built by `factory`, on start and after every failure
+    pub fn actor<A, F>(
+        mut self,
+        name: impl Into<String>,
+        mailbox: Mailbox<A::Message>,
+        factory: F,
+    ) -> Self
+    where
+        A: Actor,
+        F: Fn() -> A + Send + Sync + 'static,
+    {
+        self.children.push(ChildSpec {
+            name: name.into(),
+            start: Box::new(move |stop| {
+                let actor = factory();
+                Box::pin(run_actor(actor, mailbox.rx.clone(), stop))
+            }),
+        });
+        self
+    }
+
+    /// Adds a nested supervisor, restarted with all of its children when it
+    /// gives up
+    pub fn supervisor(mut self, spec: SupervisorSpec) -> Self {
+        let name = spec.name.clone();
+        let spec = Arc::new(spec);
+        self.children.push(ChildSpec {
+            name,
+            start: Box::new(move |stop| {
+                let restarts = Arc::new(AtomicU64::new(0));
+                Box::pin(supervise(spec.clone(), stop, restarts))
+            }),
+        });
+        self
+    }
+
+    /// Starts every child and supervises them on a new task
+    pub fn start(self) -> Supervisor {
+        let stop = CancellationToken::new();
+        let restarts = Arc::new(AtomicU64::new(0));
+        let task_name = format!("supervisor {}", self.name);
+        let task = spawn_traced(
+            &task_name,
+            supervise(Arc::new(self), stop.clone(), restarts.clone()),
+        );
+        Supervisor {
+            stop,
+            restarts,
+            task,
+        }
+    }
+}
+
+/// A running top-level supervisor
+pub struct Supervisor {
+    stop: CancellationToken,
+    restarts: Arc<AtomicU64>,
+    task: JoinHandle<Exit>,
+}
+
+impl Supervisor {
+    /// Restarts performed by this supervisor itself, not by nested ones
+    pub fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the supervisor to finish on its own
+    pub async fn join(self) -> Exit {
+        self.task
+            .await
+            .unwrap_or_else(|e| Exit::Failed(e.to_string()))
+    }
+
+    /// Stops every child, each after the message it is handling, and waits
+    /// for them
+    pub async fn shutdown(self) -> Exit {
+        self.stop.cancel();
+        self.join().await
+    }
+}
+
+struct Children<'a> {
+    spec: &'a SupervisorSpec,
+    tasks: JoinSet<Exit>,
+    /// Which child each task runs, and the token that stops it
+    running: HashMap<tokio::task::Id, (usize, CancellationToken)>,
+}
+
+impl Children<'_> {
+    fn start(&mut self, index: usize) {
+        let stop = CancellationToken::new();
+        let task = (self.spec.children[index].start)(stop.clone());
+        let id = self.tasks.spawn(task).id();
+        self.running.insert(id, (index, stop));
+    }
+
+    /// Stops every running child, returning their indices in start order
+    async fn stop_all(&mut self) -> Vec<usize> {
+        let mut stopped: Vec<usize> = self.running.values().map(|(index, _)| *index).collect();
+        stopped.sort_unstable();
+        for (_, stop) in self.running.values() {
+            stop.cancel();
+        }
+        while self.tasks.join_next().await.is_some() {}
+        self.running.clear();
+        stopped
+    }
+}
+
+async fn supervise(
+    spec: Arc<SupervisorSpec>,
+    stop: CancellationToken,
+    restarts: Arc<AtomicU64>,
+) -> Exit {
+    let mut children = Children {
+        spec: &spec,
+        tasks: JoinSet::new(),
+        running: HashMap::new(),
+    };
+    for index in 0..spec.children.len() {
+        children.start(index);
+    }
+    let mut recent = VecDeque::new();
+
+    loop {
+        if children.tasks.is_empty() {
+            return Exit::Normal;
+        }
+        let joined = tokio::select! {
+            _ = stop.cancelled() => {
+                children.stop_all().await;
+                return Exit::Normal;
+            }
+            joined = children.tasks.join_next_with_id() => joined.unwrap(),
+        };
+
+        let (id, exit) = match joined {
+            Ok((id, exit)) => (id, exit),
+            Err(e) => (e.id(), Exit::Failed(e.to_string())),
+        };
+        let (index, _) = children.running.remove(&id).unwrap();
+        let Exit::Failed(reason) = exit else {
+            continue;
+        };
+
+        let now = Instant::now();
+        while recent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > spec.within)
+        {
+            recent.pop_front();
+        }
+        if recent.len() >= spec.max_restarts {
+            children.stop_all().await;
+            return Exit::Failed(format!(
+                "supervisor `{}` gave up after child `{}` failed: {}",
+                spec.name, spec.children[index].name, reason
+            ));
+        }
+        recent.push_back(now);
+        restarts.fetch_add(1, Ordering::Relaxed);
+
+        match spec.strategy {
+            Strategy::OneForOne => children.start(index),
+            Strategy::OneForAll => {
+                let mut restart = children.stop_all().await;
+                restart.push(index);
+                restart.sort_unstable();
+                for index in restart {
+                    children.start(index);
+                }
+            }
+        }
+    }
+}
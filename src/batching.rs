@@ -0,0 +1,141 @@
+//! Coalescing individual lookups into batched backend calls
+//!
+//! A [`Batcher`] is the data-loader pattern: callers ask for one key at a
+//! time with [`Batcher::get`], and every request that arrives within a short
+//! window is answered by a single call to a user-supplied loader taking all
+//! of their keys at once. Each key is loaded once per batch however many
+//! callers asked for it. Batches are gathered with
+//! [`BatchReceiver`](crate::channels::BatchReceiver), and a batch's load runs
+//! on its own task, so the next batch can fill up while it is in progress.
+
+use crate::channels::BatchReceiver;
+use crate::spawning::spawn_traced;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// How requests are grouped into batches
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// How long a batch stays open after its first request
+    pub window: Duration,
+    /// Requests that close a batch early
+    pub max_batch: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_millis(5),
+            max_batch: 100,
+        }
+    }
+}
+
+/// Returned by [`Batcher::get`] when the loader panicked or the batcher's
+/// task has gone away
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchFailed;
+
+impl fmt::Display for BatchFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "batch load failed")
+    }
+}
+
+impl std::error::Error for BatchFailed {}
+
+type Request<K, V> = (K, oneshot::Sender<Option<V>>);
+
+/// Batches concurrent `get`s into calls to a loader; clones share batches
+///
+/// ```
+/// # async fn example() {
+/// use std::collections::HashMap;
+/// use tokio_tutorial_patterns::batching::{BatchConfig, Batcher};
+///
+/// let users = Batcher::new(BatchConfig::default(), |ids: Vec<u64>| async move {
+///     // e.g. SELECT id, name FROM users WHERE id IN (...)
+///     ids.into_iter()
+///         .map(|id| (id, format!("user {}", id)))
+///         .collect::<HashMap<_, _>>()
+/// });
+///
+/// let (alice, bob) = tokio::join!(users.get(1), users.get(2));
+/// assert_eq!(alice.unwrap().as_deref(), Some("user 1"));
+/// # let _ = bob;
+/// # }
+/// ```
+pub struct Batcher<K, V> {
+    tx: mpsc::Sender<Request<K, V>>,
+}
+
+impl<K, V> Clone for Batcher<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<K, V> Batcher<K, V>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    /// Starts a batcher calling `load` with the distinct keys of each batch
+    ///
+    /// Keys missing from the returned map resolve to `None`. Must be called
+    /// from within a Tokio runtime.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.max_batch` is zero.
+    pub fn new<F, Fut>(config: BatchConfig, load: F) -> Self
+    where
+        F: Fn(Vec<K>) -> Fut + Send + 'static,
+        Fut: Future<Output = HashMap<K, V>> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(config.max_batch);
+        let mut batches = BatchReceiver::new(rx, config.max_batch, config.window);
+
+        spawn_traced("batcher", async move {
+            while let Some(batch) = batches.recv_batch().await {
+                let mut waiting: HashMap<K, Vec<oneshot::Sender<Option<V>>>> = HashMap::new();
+                let mut keys = Vec::new();
+                for (key, reply) in batch {
+                    waiting
+                        .entry(key)
+                        .or_insert_with_key(|key| {
+                            keys.push(key.clone());
+                            Vec::new()
+                        })
+                        .push(reply);
+                }
+
+                let loading = load(keys);
+                spawn_traced("batch load", async move {
+                    let mut values = loading.await;
+                    for (key, replies) in waiting {
+                        let value = values.remove(&key);
+                        for reply in replies {
+                            let _ = reply.send(value.clone());
+                        }
+                    }
+                });
+            }
+        });
+
+        Self { tx }
+    }
+
+    /// Looks up `key` as part of the next batch
+    pub async fn get(&self, key: K) -> Result<Option<V>, BatchFailed> {
+        let (reply, value) = oneshot::channel();
+        self.tx.send((key, reply)).await.map_err(|_| BatchFailed)?;
+        value.await.map_err(|_| BatchFailed)
+    }
+}
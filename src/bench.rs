@@ -0,0 +1,379 @@
+//! Side-by-side timings of pattern variants, runnable on your own hardware
+//!
+//! Each comparison runs the same workload two ways, a baseline and a
+//! candidate, and reports both wall-clock times. The numbers are only
+//! meaningful relative to each other and on the machine that produced them;
+//! the `patterns` criterion benchmarks in `benches/` run the same workloads
+//! with proper statistics. Run comparisons on a multi-threaded runtime, or
+//! the contended variants won't see any contention.
+//!
+//! ```no_run
+//! # async fn example() {
+//! use tokio_tutorial_patterns::bench::{self, BenchConfig};
+//!
+//! for comparison in bench::run_all(&BenchConfig::default()).await {
+//!     println!("{}", comparison);
+//! }
+//! # }
+//! ```
+
+use crate::channels::RequestHandler;
+use crate::shared_state::Counter;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// How much work each comparison does
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Operations per variant, spread over `tasks`
+    pub operations: usize,
+    /// Tasks working concurrently where a workload has any concurrency
+    pub tasks: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            operations: 100_000,
+            tasks: 8,
+        }
+    }
+}
+
+impl BenchConfig {
+    fn per_task(&self) -> usize {
+        (self.operations / self.tasks.max(1)).max(1)
+    }
+}
+
+/// How long one variant took for a number of operations
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub name: &'static str,
+    pub operations: usize,
+    pub elapsed: Duration,
+}
+
+impl Measurement {
+    pub fn per_operation(&self) -> Duration {
+        self.elapsed.div_f64(self.operations.max(1) as f64)
+    }
+
+    pub fn operations_per_sec(&self) -> f64 {
+        self.operations as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// Which side of a comparison to run on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Baseline,
+    Candidate,
+}
+
+/// Two variants of the same workload
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub workload: &'static str,
+    pub baseline: Measurement,
+    pub candidate: Measurement,
+}
+
+impl Comparison {
+    /// How many times faster the candidate was; below 1 if it was slower
+    pub fn speedup(&self) -> f64 {
+        self.baseline.elapsed.as_secs_f64()
+            / self.candidate.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:", self.workload)?;
+        for m in [&self.baseline, &self.candidate] {
+            writeln!(
+                f,
+                "  {:<12} {:>10.0} ops/s ({:?}/op)",
+                m.name,
+                m.operations_per_sec(),
+                m.per_operation()
+            )?;
+        }
+        write!(f, "  speedup: {:.2}x", self.speedup())
+    }
+}
+
+async fn measure<F, Fut>(name: &'static str, operations: usize, run: F) -> Measurement
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let started = Instant::now();
+    run().await;
+    Measurement {
+        name,
+        operations,
+        elapsed: started.elapsed(),
+    }
+}
+
+async fn join_tasks<F, Fut>(tasks: usize, task: F)
+where
+    F: Fn(usize) -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let handles: Vec<_> = (0..tasks).map(|i| tokio::spawn(task(i))).collect();
+    for handle in handles {
+        handle.await.expect("benchmark task panicked");
+    }
+}
+
+/// One counter per task, summed on read, so increments never contend
+///
+/// Shards use the same `tokio::sync::Mutex` as [`Counter`], so the
+/// comparison measures contention rather than the choice of lock.
+struct ShardedCounter {
+    shards: Vec<Mutex<i64>>,
+}
+
+impl ShardedCounter {
+    fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards).map(|_| Mutex::new(0)).collect(),
+        }
+    }
+
+    async fn increment(&self, shard: usize) {
+        *self.shards[shard % self.shards.len()].lock().await += 1;
+    }
+
+    async fn get(&self) -> i64 {
+        let mut sum = 0;
+        for shard in &self.shards {
+            sum += *shard.lock().await;
+        }
+        sum
+    }
+}
+
+/// Every task incrementing one [`Counter`] vs each its own shard
+pub async fn mutex_vs_sharded_counter(config: &BenchConfig) -> Comparison {
+    Comparison {
+        workload: "shared counter",
+        baseline: shared_counter(config, Variant::Baseline).await,
+        candidate: shared_counter(config, Variant::Candidate).await,
+    }
+}
+
+/// One variant of [`mutex_vs_sharded_counter`]
+pub async fn shared_counter(config: &BenchConfig, variant: Variant) -> Measurement {
+    let per_task = config.per_task();
+    let operations = per_task * config.tasks;
+
+    match variant {
+        Variant::Baseline => {
+            let counter = Counter::new(0);
+            let measurement = measure("mutex", operations, || {
+                join_tasks(config.tasks, |_| {
+                    let counter = counter.clone();
+                    async move {
+                        for _ in 0..per_task {
+                            counter.increment().await;
+                        }
+                    }
+                })
+            })
+            .await;
+            debug_assert_eq!(counter.get().await as usize, operations);
+            measurement
+        }
+        Variant::Candidate => {
+            let sharded = Arc::new(ShardedCounter::new(config.tasks));
+            let measurement = measure("sharded", operations, || {
+                join_tasks(config.tasks, |i| {
+                    let sharded = sharded.clone();
+                    async move {
+                        for _ in 0..per_task {
+                            sharded.increment(i).await;
+                        }
+                    }
+                })
+            })
+            .await;
+            debug_assert_eq!(sharded.get().await as usize, operations);
+            measurement
+        }
+    }
+}
+
+/// Producers sending through a bounded vs an unbounded mpsc channel to one
+/// consumer
+pub async fn bounded_vs_unbounded_channel(config: &BenchConfig) -> Comparison {
+    Comparison {
+        workload: "mpsc channel",
+        baseline: mpsc_channel(config, Variant::Baseline).await,
+        candidate: mpsc_channel(config, Variant::Candidate).await,
+    }
+}
+
+/// One variant of [`bounded_vs_unbounded_channel`]
+pub async fn mpsc_channel(config: &BenchConfig, variant: Variant) -> Measurement {
+    let per_task = config.per_task();
+    let operations = per_task * config.tasks;
+
+    match variant {
+        Variant::Baseline => {
+            measure("bounded", operations, || async {
+                let (tx, mut rx) = tokio::sync::mpsc::channel(1024);
+                let consumer = tokio::spawn(async move { while rx.recv().await.is_some() {} });
+                join_tasks(config.tasks, |_| {
+                    let tx = tx.clone();
+                    async move {
+                        for n in 0..per_task {
+                            let _ = tx.send(n).await;
+                        }
+                    }
+                })
+                .await;
+                drop(tx);
+                let _ = consumer.await;
+            })
+            .await
+        }
+        Variant::Candidate => {
+            measure("unbounded", operations, || async {
+                let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+                let consumer = tokio::spawn(async move { while rx.recv().await.is_some() {} });
+                join_tasks(config.tasks, |_| {
+                    let tx = tx.clone();
+                    async move {
+                        for n in 0..per_task {
+                            let _ = tx.send(n);
+                        }
+                    }
+                })
+                .await;
+                drop(tx);
+                let _ = consumer.await;
+            })
+            .await
+        }
+    }
+}
+
+/// One caller awaiting each [`RequestHandler`] response in turn vs many
+/// callers keeping requests in flight
+pub async fn serial_vs_concurrent_requests(config: &BenchConfig) -> Comparison {
+    Comparison {
+        workload: "request handler",
+        baseline: request_handler(config, Variant::Baseline).await,
+        candidate: request_handler(config, Variant::Candidate).await,
+    }
+}
+
+/// One variant of [`serial_vs_concurrent_requests`]
+pub async fn request_handler(config: &BenchConfig, variant: Variant) -> Measurement {
+    let per_task = config.per_task();
+    let operations = per_task * config.tasks;
+    let handler = RequestHandler::new(|n: usize| async move { n.wrapping_mul(2) });
+
+    match variant {
+        Variant::Baseline => {
+            measure("serial", operations, || async {
+                for n in 0..operations {
+                    let _ = handler.request(n).await;
+                }
+            })
+            .await
+        }
+        Variant::Candidate => {
+            measure("concurrent", operations, || {
+                join_tasks(config.tasks, |_| {
+                    let handler = handler.clone();
+                    async move {
+                        for n in 0..per_task {
+                            let _ = handler.request(n).await;
+                        }
+                    }
+                })
+            })
+            .await
+        }
+    }
+}
+
+/// Many small writes to a file, directly vs through a `BufWriter`
+///
+/// Each variant writes `operations / 100` 64-byte records to a file in the
+/// system temporary directory, which is removed afterwards. Its
+/// measurements count those writes as the operations.
+pub async fn buffered_vs_unbuffered_io(config: &BenchConfig) -> std::io::Result<Comparison> {
+    Ok(Comparison {
+        workload: "file writes",
+        baseline: file_writes(config, Variant::Baseline).await?,
+        candidate: file_writes(config, Variant::Candidate).await?,
+    })
+}
+
+/// One variant of [`buffered_vs_unbuffered_io`]
+pub async fn file_writes(config: &BenchConfig, variant: Variant) -> std::io::Result<Measurement> {
+    let writes = (config.operations / 100).max(1);
+    // Unique per call, so concurrent runs in one process don't share a file
+    static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "tokio_patterns_bench_{}_{}",
+        std::process::id(),
+        NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let file = tokio::fs::File::create(&path).await?;
+    let measurement = match variant {
+        Variant::Baseline => write_records("unbuffered", file, writes).await,
+        Variant::Candidate => {
+            let file = tokio::io::BufWriter::new(file);
+            write_records("buffered", file, writes).await
+        }
+    };
+    let _ = tokio::fs::remove_file(&path).await;
+    measurement
+}
+
+async fn write_records<W>(
+    name: &'static str,
+    mut file: W,
+    writes: usize,
+) -> std::io::Result<Measurement>
+where
+    W: AsyncWrite + Unpin,
+{
+    let record = [b'x'; 64];
+    let started = Instant::now();
+    for _ in 0..writes {
+        file.write_all(&record).await?;
+    }
+    file.flush().await?;
+    Ok(Measurement {
+        name,
+        operations: writes,
+        elapsed: started.elapsed(),
+    })
+}
+
+/// Runs every comparison in turn; the I/O one is left out if the temporary
+/// file can't be written
+pub async fn run_all(config: &BenchConfig) -> Vec<Comparison> {
+    let mut comparisons = vec![
+        mutex_vs_sharded_counter(config).await,
+        bounded_vs_unbounded_channel(config).await,
+        serial_vs_concurrent_requests(config).await,
+    ];
+    if let Ok(io) = buffered_vs_unbuffered_io(config).await {
+        comparisons.push(io);
+    }
+    comparisons
+}
@@ -0,0 +1,275 @@
+//! An mpsc channel that resizes itself to fit its traffic
+//!
+//! Experimental. A bounded queue's capacity is usually a guess: too small
+//! and producers stall on every burst, too large and memory sits idle
+//! holding a backlog nobody needed. [`AdaptiveChannel`] watches both over a
+//! sampling window. When producers spent long enough waiting for room it
+//! doubles the capacity, and when the queue never got close to full it
+//! halves it, within configured bounds.
+//!
+//! Tokio's queues can't be resized, so a resize swaps a new queue in behind
+//! the senders. The receiver drains the old queue before moving on, so
+//! messages from one sender still arrive in order.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::time::Instant;
+
+/// Bounds and thresholds for an [`AdaptiveChannel`]
+#[derive(Debug, Clone)]
+pub struct AdaptiveChannelConfig {
+    /// Capacity to start with
+    pub initial: usize,
+    pub min: usize,
+    pub max: usize,
+    /// How long traffic is sampled before each resize decision
+    pub window: Duration,
+    /// Grow once producers together waited this long in a window
+    pub grow_after_stall: Duration,
+    /// Shrink when the fullest the queue got in a window is below this
+    /// fraction of its capacity (0.0-1.0)
+    pub shrink_below: f64,
+}
+
+impl Default for AdaptiveChannelConfig {
+    /// Starts at 32 and stays between 8 and 4096, deciding every second;
+    /// 10ms of stalls grows it and never filling a quarter shrinks it
+    fn default() -> Self {
+        Self {
+            initial: 32,
+            min: 8,
+            max: 4096,
+            window: Duration::from_secs(1),
+            grow_after_stall: Duration::from_millis(10),
+            shrink_below: 0.25,
+        }
+    }
+}
+
+/// Why an [`AdaptiveChannel`] resized
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResizeReason {
+    /// Producers waited for room this long in total during the window
+    Stalled { stalled: Duration },
+    /// The queue held at most this many messages during the window
+    Underused { peak: usize },
+}
+
+/// A capacity change, passed to [`AdaptiveChannel::on_resize`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResizeEvent {
+    pub from: usize,
+    pub to: usize,
+    pub reason: ResizeReason,
+}
+
+type ResizeHandler = Arc<dyn Fn(&ResizeEvent) + Send + Sync>;
+
+/// Builds a channel whose capacity follows its traffic
+///
+/// Resize decisions are made by senders, at the first send after each
+/// window ends, so an idle channel keeps its capacity until it is used
+/// again.
+///
+/// ```
+/// # async fn example() {
+/// use tokio_tutorial_patterns::channels::{AdaptiveChannel, AdaptiveChannelConfig};
+///
+/// let (tx, mut rx) = AdaptiveChannel::new(AdaptiveChannelConfig::default())
+///     .on_resize(|event| println!("queue resized from {} to {}", event.from, event.to))
+///     .build();
+/// tokio::spawn(async move {
+///     for reading in 0..10_000u32 {
+///         let _ = tx.send(reading).await;
+///     }
+/// });
+/// while let Some(reading) = rx.recv().await {
+///     // ... process the reading ...
+/// #   let _ = reading;
+/// }
+/// # }
+/// ```
+pub struct AdaptiveChannel {
+    config: AdaptiveChannelConfig,
+    on_resize: Option<ResizeHandler>,
+}
+
+impl AdaptiveChannel {
+    /// # Panics
+    ///
+    /// Panics if `config.min` is zero or `config.initial` is outside
+    /// `config.min..=config.max`.
+    pub fn new(config: AdaptiveChannelConfig) -> Self {
+        assert!(config.min > 0, "`min` must be non-zero");
+        assert!(
+            (config.min..=config.max).contains(&config.initial),
+            "`initial` must be between `min` and `max`"
+        );
+        Self {
+            config,
+            on_resize: None,
+        }
+    }
+
+    /// Called with every resize, from the send that triggered it
+    pub fn on_resize(mut self, handler: impl Fn(&ResizeEvent) + Send + Sync + 'static) -> Self {
+        self.on_resize = Some(Arc::new(handler));
+        self
+    }
+
+    pub fn build<T>(self) -> (AdaptiveSender<T>, AdaptiveReceiver<T>) {
+        let (tx, rx) = mpsc::channel(self.config.initial);
+        let (queues_tx, queues_rx) = mpsc::unbounded_channel();
+        let shared = Shared {
+            state: Mutex::new(State {
+                tx,
+                window_start: Instant::now(),
+                stalled: Duration::ZERO,
+                peak: 0,
+            }),
+            queues: queues_tx,
+            config: self.config,
+            on_resize: self.on_resize,
+        };
+        (
+            AdaptiveSender {
+                shared: Arc::new(shared),
+            },
+            AdaptiveReceiver {
+                rx,
+                queues: queues_rx,
+            },
+        )
+    }
+}
+
+struct State<T> {
+    /// The queue new messages go to
+    tx: mpsc::Sender<T>,
+    window_start: Instant,
+    stalled: Duration,
+    peak: usize,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Hands the receiver each queue swapped in, in order
+    queues: mpsc::UnboundedSender<mpsc::Receiver<T>>,
+    config: AdaptiveChannelConfig,
+    on_resize: Option<ResizeHandler>,
+}
+
+impl<T> Shared<T> {
+    /// Accounts for a send on `tx`, resizing if a window has ended
+    fn record(&self, tx: &mpsc::Sender<T>, stalled: Duration) {
+        let event = {
+            let mut state = self.state.lock().unwrap();
+            // A send that started before a resize says nothing about the
+            // new queue
+            if !tx.same_channel(&state.tx) {
+                return;
+            }
+            state.stalled += stalled;
+            state.peak = state.peak.max(tx.max_capacity() - tx.capacity());
+            if state.window_start.elapsed() < self.config.window {
+                return;
+            }
+            let event = self.decide(&state).and_then(|event| {
+                let (tx, rx) = mpsc::channel(event.to);
+                // Nothing to resize for once the receiver is gone
+                self.queues.send(rx).ok()?;
+                state.tx = tx;
+                Some(event)
+            });
+            state.window_start = Instant::now();
+            state.stalled = Duration::ZERO;
+            state.peak = 0;
+            event
+        };
+        if let (Some(event), Some(handler)) = (event, &self.on_resize) {
+            handler(&event);
+        }
+    }
+
+    fn decide(&self, state: &State<T>) -> Option<ResizeEvent> {
+        let config = &self.config;
+        let from = state.tx.max_capacity();
+        let (to, reason) = if state.stalled >= config.grow_after_stall {
+            let reason = ResizeReason::Stalled {
+                stalled: state.stalled,
+            };
+            ((from * 2).min(config.max), reason)
+        } else if (state.peak as f64) < from as f64 * config.shrink_below {
+            let reason = ResizeReason::Underused { peak: state.peak };
+            ((from / 2).max(config.min), reason)
+        } else {
+            return None;
+        };
+        (to != from).then_some(ResizeEvent { from, to, reason })
+    }
+}
+
+/// Sending half of an [`AdaptiveChannel`]
+pub struct AdaptiveSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for AdaptiveSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> AdaptiveSender<T> {
+    /// Sends `msg`, waiting for room like [`mpsc::Sender::send`]
+    pub async fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        let tx = self.current();
+        let msg = match tx.try_send(msg) {
+            Ok(()) => {
+                self.shared.record(&tx, Duration::ZERO);
+                return Ok(());
+            }
+            Err(TrySendError::Full(msg)) => msg,
+            Err(TrySendError::Closed(msg)) => return Err(SendError(msg)),
+        };
+        let started = Instant::now();
+        tx.send(msg).await?;
+        self.shared.record(&tx, started.elapsed());
+        Ok(())
+    }
+
+    /// The current queue's capacity
+    pub fn capacity(&self) -> usize {
+        self.current().max_capacity()
+    }
+
+    fn current(&self) -> mpsc::Sender<T> {
+        self.shared.state.lock().unwrap().tx.clone()
+    }
+}
+
+/// Receiving half of an [`AdaptiveChannel`]
+pub struct AdaptiveReceiver<T> {
+    rx: mpsc::Receiver<T>,
+    queues: mpsc::UnboundedReceiver<mpsc::Receiver<T>>,
+}
+
+impl<T> AdaptiveReceiver<T> {
+    /// Receives the next message, or `None` once every sender is gone
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            if let Some(msg) = self.rx.recv().await {
+                return Some(msg);
+            }
+            // A replaced queue closes only after its successor is queued
+            match self.queues.try_recv() {
+                Ok(rx) => self.rx = rx,
+                Err(_) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! Receiving channel messages in batches
+
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+
+/// Groups messages from an mpsc receiver into batches
+///
+/// A batch is handed out once it holds `max_size` messages or `max_latency`
+/// has passed since its first message arrived, whichever comes first, so a
+/// trickle of messages is never held back for long. This mirrors
+/// [`crate::streams::chunks_timeout`] on the stream side.
+#[derive(Debug)]
+pub struct BatchReceiver<T> {
+    rx: mpsc::Receiver<T>,
+    max_size: usize,
+    max_latency: Duration,
+}
+
+impl<T> BatchReceiver<T> {
+    /// # Panics
+    ///
+    /// Panics if `max_size` is zero.
+    pub fn new(rx: mpsc::Receiver<T>, max_size: usize, max_latency: Duration) -> Self {
+        assert!(max_size > 0, "`max_size` must be non-zero");
+        Self {
+            rx,
+            max_size,
+            max_latency,
+        }
+    }
+
+    /// Waits for the next batch, or `None` once the channel is closed and
+    /// drained
+    ///
+    /// Not cancel safe: dropping the future part way through a batch loses
+    /// the messages gathered so far.
+    pub async fn recv_batch(&mut self) -> Option<Vec<T>> {
+        let mut batch = Vec::with_capacity(self.max_size);
+        if self.rx.recv_many(&mut batch, self.max_size).await == 0 {
+            return None;
+        }
+
+        let deadline = Instant::now() + self.max_latency;
+        while batch.len() < self.max_size {
+            let remaining = self.max_size - batch.len();
+            tokio::select! {
+                received = self.rx.recv_many(&mut batch, remaining) => {
+                    if received == 0 {
+                        break;
+                    }
+                }
+                _ = sleep_until(deadline) => break,
+            }
+        }
+        Some(batch)
+    }
+
+    pub fn into_inner(self) -> mpsc::Receiver<T> {
+        self.rx
+    }
+}
@@ -0,0 +1,108 @@
+//! Sends that give up instead of waiting indefinitely on a full queue
+
+use super::TracedSender;
+use crate::select::Deadline;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::Instant;
+
+/// What happened to a message sent with [`SenderExt`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[must_use]
+pub enum SendOutcome<T> {
+    /// Queued after waiting this long for room
+    Sent { waited: Duration },
+    /// The queue was still full at the deadline, or at once for
+    /// [`send_or_drop`](SenderExt::send_or_drop)
+    Full(T),
+    /// The receiver is gone
+    Closed(T),
+}
+
+impl<T> SendOutcome<T> {
+    pub fn is_sent(&self) -> bool {
+        matches!(self, SendOutcome::Sent { .. })
+    }
+
+    /// The message back, unless it was sent
+    pub fn into_inner(self) -> Option<T> {
+        match self {
+            SendOutcome::Sent { .. } => None,
+            SendOutcome::Full(msg) | SendOutcome::Closed(msg) => Some(msg),
+        }
+    }
+}
+
+/// Bounded-wait sends for the crate's channel senders
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::channels::{SendOutcome, SenderExt};
+/// use tokio_tutorial_patterns::select::Deadline;
+///
+/// let (tx, _rx) = tokio::sync::mpsc::channel(16);
+/// match tx.send_deadline("reading", Deadline::after(Duration::from_millis(50))).await {
+///     SendOutcome::Sent { waited } => println!("queued after {:?}", waited),
+///     SendOutcome::Full(_) => println!("consumer is behind, dropping a reading"),
+///     SendOutcome::Closed(_) => println!("consumer is gone"),
+/// }
+/// # }
+/// ```
+pub trait SenderExt<T> {
+    /// Sends `msg`, waiting for room until `deadline` at the latest
+    ///
+    /// A message is still sent past the deadline if there is room at once.
+    fn send_deadline(
+        &self,
+        msg: T,
+        deadline: Deadline,
+    ) -> impl Future<Output = SendOutcome<T>> + Send;
+
+    /// Sends `msg` only if there is room right now
+    fn send_or_drop(&self, msg: T) -> SendOutcome<T>;
+}
+
+impl<T: Send> SenderExt<T> for mpsc::Sender<T> {
+    async fn send_deadline(&self, msg: T, deadline: Deadline) -> SendOutcome<T> {
+        let started = Instant::now();
+        // The reservation is polled before the deadline, so room wins a tie
+        match tokio::time::timeout_at(deadline.instant(), self.reserve()).await {
+            Ok(Ok(permit)) => {
+                permit.send(msg);
+                SendOutcome::Sent {
+                    waited: started.elapsed(),
+                }
+            }
+            Ok(Err(_)) => SendOutcome::Closed(msg),
+            Err(_) => SendOutcome::Full(msg),
+        }
+    }
+
+    fn send_or_drop(&self, msg: T) -> SendOutcome<T> {
+        match self.try_send(msg) {
+            Ok(()) => SendOutcome::Sent {
+                waited: Duration::ZERO,
+            },
+            Err(TrySendError::Full(msg)) => SendOutcome::Full(msg),
+            Err(TrySendError::Closed(msg)) => SendOutcome::Closed(msg),
+        }
+    }
+}
+
+/// With the `tracing` feature these are traced like [`TracedSender::send`]
+impl<T: Send> SenderExt<T> for TracedSender<T> {
+    async fn send_deadline(&self, msg: T, deadline: Deadline) -> SendOutcome<T> {
+        let outcome = self.inner().send_deadline(msg, deadline).await;
+        self.trace_outcome(&outcome);
+        outcome
+    }
+
+    fn send_or_drop(&self, msg: T) -> SendOutcome<T> {
+        let outcome = self.inner().send_or_drop(msg);
+        self.trace_outcome(&outcome);
+        outcome
+    }
+}
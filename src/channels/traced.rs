@@ -0,0 +1,105 @@
+//! An mpsc channel whose sends and receives show up in traces
+
+use super::SendOutcome;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+
+/// Creates a bounded mpsc channel named `name`
+///
+/// With the `tracing` feature every send and receive emits a trace-level
+/// event with `channel` (the name) and `queued` (messages waiting) fields,
+/// so a filling channel is easy to spot. Without the feature the wrappers
+/// cost nothing over plain `mpsc`.
+pub fn traced_channel<T>(name: &str, buffer: usize) -> (TracedSender<T>, TracedReceiver<T>) {
+    let (tx, rx) = mpsc::channel(buffer);
+    let name: Arc<str> = name.into();
+    (
+        TracedSender {
+            tx,
+            name: name.clone(),
+        },
+        TracedReceiver { rx, name },
+    )
+}
+
+/// Sending half of a [`traced_channel`]
+#[derive(Debug)]
+pub struct TracedSender<T> {
+    tx: mpsc::Sender<T>,
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    name: Arc<str>,
+}
+
+impl<T> Clone for TracedSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            name: self.name.clone(),
+        }
+    }
+}
+
+impl<T> TracedSender<T> {
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let result = self.tx.send(value).await;
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(()) => tracing::trace!(channel = %self.name, queued = self.queued(), "send"),
+            Err(_) => tracing::debug!(channel = %self.name, "send on closed channel"),
+        }
+        result
+    }
+
+    /// Messages sent but not yet received
+    pub fn queued(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    pub fn inner(&self) -> &mpsc::Sender<T> {
+        &self.tx
+    }
+
+    /// Traces a [`SenderExt`](super::SenderExt) send like [`send`](Self::send)
+    pub(super) fn trace_outcome(&self, outcome: &SendOutcome<T>) {
+        #[cfg(feature = "tracing")]
+        match outcome {
+            SendOutcome::Sent { waited } => {
+                tracing::trace!(channel = %self.name, queued = self.queued(), ?waited, "send")
+            }
+            SendOutcome::Full(_) => {
+                tracing::debug!(channel = %self.name, "send dropped on full channel")
+            }
+            SendOutcome::Closed(_) => {
+                tracing::debug!(channel = %self.name, "send on closed channel")
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = outcome;
+    }
+}
+
+/// Receiving half of a [`traced_channel`]
+#[derive(Debug)]
+pub struct TracedReceiver<T> {
+    rx: mpsc::Receiver<T>,
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    name: Arc<str>,
+}
+
+impl<T> TracedReceiver<T> {
+    /// Receives the next message, or `None` once every sender is gone
+    pub async fn recv(&mut self) -> Option<T> {
+        let value = self.rx.recv().await;
+        #[cfg(feature = "tracing")]
+        match &value {
+            Some(_) => tracing::trace!(channel = %self.name, queued = self.rx.len(), "recv"),
+            None => tracing::debug!(channel = %self.name, "channel closed"),
+        }
+        value
+    }
+
+    pub fn into_inner(self) -> mpsc::Receiver<T> {
+        self.rx
+    }
+}
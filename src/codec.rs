@@ -0,0 +1,416 @@
+//! Reusable frame codecs for `tokio_util::codec`
+//!
+//! [`LengthPrefixed`] and [`Delimited`] split a byte stream into frames;
+//! with the `json` or `bincode` feature, [`SerdeCodec`] layers typed
+//! payloads on top of either. All of them report failures as one
+//! [`CodecError`], and the helpers at the bottom wrap any
+//! `AsyncRead + AsyncWrite` in a `Framed` with the usual codec, so a
+//! protocol can pick its framing without writing a decoder.
+//!
+//! ```
+//! # async fn example() -> Result<(), tokio_tutorial_patterns::codec::CodecError> {
+//! use futures::{SinkExt, StreamExt};
+//! use tokio_tutorial_patterns::codec;
+//!
+//! let (client, server) = tokio::io::duplex(1024);
+//! let mut client = codec::length_prefixed(client);
+//! let mut server = codec::length_prefixed(server);
+//!
+//! client.send(bytes::Bytes::from_static(b"hello")).await?;
+//! assert_eq!(&server.next().await.unwrap()?[..], b"hello");
+//! # Ok(())
+//! # }
+//! ```
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+/// Frames larger than this are rejected unless a codec is told otherwise
+pub const DEFAULT_MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// Why a frame couldn't be read or written
+#[derive(Debug)]
+pub enum CodecError {
+    Io(std::io::Error),
+    /// A frame, announced or buffered, exceeds the codec's limit
+    FrameTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// The input ended in the middle of a frame
+    Truncated,
+    /// A frame's payload couldn't be serialized or deserialized
+    Payload(String),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "I/O error: {}", e),
+            CodecError::FrameTooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds the {} byte limit", len, max)
+            }
+            CodecError::Truncated => write!(f, "input ended mid-frame"),
+            CodecError::Payload(e) => write!(f, "invalid payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CodecError {
+    fn from(e: std::io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+/// Frames preceded by their length as a big-endian `u32`
+#[derive(Debug, Clone)]
+pub struct LengthPrefixed {
+    max_frame_len: usize,
+}
+
+impl LengthPrefixed {
+    pub fn new() -> Self {
+        Self {
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+
+    /// Rejects frames longer than `max` bytes, in both directions
+    pub fn max_frame_len(mut self, max: usize) -> Self {
+        self.max_frame_len = max;
+        self
+    }
+}
+
+impl Default for LengthPrefixed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for LengthPrefixed {
+    type Item = BytesMut;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, CodecError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(src[..4].try_into().unwrap()) as usize;
+        if len > self.max_frame_len {
+            return Err(CodecError::FrameTooLarge {
+                len,
+                max: self.max_frame_len,
+            });
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        Ok(Some(src.split_to(len)))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, CodecError> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(CodecError::Truncated),
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> Encoder<B> for LengthPrefixed {
+    type Error = CodecError;
+
+    fn encode(&mut self, frame: B, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let frame = frame.as_ref();
+        if frame.len() > self.max_frame_len || frame.len() > u32::MAX as usize {
+            return Err(CodecError::FrameTooLarge {
+                len: frame.len(),
+                max: self.max_frame_len.min(u32::MAX as usize),
+            });
+        }
+        dst.reserve(4 + frame.len());
+        dst.put_u32(frame.len() as u32);
+        dst.put_slice(frame);
+        Ok(())
+    }
+}
+
+/// Frames ended by a delimiter, such as `\n` or `\r\n`
+///
+/// Decoded frames don't include the delimiter. Bytes left after the last
+/// delimiter at the end of input form one final frame, as with lines.
+#[derive(Debug, Clone)]
+pub struct Delimited {
+    delimiter: Vec<u8>,
+    max_frame_len: usize,
+    /// Where to resume searching for the delimiter
+    searched: usize,
+}
+
+impl Delimited {
+    /// # Panics
+    ///
+    /// Panics if `delimiter` is empty.
+    pub fn new(delimiter: impl Into<Vec<u8>>) -> Self {
+        let delimiter = delimiter.into();
+        assert!(!delimiter.is_empty(), "`delimiter` must not be empty");
+        Self {
+            delimiter,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            searched: 0,
+        }
+    }
+
+    /// Frames ended by `\n`
+    pub fn lines() -> Self {
+        Self::new(b"\n".to_vec())
+    }
+
+    /// Rejects frames longer than `max` bytes, delimiter excluded
+    pub fn max_frame_len(mut self, max: usize) -> Self {
+        self.max_frame_len = max;
+        self
+    }
+}
+
+impl Decoder for Delimited {
+    type Item = BytesMut;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, CodecError> {
+        let found = src[self.searched..]
+            .windows(self.delimiter.len())
+            .position(|window| window == self.delimiter.as_slice());
+        match found {
+            Some(at) => {
+                let len = self.searched + at;
+                self.searched = 0;
+                if len > self.max_frame_len {
+                    return Err(CodecError::FrameTooLarge {
+                        len,
+                        max: self.max_frame_len,
+                    });
+                }
+                let frame = src.split_to(len);
+                src.advance(self.delimiter.len());
+                Ok(Some(frame))
+            }
+            None => {
+                if src.len() > self.max_frame_len + self.delimiter.len() {
+                    return Err(CodecError::FrameTooLarge {
+                        len: src.len(),
+                        max: self.max_frame_len,
+                    });
+                }
+                // A delimiter may straddle what's buffered and what comes next
+                self.searched = src.len().saturating_sub(self.delimiter.len() - 1);
+                Ok(None)
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, CodecError> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                self.searched = 0;
+                Ok(Some(src.split()))
+            }
+        }
+    }
+}
+
+impl<B: AsRef<[u8]>> Encoder<B> for Delimited {
+    type Error = CodecError;
+
+    fn encode(&mut self, frame: B, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let frame = frame.as_ref();
+        if frame.len() > self.max_frame_len {
+            return Err(CodecError::FrameTooLarge {
+                len: frame.len(),
+                max: self.max_frame_len,
+            });
+        }
+        dst.reserve(frame.len() + self.delimiter.len());
+        dst.put_slice(frame);
+        dst.put_slice(&self.delimiter);
+        Ok(())
+    }
+}
+
+/// How [`SerdeCodec`] turns values into frame payloads and back
+#[cfg(any(feature = "json", feature = "bincode"))]
+pub trait Format {
+    fn to_bytes<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, String>;
+    fn from_bytes<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, String>;
+}
+
+/// Payloads as JSON documents
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Format for Json {
+    fn to_bytes<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(value).map_err(|e| e.to_string())
+    }
+
+    fn from_bytes<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+        serde_json::from_slice(bytes).map_err(|e| e.to_string())
+    }
+}
+
+/// Payloads in bincode's compact binary encoding, with its standard config
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Format for Bincode {
+    fn to_bytes<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, String> {
+        ::bincode::serde::encode_to_vec(value, ::bincode::config::standard())
+            .map_err(|e| e.to_string())
+    }
+
+    fn from_bytes<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+        let (value, read) =
+            ::bincode::serde::decode_from_slice(bytes, ::bincode::config::standard())
+                .map_err(|e| e.to_string())?;
+        if read != bytes.len() {
+            return Err(format!("{} trailing bytes", bytes.len() - read));
+        }
+        Ok(value)
+    }
+}
+
+/// Typed frames: values of `T` serialized with `F`, framed by `C`
+///
+/// A frame that fails to deserialize is reported as [`CodecError::Payload`].
+#[cfg(any(feature = "json", feature = "bincode"))]
+pub struct SerdeCodec<T, F, C = LengthPrefixed> {
+    framing: C,
+    _marker: std::marker::PhantomData<fn(T) -> (T, F)>,
+}
+
+/// JSON values, one per frame
+#[cfg(feature = "json")]
+pub type JsonCodec<T, C = LengthPrefixed> = SerdeCodec<T, Json, C>;
+
+/// Bincode-encoded values, one per frame
+#[cfg(feature = "bincode")]
+pub type BincodeCodec<T, C = LengthPrefixed> = SerdeCodec<T, Bincode, C>;
+
+#[cfg(any(feature = "json", feature = "bincode"))]
+impl<T, F, C> SerdeCodec<T, F, C> {
+    pub fn new(framing: C) -> Self {
+        Self {
+            framing,
+            _marker: std::marker::PhantomData,
+        }
+    }
+}
+
+#[cfg(any(feature = "json", feature = "bincode"))]
+impl<T, F> Default for SerdeCodec<T, F> {
+    fn default() -> Self {
+        Self::new(LengthPrefixed::new())
+    }
+}
+
+#[cfg(any(feature = "json", feature = "bincode"))]
+impl<T, F, C> Decoder for SerdeCodec<T, F, C>
+where
+    T: serde::de::DeserializeOwned,
+    F: Format,
+    C: Decoder<Item = BytesMut, Error = CodecError>,
+{
+    type Item = T;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<T>, CodecError> {
+        match self.framing.decode(src)? {
+            Some(frame) => F::from_bytes(&frame).map(Some).map_err(CodecError::Payload),
+            None => Ok(None),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<T>, CodecError> {
+        match self.framing.decode_eof(src)? {
+            Some(frame) => F::from_bytes(&frame).map(Some).map_err(CodecError::Payload),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(any(feature = "json", feature = "bincode"))]
+impl<T, F, C> Encoder<T> for SerdeCodec<T, F, C>
+where
+    T: serde::Serialize,
+    F: Format,
+    C: Encoder<bytes::Bytes, Error = CodecError>,
+{
+    type Error = CodecError;
+
+    fn encode(&mut self, value: T, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let payload = F::to_bytes(&value).map_err(CodecError::Payload)?;
+        self.framing.encode(bytes::Bytes::from(payload), dst)
+    }
+}
+
+/// Wraps `io` in `codec`, for reading and writing frames on one object
+pub fn framed<T, C>(io: T, codec: C) -> Framed<T, C>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    Framed::new(io, codec)
+}
+
+/// `io` carrying [`LengthPrefixed`] frames
+pub fn length_prefixed<T>(io: T) -> Framed<T, LengthPrefixed>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    framed(io, LengthPrefixed::new())
+}
+
+/// `io` carrying frames ended by `delimiter`
+pub fn delimited<T>(io: T, delimiter: impl Into<Vec<u8>>) -> Framed<T, Delimited>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    framed(io, Delimited::new(delimiter))
+}
+
+/// `io` carrying one JSON-encoded `M` per [`LengthPrefixed`] frame
+#[cfg(feature = "json")]
+pub fn json<T, M>(io: T) -> Framed<T, JsonCodec<M>>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    framed(io, JsonCodec::default())
+}
+
+/// `io` carrying one bincode-encoded `M` per [`LengthPrefixed`] frame
+#[cfg(feature = "bincode")]
+pub fn bincode<T, M>(io: T) -> Framed<T, BincodeCodec<M>>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    framed(io, BincodeCodec::default())
+}
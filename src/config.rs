@@ -0,0 +1,400 @@
+//! Configuration files that reload themselves while the program runs
+//!
+//! A [`ConfigLoader`] knows where a file lives and how to turn its contents
+//! into a value: any parse function, or serde with the `json` and `toml`
+//! features. [`ConfigLoader::watch`] loads it once, then keeps checking the
+//! file and publishes every version that parses and passes validation over
+//! a [`ConfigWatch`]. A version that doesn't is reported and otherwise
+//! ignored, so readers keep the last good configuration until the file is
+//! fixed.
+//!
+//! Changes are picked up by polling the file's contents, or with the
+//! `notify` feature by file-system events.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), tokio_tutorial_patterns::config::ConfigError> {
+//! use std::time::Duration;
+//! use tokio_tutorial_patterns::config::ConfigLoader;
+//!
+//! let config = ConfigLoader::new("workers.conf", |s: &str| s.trim().parse::<usize>())
+//!     .validate(|workers: &usize| match *workers {
+//!         0 => Err("need at least one worker"),
+//!         _ => Ok(()),
+//!     })
+//!     .poll_interval(Duration::from_secs(5))
+//!     .watch()
+//!     .await?;
+//!
+//! let mut updates = config.subscribe();
+//! while updates.changed().await.is_ok() {
+//!     println!("now running {} workers", config.current());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::spawning::spawn_traced;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+#[cfg(any(feature = "json", feature = "toml"))]
+use serde::de::DeserializeOwned;
+
+/// Why a configuration could not be loaded
+#[derive(Debug, Clone)]
+pub enum ConfigError {
+    /// The file could not be read, or watched
+    Io(Arc<std::io::Error>),
+    /// The contents could not be parsed
+    Parse(String),
+    /// The contents parsed but failed validation
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "config io error: {}", e),
+            ConfigError::Parse(msg) => write!(f, "config parse error: {}", msg),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::Io(Arc::new(e))
+    }
+}
+
+type ParseFn<T> = Arc<dyn Fn(&str) -> Result<T, ConfigError> + Send + Sync>;
+type ValidateFn<T> = Arc<dyn Fn(&T) -> Result<(), ConfigError> + Send + Sync>;
+
+/// Where a configuration lives and how to read it
+pub struct ConfigLoader<T> {
+    path: PathBuf,
+    parse: ParseFn<T>,
+    validate: Option<ValidateFn<T>>,
+    poll_interval: Duration,
+    #[cfg(feature = "notify")]
+    use_notify: bool,
+}
+
+impl<T: Send + Sync + 'static> ConfigLoader<T> {
+    /// Reads `path` with `parse`; checks for changes every second
+    pub fn new<F, E>(path: impl Into<PathBuf>, parse: F) -> Self
+    where
+        F: Fn(&str) -> Result<T, E> + Send + Sync + 'static,
+        E: fmt::Display,
+    {
+        Self {
+            path: path.into(),
+            parse: Arc::new(move |s| parse(s).map_err(|e| ConfigError::Parse(e.to_string()))),
+            validate: None,
+            poll_interval: Duration::from_secs(1),
+            #[cfg(feature = "notify")]
+            use_notify: false,
+        }
+    }
+
+    /// Reads `path` as JSON
+    #[cfg(feature = "json")]
+    pub fn json(path: impl Into<PathBuf>) -> Self
+    where
+        T: DeserializeOwned,
+    {
+        Self::new(path, |s| serde_json::from_str(s))
+    }
+
+    /// Reads `path` as TOML
+    #[cfg(feature = "toml")]
+    pub fn toml(path: impl Into<PathBuf>) -> Self
+    where
+        T: DeserializeOwned,
+    {
+        Self::new(path, |s| toml::from_str(s))
+    }
+
+    /// Rejects versions for which `check` fails, as if they didn't parse
+    pub fn validate<F, E>(mut self, check: F) -> Self
+    where
+        F: Fn(&T) -> Result<(), E> + Send + Sync + 'static,
+        E: fmt::Display,
+    {
+        self.validate = Some(Arc::new(move |value| {
+            check(value).map_err(|e| ConfigError::Invalid(e.to_string()))
+        }));
+        self
+    }
+
+    /// How often the file is checked for changes when polling
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        assert!(!interval.is_zero(), "`interval` must be non-zero");
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Reloads on file-system events instead of polling
+    ///
+    /// The file's directory is watched, so files replaced by renaming a new
+    /// one over them, as many editors and deploy tools do, are picked up.
+    #[cfg(feature = "notify")]
+    pub fn notify(mut self) -> Self {
+        self.use_notify = true;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads, parses and validates the file once
+    pub async fn load(&self) -> Result<T, ConfigError> {
+        let contents = tokio::fs::read_to_string(&self.path).await?;
+        self.decode(&contents)
+    }
+
+    fn decode(&self, contents: &str) -> Result<T, ConfigError> {
+        let value = (self.parse)(contents)?;
+        if let Some(validate) = &self.validate {
+            validate(&value)?;
+        }
+        Ok(value)
+    }
+
+    /// Loads the file, then keeps watching it in the background
+    ///
+    /// Fails if the first load does; after that, bad versions are only
+    /// reported through [`ConfigWatch::last_error`] and logs. Must be called
+    /// from within a Tokio runtime.
+    pub async fn watch(self) -> Result<ConfigWatch<T>, ConfigError> {
+        let contents = tokio::fs::read_to_string(&self.path).await?;
+        let initial = self.decode(&contents)?;
+        let (tx, rx) = watch::channel(Arc::new(initial));
+
+        #[cfg(feature = "notify")]
+        let events = if self.use_notify {
+            Some(file_events(&self.path)?)
+        } else {
+            None
+        };
+
+        let reloader = Arc::new(Reloader {
+            loader: self,
+            tx,
+            last_seen: tokio::sync::Mutex::new(Seen {
+                contents,
+                contents_error: None,
+                read_error: None,
+            }),
+            status: Mutex::new(Status {
+                version: 1,
+                last_error: None,
+            }),
+        });
+
+        let background = reloader.clone();
+        #[cfg(feature = "notify")]
+        let task = match events {
+            Some((watcher, events)) => spawn_traced("config watcher", async move {
+                background.follow(watcher, events).await
+            }),
+            None => spawn_traced("config watcher", async move { background.poll().await }),
+        };
+        #[cfg(not(feature = "notify"))]
+        let task = spawn_traced("config watcher", async move { background.poll().await });
+
+        Ok(ConfigWatch { reloader, rx, task })
+    }
+}
+
+struct Status {
+    version: u64,
+    last_error: Option<ConfigError>,
+}
+
+/// What the last check found, so each bad version and each read error is
+/// reported once rather than on every check
+struct Seen {
+    /// Contents of the last version read, good or bad
+    contents: String,
+    /// Why those contents were rejected, if they were
+    contents_error: Option<ConfigError>,
+    /// Why the file couldn't be read, while it can't
+    read_error: Option<String>,
+}
+
+struct Reloader<T> {
+    loader: ConfigLoader<T>,
+    tx: watch::Sender<Arc<T>>,
+    last_seen: tokio::sync::Mutex<Seen>,
+    status: Mutex<Status>,
+}
+
+impl<T: Send + Sync + 'static> Reloader<T> {
+    async fn poll(&self) {
+        let mut checks = tokio::time::interval(self.loader.poll_interval);
+        checks.tick().await;
+        loop {
+            checks.tick().await;
+            let _ = self.reload(false).await;
+        }
+    }
+
+    #[cfg(feature = "notify")]
+    async fn follow(
+        &self,
+        _watcher: notify::RecommendedWatcher,
+        mut events: tokio::sync::mpsc::Receiver<()>,
+    ) {
+        while events.recv().await.is_some() {
+            let _ = self.reload(false).await;
+        }
+    }
+
+    /// Returns whether a new version was published
+    async fn reload(&self, force: bool) -> Result<bool, ConfigError> {
+        let mut last_seen = self.last_seen.lock().await;
+        let result = match tokio::fs::read_to_string(&self.loader.path).await {
+            Ok(contents) => {
+                if last_seen.read_error.take().is_some() {
+                    // Unchanged contents return early below, so the read
+                    // error has to give way to their verdict here
+                    self.status.lock().unwrap().last_error = last_seen.contents_error.clone();
+                    #[cfg(feature = "tracing")]
+                    tracing::info!(path = %self.loader.path.display(), "config file readable again");
+                    #[cfg(not(feature = "tracing"))]
+                    eprintln!("{}: readable again", self.loader.path.display());
+                }
+                if !force && contents == last_seen.contents {
+                    return Ok(false);
+                }
+                let decoded = self.loader.decode(&contents);
+                last_seen.contents = contents;
+                last_seen.contents_error = decoded.as_ref().err().cloned();
+                decoded
+            }
+            Err(e) => {
+                let error = e.to_string();
+                // Already reported; stay quiet until it changes or clears
+                if !force && last_seen.read_error.as_ref() == Some(&error) {
+                    return Err(e.into());
+                }
+                last_seen.read_error = Some(error);
+                Err(e.into())
+            }
+        };
+
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(value) => {
+                self.tx.send_replace(Arc::new(value));
+                status.version += 1;
+                status.last_error = None;
+                Ok(true)
+            }
+            Err(e) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    path = %self.loader.path.display(),
+                    error = %e,
+                    "keeping last good config"
+                );
+                #[cfg(not(feature = "tracing"))]
+                eprintln!(
+                    "{}: {}; keeping last good config",
+                    self.loader.path.display(),
+                    e
+                );
+                status.last_error = Some(e.clone());
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Forwards file-system events for `path`'s directory as wake-ups
+#[cfg(feature = "notify")]
+fn file_events(
+    path: &Path,
+) -> Result<(notify::RecommendedWatcher, tokio::sync::mpsc::Receiver<()>), ConfigError> {
+    use notify::Watcher;
+
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok() {
+            // A wake-up already queued covers this event too
+            let _ = tx.try_send(());
+        }
+    })
+    .map_err(std::io::Error::other)?;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher
+        .watch(dir, notify::RecursiveMode::NonRecursive)
+        .map_err(std::io::Error::other)?;
+    Ok((watcher, rx))
+}
+
+/// The live configuration; dropping it stops watching the file
+pub struct ConfigWatch<T> {
+    reloader: Arc<Reloader<T>>,
+    rx: watch::Receiver<Arc<T>>,
+    task: JoinHandle<()>,
+}
+
+impl<T: Send + Sync + 'static> ConfigWatch<T> {
+    /// The last good version
+    pub fn current(&self) -> Arc<T> {
+        self.rx.borrow().clone()
+    }
+
+    /// Notified whenever a new good version is published
+    pub fn subscribe(&self) -> watch::Receiver<Arc<T>> {
+        self.rx.clone()
+    }
+
+    /// Good versions published so far, counting the initial load
+    pub fn version(&self) -> u64 {
+        self.reloader.status.lock().unwrap().version
+    }
+
+    /// Why the most recent version was rejected, until a good one replaces it
+    pub fn last_error(&self) -> Option<ConfigError> {
+        self.reloader.status.lock().unwrap().last_error.clone()
+    }
+
+    /// Reloads the file now, even if it hasn't changed, e.g. on `SIGHUP`
+    ///
+    /// Returns the error if the file is bad; the last good version stays
+    /// current either way.
+    pub async fn reload(&self) -> Result<(), ConfigError> {
+        self.reloader.reload(true).await.map(|_| ())
+    }
+}
+
+impl<T> Drop for ConfigWatch<T> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,284 @@
+//! Leader election between tasks or processes competing for one role
+//!
+//! Candidates each start a [`LeaderElection`] against a shared
+//! [`LeaseBackend`]. The backend hands out a lease to one candidate at a
+//! time; the holder keeps renewing it, and everyone else keeps trying to
+//! take it. Whether a candidate currently leads is published over a watch
+//! channel, so work that must only run in one place ("only one instance
+//! runs the scheduler") can start on gaining leadership and stop on losing
+//! it.
+//!
+//! [`MemoryLease`] coordinates tasks within a process; [`LockFileLease`]
+//! coordinates processes on one machine through an advisory file lock.
+
+use crate::io::{FileLock, FileLockGuard};
+use crate::spawning::spawn_traced;
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Where a lease lives and who holds it
+///
+/// Methods are called synchronously from the election task, so they should
+/// be quick; implementations must make taking the lease atomic.
+pub trait LeaseBackend: Send + Sync + 'static {
+    /// Takes the lease for `candidate` for `ttl` if it is free or expired,
+    /// or extends it if `candidate` already holds it; returns whether
+    /// `candidate` holds it now
+    fn try_acquire(&self, candidate: &str, ttl: Duration) -> std::io::Result<bool>;
+
+    /// Gives the lease up if `candidate` holds it
+    fn release(&self, candidate: &str) -> std::io::Result<()>;
+}
+
+struct Holder {
+    candidate: String,
+    expires: Instant,
+}
+
+/// A lease shared by candidates in one process; clones share the lease
+#[derive(Clone, Default)]
+pub struct MemoryLease {
+    holder: Arc<Mutex<Option<Holder>>>,
+}
+
+impl MemoryLease {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The candidate holding an unexpired lease, if any
+    pub fn holder(&self) -> Option<String> {
+        let holder = self.holder.lock().unwrap();
+        holder
+            .as_ref()
+            .filter(|h| h.expires > Instant::now())
+            .map(|h| h.candidate.clone())
+    }
+}
+
+impl LeaseBackend for MemoryLease {
+    fn try_acquire(&self, candidate: &str, ttl: Duration) -> std::io::Result<bool> {
+        let now = Instant::now();
+        let mut holder = self.holder.lock().unwrap();
+        let free = match &*holder {
+            Some(h) => h.candidate == candidate || h.expires <= now,
+            None => true,
+        };
+        if free {
+            *holder = Some(Holder {
+                candidate: candidate.to_string(),
+                expires: now + ttl,
+            });
+        }
+        Ok(free)
+    }
+
+    fn release(&self, candidate: &str) -> std::io::Result<()> {
+        let mut holder = self.holder.lock().unwrap();
+        if holder.as_ref().is_some_and(|h| h.candidate == candidate) {
+            *holder = None;
+        }
+        Ok(())
+    }
+}
+
+/// A lease held as an exclusive [`FileLock`], for processes on one machine
+///
+/// Each candidate must [`open`](Self::open) its own `LockFileLease`. The
+/// operating system drops the lock when the holding process exits, so the
+/// lease never needs to expire and the ttl is ignored.
+pub struct LockFileLease {
+    lock: FileLock,
+    guard: Mutex<Option<FileLockGuard>>,
+}
+
+impl LockFileLease {
+    /// Opens (creating if needed) the lock file
+    pub async fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self {
+            lock: FileLock::open(path).await?,
+            guard: Mutex::new(None),
+        })
+    }
+}
+
+impl LeaseBackend for LockFileLease {
+    fn try_acquire(&self, _candidate: &str, _ttl: Duration) -> std::io::Result<bool> {
+        let mut guard = self.guard.lock().unwrap();
+        if guard.is_none() {
+            *guard = self.lock.try_lock_exclusive()?;
+        }
+        Ok(guard.is_some())
+    }
+
+    fn release(&self, _candidate: &str) -> std::io::Result<()> {
+        self.guard.lock().unwrap().take();
+        Ok(())
+    }
+}
+
+/// Timing for a [`LeaderElection`]
+#[derive(Debug, Clone)]
+pub struct ElectionConfig {
+    /// How long a lease lasts without renewal
+    pub lease: Duration,
+    /// How often the leader renews; well under `lease`, so a slow or
+    /// failed renewal can be retried before the lease runs out
+    pub renew_interval: Duration,
+    /// How often followers try to take the lease
+    pub retry_interval: Duration,
+}
+
+impl Default for ElectionConfig {
+    fn default() -> Self {
+        Self {
+            lease: Duration::from_secs(10),
+            renew_interval: Duration::from_secs(3),
+            retry_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// One candidate's participation in an election, until resigned or dropped
+///
+/// ```
+/// # async fn example() {
+/// use tokio_tutorial_patterns::coordination::{ElectionConfig, LeaderElection, MemoryLease};
+///
+/// let lease = MemoryLease::new();
+/// let election = LeaderElection::start("worker-1", lease.clone(), ElectionConfig::default());
+///
+/// // Runs the scheduler while this candidate leads, and stops it if
+/// // leadership is lost
+/// let finished = election
+///     .run_while_leader(async {
+///         // scheduler loop
+///     })
+///     .await;
+/// # }
+/// ```
+pub struct LeaderElection {
+    candidate: Arc<str>,
+    backend: Arc<dyn LeaseBackend>,
+    leader: Arc<watch::Sender<bool>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl LeaderElection {
+    /// Starts competing for the lease as `candidate`, which must be unique
+    /// among the candidates sharing `backend`
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn start<B: LeaseBackend>(candidate: &str, backend: B, config: ElectionConfig) -> Self {
+        let candidate: Arc<str> = candidate.into();
+        let backend: Arc<dyn LeaseBackend> = Arc::new(backend);
+        let leader = Arc::new(watch::channel(false).0);
+        let task = spawn_traced(
+            "leader election",
+            campaign(candidate.clone(), backend.clone(), leader.clone(), config),
+        );
+
+        Self {
+            candidate,
+            backend,
+            leader,
+            task: Some(task),
+        }
+    }
+
+    pub fn candidate(&self) -> &str {
+        &self.candidate
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.leader.borrow()
+    }
+
+    /// Follows leadership: `true` while this candidate leads
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.leader.subscribe()
+    }
+
+    /// Waits until this candidate leads
+    pub async fn wait_for_leadership(&self) {
+        // The sender lives as long as `self`, so this never fails
+        let _ = self.subscribe().wait_for(|leader| *leader).await;
+    }
+
+    /// Waits for leadership, then runs `work` until it finishes or
+    /// leadership is lost
+    ///
+    /// Returns `None` if `work` was dropped because leadership was lost;
+    /// call again to wait for the next term.
+    pub async fn run_while_leader<F: Future>(&self, work: F) -> Option<F::Output> {
+        let mut leader = self.subscribe();
+        let _ = leader.wait_for(|leader| *leader).await;
+        tokio::select! {
+            output = work => Some(output),
+            _ = leader.wait_for(|leader| !*leader) => None,
+        }
+    }
+
+    /// Stops competing and releases the lease if held
+    pub async fn resign(mut self) -> std::io::Result<()> {
+        if let Some(task) = self.task.take() {
+            task.abort();
+            let _ = task.await;
+        }
+        self.leader.send_replace(false);
+        self.backend.release(&self.candidate)
+    }
+}
+
+impl Drop for LeaderElection {
+    /// Stops competing and releases the lease, as far as that can be done
+    /// without waiting; a lease taken back by a renewal already in progress
+    /// runs out on its own
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+            self.leader.send_replace(false);
+            let _ = self.backend.release(&self.candidate);
+        }
+    }
+}
+
+async fn campaign(
+    candidate: Arc<str>,
+    backend: Arc<dyn LeaseBackend>,
+    leader: Arc<watch::Sender<bool>>,
+    config: ElectionConfig,
+) {
+    // When the lease we hold runs out, if we can't renew it before then
+    let mut expires: Option<Instant> = None;
+    loop {
+        let now = Instant::now();
+        match backend.try_acquire(&candidate, config.lease) {
+            Ok(true) => expires = Some(now + config.lease),
+            Ok(false) => expires = None,
+            Err(e) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(candidate = %candidate, error = %e, "lease renewal failed");
+                #[cfg(not(feature = "tracing"))]
+                eprintln!("{}: lease renewal failed: {}", candidate, e);
+                // Still leading until the last lease we got runs out
+                expires = expires.filter(|expires| *expires > now);
+            }
+        }
+        leader.send_if_modified(|leader| {
+            let was = std::mem::replace(leader, expires.is_some());
+            was != *leader
+        });
+
+        let next = match expires {
+            Some(expires) => (now + config.renew_interval).min(expires),
+            None => now + config.retry_interval,
+        };
+        tokio::time::sleep_until(next).await;
+    }
+}
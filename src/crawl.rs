@@ -0,0 +1,294 @@
+//! Crawling a graph of items with bounded concurrency
+//!
+//! The classic crawler loop, without the HTTP: a frontier queue of items to
+//! fetch, a visited set so every item is fetched once however many times it
+//! is linked to, and a [`JoinSet`] holding at most `concurrency` fetches at
+//! a time. Each fetch returns its output and the items it links to, which
+//! join the frontier one level deeper. Results come back as a stream in
+//! completion order; dropping the stream stops the crawl.
+//!
+//! Items can be web pages, but equally files and their includes, or
+//! packages and their dependencies.
+
+use crate::spawning::spawn_traced;
+use futures::Stream;
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+
+/// What fetching one item produced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fetched<K, T> {
+    pub output: T,
+    /// Items to crawl next, one level deeper
+    pub links: Vec<K>,
+}
+
+impl<K, T> Fetched<K, T> {
+    pub fn new(output: T, links: Vec<K>) -> Self {
+        Self { output, links }
+    }
+
+    /// A leaf, linking nowhere
+    pub fn leaf(output: T) -> Self {
+        Self::new(output, Vec::new())
+    }
+}
+
+/// One fetched item, from the [`Crawl`] stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrawlResult<K, T, E> {
+    pub item: K,
+    /// Links followed from a seed to reach the item; seeds are at 0
+    pub depth: usize,
+    /// The fetch's output, or its error; a failed item's links aren't
+    /// known, so nothing past it is crawled
+    pub result: Result<T, E>,
+}
+
+type LinkHook<K> = Arc<dyn Fn(&K, usize) -> bool + Send + Sync>;
+type RevisitHook<K> = Arc<dyn Fn(&K, usize) + Send + Sync>;
+
+/// Configures a crawl; [`crawl`] covers the common case
+pub struct Crawler<F, K> {
+    fetch: F,
+    concurrency: usize,
+    max_depth: Option<usize>,
+    max_items: Option<usize>,
+    follow: Option<LinkHook<K>>,
+    on_revisit: Option<RevisitHook<K>>,
+}
+
+impl<F, K> Crawler<F, K> {
+    /// Fetches items with `fetch`, 8 at a time, with no depth or item limit
+    pub fn new(fetch: F) -> Self {
+        Self {
+            fetch,
+            concurrency: 8,
+            max_depth: None,
+            max_items: None,
+            follow: None,
+            on_revisit: None,
+        }
+    }
+
+    /// Fetches running at once
+    ///
+    /// # Panics
+    ///
+    /// Panics if `concurrency` is zero.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        assert!(concurrency > 0, "`concurrency` must be non-zero");
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Doesn't follow links from items at `depth`
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Stops starting fetches once `items` have been started
+    pub fn max_items(mut self, items: usize) -> Self {
+        self.max_items = Some(items);
+        self
+    }
+
+    /// Follows only links `follow` accepts, given the link and the depth it
+    /// would be crawled at; seeds are always fetched
+    pub fn follow(mut self, follow: impl Fn(&K, usize) -> bool + Send + Sync + 'static) -> Self {
+        self.follow = Some(Arc::new(follow));
+        self
+    }
+
+    /// Called with every link to an item that has already been seen and the
+    /// depth it was found at, e.g. to report cycles
+    pub fn on_revisit(mut self, hook: impl Fn(&K, usize) + Send + Sync + 'static) -> Self {
+        self.on_revisit = Some(Arc::new(hook));
+        self
+    }
+
+    /// Starts crawling from `seeds`
+    ///
+    /// Must be called from within a Tokio runtime. A fetch that panics ends
+    /// the crawl.
+    pub fn run<T, E, Fut>(self, seeds: impl IntoIterator<Item = K>) -> Crawl<K, T, E>
+    where
+        F: Fn(K) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Fetched<K, T>, E>> + Send + 'static,
+        K: Hash + Eq + Clone + Send + 'static,
+        T: Send + 'static,
+        E: Send + 'static,
+    {
+        let seen = Arc::new(Mutex::new(HashSet::new()));
+        let mut frontier = VecDeque::new();
+        {
+            let mut seen = seen.lock().unwrap();
+            for seed in seeds {
+                if seen.insert(seed.clone()) {
+                    frontier.push_back((seed, 0));
+                }
+            }
+        }
+
+        let (tx, results) = mpsc::channel(self.concurrency);
+        let task = spawn_traced("crawler", drive(self, frontier, seen.clone(), tx));
+        Crawl {
+            results,
+            seen,
+            task,
+        }
+    }
+}
+
+/// Crawls from `seeds` with `fetch`, running up to `concurrency` fetches at
+/// once
+///
+/// Must be called from within a Tokio runtime. See [`Crawler`] for depth
+/// and item limits and link hooks.
+///
+/// # Panics
+///
+/// Panics if `concurrency` is zero.
+///
+/// ```
+/// # async fn example() {
+/// use futures::StreamExt;
+/// use tokio_tutorial_patterns::crawl::{crawl, Fetched};
+///
+/// // Each number links to its double and its half, so everything reachable
+/// // from 1 up to 100 is a power of two
+/// let mut results = crawl([1u32], 4, |n| async move {
+///     let links = [n * 2, n / 2].into_iter().filter(|&m| m > 0 && m <= 100).collect();
+///     Ok::<_, std::convert::Infallible>(Fetched::new(n.to_string(), links))
+/// });
+/// while let Some(found) = results.next().await {
+///     println!("{} at depth {}: {:?}", found.item, found.depth, found.result);
+/// }
+/// # }
+/// ```
+pub fn crawl<K, T, E, F, Fut>(
+    seeds: impl IntoIterator<Item = K>,
+    concurrency: usize,
+    fetch: F,
+) -> Crawl<K, T, E>
+where
+    F: Fn(K) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Fetched<K, T>, E>> + Send + 'static,
+    K: Hash + Eq + Clone + Send + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    Crawler::new(fetch).concurrency(concurrency).run(seeds)
+}
+
+/// Results of a running crawl, in the order fetches finish
+///
+/// The stream ends once the frontier is empty and every fetch has finished.
+/// Dropping it stops the crawl and aborts fetches in progress.
+pub struct Crawl<K, T, E> {
+    results: mpsc::Receiver<CrawlResult<K, T, E>>,
+    seen: Arc<Mutex<HashSet<K>>>,
+    task: JoinHandle<()>,
+}
+
+impl<K, T, E> Crawl<K, T, E> {
+    /// Distinct items found so far, fetched or still waiting
+    pub fn seen(&self) -> usize {
+        self.seen.lock().unwrap().len()
+    }
+}
+
+impl<K, T, E> Stream for Crawl<K, T, E> {
+    type Item = CrawlResult<K, T, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().results.poll_recv(cx)
+    }
+}
+
+impl<K, T, E> Drop for Crawl<K, T, E> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn drive<F, Fut, K, T, E>(
+    crawler: Crawler<F, K>,
+    mut frontier: VecDeque<(K, usize)>,
+    seen: Arc<Mutex<HashSet<K>>>,
+    results: mpsc::Sender<CrawlResult<K, T, E>>,
+) where
+    F: Fn(K) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Fetched<K, T>, E>> + Send + 'static,
+    K: Hash + Eq + Clone + Send + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    let fetch = Arc::new(crawler.fetch);
+    let mut running = JoinSet::new();
+    let mut started = 0;
+
+    loop {
+        while running.len() < crawler.concurrency
+            && crawler.max_items.is_none_or(|max| started < max)
+        {
+            let Some((item, depth)) = frontier.pop_front() else {
+                break;
+            };
+            let fetch = fetch.clone();
+            running.spawn(async move {
+                let fetched = fetch(item.clone()).await;
+                (item, depth, fetched)
+            });
+            started += 1;
+        }
+
+        let Some(joined) = running.join_next().await else {
+            return;
+        };
+        let (item, depth, fetched) = match joined {
+            Ok(joined) => joined,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        };
+
+        let result = fetched.map(|fetched| {
+            let depth = depth + 1;
+            if crawler.max_depth.is_some_and(|max| depth > max) {
+                return fetched.output;
+            }
+            // Only this task inserts, so the hooks can run unlocked
+            for link in fetched.links {
+                if seen.lock().unwrap().contains(&link) {
+                    if let Some(hook) = &crawler.on_revisit {
+                        hook(&link, depth);
+                    }
+                } else if crawler
+                    .follow
+                    .as_ref()
+                    .is_none_or(|follow| follow(&link, depth))
+                {
+                    seen.lock().unwrap().insert(link.clone());
+                    frontier.push_back((link, depth));
+                }
+            }
+            fetched.output
+        });
+
+        let result = CrawlResult {
+            item,
+            depth,
+            result,
+        };
+        // Nobody wants the rest
+        if results.send(result).await.is_err() {
+            return;
+        }
+    }
+}
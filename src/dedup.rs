@@ -0,0 +1,185 @@
+//! Sharing one in-flight computation between concurrent callers
+//!
+//! When many tasks ask for the same expensive thing at once, e.g. right
+//! after a popular cache entry expires, a [`SingleFlight`] runs the work for
+//! the first caller only and hands every other caller with the same key a
+//! copy of that result. Results can optionally be kept for a short time so
+//! callers arriving just after the work finished don't start it again.
+
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+type Flight<V> = Shared<BoxFuture<'static, V>>;
+
+struct State<K, V> {
+    in_flight: HashMap<K, Flight<V>>,
+    cached: HashMap<K, (V, Instant)>,
+}
+
+impl<K: Eq + Hash, V> State<K, V> {
+    /// Removes `flight` if it is still the one in flight for `key`
+    fn land(&mut self, key: &K, flight: &Flight<V>) -> bool {
+        let current = self.in_flight.get(key);
+        if !current.is_some_and(|current| current.ptr_eq(flight)) {
+            return false;
+        }
+        self.in_flight.remove(key);
+        true
+    }
+}
+
+/// Lands a flight whose work panicked, so it isn't handed out again
+///
+/// Forgotten once the work finishes normally; a caller that merely stops
+/// waiting leaves the flight to the others.
+struct Landing<'a, K: Eq + Hash, V> {
+    state: &'a Mutex<State<K, V>>,
+    key: &'a K,
+    flight: &'a Flight<V>,
+}
+
+impl<K: Eq + Hash, V> Drop for Landing<'_, K, V> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            if let Ok(mut state) = self.state.lock() {
+                state.land(self.key, self.flight);
+            }
+        }
+    }
+}
+
+/// Deduplicates concurrent calls by key; clones share the same calls
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::dedup::SingleFlight;
+///
+/// async fn render_homepage() -> String {
+///     "<html>...</html>".to_string()
+/// }
+///
+/// let flights = SingleFlight::new().cache_for(Duration::from_millis(500));
+/// let (a, b) = tokio::join!(
+///     flights.get("home", render_homepage),
+///     flights.get("home", render_homepage),
+/// );
+/// assert_eq!(a, b); // rendered only once
+/// # }
+/// ```
+pub struct SingleFlight<K, V> {
+    state: Arc<Mutex<State<K, V>>>,
+    ttl: Option<Duration>,
+}
+
+impl<K, V> Clone for SingleFlight<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            ttl: self.ttl,
+        }
+    }
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Shares calls while they are in flight and keeps nothing afterwards
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                in_flight: HashMap::new(),
+                cached: HashMap::new(),
+            })),
+            ttl: None,
+        }
+    }
+
+    /// Also hands out each result for `ttl` after it was produced
+    pub fn cache_for(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Returns the result for `key`, running `f` only if no call for `key`
+    /// is in flight and no cached result is fresh
+    ///
+    /// The work is driven by whichever callers are waiting for it, so it
+    /// keeps going if the caller that started it gives up. If it panics,
+    /// every caller waiting for it panics too, and the next call for `key`
+    /// starts afresh.
+    pub async fn get<F, Fut>(&self, key: K, f: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V> + Send + 'static,
+    {
+        let flight = {
+            let mut state = self.state.lock().unwrap();
+            match state.cached.get(&key) {
+                Some((value, expires)) if *expires > Instant::now() => return value.clone(),
+                Some(_) => {
+                    state.cached.remove(&key);
+                }
+                None => {}
+            }
+            state
+                .in_flight
+                .entry(key.clone())
+                .or_insert_with(|| f().boxed().shared())
+                .clone()
+        };
+
+        let landing = Landing {
+            state: &self.state,
+            key: &key,
+            flight: &flight,
+        };
+        let value = flight.clone().await;
+        std::mem::forget(landing);
+
+        let mut state = self.state.lock().unwrap();
+        // Only the first caller to finish cleans up, and only its own flight
+        if state.land(&key, &flight) {
+            if let Some(ttl) = self.ttl {
+                let now = Instant::now();
+                state.cached.retain(|_, (_, expires)| *expires > now);
+                state.cached.insert(key, (value.clone(), now + ttl));
+            }
+        }
+        value
+    }
+
+    /// Drops the cached result for `key`, if any; calls in flight carry on
+    pub fn forget(&self, key: &K) {
+        self.state.lock().unwrap().cached.remove(key);
+    }
+
+    /// Keys with a call currently in flight
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight.len()
+    }
+
+    /// Results held in the cache, including expired ones not yet pruned
+    ///
+    /// Expired results are pruned whenever a new one is cached.
+    pub fn cached(&self) -> usize {
+        self.state.lock().unwrap().cached.len()
+    }
+}
+
+impl<K, V> Default for SingleFlight<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,256 @@
+//! An in-memory, append-only event log with replaying subscribers
+//!
+//! Every event appended to an [`EventLog`] gets the next offset, starting at
+//! 0. [`EventLog::subscribe_from`] replays the log from any offset still
+//! held and then keeps following new events, so a consumer that remembers
+//! the last offset it handled can resume exactly where it left off.
+//!
+//! Old events can be compacted away, either explicitly or by bounding how
+//! many the log keeps. A log created [`with_snapshots`](EventLog::with_snapshots)
+//! folds every event it drops into a snapshot first, so the state they
+//! describe can still be rebuilt from the snapshot plus the events after it.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+use tokio_stream::Stream;
+
+/// Returned by a subscription that asked for, or fell behind to, an
+/// offset that has been compacted away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compacted {
+    pub requested: u64,
+    /// The oldest offset still in the log
+    pub first_available: u64,
+}
+
+impl fmt::Display for Compacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "offset {} was compacted; the log starts at {}",
+            self.requested, self.first_available
+        )
+    }
+}
+
+impl std::error::Error for Compacted {}
+
+type ApplyFn<E, S> = Box<dyn Fn(&mut S, &E) + Send + Sync>;
+
+struct Snapshots<E, S> {
+    state: S,
+    apply: ApplyFn<E, S>,
+}
+
+struct State<E, S> {
+    /// Offset of `events[0]`
+    first: u64,
+    events: VecDeque<E>,
+    max_retained: Option<usize>,
+    snapshots: Option<Snapshots<E, S>>,
+}
+
+impl<E, S> State<E, S> {
+    fn next_offset(&self) -> u64 {
+        self.first + self.events.len() as u64
+    }
+
+    fn drop_oldest(&mut self, count: usize) {
+        for event in self.events.drain(..count) {
+            if let Some(snapshots) = &mut self.snapshots {
+                (snapshots.apply)(&mut snapshots.state, &event);
+            }
+        }
+        self.first += count as u64;
+    }
+}
+
+struct Shared<E, S> {
+    state: Mutex<State<E, S>>,
+    /// Carries the next offset, so subscribers wake up on every append
+    appended: watch::Sender<u64>,
+}
+
+/// An append-only log of `E`s, with optional snapshots of type `S`; clones
+/// share the same log
+///
+/// ```
+/// # async fn example() {
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::eventlog::EventLog;
+///
+/// let log = EventLog::new();
+/// log.append("created");
+/// log.append("renamed");
+///
+/// let mut events = Box::pin(log.subscribe_from(0));
+/// assert_eq!(events.next().await, Some(Ok((0, "created"))));
+/// assert_eq!(events.next().await, Some(Ok((1, "renamed"))));
+///
+/// log.append("deleted"); // followed live
+/// assert_eq!(events.next().await, Some(Ok((2, "deleted"))));
+/// # }
+/// ```
+pub struct EventLog<E, S = ()> {
+    shared: Arc<Shared<E, S>>,
+}
+
+impl<E, S> Clone for EventLog<E, S> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<E: Clone> EventLog<E> {
+    /// An empty log that keeps every event until told otherwise
+    pub fn new() -> Self {
+        Self::with_state(None)
+    }
+}
+
+impl<E: Clone> Default for EventLog<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Clone, S: Clone> EventLog<E, S> {
+    /// An empty log that folds compacted events into a snapshot, starting
+    /// from `initial`, with `apply`
+    pub fn with_snapshots<F>(initial: S, apply: F) -> Self
+    where
+        F: Fn(&mut S, &E) + Send + Sync + 'static,
+    {
+        Self::with_state(Some(Snapshots {
+            state: initial,
+            apply: Box::new(apply),
+        }))
+    }
+
+    fn with_state(snapshots: Option<Snapshots<E, S>>) -> Self {
+        let (appended, _) = watch::channel(0);
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    first: 0,
+                    events: VecDeque::new(),
+                    max_retained: None,
+                    snapshots,
+                }),
+                appended,
+            }),
+        }
+    }
+
+    /// Keeps at most `max_events`, compacting the oldest as new ones arrive
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_events` is zero.
+    pub fn retain(self, max_events: usize) -> Self {
+        assert!(max_events > 0, "`max_events` must be non-zero");
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.max_retained = Some(max_events);
+            let excess = state.events.len().saturating_sub(max_events);
+            state.drop_oldest(excess);
+        }
+        self
+    }
+
+    /// Appends `event`, returning its offset
+    pub fn append(&self, event: E) -> u64 {
+        let mut state = self.shared.state.lock().unwrap();
+        let offset = state.next_offset();
+        state.events.push_back(event);
+        if let Some(max) = state.max_retained {
+            let excess = state.events.len().saturating_sub(max);
+            state.drop_oldest(excess);
+        }
+        self.shared.appended.send_replace(offset + 1);
+        offset
+    }
+
+    /// The offset the next event will get
+    pub fn next_offset(&self) -> u64 {
+        self.shared.state.lock().unwrap().next_offset()
+    }
+
+    /// The oldest offset still in the log
+    pub fn first_offset(&self) -> u64 {
+        self.shared.state.lock().unwrap().first
+    }
+
+    pub fn get(&self, offset: u64) -> Result<Option<E>, Compacted> {
+        let state = self.shared.state.lock().unwrap();
+        read(&state, offset)
+    }
+
+    /// Drops every event before `offset`, folding them into the snapshot
+    /// if there is one
+    pub fn compact_before(&self, offset: u64) {
+        let mut state = self.shared.state.lock().unwrap();
+        let count = offset
+            .saturating_sub(state.first)
+            .min(state.events.len() as u64);
+        state.drop_oldest(count as usize);
+    }
+
+    /// The snapshot and the offset of the first event not yet folded into
+    /// it; replaying from that offset onto the snapshot gives the current
+    /// state
+    pub fn snapshot(&self) -> Option<(u64, S)> {
+        let state = self.shared.state.lock().unwrap();
+        let snapshots = state.snapshots.as_ref()?;
+        Some((state.first, snapshots.state.clone()))
+    }
+
+    /// Every event from `offset` on, followed by new ones as they are
+    /// appended
+    ///
+    /// If `offset` has already been compacted, or is compacted before the
+    /// subscriber gets to it, the stream yields one [`Compacted`] error and
+    /// ends. Otherwise it never ends; drop it to unsubscribe.
+    pub fn subscribe_from(&self, offset: u64) -> impl Stream<Item = Result<(u64, E), Compacted>>
+    where
+        E: Send + 'static,
+        S: Send + 'static,
+    {
+        let log = self.clone();
+        let appended = self.shared.appended.subscribe();
+        futures::stream::unfold(Some((log, appended, offset)), |subscription| async move {
+            let (log, mut appended, offset) = subscription?;
+            loop {
+                appended.borrow_and_update();
+                let next = {
+                    let state = log.shared.state.lock().unwrap();
+                    read(&state, offset)
+                };
+                match next {
+                    Ok(Some(event)) => {
+                        return Some((Ok((offset, event)), Some((log, appended, offset + 1))))
+                    }
+                    Err(compacted) => return Some((Err(compacted), None)),
+                    Ok(None) => {
+                        // The log holds a sender, so this never fails
+                        let _ = appended.changed().await;
+                    }
+                }
+            }
+        })
+    }
+}
+
+fn read<E: Clone, S>(state: &State<E, S>, offset: u64) -> Result<Option<E>, Compacted> {
+    if offset < state.first {
+        return Err(Compacted {
+            requested: offset,
+            first_available: state.first,
+        });
+    }
+    Ok(state.events.get((offset - state.first) as usize).cloned())
+}
@@ -0,0 +1,269 @@
+//! Liveness and readiness checks, evaluated in the background
+//!
+//! Components register probes with a [`HealthChecker`]: async closures that
+//! succeed while the component is fine, each bounded by its own timeout.
+//! Liveness probes say whether the process is working at all (failing them
+//! usually means "restart me"); readiness probes say whether it should get
+//! traffic right now. Once started, every probe runs concurrently once per
+//! interval and the resulting [`HealthReport`] is published over a watch
+//! channel. A probe that panics has failed, and if the checks stop
+//! altogether the report says neither live nor ready.
+//! [`HealthMonitor::routes`] serves it as `/healthz` and `/readyz` on an
+//! [`http_lite`](crate::io::http_lite) router.
+
+use crate::io::http_lite::{Response, Router};
+use crate::spawning::spawn_traced;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// What a failing probe means
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeKind {
+    Liveness,
+    Readiness,
+}
+
+/// The latest result of one probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub kind: ProbeKind,
+    /// `None` if the probe passed, otherwise why it failed
+    pub error: Option<String>,
+    pub latency: Duration,
+}
+
+impl Check {
+    pub fn is_healthy(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Aggregate health, as published by a [`HealthMonitor`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HealthReport {
+    /// Every liveness probe passed
+    pub live: bool,
+    /// Every readiness probe passed; false until the first round finishes
+    pub ready: bool,
+    pub checks: BTreeMap<String, Check>,
+}
+
+impl fmt::Display for HealthReport {
+    /// One `name: ok` or `name: failing (reason)` line per probe
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, check) in &self.checks {
+            match &check.error {
+                None => writeln!(f, "{}: ok", name)?,
+                Some(e) => writeln!(f, "{}: failing ({})", name, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+type ProbeFn = Arc<dyn Fn() -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+struct Probe {
+    name: String,
+    kind: ProbeKind,
+    timeout: Duration,
+    check: ProbeFn,
+}
+
+/// Collects probes, then starts evaluating them with [`start`](Self::start)
+pub struct HealthChecker {
+    interval: Duration,
+    probes: Vec<Probe>,
+}
+
+impl HealthChecker {
+    /// Runs every probe once per `interval`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn new(interval: Duration) -> Self {
+        assert!(!interval.is_zero(), "`interval` must be non-zero");
+        Self {
+            interval,
+            probes: Vec::new(),
+        }
+    }
+
+    pub fn liveness<F, Fut, E>(self, name: impl Into<String>, timeout: Duration, probe: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: fmt::Display,
+    {
+        self.probe(name.into(), ProbeKind::Liveness, timeout, probe)
+    }
+
+    pub fn readiness<F, Fut, E>(self, name: impl Into<String>, timeout: Duration, probe: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: fmt::Display,
+    {
+        self.probe(name.into(), ProbeKind::Readiness, timeout, probe)
+    }
+
+    fn probe<F, Fut, E>(
+        mut self,
+        name: String,
+        kind: ProbeKind,
+        timeout: Duration,
+        probe: F,
+    ) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: fmt::Display,
+    {
+        let check: ProbeFn = Arc::new(move || {
+            let fut = probe();
+            Box::pin(async move { fut.await.map_err(|e| e.to_string()) })
+        });
+        self.probes.push(Probe {
+            name,
+            kind,
+            timeout,
+            check,
+        });
+        self
+    }
+
+    /// Starts the background task; the first round runs straight away
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn start(self) -> HealthMonitor {
+        let initial = HealthReport {
+            live: true,
+            ready: false,
+            checks: BTreeMap::new(),
+        };
+        let (tx, report) = watch::channel(initial);
+
+        let task = spawn_traced("health checker", async move {
+            let publisher = Publisher(tx);
+            let mut rounds = tokio::time::interval(self.interval);
+            loop {
+                rounds.tick().await;
+                publisher.0.send_replace(evaluate(&self.probes).await);
+            }
+        });
+
+        HealthMonitor { report, task }
+    }
+}
+
+/// Publishes reports from the checker task, and a failing one once the
+/// task stops, so a stale report can't keep claiming health
+struct Publisher(watch::Sender<HealthReport>);
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.0.send_modify(|report| {
+            report.live = false;
+            report.ready = false;
+            let stopped = Check {
+                kind: ProbeKind::Liveness,
+                error: Some("health checks stopped".to_string()),
+                latency: Duration::ZERO,
+            };
+            report.checks.insert("health checker".to_string(), stopped);
+        });
+    }
+}
+
+async fn evaluate(probes: &[Probe]) -> HealthReport {
+    let checks = futures::future::join_all(probes.iter().map(|probe| async move {
+        let started = Instant::now();
+        let check = AssertUnwindSafe(async { (probe.check)().await }).catch_unwind();
+        let error = match tokio::time::timeout(probe.timeout, check).await {
+            Ok(Ok(Ok(()))) => None,
+            Ok(Ok(Err(e))) => Some(e),
+            Ok(Err(_)) => Some("probe panicked".to_string()),
+            Err(_) => Some(format!("timed out after {:?}", probe.timeout)),
+        };
+        let check = Check {
+            kind: probe.kind,
+            error,
+            latency: started.elapsed(),
+        };
+        (probe.name.clone(), check)
+    }))
+    .await;
+
+    let passing = |kind| {
+        checks
+            .iter()
+            .filter(|(_, check)| check.kind == kind)
+            .all(|(_, check)| check.is_healthy())
+    };
+    let live = passing(ProbeKind::Liveness);
+    let ready = passing(ProbeKind::Readiness);
+    HealthReport {
+        live,
+        ready,
+        checks: checks.into_iter().collect(),
+    }
+}
+
+/// Handle to the running health checks; dropping it stops them, and
+/// reports from then on are failing
+pub struct HealthMonitor {
+    report: watch::Receiver<HealthReport>,
+    task: JoinHandle<()>,
+}
+
+impl HealthMonitor {
+    /// The most recent report
+    pub fn report(&self) -> HealthReport {
+        self.report.borrow().clone()
+    }
+
+    /// Notified after every round
+    pub fn subscribe(&self) -> watch::Receiver<HealthReport> {
+        self.report.clone()
+    }
+
+    /// Adds `GET /healthz` (liveness) and `GET /readyz` (readiness) to
+    /// `router`, answering 200 or 503 with one line per probe
+    pub fn routes(&self, router: Router) -> Router {
+        let live = self.report.clone();
+        let ready = self.report.clone();
+        router
+            .route("GET", "/healthz", move |_| {
+                let report = live.borrow().clone();
+                async move { respond(report.live, &report) }
+            })
+            .route("GET", "/readyz", move |_| {
+                let report = ready.borrow().clone();
+                async move { respond(report.ready, &report) }
+            })
+    }
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn respond(passing: bool, report: &HealthReport) -> Response {
+    let mut body = String::from(if passing { "ok\n" } else { "unavailable\n" });
+    let _ = write!(body, "{}", report);
+    let mut response = Response::text(body);
+    response.status = if passing { 200 } else { 503 };
+    response
+}
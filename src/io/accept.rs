@@ -0,0 +1,259 @@
+//! Accept loops that survive transient `accept()` errors
+//!
+//! `accept()` fails for reasons that have nothing to do with the listener:
+//! a client gave up while its connection sat in the backlog
+//! (`ECONNABORTED`), or the process ran out of file descriptors (`EMFILE`)
+//! until some connections close. A loop that returns on the first error
+//! takes the whole server down for either. An [`Acceptor`] retries those
+//! with backoff, so an out-of-descriptors server doesn't spin, and only
+//! gives up on errors that mean the listener itself is broken.
+//!
+//! Every server in [`io`](super) accepts through one; passing a plain
+//! [`TcpListener`] uses the default [`AcceptPolicy`].
+
+use crate::select::{Backoff, RetryPolicy};
+use socket2::SockRef;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+/// What an `accept()` error says about the listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptErrorKind {
+    /// One pending connection failed before it was accepted, e.g.
+    /// `ECONNABORTED`; the next one is unaffected
+    Connection,
+    /// Out of file descriptors or memory (`EMFILE`, `ENFILE`, `ENOBUFS`,
+    /// `ENOMEM`), which clears as connections close
+    Resources,
+    /// The listener can't accept anything any more
+    Fatal,
+}
+
+impl AcceptErrorKind {
+    pub fn of(error: &io::Error) -> Self {
+        #[cfg(unix)]
+        if matches!(
+            error.raw_os_error(),
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+        ) {
+            return AcceptErrorKind::Resources;
+        }
+        match error.kind() {
+            io::ErrorKind::OutOfMemory => AcceptErrorKind::Resources,
+            // Linux also reports the pending connection's network errors,
+            // and firewall rejections, from accept()
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::NetworkDown
+            | io::ErrorKind::PermissionDenied => AcceptErrorKind::Connection,
+            _ => AcceptErrorKind::Fatal,
+        }
+    }
+}
+
+/// What an [`Acceptor`] does about an error, as passed to
+/// [`AcceptPolicy::on_error`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptAction {
+    /// Accepts again after this delay
+    Retry { after: Duration },
+    /// Gives up and returns the error, which stops the server
+    Abort,
+}
+
+type ErrorHook = Arc<dyn Fn(&io::Error, AcceptAction) + Send + Sync>;
+
+/// How an [`Acceptor`] handles `accept()` errors
+///
+/// By default [`Connection`](AcceptErrorKind::Connection) and
+/// [`Resources`](AcceptErrorKind::Resources) errors are retried forever,
+/// waiting 5ms after the first of a run of errors and doubling up to 1s,
+/// and every error is logged. [`Fatal`](AcceptErrorKind::Fatal) errors
+/// always abort.
+#[derive(Clone)]
+pub struct AcceptPolicy {
+    retry: RetryPolicy<io::Error>,
+    on_error: Option<ErrorHook>,
+}
+
+impl Default for AcceptPolicy {
+    fn default() -> Self {
+        Self {
+            retry: RetryPolicy::exponential(Duration::from_millis(5))
+                .backoff(Backoff::Exponential {
+                    initial: Duration::from_millis(5),
+                    multiplier: 2.0,
+                    max: Duration::from_secs(1),
+                })
+                .max_attempts(None),
+            on_error: None,
+        }
+    }
+}
+
+impl AcceptPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Retries non-fatal errors according to `policy`, counting attempts
+    /// over a run of consecutive errors
+    ///
+    /// Once `policy` gives up, the error is returned as if it were fatal.
+    pub fn retry(mut self, policy: RetryPolicy<io::Error>) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Called with every error and what is done about it, instead of
+    /// logging it
+    pub fn on_error(
+        mut self,
+        hook: impl Fn(&io::Error, AcceptAction) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_error = Some(Arc::new(hook));
+        self
+    }
+}
+
+/// A listener that accepts according to an [`AcceptPolicy`]
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use tokio::net::TcpListener;
+/// use tokio_tutorial_patterns::io::{AcceptPolicy, Acceptor};
+/// use tokio_tutorial_patterns::select::RetryPolicy;
+///
+/// let policy = AcceptPolicy::new()
+///     .retry(RetryPolicy::fixed(Duration::from_millis(100)).max_attempts(Some(50)))
+///     .on_error(|e, action| println!("accept failed ({}), {:?}", e, action));
+/// let listener = TcpListener::bind("127.0.0.1:8080").await?;
+/// let mut acceptor = Acceptor::new(listener, policy);
+/// loop {
+///     let (socket, peer) = acceptor.accept().await?;
+///     // ... handle the connection ...
+/// #   let _ = (socket, peer);
+/// }
+/// # }
+/// ```
+pub struct Acceptor {
+    listener: TcpListener,
+    policy: AcceptPolicy,
+    /// Errors since the last successful accept
+    failures: u32,
+    failing_since: Instant,
+}
+
+impl Acceptor {
+    pub fn new(listener: TcpListener, policy: AcceptPolicy) -> Self {
+        Self {
+            listener,
+            policy,
+            failures: 0,
+            failing_since: Instant::now(),
+        }
+    }
+
+    /// Accepts the next connection, retrying errors as the policy allows
+    ///
+    /// Cancel safe: dropping the future during a backoff only cuts the
+    /// backoff short.
+    pub async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        loop {
+            let error = match self.listener.accept().await {
+                Ok(accepted) => {
+                    self.failures = 0;
+                    return Ok(accepted);
+                }
+                Err(e) => e,
+            };
+            if self.failures == 0 {
+                self.failing_since = Instant::now();
+            }
+            self.failures += 1;
+
+            let delay = match AcceptErrorKind::of(&error) {
+                AcceptErrorKind::Fatal => None,
+                _ => {
+                    let policy = &self.policy.retry;
+                    policy.next_delay(self.failures, self.failing_since, &error)
+                }
+            };
+            let action = match delay {
+                Some(after) => AcceptAction::Retry { after },
+                None => AcceptAction::Abort,
+            };
+            self.report(&error, action);
+            match delay {
+                Some(after) => tokio::time::sleep(after).await,
+                None => return Err(error),
+            }
+        }
+    }
+
+    /// Accepts a connection only if one is already queued, bypassing the
+    /// policy
+    ///
+    /// Asks the operating system directly, so a connection that has just
+    /// arrived is seen even before the runtime has been told about it.
+    pub fn try_accept(&self) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+        let (socket, peer) = match SockRef::from(&self.listener).accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let peer = peer
+            .as_socket()
+            .ok_or_else(|| io::Error::other("peer address is not an IP address"))?;
+        socket.set_nonblocking(true)?;
+        Ok(Some((TcpStream::from_std(socket.into())?, peer)))
+    }
+
+    fn report(&self, error: &io::Error, action: AcceptAction) {
+        if let Some(hook) = &self.policy.on_error {
+            return hook(error, action);
+        }
+        let addr = self.listener.local_addr().ok();
+        #[cfg(feature = "tracing")]
+        match action {
+            AcceptAction::Retry { after } => {
+                tracing::warn!(?addr, error = %error, ?after, "accept failed, retrying")
+            }
+            AcceptAction::Abort => tracing::error!(?addr, error = %error, "accept failed"),
+        }
+        #[cfg(not(feature = "tracing"))]
+        match action {
+            AcceptAction::Retry { after } => {
+                eprintln!(
+                    "accept on {:?} failed, retrying in {:?}: {}",
+                    addr, after, error
+                )
+            }
+            AcceptAction::Abort => eprintln!("accept on {:?} failed: {}", addr, error),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn into_inner(self) -> TcpListener {
+        self.listener
+    }
+}
+
+impl From<TcpListener> for Acceptor {
+    fn from(listener: TcpListener) -> Self {
+        Self::new(listener, AcceptPolicy::default())
+    }
+}
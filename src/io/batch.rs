@@ -0,0 +1,118 @@
+//! Bounded-concurrency processing of many files
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// Snapshot of how far a [`process_files`] run has got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+impl Progress {
+    pub fn completed(&self) -> usize {
+        self.succeeded + self.failed
+    }
+}
+
+/// Outcome of a batch: every file ends up in exactly one of the two lists
+#[derive(Debug)]
+pub struct BatchReport<T> {
+    pub succeeded: Vec<(PathBuf, T)>,
+    pub failed: Vec<(PathBuf, std::io::Error)>,
+}
+
+impl<T> BatchReport<T> {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Runs `f` on every path with at most `limit` files in flight
+///
+/// A failing (or panicking) file is recorded in the report instead of
+/// aborting the rest of the batch.
+pub async fn process_files<I, P, F, Fut, T>(paths: I, limit: usize, f: F) -> BatchReport<T>
+where
+    I: IntoIterator<Item = P>,
+    P: Into<PathBuf>,
+    F: Fn(PathBuf) -> Fut,
+    Fut: Future<Output = std::io::Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    process_files_with_progress(paths, limit, f, |_| {}).await
+}
+
+/// Like [`process_files`], calling `on_progress` after every finished file
+pub async fn process_files_with_progress<I, P, F, Fut, T, G>(
+    paths: I,
+    limit: usize,
+    f: F,
+    mut on_progress: G,
+) -> BatchReport<T>
+where
+    I: IntoIterator<Item = P>,
+    P: Into<PathBuf>,
+    F: Fn(PathBuf) -> Fut,
+    Fut: Future<Output = std::io::Result<T>> + Send + 'static,
+    T: Send + 'static,
+    G: FnMut(Progress),
+{
+    let semaphore = Arc::new(Semaphore::new(limit.max(1)));
+    let mut tasks = JoinSet::new();
+    let mut task_paths = HashMap::new();
+
+    for path in paths {
+        let path = path.into();
+        let semaphore = Arc::clone(&semaphore);
+        let fut = f(path.clone());
+        let handle = tasks.spawn(async move {
+            let _permit = semaphore
+                .acquire()
+                .await
+                .expect("semaphore is never closed");
+            fut.await
+        });
+        task_paths.insert(handle.id(), path);
+    }
+
+    let mut progress = Progress {
+        total: task_paths.len(),
+        succeeded: 0,
+        failed: 0,
+    };
+    let mut report = BatchReport {
+        succeeded: Vec::new(),
+        failed: Vec::new(),
+    };
+
+    while let Some(joined) = tasks.join_next_with_id().await {
+        match joined {
+            Ok((id, Ok(value))) => {
+                report
+                    .succeeded
+                    .push((task_paths.remove(&id).unwrap(), value));
+                progress.succeeded += 1;
+            }
+            Ok((id, Err(e))) => {
+                report.failed.push((task_paths.remove(&id).unwrap(), e));
+                progress.failed += 1;
+            }
+            Err(join_error) => {
+                let path = task_paths.remove(&join_error.id()).unwrap();
+                let e = std::io::Error::other(format!("task failed: {}", join_error));
+                report.failed.push((path, e));
+                progress.failed += 1;
+            }
+        }
+        on_progress(progress);
+    }
+
+    report
+}
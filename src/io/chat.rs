@@ -0,0 +1,203 @@
+//! A line-based chat server built on a broadcast channel
+
+use super::{serve_traced, Acceptor, Goodbye};
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+
+/// How many messages a slow client may fall behind before it is disconnected
+const CHAT_CAPACITY: usize = 64;
+
+/// Longest line a client may send
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+
+/// How long a client gets to take a line before it is disconnected as too slow
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+struct ChatMessage {
+    from: SocketAddr,
+    text: String,
+}
+
+/// Runs a chat server on the given address until Ctrl-C is pressed
+pub async fn chat_server(addr: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("Chat server listening on: {}", addr);
+
+    serve_chat(listener, async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+    .await
+}
+
+/// Serves chat clients on an existing listener until `shutdown` completes
+///
+/// Every client first sends its username on a line of its own; after that each
+/// line it sends is fanned out to all other clients as `name: text`. Clients
+/// that fall more than `CHAT_CAPACITY` messages behind, stop reading for
+/// `WRITE_TIMEOUT` or send a line longer than `MAX_LINE_LENGTH` are
+/// disconnected. Once
+/// `shutdown` resolves the server stops accepting and reading, says a
+/// default [`Goodbye`] to every client and waits for every connection task
+/// to finish.
+///
+/// `listener` may be an [`Acceptor`] with its own policy for accept errors.
+pub async fn serve_chat<F>(listener: impl Into<Acceptor>, shutdown: F) -> std::io::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    serve_chat_with(listener, shutdown, Goodbye::default()).await
+}
+
+/// [`serve_chat`], telling clients `goodbye` on shutdown
+///
+/// Clients see `* server going away: reason`, followed by
+/// `* reconnect to addr` if there is a redirect.
+pub async fn serve_chat_with<F>(
+    listener: impl Into<Acceptor>,
+    shutdown: F,
+    goodbye: Goodbye,
+) -> std::io::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let (tx, _rx) = broadcast::channel::<ChatMessage>(CHAT_CAPACITY);
+    let handler = move |socket, addr, shutdown_rx| {
+        handle_chat_client(socket, addr, tx.clone(), shutdown_rx, goodbye.clone())
+    };
+    let shutdown = async {
+        shutdown.await;
+        println!("Chat server shutting down");
+    };
+
+    serve_traced(listener, "chat", handler, shutdown).await
+}
+
+async fn handle_chat_client(
+    socket: TcpStream,
+    addr: SocketAddr,
+    tx: broadcast::Sender<ChatMessage>,
+    mut shutdown_rx: watch::Receiver<bool>,
+    goodbye: Goodbye,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+
+    write_line(&mut writer, "Enter username:").await?;
+    let name = tokio::select! {
+        line = lines.next() => line,
+        _ = shutdown_rx.changed() => return say_goodbye(writer, &goodbye).await,
+    };
+    let username = match name {
+        Some(Ok(name)) if !name.trim().is_empty() => name.trim().to_string(),
+        Some(Err(e)) => return refuse_line(&mut writer, e).await,
+        _ => return Ok(()),
+    };
+
+    // Subscribe before announcing so the client sees everything after its join
+    let mut rx = tx.subscribe();
+    write_line(&mut writer, &format!("* welcome, {}", username)).await?;
+    let _ = tx.send(ChatMessage {
+        from: addr,
+        text: format!("* {} joined", username),
+    });
+
+    let outcome = loop {
+        tokio::select! {
+            line = lines.next() => {
+                match line {
+                    Some(Ok(text)) => {
+                        let _ = tx.send(ChatMessage {
+                            from: addr,
+                            text: format!("{}: {}", username, text),
+                        });
+                    }
+                    Some(Err(e)) => break refuse_line(&mut writer, e).await.map(|_| false),
+                    None => break Ok(false),
+                }
+            }
+            msg = rx.recv() => {
+                match msg {
+                    // Writes race shutdown so a stalled client can't hold up draining
+                    Ok(msg) if msg.from != addr => {
+                        tokio::select! {
+                            written = write_line(&mut writer, &msg.text) => {
+                                if let Err(e) = written {
+                                    break Err(e);
+                                }
+                            }
+                            _ = shutdown_rx.changed() => break Ok(true),
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        let notice = format!("* disconnected: fell behind by {} messages", skipped);
+                        tokio::select! {
+                            written = write_line(&mut writer, &notice) => break written.map(|_| false),
+                            _ = shutdown_rx.changed() => break Ok(true),
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break Ok(false),
+                }
+            }
+            _ = shutdown_rx.changed() => break Ok(true),
+        }
+    };
+
+    let _ = tx.send(ChatMessage {
+        from: addr,
+        text: format!("* {} left", username),
+    });
+
+    if outcome? {
+        say_goodbye(writer, &goodbye).await?;
+    }
+    Ok(())
+}
+
+/// Writes one line, failing with `TimedOut` if the client doesn't take it
+/// within `WRITE_TIMEOUT`
+async fn write_line(writer: &mut OwnedWriteHalf, line: &str) -> std::io::Result<()> {
+    let write = async {
+        writer.write_all(line.as_bytes()).await?;
+        writer.write_all(b"\n").await
+    };
+    tokio::time::timeout(WRITE_TIMEOUT, write)
+        .await
+        .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()))
+}
+
+/// Tells a client its line was too long before disconnecting it, or passes
+/// on a read error
+async fn refuse_line(writer: &mut OwnedWriteHalf, e: LinesCodecError) -> std::io::Result<()> {
+    match e {
+        LinesCodecError::MaxLineLengthExceeded => {
+            write_line(writer, "* disconnected: line too long").await
+        }
+        LinesCodecError::Io(e) => Err(e),
+    }
+}
+
+/// Sends the parting lines and closes the connection, giving up on a client
+/// that doesn't take them in time
+async fn say_goodbye(mut writer: OwnedWriteHalf, goodbye: &Goodbye) -> std::io::Result<()> {
+    let mut farewell = format!("* server going away: {}\n", goodbye.reason);
+    if let Some(addr) = &goodbye.redirect {
+        farewell.push_str(&format!("* reconnect to {}\n", addr));
+    }
+    let send = async {
+        writer.write_all(farewell.as_bytes()).await?;
+        writer.shutdown().await
+    };
+    tokio::time::timeout(goodbye.flush_timeout, send)
+        .await
+        .unwrap_or(Ok(()))
+}
@@ -0,0 +1,83 @@
+//! Gzip/zstd `AsyncRead`/`AsyncWrite` adapters built on `async-compression`
+
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+#[cfg(feature = "gzip")]
+use async_compression::tokio::{bufread::GzipDecoder, write::GzipEncoder};
+#[cfg(feature = "zstd")]
+use async_compression::tokio::{bufread::ZstdDecoder, write::ZstdEncoder};
+
+/// Wraps a reader so that gzip-compressed bytes are decompressed while reading
+#[cfg(feature = "gzip")]
+pub fn gzip_reader<R: AsyncRead>(reader: R) -> GzipDecoder<BufReader<R>> {
+    let mut decoder = GzipDecoder::new(BufReader::new(reader));
+    // Concatenated gzip members (e.g. appended log chunks) are read as one stream
+    decoder.multiple_members(true);
+    decoder
+}
+
+/// Wraps a writer so that everything written is gzip-compressed
+///
+/// Call `shutdown()` when done so the gzip trailer gets written.
+#[cfg(feature = "gzip")]
+pub fn gzip_writer<W: AsyncWrite>(writer: W) -> GzipEncoder<W> {
+    GzipEncoder::new(writer)
+}
+
+/// Wraps a reader so that zstd-compressed bytes are decompressed while reading
+#[cfg(feature = "zstd")]
+pub fn zstd_reader<R: AsyncRead>(reader: R) -> ZstdDecoder<BufReader<R>> {
+    let mut decoder = ZstdDecoder::new(BufReader::new(reader));
+    decoder.multiple_members(true);
+    decoder
+}
+
+/// Wraps a writer so that everything written is zstd-compressed
+///
+/// Call `shutdown()` when done so the final frame gets written.
+#[cfg(feature = "zstd")]
+pub fn zstd_writer<W: AsyncWrite>(writer: W) -> ZstdEncoder<W> {
+    ZstdEncoder::new(writer)
+}
+
+/// Reads and decompresses an entire gzip file
+#[cfg(feature = "gzip")]
+pub async fn read_file_gz<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
+    let file = tokio::fs::File::open(path).await?;
+    read_all(gzip_reader(file)).await
+}
+
+/// Compresses data with gzip and writes it to a file
+#[cfg(feature = "gzip")]
+pub async fn write_file_gz<P: AsRef<Path>>(path: P, contents: &[u8]) -> std::io::Result<()> {
+    let file = tokio::fs::File::create(path).await?;
+    write_all(gzip_writer(file), contents).await
+}
+
+/// Reads and decompresses an entire zstd file
+#[cfg(feature = "zstd")]
+pub async fn read_file_zst<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
+    let file = tokio::fs::File::open(path).await?;
+    read_all(zstd_reader(file)).await
+}
+
+/// Compresses data with zstd and writes it to a file
+#[cfg(feature = "zstd")]
+pub async fn write_file_zst<P: AsRef<Path>>(path: P, contents: &[u8]) -> std::io::Result<()> {
+    let file = tokio::fs::File::create(path).await?;
+    write_all(zstd_writer(file), contents).await
+}
+
+async fn read_all<R: AsyncRead + Unpin>(mut reader: R) -> std::io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents).await?;
+    Ok(contents)
+}
+
+async fn write_all<W: AsyncWrite + Unpin>(mut writer: W, contents: &[u8]) -> std::io::Result<()> {
+    writer.write_all(contents).await?;
+    // Shutdown flushes the encoder's trailer and then the file itself
+    writer.shutdown().await?;
+    Ok(())
+}
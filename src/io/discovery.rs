@@ -0,0 +1,311 @@
+//! Finding services on the local network with UDP beacons
+//!
+//! A [`Beacon`] announces a service's name and port every interval, by
+//! multicast, broadcast or plain unicast depending on the group address.
+//! A [`DiscoveryListener`] receives those announcements and yields a
+//! [`DiscoveryEvent`] stream: a peer is up when its first announcement
+//! arrives and down once it goes quiet for the TTL, or right away when its
+//! beacon is dropped and says goodbye. Announcements are single text
+//! datagrams, so they are easy to watch with `tcpdump` or `socat`.
+
+use crate::select::ExpiryQueue;
+use crate::spawning::spawn_traced;
+use futures::Stream;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+/// Starts every announcement, so unrelated traffic on the port is ignored
+const MAGIC: &str = "tokio-patterns-discovery/1";
+
+/// Large enough for any announcement with a reasonable service name
+const MAX_DATAGRAM: usize = 1024;
+
+/// Where beacons announce and listeners listen, and how often
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// A multicast group, a broadcast address (`255.255.255.255` or one
+    /// ending in `.255`), or a single listener's address
+    pub group: SocketAddr,
+    /// Time between announcements
+    pub interval: Duration,
+    /// How long a listener keeps a peer it hasn't heard from
+    pub ttl: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    /// Multicast group `239.255.70.77:7946`, announcing every second and
+    /// expiring peers after three missed announcements
+    fn default() -> Self {
+        Self {
+            group: SocketAddr::from((Ipv4Addr::new(239, 255, 70, 77), 7946)),
+            interval: Duration::from_secs(1),
+            ttl: Duration::from_secs(3),
+        }
+    }
+}
+
+/// A service instance found by a [`DiscoveryListener`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Peer {
+    pub name: String,
+    /// The announcing host with the service's port
+    pub addr: SocketAddr,
+    /// Random per [`Beacon`], telling apart instances on one host and
+    /// restarts of the same one
+    pub instance: u64,
+}
+
+/// A change in the set of live peers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoveryEvent {
+    Up(Peer),
+    /// The peer said goodbye or missed announcements for the TTL
+    Down(Peer),
+}
+
+/// One datagram: `<magic> hello|bye <instance> <port> <name>`
+struct Announcement<'a> {
+    bye: bool,
+    instance: u64,
+    port: u16,
+    name: &'a str,
+}
+
+impl Announcement<'_> {
+    fn encode(&self) -> String {
+        let verb = if self.bye { "bye" } else { "hello" };
+        format!(
+            "{} {} {:016x} {} {}",
+            MAGIC, verb, self.instance, self.port, self.name
+        )
+    }
+
+    fn decode(datagram: &[u8]) -> Option<Announcement<'_>> {
+        let text = std::str::from_utf8(datagram).ok()?;
+        let mut parts = text.splitn(5, ' ');
+        if parts.next()? != MAGIC {
+            return None;
+        }
+        let bye = match parts.next()? {
+            "hello" => false,
+            "bye" => true,
+            _ => return None,
+        };
+        Some(Announcement {
+            bye,
+            instance: u64::from_str_radix(parts.next()?, 16).ok()?,
+            port: parts.next()?.parse().ok()?,
+            name: parts.next()?,
+        })
+    }
+}
+
+/// The limited broadcast address or, as a guess, a /24 subnet's one
+fn is_broadcast(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_broadcast() || addr.octets()[3] == 255,
+        IpAddr::V6(_) => false,
+    }
+}
+
+/// Announces one service instance until dropped
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use futures::StreamExt;
+/// use tokio_tutorial_patterns::io::{Beacon, DiscoveryConfig, DiscoveryEvent, DiscoveryListener};
+///
+/// let _beacon = Beacon::start("kv-store", 6379, DiscoveryConfig::default()).await?;
+///
+/// let mut peers = DiscoveryListener::bind(DiscoveryConfig::default()).await?;
+/// while let Some(event) = peers.next().await {
+///     match event? {
+///         DiscoveryEvent::Up(peer) => println!("{} is up at {}", peer.name, peer.addr),
+///         DiscoveryEvent::Down(peer) => println!("{} at {} is gone", peer.name, peer.addr),
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Beacon {
+    socket: Arc<UdpSocket>,
+    group: SocketAddr,
+    goodbye: String,
+    task: JoinHandle<()>,
+}
+
+impl Beacon {
+    /// Announces `name` on `port` to `config.group` every `config.interval`,
+    /// starting right away
+    pub async fn start(
+        name: impl Into<String>,
+        port: u16,
+        config: DiscoveryConfig,
+    ) -> io::Result<Self> {
+        let name = name.into();
+        let local: SocketAddr = match config.group {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        if is_broadcast(config.group.ip()) {
+            socket.set_broadcast(true)?;
+        }
+
+        let mut announcement = Announcement {
+            bye: false,
+            instance: rand::random(),
+            port,
+            name: &name,
+        };
+        let hello = announcement.encode();
+        announcement.bye = true;
+        let goodbye = announcement.encode();
+        let socket = Arc::new(socket);
+        let task = spawn_traced(
+            "discovery beacon",
+            announce(socket.clone(), config.group, hello, config.interval),
+        );
+
+        Ok(Self {
+            socket,
+            group: config.group,
+            goodbye,
+            task,
+        })
+    }
+}
+
+/// Stops announcing and says goodbye, so listeners drop the peer without
+/// waiting for the TTL
+impl Drop for Beacon {
+    fn drop(&mut self) {
+        self.task.abort();
+        // Best effort; a lost goodbye just leaves the peer to expire
+        let _ = self.socket.try_send_to(self.goodbye.as_bytes(), self.group);
+    }
+}
+
+async fn announce(socket: Arc<UdpSocket>, group: SocketAddr, hello: String, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if let Err(e) = socket.send_to(hello.as_bytes(), group).await {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(%group, error = %e, "discovery announcement failed");
+            #[cfg(not(feature = "tracing"))]
+            eprintln!("discovery announcement to {} failed: {}", group, e);
+        }
+    }
+}
+
+/// Receives announcements and tracks which peers are live, as a stream of
+/// [`DiscoveryEvent`]s
+///
+/// The stream never ends; a receive error is yielded and listening goes on.
+pub struct DiscoveryListener {
+    socket: UdpSocket,
+    ttl: Duration,
+    peers: ExpiryQueue<u64, Peer>,
+    buf: Box<[u8; MAX_DATAGRAM]>,
+}
+
+impl DiscoveryListener {
+    /// Listens on `config.group`'s port, joining the group if it is a
+    /// multicast address
+    ///
+    /// For multicast and broadcast the port is shared, so several listeners
+    /// on one host all see every announcement. Any other address is bound
+    /// as is.
+    pub async fn bind(config: DiscoveryConfig) -> io::Result<Self> {
+        let group = config.group;
+        let shared = group.ip().is_multicast() || is_broadcast(group.ip());
+        let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+        let local: SocketAddr = match group.ip() {
+            _ if !shared => group,
+            IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, group.port()).into(),
+            IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, group.port()).into(),
+        };
+        if shared {
+            socket.set_reuse_address(true)?;
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&local.into())?;
+        match group.ip() {
+            IpAddr::V4(ip) if ip.is_multicast() => {
+                socket.join_multicast_v4(&ip, &Ipv4Addr::UNSPECIFIED)?;
+            }
+            IpAddr::V6(ip) if ip.is_multicast() => socket.join_multicast_v6(&ip, 0)?,
+            _ => {}
+        }
+
+        Ok(Self {
+            socket: UdpSocket::from_std(socket.into())?,
+            ttl: config.ttl,
+            peers: ExpiryQueue::new(),
+            buf: Box::new([0; MAX_DATAGRAM]),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Peers currently considered live
+    pub fn live(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Applies one announcement, returning the event it causes, if any
+    fn receive(&mut self, len: usize, from: SocketAddr) -> Option<DiscoveryEvent> {
+        let announcement = Announcement::decode(&self.buf[..len])?;
+        let instance = announcement.instance;
+        if announcement.bye {
+            return self.peers.remove(&instance).map(DiscoveryEvent::Down);
+        }
+        if self.peers.reset(&instance, self.ttl) {
+            return None;
+        }
+        let peer = Peer {
+            name: announcement.name.to_string(),
+            addr: SocketAddr::new(from.ip(), announcement.port),
+            instance,
+        };
+        self.peers.insert(instance, peer.clone(), self.ttl);
+        Some(DiscoveryEvent::Up(peer))
+    }
+}
+
+impl Stream for DiscoveryListener {
+    type Item = io::Result<DiscoveryEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let mut buf = ReadBuf::new(&mut this.buf[..]);
+            match this.socket.poll_recv_from(cx, &mut buf) {
+                Poll::Ready(Ok(from)) => {
+                    let len = buf.filled().len();
+                    if let Some(event) = this.receive(len, from) {
+                        return Poll::Ready(Some(Ok(event)));
+                    }
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => break,
+            }
+        }
+        this.peers
+            .poll_expired(cx)
+            .map(|(_, peer)| Some(Ok(DiscoveryEvent::Down(peer))))
+    }
+}
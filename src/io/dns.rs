@@ -0,0 +1,123 @@
+//! DNS resolution with deadlines and Happy Eyeballs connection racing
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Resolution deadline used by [`connect_happy_eyeballs`]
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// RFC 8305 recommends 250ms between connection attempts
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolves `host` to all of its addresses, failing with `TimedOut` if the
+/// lookup takes longer than `timeout`
+pub async fn resolve(host: &str, timeout: Duration) -> std::io::Result<Vec<IpAddr>> {
+    let lookup = tokio::net::lookup_host((host, 0));
+    let addrs = tokio::time::timeout(timeout, lookup).await.map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("resolving {} timed out after {:?}", host, timeout),
+        )
+    })??;
+
+    let mut ips: Vec<IpAddr> = Vec::new();
+    for addr in addrs {
+        if !ips.contains(&addr.ip()) {
+            ips.push(addr.ip());
+        }
+    }
+
+    if ips.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no addresses found for {}", host),
+        ));
+    }
+    Ok(ips)
+}
+
+/// Resolves `host` and connects using Happy Eyeballs (RFC 8305)
+///
+/// IPv6 and IPv4 addresses are interleaved and attempted in a staggered race;
+/// the first connection to succeed wins and the others are dropped.
+pub async fn connect_happy_eyeballs(host: &str, port: u16) -> std::io::Result<TcpStream> {
+    let ips = resolve(host, RESOLVE_TIMEOUT).await?;
+    let addrs = ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect();
+    connect_addrs(addrs, CONNECTION_ATTEMPT_DELAY).await
+}
+
+/// Races connection attempts to `addrs`, starting a new attempt every
+/// `attempt_delay` or as soon as the previous one fails
+///
+/// Returns the last error if every attempt fails.
+pub async fn connect_addrs(
+    addrs: Vec<SocketAddr>,
+    attempt_delay: Duration,
+) -> std::io::Result<TcpStream> {
+    let mut pending = interleave_families(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    match pending.next() {
+        Some(addr) => attempts.push(TcpStream::connect(addr)),
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no addresses to connect to",
+            ))
+        }
+    }
+
+    let delay = tokio::time::sleep(attempt_delay);
+    tokio::pin!(delay);
+
+    loop {
+        tokio::select! {
+            result = attempts.next(), if !attempts.is_empty() => {
+                match result {
+                    Some(Ok(stream)) => return Ok(stream),
+                    Some(Err(e)) => {
+                        last_error = Some(e);
+                        // A failure starts the next attempt right away
+                        if let Some(addr) = pending.next() {
+                            attempts.push(TcpStream::connect(addr));
+                            delay.as_mut().reset(tokio::time::Instant::now() + attempt_delay);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            _ = &mut delay, if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(TcpStream::connect(addr));
+                }
+                delay.as_mut().reset(tokio::time::Instant::now() + attempt_delay);
+            }
+        }
+
+        if attempts.is_empty() && pending.len() == 0 {
+            return Err(last_error.unwrap_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotConnected, "all attempts failed")
+            }));
+        }
+    }
+}
+
+/// Orders addresses IPv6 first, alternating families (RFC 8305 section 4)
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut ordered = Vec::new();
+
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Advisory file locks usable from async code
+
+use std::fs::{File, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often the timeout variants retry a contended lock
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Shared,
+    Exclusive,
+}
+
+/// An advisory lock on a file, coordinating processes that share state files
+///
+/// Locks are advisory: they only exclude other processes that also lock the
+/// file. Blocking acquisition runs on the blocking thread pool so it never
+/// stalls the runtime.
+///
+/// The lock belongs to the open file handle, so tasks that must exclude each
+/// other should each [`open`](Self::open) their own `FileLock`, and each
+/// `FileLock` should hand out one guard at a time.
+#[derive(Debug)]
+pub struct FileLock {
+    file: Arc<File>,
+    path: PathBuf,
+}
+
+/// Holds a lock until dropped
+#[derive(Debug)]
+pub struct FileLockGuard {
+    file: Arc<File>,
+}
+
+impl Drop for FileLockGuard {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+impl FileLock {
+    /// Opens (creating if needed) the file to lock
+    pub async fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await?
+            .into_std()
+            .await;
+
+        Ok(Self {
+            file: Arc::new(file),
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Waits until no other holder has any lock on the file
+    pub async fn lock_exclusive(&self) -> std::io::Result<FileLockGuard> {
+        self.lock_blocking(Mode::Exclusive).await
+    }
+
+    /// Waits until no other holder has an exclusive lock on the file
+    pub async fn lock_shared(&self) -> std::io::Result<FileLockGuard> {
+        self.lock_blocking(Mode::Shared).await
+    }
+
+    /// Takes the exclusive lock if it is free right now
+    pub fn try_lock_exclusive(&self) -> std::io::Result<Option<FileLockGuard>> {
+        self.try_lock(Mode::Exclusive)
+    }
+
+    /// Takes a shared lock if no exclusive lock is held right now
+    pub fn try_lock_shared(&self) -> std::io::Result<Option<FileLockGuard>> {
+        self.try_lock(Mode::Shared)
+    }
+
+    /// Like [`lock_exclusive`](Self::lock_exclusive), failing with
+    /// `ErrorKind::TimedOut` if the lock isn't acquired in time
+    pub async fn lock_exclusive_timeout(
+        &self,
+        timeout: Duration,
+    ) -> std::io::Result<FileLockGuard> {
+        self.lock_until(Mode::Exclusive, Instant::now() + timeout)
+            .await
+    }
+
+    /// Like [`lock_shared`](Self::lock_shared), failing with
+    /// `ErrorKind::TimedOut` if the lock isn't acquired in time
+    pub async fn lock_shared_timeout(&self, timeout: Duration) -> std::io::Result<FileLockGuard> {
+        self.lock_until(Mode::Shared, Instant::now() + timeout)
+            .await
+    }
+
+    async fn lock_blocking(&self, mode: Mode) -> std::io::Result<FileLockGuard> {
+        let file = Arc::clone(&self.file);
+        tokio::task::spawn_blocking(move || {
+            match mode {
+                Mode::Shared => file.lock_shared()?,
+                Mode::Exclusive => file.lock()?,
+            }
+            Ok(FileLockGuard { file })
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    fn try_lock(&self, mode: Mode) -> std::io::Result<Option<FileLockGuard>> {
+        let result = match mode {
+            Mode::Shared => self.file.try_lock_shared(),
+            Mode::Exclusive => self.file.try_lock(),
+        };
+
+        match result {
+            Ok(()) => Ok(Some(FileLockGuard {
+                file: Arc::clone(&self.file),
+            })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    // A blocking lock() can't be cancelled once it is running on the blocking
+    // pool, so timeouts poll try_lock instead of racing spawn_blocking.
+    async fn lock_until(&self, mode: Mode, deadline: Instant) -> std::io::Result<FileLockGuard> {
+        loop {
+            if let Some(guard) = self.try_lock(mode)? {
+                return Ok(guard);
+            }
+            if Instant::now() >= deadline {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("timed out locking {}", self.path.display()),
+                ));
+            }
+            tokio::time::sleep_until((Instant::now() + LOCK_POLL_INTERVAL).min(deadline)).await;
+        }
+    }
+}
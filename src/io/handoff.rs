@@ -0,0 +1,117 @@
+//! Moving a listening address to a new listener while refusing as few
+//! connections as possible
+//!
+//! With `SO_REUSEPORT` several sockets can listen on one address at once,
+//! and the kernel spreads new connections between them. That makes
+//! replacing a listener, to pick up new socket options on a config reload
+//! or to hand the port to an upgraded binary, a matter of binding the
+//! successor first, then retiring the old listener: stop accepting on it,
+//! accept whatever it already has queued, and close it. Closing a listener
+//! resets connections still in its queue, which the middle step keeps to a
+//! minimum.
+//!
+//! This is not a zero-downtime handoff. The kernel keeps sending some new
+//! connections to the old listener until it is closed, and one that
+//! arrives after its last accept is reset. The window is short, but under
+//! steady load a handful of clients can hit it. Only Linux with
+//! `net.ipv4.tcp_migrate_req = 1` moves those connections to a remaining
+//! listener instead; elsewhere clients should retry a reset connect.
+//!
+//! Only on Unix, where `SO_REUSEPORT` exists.
+
+use super::{Server, SocketConfig};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+/// Binds listeners that can take over one address from each other
+///
+/// In one process, [`replace`](Self::replace) starts a successor [`Server`]
+/// and retires the old one's listener. For a binary upgrade the new process
+/// binds with [`ListenerHandoff::bind`] on the same address while the old
+/// one is still running, and the old process then calls
+/// [`Server::stop_accepting`] before shutting down. Both processes must run
+/// as the same user.
+///
+/// A connection that arrives in the instant between the old listener's last
+/// accept and its close is reset, unless the kernel migrates it (Linux with
+/// `net.ipv4.tcp_migrate_req = 1`), so this is not a zero-downtime handoff.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use tokio_tutorial_patterns::io::{ListenerHandoff, Server, ShutdownConfig, SocketConfig};
+///
+/// async fn echo(
+///     mut socket: tokio::net::TcpStream,
+///     _peer: std::net::SocketAddr,
+///     _shutdown: tokio::sync::watch::Receiver<bool>,
+/// ) -> std::io::Result<()> {
+///     let (mut reader, mut writer) = socket.split();
+///     tokio::io::copy(&mut reader, &mut writer).await.map(|_| ())
+/// }
+///
+/// let (handoff, listener) = ListenerHandoff::bind("127.0.0.1:7000".parse().unwrap())?;
+/// let mut current = Server::start(listener, "echo", echo);
+///
+/// // On SIGHUP, with the reloaded socket options
+/// let handoff = handoff.config(SocketConfig::new().backlog(4096));
+/// let next = handoff
+///     .replace(&mut current, |listener| Server::start(listener, "echo", echo))
+///     .await?;
+/// let old = std::mem::replace(&mut current, next);
+/// old.shutdown_phased(ShutdownConfig::default()).await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ListenerHandoff {
+    addr: SocketAddr,
+    config: SocketConfig,
+}
+
+impl ListenerHandoff {
+    /// Binds the first listener on `addr`
+    ///
+    /// Port 0 picks a free port, which successors then share.
+    pub fn bind(addr: SocketAddr) -> io::Result<(Self, TcpListener)> {
+        Self::bind_with(addr, SocketConfig::new())
+    }
+
+    /// [`bind`](Self::bind) with socket options; `SO_REUSEPORT` is always set
+    pub fn bind_with(addr: SocketAddr, config: SocketConfig) -> io::Result<(Self, TcpListener)> {
+        let config = config.reuse_port(true);
+        let listener = config.bind(addr)?;
+        let addr = listener.local_addr()?;
+        Ok((Self { addr, config }, listener))
+    }
+
+    /// Socket options for successors from now on; `SO_REUSEPORT` is always
+    /// set
+    pub fn config(mut self, config: SocketConfig) -> Self {
+        self.config = config.reuse_port(true);
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Binds another listener on the address, alongside those already there
+    pub fn successor(&self) -> io::Result<TcpListener> {
+        self.config.bind(self.addr)
+    }
+
+    /// Starts the server `start` builds on a [`successor`](Self::successor),
+    /// then retires `old`'s listener
+    ///
+    /// `old` keeps serving the connections it has, including any it picks
+    /// up from its queue while retiring, until it is shut down.
+    pub async fn replace<F>(&self, old: &mut Server, start: F) -> io::Result<Server>
+    where
+        F: FnOnce(TcpListener) -> Server,
+    {
+        let server = start(self.successor()?);
+        old.stop_accepting().await;
+        Ok(server)
+    }
+}
@@ -0,0 +1,206 @@
+//! Checksumming `AsyncRead`/`AsyncWrite` adapters
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// An incremental digest that can be fed while data streams through an adapter
+pub trait Digest {
+    /// The final hash value
+    type Output: Clone;
+
+    /// Feeds more bytes into the digest
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the hash of everything fed so far
+    fn finalize(&self) -> Self::Output;
+}
+
+/// CRC-32 (IEEE) digest
+#[cfg(feature = "crc32")]
+#[derive(Default, Clone)]
+pub struct Crc32 {
+    hasher: crc32fast::Hasher,
+}
+
+#[cfg(feature = "crc32")]
+impl Crc32 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "crc32")]
+impl Digest for Crc32 {
+    type Output = u32;
+
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn finalize(&self) -> u32 {
+        self.hasher.clone().finalize()
+    }
+}
+
+/// SHA-256 digest
+#[cfg(feature = "sha256")]
+#[derive(Default, Clone)]
+pub struct Sha256 {
+    hasher: sha2::Sha256,
+}
+
+#[cfg(feature = "sha256")]
+impl Sha256 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "sha256")]
+impl Digest for Sha256 {
+    type Output = [u8; 32];
+
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(&mut self.hasher, data);
+    }
+
+    fn finalize(&self) -> [u8; 32] {
+        sha2::Digest::finalize(self.hasher.clone()).into()
+    }
+}
+
+/// Wraps a reader and hashes every byte read through it
+pub struct HashingReader<R, D> {
+    inner: R,
+    digest: D,
+    bytes: u64,
+    eof: bool,
+}
+
+impl<R, D: Digest> HashingReader<R, D> {
+    pub fn new(inner: R, digest: D) -> Self {
+        Self {
+            inner,
+            digest,
+            bytes: 0,
+            eof: false,
+        }
+    }
+
+    /// Returns the final hash once the inner reader has reached EOF
+    pub fn hash(&self) -> Option<D::Output> {
+        self.eof.then(|| self.digest.finalize())
+    }
+
+    /// Number of bytes read so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Consumes the adapter, returning the inner reader and the hash so far
+    pub fn into_parts(self) -> (R, D::Output) {
+        let hash = self.digest.finalize();
+        (self.inner, hash)
+    }
+}
+
+impl<R: AsyncRead + Unpin, D: Digest + Unpin> AsyncRead for HashingReader<R, D> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let this = &mut *self;
+
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                let new = &buf.filled()[before..];
+                if new.is_empty() && buf.remaining() > 0 {
+                    this.eof = true;
+                } else {
+                    this.digest.update(new);
+                    this.bytes += new.len() as u64;
+                }
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+/// Wraps a writer and hashes every byte accepted by it
+pub struct HashingWriter<W, D> {
+    inner: W,
+    digest: D,
+    bytes: u64,
+    flushed: bool,
+}
+
+impl<W, D: Digest> HashingWriter<W, D> {
+    pub fn new(inner: W, digest: D) -> Self {
+        Self {
+            inner,
+            digest,
+            bytes: 0,
+            flushed: false,
+        }
+    }
+
+    /// Returns the final hash once everything written has been flushed
+    pub fn hash(&self) -> Option<D::Output> {
+        self.flushed.then(|| self.digest.finalize())
+    }
+
+    /// Number of bytes written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Consumes the adapter, returning the inner writer and the hash so far
+    pub fn into_parts(self) -> (W, D::Output) {
+        let hash = self.digest.finalize();
+        (self.inner, hash)
+    }
+}
+
+impl<W: AsyncWrite + Unpin, D: Digest + Unpin> AsyncWrite for HashingWriter<W, D> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => {
+                // Only the bytes the inner writer accepted are part of the stream
+                this.digest.update(&buf[..n]);
+                this.bytes += n as u64;
+                this.flushed = false;
+                Poll::Ready(Ok(n))
+            }
+            other => other,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_flush(cx);
+        if let Poll::Ready(Ok(())) = result {
+            this.flushed = true;
+        }
+        result
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_shutdown(cx);
+        if let Poll::Ready(Ok(())) = result {
+            this.flushed = true;
+        }
+        result
+    }
+}
@@ -0,0 +1,211 @@
+//! Heartbeat layer that detects silent peers on framed transports
+
+use futures::{Sink, Stream};
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior, Sleep};
+
+/// How a frame type represents heartbeat pings and pongs
+pub trait HeartbeatFrame: Sized {
+    fn ping() -> Self;
+    fn pong() -> Self;
+    fn is_ping(&self) -> bool;
+    fn is_pong(&self) -> bool;
+}
+
+/// Line-based transports (e.g. `LinesCodec`) use `PING` and `PONG` lines
+impl HeartbeatFrame for String {
+    fn ping() -> Self {
+        "PING".to_string()
+    }
+
+    fn pong() -> Self {
+        "PONG".to_string()
+    }
+
+    fn is_ping(&self) -> bool {
+        self == "PING"
+    }
+
+    fn is_pong(&self) -> bool {
+        self == "PONG"
+    }
+}
+
+/// Errors surfaced by [`Heartbeat`]
+#[derive(Debug)]
+pub enum HeartbeatError<E> {
+    /// No pong arrived within the deadline after a ping
+    ConnectionDead { silent_for: Duration },
+    /// The underlying transport failed
+    Transport(E),
+}
+
+impl<E: fmt::Display> fmt::Display for HeartbeatError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeartbeatError::ConnectionDead { silent_for } => {
+                write!(f, "connection dead: peer silent for {:?}", silent_for)
+            }
+            HeartbeatError::Transport(e) => write!(f, "transport error: {}", e),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for HeartbeatError<E> {}
+
+/// Wraps a framed transport, sending pings every `interval` and failing with
+/// [`HeartbeatError::ConnectionDead`] if a pong doesn't arrive within `timeout`
+///
+/// Pings from the peer are answered automatically and neither pings nor pongs
+/// are yielded to the caller. Heartbeats only run while the stream side is
+/// being polled, which is the case for any connection with a read loop.
+pub struct Heartbeat<S, F> {
+    inner: S,
+    interval: Interval,
+    timeout: Duration,
+    pong_deadline: Option<Pin<Box<Sleep>>>,
+    last_heard: Instant,
+    outgoing: VecDeque<F>,
+    dead: bool,
+}
+
+impl<S, F> Heartbeat<S, F> {
+    pub fn new(inner: S, interval: Duration, timeout: Duration) -> Self {
+        let mut interval = tokio::time::interval_at(Instant::now() + interval, interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            inner,
+            interval,
+            timeout,
+            pong_deadline: None,
+            last_heard: Instant::now(),
+            outgoing: VecDeque::new(),
+            dead: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, F, E> Heartbeat<S, F>
+where
+    S: Sink<F, Error = E> + Unpin,
+    F: Unpin,
+{
+    /// Pushes queued pings/pongs into the transport without blocking on it
+    fn poll_send_control(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        if self.outgoing.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+
+        while !self.outgoing.is_empty() {
+            match Pin::new(&mut self.inner).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let frame = self.outgoing.pop_front().unwrap();
+                    Pin::new(&mut self.inner).start_send(frame)?;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+}
+
+impl<S, F, E> Stream for Heartbeat<S, F>
+where
+    S: Stream<Item = Result<F, E>> + Sink<F, Error = E> + Unpin,
+    F: HeartbeatFrame + Unpin,
+{
+    type Item = Result<F, HeartbeatError<E>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if this.dead {
+            return Poll::Ready(None);
+        }
+
+        // Only one ping is outstanding at a time
+        while this.interval.poll_tick(cx).is_ready() {
+            if this.pong_deadline.is_none() {
+                this.outgoing.push_back(F::ping());
+                this.pong_deadline = Some(Box::pin(tokio::time::sleep(this.timeout)));
+            }
+        }
+
+        if let Some(deadline) = this.pong_deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_ready() {
+                this.dead = true;
+                return Poll::Ready(Some(Err(HeartbeatError::ConnectionDead {
+                    silent_for: this.last_heard.elapsed(),
+                })));
+            }
+        }
+
+        if let Poll::Ready(Err(e)) = this.poll_send_control(cx) {
+            return Poll::Ready(Some(Err(HeartbeatError::Transport(e))));
+        }
+
+        loop {
+            match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(frame))) => {
+                    this.last_heard = Instant::now();
+                    if frame.is_ping() {
+                        this.outgoing.push_back(F::pong());
+                        if let Poll::Ready(Err(e)) = this.poll_send_control(cx) {
+                            return Poll::Ready(Some(Err(HeartbeatError::Transport(e))));
+                        }
+                    } else if frame.is_pong() {
+                        this.pong_deadline = None;
+                    } else {
+                        return Poll::Ready(Some(Ok(frame)));
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    return Poll::Ready(Some(Err(HeartbeatError::Transport(e))))
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S, F, E> Sink<F> for Heartbeat<S, F>
+where
+    S: Sink<F, Error = E> + Unpin,
+    F: Unpin,
+{
+    type Error = E;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        futures::ready!(self.poll_send_control(cx))?;
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: F) -> Result<(), E> {
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        futures::ready!(self.poll_send_control(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), E>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
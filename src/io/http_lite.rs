@@ -0,0 +1,434 @@
+//! Just enough HTTP/1.1 to serve health-check and metrics endpoints
+//!
+//! Supports request lines, headers, `Content-Length` bodies, keep-alive and
+//! graceful shutdown. Chunked request bodies, pipelining tricks and TLS are
+//! deliberately out of scope; reach for hyper when you need those.
+
+use super::{serve_traced, Acceptor};
+use crate::ratelimit::KeyedLimiter;
+use crate::select::{LoadShed, ShedError};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+/// Upper bound on the request line plus all headers
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// Upper bound on a request body
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+/// How long an idle keep-alive connection is held open
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A parsed HTTP request
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// The path without the query string
+    pub path: String,
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// The client's address, or `None` if the connection wasn't accepted
+    /// from a TCP listener
+    pub peer: Option<SocketAddr>,
+}
+
+impl Request {
+    /// Looks up a header value, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn wants_keep_alive(&self) -> bool {
+        match self.header("connection") {
+            Some(v) if v.eq_ignore_ascii_case("close") => false,
+            Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+}
+
+/// An HTTP response to be written back to the client
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// A `200 OK` with a plain-text body
+    pub fn text(body: impl Into<String>) -> Self {
+        Self::new(200)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body.into().into_bytes())
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(404).body(b"Not Found".to_vec())
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    async fn write_to<W>(&self, writer: &mut W, keep_alive: bool) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        head.push_str(if keep_alive {
+            "Connection: keep-alive\r\n\r\n"
+        } else {
+            "Connection: close\r\n\r\n"
+        });
+
+        writer.write_all(head.as_bytes()).await?;
+        writer.write_all(&self.body).await?;
+        writer.flush().await
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+type Handler = Arc<dyn Fn(Request) -> BoxFuture<'static, Response> + Send + Sync>;
+type KeyFn = Arc<dyn Fn(&Request) -> String + Send + Sync>;
+
+/// Maps `(method, path)` pairs to async handler closures
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: HashMap<(String, String), Handler>,
+    shed: Option<LoadShed>,
+    limiter: Option<(KeyedLimiter<String>, KeyFn)>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler for an exact method and path
+    pub fn route<F, Fut>(mut self, method: &str, path: &str, handler: F) -> Self
+    where
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |req| Box::pin(handler(req)));
+        self.routes
+            .insert((method.to_ascii_uppercase(), path.to_string()), handler);
+        self
+    }
+
+    /// Answers `503 Service Unavailable` while `shed` reports overload
+    ///
+    /// Responses with a 5xx status count as failed requests.
+    pub fn load_shed(mut self, shed: LoadShed) -> Self {
+        self.shed = Some(shed);
+        self
+    }
+
+    /// Answers `429 Too Many Requests` to requests whose key, as returned
+    /// by `key`, is over its quota in `limiter`
+    ///
+    /// Requests are checked before load shedding, so rejected ones don't
+    /// count towards it. Typical keys are an API key or the client's
+    /// address, [`Request::peer`]. Clients choose their own headers, so
+    /// key on `X-Forwarded-For` only behind a trusted proxy that overwrites
+    /// it.
+    ///
+    /// A key evicted from `limiter` starts over with a full bucket, so a
+    /// client able to pick from more keys than `limiter` tracks can get
+    /// past its quota by rotating through them.
+    pub fn rate_limit<F>(mut self, limiter: KeyedLimiter<String>, key: F) -> Self
+    where
+        F: Fn(&Request) -> String + Send + Sync + 'static,
+    {
+        self.limiter = Some((limiter, Arc::new(key)));
+        self
+    }
+
+    /// Dispatches a request, answering 404/405 when nothing matches
+    pub async fn handle(&self, req: Request) -> Response {
+        if let Some((limiter, key)) = &self.limiter {
+            if let Err(limited) = limiter.check(&key(&req)) {
+                let retry_after = limited.retry_after.as_secs_f64().ceil() as u64;
+                return Response::new(429)
+                    .header("Retry-After", retry_after.max(1).to_string())
+                    .body(b"Too Many Requests".to_vec());
+            }
+        }
+
+        let Some(shed) = &self.shed else {
+            return self.dispatch(req).await;
+        };
+
+        let result = shed
+            .call(|| async {
+                let response = self.dispatch(req).await;
+                if response.status >= 500 {
+                    Err(response)
+                } else {
+                    Ok(response)
+                }
+            })
+            .await;
+        match result {
+            Ok(response) | Err(ShedError::Failed(response)) => response,
+            Err(ShedError::Overloaded(_)) => Response::new(503)
+                .header("Retry-After", "1")
+                .body(b"Service Unavailable".to_vec()),
+        }
+    }
+
+    async fn dispatch(&self, req: Request) -> Response {
+        if let Some(handler) = self.routes.get(&(req.method.clone(), req.path.clone())) {
+            return handler(req).await;
+        }
+
+        if self.routes.keys().any(|(_, path)| *path == req.path) {
+            Response::new(405).body(b"Method Not Allowed".to_vec())
+        } else {
+            Response::not_found()
+        }
+    }
+}
+
+/// Serves the router on the given address until Ctrl-C is pressed
+pub async fn http_server(addr: &str, router: Router) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("HTTP server listening on: {}", addr);
+
+    serve(listener, router, async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+    .await
+}
+
+/// Serves the router on an existing listener until `shutdown` completes
+///
+/// On shutdown the listener is closed, idle keep-alive connections are
+/// dropped, and in-flight requests are answered with `Connection: close`.
+/// Accept errors are retried as the [`Acceptor`]'s policy allows.
+pub async fn serve<F>(
+    listener: impl Into<Acceptor>,
+    router: Router,
+    shutdown: F,
+) -> std::io::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let handler = move |socket, peer, shutdown_rx| {
+        serve_connection(socket, Some(peer), router.clone(), shutdown_rx)
+    };
+    serve_traced(listener, "http", handler, shutdown).await
+}
+
+/// Serves requests on a single connection until it closes
+///
+/// `peer` is passed on to handlers as [`Request::peer`].
+pub async fn serve_connection<S>(
+    stream: S,
+    peer: Option<SocketAddr>,
+    router: Router,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        if *shutdown_rx.borrow() {
+            return Ok(());
+        }
+
+        let request = tokio::select! {
+            request = tokio::time::timeout(KEEP_ALIVE_TIMEOUT, read_request(&mut stream)) => {
+                match request {
+                    Ok(request) => request,
+                    Err(_) => return Ok(()),
+                }
+            }
+            _ = shutdown_rx.changed() => return Ok(()),
+        };
+
+        let request = match request {
+            Ok(Some(request)) => Request { peer, ..request },
+            Ok(None) => return Ok(()),
+            Err(status) => {
+                return Response::new(status)
+                    .write_to(stream.get_mut(), false)
+                    .await;
+            }
+        };
+
+        let keep_alive = request.wants_keep_alive();
+        let response = router.handle(request).await;
+        let keep_alive = keep_alive && !*shutdown_rx.borrow();
+        response.write_to(stream.get_mut(), keep_alive).await?;
+
+        if !keep_alive {
+            return stream.get_mut().shutdown().await;
+        }
+    }
+}
+
+/// Reads one line of the request head, buffering at most `limit` bytes, so
+/// a client that never sends a newline can't make it grow without bound
+///
+/// A line cut short by the limit comes back without its `\n`.
+async fn read_head_line<R>(reader: &mut R, line: &mut String, limit: usize) -> Result<usize, u16>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut limited = (&mut *reader).take(limit as u64);
+    limited.read_line(line).await.map_err(|_| 400u16)
+}
+
+/// Reads one request, returning `Ok(None)` on a clean EOF between requests
+/// and `Err(status)` when the request is malformed
+async fn read_request<R>(reader: &mut R) -> Result<Option<Request>, u16>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut head_size = 0;
+    let mut line = String::new();
+
+    let read = read_head_line(reader, &mut line, MAX_HEAD_SIZE).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(400);
+    }
+    head_size += read;
+
+    let mut parts = line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => {
+            (m.to_string(), t.to_string(), v.to_string())
+        }
+        _ => return Err(400),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        let read = read_head_line(reader, &mut line, MAX_HEAD_SIZE - head_size).await?;
+        if read == 0 {
+            // Either the budget ran out on a line boundary or the client hung up
+            return Err(if head_size == MAX_HEAD_SIZE { 431 } else { 400 });
+        }
+        head_size += read;
+        if !line.ends_with('\n') {
+            // Out of budget, unless the client hung up mid-line
+            return Err(if head_size == MAX_HEAD_SIZE { 431 } else { 400 });
+        }
+
+        let trimmed = line.trim_end();
+        if trimmed.is_empty() {
+            break;
+        }
+        match trimmed.split_once(':') {
+            Some((name, value)) => {
+                headers.push((name.trim().to_string(), value.trim().to_string()))
+            }
+            None => return Err(400),
+        }
+    }
+
+    let mut request = Request {
+        method: method.to_ascii_uppercase(),
+        path: String::new(),
+        query: None,
+        version,
+        headers,
+        body: Vec::new(),
+        peer: None,
+    };
+
+    match target.split_once('?') {
+        Some((path, query)) => {
+            request.path = path.to_string();
+            request.query = Some(query.to_string());
+        }
+        None => request.path = target,
+    }
+
+    if request.header("transfer-encoding").is_some() {
+        return Err(501);
+    }
+
+    // Differing lengths would let a proxy and this server disagree on where
+    // the body ends, so they are refused rather than picking one
+    let mut lengths = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.as_str());
+    if let Some(length) = lengths.next() {
+        if lengths.any(|other| other != length) {
+            return Err(400);
+        }
+        let length: usize = length.parse().map_err(|_| 400u16)?;
+        if length > MAX_BODY_SIZE {
+            return Err(413);
+        }
+        request.body = vec![0; length];
+        reader
+            .read_exact(&mut request.body)
+            .await
+            .map_err(|_| 400u16)?;
+    }
+
+    Ok(Some(request))
+}
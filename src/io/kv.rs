@@ -0,0 +1,391 @@
+//! A mini key-value server speaking a GET/SET/DEL line protocol
+//!
+//! Each request and response is a single line:
+//!
+//! | Request           | Response                          |
+//! |-------------------|-----------------------------------|
+//! | `GET key`         | `VALUE value` or `NIL`            |
+//! | `SET key value`   | `OK`                              |
+//! | `DEL key`         | `DELETED` or `NIL`                |
+//! | anything else     | `ERR message`                     |
+//!
+//! Values may contain spaces; everything after the key is the value. Keys
+//! can't contain whitespace and values can't contain line breaks, and
+//! [`Client`] refuses to send either.
+//!
+//! When the server shuts down it stops reading requests and, after any
+//! response in progress, sends one last unsolicited line before closing:
+//! `BYE reason`, or `REDIRECT addr reason` when clients should reconnect
+//! elsewhere. [`Client`] reports either as [`ClientError::GoingAway`].
+
+use super::{serve_traced, Acceptor, Goodbye};
+use crate::shared_state::AsyncMap;
+use bytes::BytesMut;
+use futures::SinkExt;
+use std::fmt;
+use std::future::Future;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::watch;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Decoder, Encoder, Framed, LinesCodec, LinesCodecError};
+
+/// Longest request or response line accepted on the wire
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// A parsed client request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Get(String),
+    Set(String, String),
+    Del(String),
+}
+
+impl Command {
+    /// Parses one request line
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let line = line.trim_end_matches('\r');
+        let (verb, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+        match verb.to_ascii_uppercase().as_str() {
+            "GET" => single_key(rest).map(Command::Get),
+            "DEL" => single_key(rest).map(Command::Del),
+            "SET" => match rest.split_once(' ') {
+                Some((key, value)) if is_valid_key(key) => {
+                    Ok(Command::Set(key.to_string(), value.to_string()))
+                }
+                _ => Err("SET requires a key and a value".to_string()),
+            },
+            "" => Err("empty command".to_string()),
+            other => Err(format!("unknown command '{}'", other)),
+        }
+    }
+}
+
+/// Checks that a command survives the trip through a single request line:
+/// keys must be non-empty with no whitespace, values must not break the line
+fn check_framing(command: &Command) -> std::io::Result<()> {
+    let (key, value) = match command {
+        Command::Get(key) | Command::Del(key) => (key, None),
+        Command::Set(key, value) => (key, Some(value)),
+    };
+    if !is_valid_key(key) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "key must be non-empty and contain no whitespace",
+        ));
+    }
+    if value.is_some_and(|value| value.contains(['\r', '\n'])) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "value must not contain CR or LF",
+        ));
+    }
+    Ok(())
+}
+
+/// Whether a key can be sent as one word of a request line; shared by the
+/// server's parser and [`Client`]'s check so both agree
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && !key.contains(char::is_whitespace)
+}
+
+fn single_key(rest: &str) -> Result<String, String> {
+    match rest {
+        "" => Err("missing key".to_string()),
+        key if !is_valid_key(key) => Err("expected a single key".to_string()),
+        key => Ok(key.to_string()),
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Get(key) => write!(f, "GET {}", key),
+            Command::Set(key, value) => write!(f, "SET {} {}", key, value),
+            Command::Del(key) => write!(f, "DEL {}", key),
+        }
+    }
+}
+
+/// Applies a command to the store and renders the response line
+pub async fn execute(store: &AsyncMap<String, String>, command: Command) -> String {
+    match command {
+        Command::Get(key) => match store.get(&key).await {
+            Some(value) => format!("VALUE {}", value),
+            None => "NIL".to_string(),
+        },
+        Command::Set(key, value) => {
+            store.insert(key, value).await;
+            "OK".to_string()
+        }
+        Command::Del(key) => match store.remove(&key).await {
+            Some(_) => "DELETED".to_string(),
+            None => "NIL".to_string(),
+        },
+    }
+}
+
+/// Runs the key-value server on the given address until Ctrl-C is pressed
+pub async fn kv_server(addr: &str) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("KV server listening on: {}", addr);
+
+    serve(listener, AsyncMap::new(), async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+    .await
+}
+
+/// Serves the protocol on an existing listener until `shutdown` completes,
+/// then says a default [`Goodbye`] to every client
+///
+/// A fatal accept error stops the server and is returned; pass an
+/// [`Acceptor`] to choose which errors are retried.
+pub async fn serve<F>(
+    listener: impl Into<Acceptor>,
+    store: AsyncMap<String, String>,
+    shutdown: F,
+) -> std::io::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    serve_with(listener, store, shutdown, Goodbye::default()).await
+}
+
+/// [`serve`], telling clients `goodbye` on shutdown
+pub async fn serve_with<F>(
+    listener: impl Into<Acceptor>,
+    store: AsyncMap<String, String>,
+    shutdown: F,
+    goodbye: Goodbye,
+) -> std::io::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let handler = move |socket, _addr, shutdown_rx| {
+        handle_connection(socket, store.clone(), shutdown_rx, goodbye.clone())
+    };
+    serve_traced(listener, "kv", handler, shutdown).await
+}
+
+/// Request lines, with an over-long line decoded as an `Err` item rather
+/// than an error, which would end the `Framed` stream
+struct RequestCodec(LinesCodec);
+
+impl RequestCodec {
+    fn new() -> Self {
+        Self(LinesCodec::new_with_max_length(MAX_LINE_LENGTH))
+    }
+
+    fn skip_too_long(
+        line: Result<Option<String>, LinesCodecError>,
+    ) -> Result<Option<Result<String, LinesCodecError>>, LinesCodecError> {
+        match line {
+            Ok(line) => Ok(line.map(Ok)),
+            // The codec discards the rest of the line before the next one
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                Ok(Some(Err(LinesCodecError::MaxLineLengthExceeded)))
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Decoder for RequestCodec {
+    type Item = Result<String, LinesCodecError>;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, LinesCodecError> {
+        Self::skip_too_long(self.0.decode(src))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, LinesCodecError> {
+        Self::skip_too_long(self.0.decode_eof(src))
+    }
+}
+
+impl Encoder<String> for RequestCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, line: String, dst: &mut BytesMut) -> Result<(), LinesCodecError> {
+        self.0.encode(line, dst)
+    }
+}
+
+async fn handle_connection(
+    socket: TcpStream,
+    store: AsyncMap<String, String>,
+    mut shutdown_rx: watch::Receiver<bool>,
+    goodbye: Goodbye,
+) -> Result<(), LinesCodecError> {
+    let mut framed = Framed::new(socket, RequestCodec::new());
+
+    loop {
+        let line = tokio::select! {
+            line = framed.next() => line,
+            _ = shutdown_rx.changed() => break,
+        };
+
+        let response = match line {
+            Some(Ok(Ok(line))) => match Command::parse(&line) {
+                Ok(command) => execute(&store, command).await,
+                Err(e) => format!("ERR {}", e),
+            },
+            Some(Ok(Err(_))) => "ERR line too long".to_string(),
+            Some(Err(e)) => return Err(e),
+            None => return Ok(()),
+        };
+
+        // A client that stops reading mustn't keep the server from draining
+        tokio::select! {
+            sent = framed.send(response) => sent?,
+            _ = shutdown_rx.changed() => break,
+        }
+    }
+
+    let line = match &goodbye.redirect {
+        Some(addr) => format!("REDIRECT {} {}", addr, goodbye.reason),
+        None => format!("BYE {}", goodbye.reason),
+    };
+    let farewell = async {
+        framed.send(line).await?;
+        framed.get_mut().shutdown().await?;
+        Ok(())
+    };
+    // A client that isn't reading doesn't get to hold up shutdown
+    tokio::time::timeout(goodbye.flush_timeout, farewell)
+        .await
+        .unwrap_or(Ok(()))
+}
+
+/// Errors returned by [`Client`]
+#[derive(Debug)]
+pub enum ClientError {
+    Io(std::io::Error),
+    /// The server replied with `ERR ...`
+    Server(String),
+    /// The server replied with something this client doesn't understand
+    Protocol(String),
+    ConnectionClosed,
+    /// The server is shutting down, and may have named another to use
+    GoingAway {
+        reason: String,
+        redirect: Option<String>,
+    },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "io error: {}", e),
+            ClientError::Server(msg) => write!(f, "server error: {}", msg),
+            ClientError::Protocol(line) => write!(f, "unexpected response: {}", line),
+            ClientError::ConnectionClosed => write!(f, "connection closed by server"),
+            ClientError::GoingAway { reason, redirect } => {
+                write!(f, "server going away: {}", reason)?;
+                match redirect {
+                    Some(addr) => write!(f, "; reconnect to {}", addr),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<LinesCodecError> for ClientError {
+    fn from(e: LinesCodecError) -> Self {
+        match e {
+            LinesCodecError::Io(e) => ClientError::Io(e),
+            LinesCodecError::MaxLineLengthExceeded => {
+                ClientError::Protocol("response line too long".to_string())
+            }
+        }
+    }
+}
+
+/// An async client for the key-value server
+pub struct Client {
+    framed: Framed<TcpStream, LinesCodec>,
+}
+
+impl Client {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> std::io::Result<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        Ok(Self {
+            framed: Framed::new(socket, LinesCodec::new_with_max_length(MAX_LINE_LENGTH)),
+        })
+    }
+
+    pub async fn get(&mut self, key: &str) -> Result<Option<String>, ClientError> {
+        let response = self.call(Command::Get(key.to_string())).await?;
+        match response.as_str() {
+            "NIL" => Ok(None),
+            _ => match response.strip_prefix("VALUE ") {
+                Some(value) => Ok(Some(value.to_string())),
+                None => Err(ClientError::Protocol(response)),
+            },
+        }
+    }
+
+    pub async fn set(&mut self, key: &str, value: &str) -> Result<(), ClientError> {
+        let response = self
+            .call(Command::Set(key.to_string(), value.to_string()))
+            .await?;
+        match response.as_str() {
+            "OK" => Ok(()),
+            _ => Err(ClientError::Protocol(response)),
+        }
+    }
+
+    /// Deletes a key, returning whether it existed
+    pub async fn del(&mut self, key: &str) -> Result<bool, ClientError> {
+        let response = self.call(Command::Del(key.to_string())).await?;
+        match response.as_str() {
+            "DELETED" => Ok(true),
+            "NIL" => Ok(false),
+            _ => Err(ClientError::Protocol(response)),
+        }
+    }
+
+    /// Sends one request and reads its response
+    ///
+    /// Commands that wouldn't encode as exactly one line are refused with
+    /// [`ErrorKind::InvalidInput`](std::io::ErrorKind::InvalidInput) before
+    /// anything is sent.
+    async fn call(&mut self, command: Command) -> Result<String, ClientError> {
+        check_framing(&command).map_err(ClientError::Io)?;
+        self.framed.send(command.to_string()).await?;
+
+        match self.framed.next().await {
+            Some(Ok(line)) => {
+                if let Some(going_away) = going_away(&line) {
+                    return Err(going_away);
+                }
+                match line.strip_prefix("ERR ") {
+                    Some(msg) => Err(ClientError::Server(msg.to_string())),
+                    None => Ok(line),
+                }
+            }
+            Some(Err(e)) => Err(e.into()),
+            None => Err(ClientError::ConnectionClosed),
+        }
+    }
+}
+
+/// Parses the server's parting line
+fn going_away(line: &str) -> Option<ClientError> {
+    if let Some(reason) = line.strip_prefix("BYE ") {
+        return Some(ClientError::GoingAway {
+            reason: reason.to_string(),
+            redirect: None,
+        });
+    }
+    let (addr, reason) = line.strip_prefix("REDIRECT ")?.split_once(' ')?;
+    Some(ClientError::GoingAway {
+        reason: reason.to_string(),
+        redirect: Some(addr.to_string()),
+    })
+}
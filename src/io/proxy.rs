@@ -0,0 +1,19 @@
+//! Relaying a connection between two peers
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Copies data in both directions until both sides have finished sending
+///
+/// When one side reaches EOF only the write half of the other side is shut
+/// down (a TCP half-close), so data still flowing the opposite way is
+/// delivered. Returns the bytes copied `(a -> b, b -> a)`.
+///
+/// This is [`tokio::io::copy_bidirectional`], which already half-closes;
+/// the name spells out the behaviour proxies rely on.
+pub async fn copy_bidirectional_graceful<A, B>(a: &mut A, b: &mut B) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    tokio::io::copy_bidirectional(a, b).await
+}
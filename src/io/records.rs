@@ -0,0 +1,197 @@
+//! Streaming NDJSON and CSV record readers
+//!
+//! Both readers yield `Result<T, RecordError>` per record: a malformed line
+//! produces an error item and the stream carries on with the next line, so a
+//! single bad record doesn't take the whole pipeline down.
+
+use serde::de::DeserializeOwned;
+use std::fmt;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio_stream::{Stream, StreamExt};
+
+/// Longest line accepted before it is reported as an error and skipped
+const MAX_RECORD_LENGTH: usize = 1024 * 1024;
+
+/// A record that could not be read, with the 1-based line it came from
+#[derive(Debug)]
+pub struct RecordError {
+    pub line: usize,
+    pub kind: RecordErrorKind,
+}
+
+#[derive(Debug)]
+pub enum RecordErrorKind {
+    Io(std::io::Error),
+    TooLong,
+    Parse(String),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            RecordErrorKind::Io(e) => write!(f, "line {}: io error: {}", self.line, e),
+            RecordErrorKind::TooLong => write!(f, "line {}: record too long", self.line),
+            RecordErrorKind::Parse(msg) => write!(f, "line {}: {}", self.line, msg),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+/// Numbered lines, with read failures turned into [`RecordError`]s
+///
+/// Over-long and non-UTF-8 lines are reported and skipped; an I/O error
+/// ends the stream.
+fn numbered_lines<R: AsyncRead>(
+    reader: R,
+) -> impl Stream<Item = (usize, Result<String, RecordError>)> {
+    let state = (Box::pin(BufReader::new(reader)), 0, false);
+    futures::stream::unfold(state, |(mut reader, line_no, failed)| async move {
+        if failed {
+            return None;
+        }
+        let line_no = line_no + 1;
+        let (line, failed) = match read_record(&mut reader).await {
+            Ok(None) => return None,
+            Ok(Some(line)) => (Ok(line), false),
+            Err(kind) => {
+                let failed = matches!(kind, RecordErrorKind::Io(_));
+                (
+                    Err(RecordError {
+                        line: line_no,
+                        kind,
+                    }),
+                    failed,
+                )
+            }
+        };
+        Some(((line_no, line), (reader, line_no, failed)))
+    })
+}
+
+/// Reads the next line without its `\n` or `\r\n`, buffering at most
+/// [`MAX_RECORD_LENGTH`] bytes of it; `Ok(None)` at the end of input
+async fn read_record<R>(reader: &mut R) -> Result<Option<String>, RecordErrorKind>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // Room for the longest record and its `\r\n`
+    let limit = MAX_RECORD_LENGTH + 2;
+    let mut line = Vec::new();
+    let read = (&mut *reader)
+        .take(limit as u64)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(RecordErrorKind::Io)?;
+    if read == 0 {
+        return Ok(None);
+    }
+
+    if line.ends_with(b"\n") {
+        line.pop();
+        if line.ends_with(b"\r") {
+            line.pop();
+        }
+    } else if line.len() == limit {
+        skip_line(reader).await.map_err(RecordErrorKind::Io)?;
+        return Err(RecordErrorKind::TooLong);
+    }
+    if line.len() > MAX_RECORD_LENGTH {
+        return Err(RecordErrorKind::TooLong);
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| RecordErrorKind::Parse(e.to_string()))
+}
+
+/// Discards input up to and including the next `\n`
+async fn skip_line<R>(reader: &mut R) -> std::io::Result<()>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        match buf.iter().position(|&b| b == b'\n') {
+            Some(at) => {
+                reader.consume(at + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buf.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+/// Yields one deserialized value per non-empty line of newline-delimited JSON
+#[cfg(feature = "ndjson")]
+pub fn ndjson_stream<T, R>(reader: R) -> impl Stream<Item = Result<T, RecordError>>
+where
+    T: DeserializeOwned,
+    R: AsyncRead,
+{
+    numbered_lines(reader).filter_map(|(line_no, line)| match line {
+        Ok(line) if line.trim().is_empty() => None,
+        Ok(line) => Some(serde_json::from_str(&line).map_err(|e| RecordError {
+            line: line_no,
+            kind: RecordErrorKind::Parse(e.to_string()),
+        })),
+        Err(e) => Some(Err(e)),
+    })
+}
+
+/// Yields one deserialized value per CSV row, using the first line as headers
+///
+/// Rows are parsed line by line, so quoted fields may not contain newlines.
+#[cfg(feature = "csv")]
+pub fn csv_stream<T, R>(reader: R) -> impl Stream<Item = Result<T, RecordError>>
+where
+    T: DeserializeOwned,
+    R: AsyncRead,
+{
+    let mut headers: Option<csv::StringRecord> = None;
+
+    numbered_lines(reader).filter_map(move |(line_no, line)| {
+        let parse_error = |msg: String| RecordError {
+            line: line_no,
+            kind: RecordErrorKind::Parse(msg),
+        };
+
+        let line = match line {
+            Ok(line) if line.trim().is_empty() => return None,
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let record = match parse_csv_line(&line) {
+            Ok(record) => record,
+            Err(e) => return Some(Err(parse_error(e.to_string()))),
+        };
+
+        match &headers {
+            None => {
+                headers = Some(record);
+                None
+            }
+            Some(headers) => Some(
+                record
+                    .deserialize(Some(headers))
+                    .map_err(|e| parse_error(e.to_string())),
+            ),
+        }
+    })
+}
+
+#[cfg(feature = "csv")]
+fn parse_csv_line(line: &str) -> Result<csv::StringRecord, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(line.as_bytes());
+    let mut record = csv::StringRecord::new();
+    reader.read_record(&mut record)?;
+    Ok(record)
+}
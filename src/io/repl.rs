@@ -0,0 +1,98 @@
+//! An async read-eval-print loop over stdin/stdout
+
+use std::future::Future;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Why a REPL stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplExit {
+    /// Input was closed (Ctrl-D on a terminal)
+    Eof,
+    /// The interrupt signal fired (Ctrl-C)
+    Interrupted,
+}
+
+/// Runs a REPL on stdin/stdout until Ctrl-D or Ctrl-C
+///
+/// Each line is passed to `handler`; whatever it returns is printed before
+/// the next prompt, so output never interleaves with the prompt itself.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use tokio_tutorial_patterns::io::repl;
+///
+/// repl("> ", |line| async move { Some(line.to_uppercase()) }).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn repl<F, Fut>(prompt: &str, handler: F) -> std::io::Result<ReplExit>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Option<String>>,
+{
+    let stdin = BufReader::new(tokio::io::stdin());
+    let stdout = tokio::io::stdout();
+
+    let exit = repl_with(stdin, stdout, prompt, handler, async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+    .await?;
+
+    if exit == ReplExit::Interrupted {
+        // Leave the terminal on a fresh line after the ^C echo
+        let mut stdout = tokio::io::stdout();
+        stdout.write_all(b"\n").await?;
+        stdout.flush().await?;
+    }
+    Ok(exit)
+}
+
+/// Runs a REPL over any reader/writer pair, stopping when `interrupt` completes
+///
+/// An interrupt also cancels a handler that is still running.
+pub async fn repl_with<R, W, F, Fut, I>(
+    input: R,
+    mut output: W,
+    prompt: &str,
+    mut handler: F,
+    interrupt: I,
+) -> std::io::Result<ReplExit>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Option<String>>,
+    I: Future<Output = ()>,
+{
+    let mut lines = input.lines();
+    tokio::pin!(interrupt);
+
+    loop {
+        output.write_all(prompt.as_bytes()).await?;
+        output.flush().await?;
+
+        // Check the interrupt first so Ctrl-C wins over input that is already buffered
+        let line = tokio::select! {
+            biased;
+            _ = &mut interrupt => return Ok(ReplExit::Interrupted),
+            line = lines.next_line() => line?,
+        };
+        let line = match line {
+            Some(line) => line,
+            None => return Ok(ReplExit::Eof),
+        };
+
+        let response = tokio::select! {
+            biased;
+            _ = &mut interrupt => return Ok(ReplExit::Interrupted),
+            response = handler(line) => response,
+        };
+
+        if let Some(mut response) = response {
+            if !response.ends_with('\n') {
+                response.push('\n');
+            }
+            output.write_all(response.as_bytes()).await?;
+        }
+    }
+}
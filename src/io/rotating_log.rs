@@ -0,0 +1,328 @@
+//! Append-only log file with size-based rotation
+//!
+//! Callers only enqueue records; a dedicated writer task owns the file and
+//! does the buffering, flushing, fsync and rotation, so a slow disk never
+//! stalls the tasks producing log records. A record that can't be written
+//! fails the next [`append`](RotatingLog::append) or
+//! [`flush`](RotatingLog::flush) instead.
+
+use crate::spawning::spawn_traced;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+
+enum LogCommand {
+    Record(Vec<u8>),
+    Flush(oneshot::Sender<std::io::Result<()>>),
+    Close(oneshot::Sender<std::io::Result<()>>),
+}
+
+/// Error returned by [`RotatingLog::append`]
+#[derive(Debug)]
+pub enum LogError {
+    /// The writer task is no longer running
+    Closed,
+    /// Writing or rotating for an earlier record failed, and that record
+    /// was lost
+    Write(std::io::Error),
+}
+
+impl std::fmt::Display for LogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogError::Closed => write!(f, "rotating log writer has stopped"),
+            LogError::Write(e) => write!(f, "rotating log write failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LogError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LogError::Closed => None,
+            LogError::Write(e) => Some(e),
+        }
+    }
+}
+
+impl From<LogError> for std::io::Error {
+    fn from(error: LogError) -> Self {
+        match error {
+            LogError::Closed => closed_error(),
+            LogError::Write(e) => e,
+        }
+    }
+}
+
+/// The first write error the writer task hit since one was last reported
+type Failure = Arc<Mutex<Option<std::io::Error>>>;
+
+/// Builder for [`RotatingLog`]
+#[derive(Debug, Clone)]
+pub struct RotatingLogBuilder {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    flush_interval: Duration,
+    flush_bytes: usize,
+    capacity: usize,
+}
+
+impl RotatingLogBuilder {
+    /// Rotate once the active file would grow beyond this many bytes
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Number of rotated files (`name.1` .. `name.N`) to keep
+    pub fn keep(mut self, files: usize) -> Self {
+        self.keep = files;
+        self
+    }
+
+    /// Flush buffered records at least this often
+    ///
+    /// # Panics
+    ///
+    /// Panics if `interval` is zero.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        assert!(!interval.is_zero(), "`interval` must be non-zero");
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Flush as soon as this many bytes are buffered
+    pub fn flush_bytes(mut self, bytes: usize) -> Self {
+        self.flush_bytes = bytes;
+        self
+    }
+
+    /// Size of the queue between producers and the writer task
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "`capacity` must be non-zero");
+        self.capacity = capacity;
+        self
+    }
+
+    /// Opens (or creates) the log file and starts the writer task
+    pub async fn open(self) -> std::io::Result<RotatingLog> {
+        let file = open_append(&self.path).await?;
+        let size = file.metadata().await?.len();
+        let (tx, rx) = mpsc::channel(self.capacity);
+        let failure = Failure::default();
+
+        let writer = Writer {
+            file: BufWriter::new(file),
+            size,
+            buffered: 0,
+            failure: failure.clone(),
+            config: self,
+        };
+        spawn_traced("rotating log writer", writer.run(rx));
+
+        Ok(RotatingLog { tx, failure })
+    }
+}
+
+/// A cloneable handle to a rotating, append-only log file
+#[derive(Clone)]
+pub struct RotatingLog {
+    tx: mpsc::Sender<LogCommand>,
+    failure: Failure,
+}
+
+impl RotatingLog {
+    pub fn builder<P: AsRef<Path>>(path: P) -> RotatingLogBuilder {
+        RotatingLogBuilder {
+            path: path.as_ref().to_path_buf(),
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+            flush_interval: Duration::from_secs(1),
+            flush_bytes: 64 * 1024,
+            capacity: 1024,
+        }
+    }
+
+    /// Enqueues a raw record, waiting only if the writer queue is full
+    ///
+    /// Records are written after this returns, so a record that can't be
+    /// written is reported by the next call to this or
+    /// [`flush`](Self::flush), on any clone of the handle, and `record` is
+    /// not enqueued then. Each error is reported once.
+    pub async fn append(&self, record: impl Into<Vec<u8>>) -> Result<(), LogError> {
+        if let Some(e) = self.failure.lock().unwrap().take() {
+            return Err(LogError::Write(e));
+        }
+        self.tx
+            .send(LogCommand::Record(record.into()))
+            .await
+            .map_err(|_| LogError::Closed)
+    }
+
+    /// Enqueues a record followed by a newline
+    pub async fn append_line(&self, line: &str) -> Result<(), LogError> {
+        let mut record = Vec::with_capacity(line.len() + 1);
+        record.extend_from_slice(line.as_bytes());
+        record.push(b'\n');
+        self.append(record).await
+    }
+
+    /// Waits until everything enqueued so far has been written and fsynced
+    ///
+    /// Fails with the first write error not yet reported, if any record
+    /// since the last report was lost.
+    pub async fn flush(&self) -> std::io::Result<()> {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.tx
+            .send(LogCommand::Flush(ack_tx))
+            .await
+            .map_err(|_| closed_error())?;
+        ack_rx.await.map_err(|_| closed_error())?
+    }
+
+    /// Flushes, fsyncs and stops the writer task
+    ///
+    /// Other clones of the handle get [`LogError::Closed`] afterwards.
+    pub async fn close(&self) -> std::io::Result<()> {
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.tx
+            .send(LogCommand::Close(ack_tx))
+            .await
+            .map_err(|_| closed_error())?;
+        ack_rx.await.map_err(|_| closed_error())?
+    }
+}
+
+fn closed_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, LogError::Closed)
+}
+
+async fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
+
+/// `app.log` -> `app.log.3`
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+struct Writer {
+    file: BufWriter<File>,
+    size: u64,
+    buffered: usize,
+    failure: Failure,
+    config: RotatingLogBuilder,
+}
+
+impl Writer {
+    async fn run(mut self, mut rx: mpsc::Receiver<LogCommand>) {
+        let mut ticker = tokio::time::interval(self.config.flush_interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                command = rx.recv() => match command {
+                    Some(LogCommand::Record(record)) => {
+                        let result = self.write(&record).await;
+                        self.fail(result);
+                    }
+                    Some(LogCommand::Flush(ack)) => {
+                        let result = self.sync().await;
+                        let _ = ack.send(self.report(result));
+                    }
+                    Some(LogCommand::Close(ack)) => {
+                        let result = self.sync().await;
+                        let _ = ack.send(self.report(result));
+                        return;
+                    }
+                    None => {
+                        let _ = self.sync().await;
+                        return;
+                    }
+                },
+                _ = ticker.tick(), if self.buffered > 0 => {
+                    let result = self.file.flush().await;
+                    self.fail(result);
+                    self.buffered = 0;
+                }
+            }
+        }
+    }
+
+    /// Keeps a write error for the next append or flush, unless an earlier
+    /// one is still waiting to be reported
+    fn fail(&self, result: std::io::Result<()>) {
+        if let Err(e) = result {
+            self.failure.lock().unwrap().get_or_insert(e);
+        }
+    }
+
+    /// `result`, unless an earlier write error is waiting to be reported
+    fn report(&self, result: std::io::Result<()>) -> std::io::Result<()> {
+        match self.failure.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => result,
+        }
+    }
+
+    async fn write(&mut self, record: &[u8]) -> std::io::Result<()> {
+        if self.size > 0 && self.size + record.len() as u64 > self.config.max_size {
+            self.rotate().await?;
+        }
+
+        self.file.write_all(record).await?;
+        self.size += record.len() as u64;
+        self.buffered += record.len();
+
+        if self.buffered >= self.config.flush_bytes {
+            self.file.flush().await?;
+            self.buffered = 0;
+        }
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+        self.buffered = 0;
+        self.file.get_ref().sync_all().await
+    }
+
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        self.sync().await?;
+
+        let path = self.config.path.clone();
+        let keep = self.config.keep;
+
+        if keep == 0 {
+            tokio::fs::remove_file(&path).await?;
+        } else {
+            // Shift name.(N-1) -> name.N, ..., name -> name.1; the oldest is overwritten
+            for index in (1..keep).rev() {
+                let from = rotated_path(&path, index);
+                if tokio::fs::try_exists(&from).await? {
+                    tokio::fs::rename(&from, rotated_path(&path, index + 1)).await?;
+                }
+            }
+            tokio::fs::rename(&path, rotated_path(&path, 1)).await?;
+        }
+
+        self.file = BufWriter::new(open_append(&path).await?);
+        self.size = 0;
+        Ok(())
+    }
+}
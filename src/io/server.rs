@@ -0,0 +1,581 @@
+//! A generic TCP server with connection hooks, per-message middleware and
+//! phased, bounded shutdown
+
+use super::traced::traced_connection;
+use super::{AcceptPolicy, Acceptor};
+use crate::service::{BoxError, Service};
+use crate::shutdown::{Coordinator, ShutdownReport};
+use crate::spawning::spawn_traced;
+use futures::{SinkExt, StreamExt};
+use socket2::{SockRef, Socket};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{watch, Notify};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::Instant;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::sync::CancellationToken;
+
+/// Who a connection is with, passed to handlers, hooks and services
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Unique among the server's connections
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub connected_at: Instant,
+}
+
+/// A decoded message and the connection it arrived on
+#[derive(Debug, Clone)]
+pub struct Message<T> {
+    pub connection: ConnectionInfo,
+    pub body: T,
+}
+
+type Hook = Arc<dyn Fn(&ConnectionInfo) + Send + Sync>;
+
+/// How [`Server::shutdown_phased`] winds connections down
+#[derive(Clone)]
+pub struct ShutdownConfig {
+    /// Written to every open connection when shutdown starts
+    ///
+    /// This goes straight to the socket, so it can land in the middle of
+    /// a response the handler is writing. Protocols with framing should
+    /// instead send their own goodbye when the handler is notified, as
+    /// with [`Goodbye`].
+    pub goodbye: Option<Vec<u8>>,
+    /// How long connections get to finish on their own before they are
+    /// force-closed
+    pub drain_timeout: Duration,
+    /// Shut down after the connections, to flush logs, metrics and the like
+    pub coordinator: Option<Coordinator>,
+    /// Bound on the coordinator's shutdown
+    pub flush_timeout: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            goodbye: None,
+            drain_timeout: Duration::from_secs(30),
+            coordinator: None,
+            flush_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What the line-protocol servers, [`kv`](super::kv) and
+/// [`serve_chat`](super::serve_chat), tell their clients on shutdown
+///
+/// Each server sends it in its own framing once it stops reading commands,
+/// after any response still being written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Goodbye {
+    pub reason: String,
+    /// Where clients should reconnect, e.g. the server taking over
+    pub redirect: Option<String>,
+    /// How long a client gets to take the goodbye before its connection is
+    /// dropped anyway
+    pub flush_timeout: Duration,
+}
+
+impl Goodbye {
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+            redirect: None,
+            flush_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Sends clients to `addr`
+    pub fn redirect(mut self, addr: impl Into<String>) -> Self {
+        self.redirect = Some(addr.into());
+        self
+    }
+}
+
+impl Default for Goodbye {
+    fn default() -> Self {
+        Self::new("server shutting down")
+    }
+}
+
+/// What happened during [`Server::shutdown_phased`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerShutdownReport {
+    /// Connections that finished within the drain timeout
+    pub drained: usize,
+    /// Connections still open after it, which were closed and aborted
+    pub forced: usize,
+    /// The coordinator's report, if one was configured
+    pub flush: Option<ShutdownReport>,
+    pub elapsed: Duration,
+}
+
+impl ServerShutdownReport {
+    /// No connection had to be forced and every flush subsystem finished
+    pub fn is_clean(&self) -> bool {
+        self.forced == 0 && self.flush.as_ref().is_none_or(|flush| flush.is_clean())
+    }
+}
+
+/// A second handle on a connection's socket, for the server's own use
+struct Tracked {
+    socket: Socket,
+    task: Option<AbortHandle>,
+}
+
+struct Shared {
+    name: Arc<str>,
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Tracked>>,
+    /// Notified whenever a connection finishes
+    closed: Notify,
+}
+
+impl Shared {
+    async fn wait_until_closed(&self) {
+        loop {
+            let closed = self.closed.notified();
+            tokio::pin!(closed);
+            closed.as_mut().enable();
+            if self.connections.lock().unwrap().is_empty() {
+                return;
+            }
+            closed.await;
+        }
+    }
+}
+
+/// Forgets a connection when its task finishes or is aborted
+struct Untrack {
+    shared: Arc<Shared>,
+    info: ConnectionInfo,
+    on_disconnect: Option<Hook>,
+}
+
+impl Drop for Untrack {
+    fn drop(&mut self) {
+        if let Some(on_disconnect) = &self.on_disconnect {
+            on_disconnect(&self.info);
+        }
+        self.shared
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&self.info.id);
+        self.shared.closed.notify_waiters();
+    }
+}
+
+/// Configures a [`Server`] before it starts, created with
+/// [`Server::builder`]
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use tokio::net::TcpListener;
+/// use tokio_tutorial_patterns::io::{Message, Server};
+/// use tokio_tutorial_patterns::service::{service_fn, BoxError, ServiceBuilder};
+/// use tokio_util::codec::LinesCodec;
+///
+/// let upper = ServiceBuilder::new()
+///     .logging("upper")
+///     .service(service_fn(|msg: Message<String>| async move {
+///         Ok::<_, BoxError>(msg.body.to_uppercase())
+///     }));
+///
+/// let server = Server::builder("upper")
+///     .on_connect(|conn| println!("#{} connected from {}", conn.id, conn.peer))
+///     .on_disconnect(|conn| println!("#{} left after {:?}", conn.id, conn.connected_at.elapsed()))
+///     .serve(TcpListener::bind("127.0.0.1:7000").await?, LinesCodec::new(), upper);
+/// # Ok(())
+/// # }
+/// ```
+pub struct ServerBuilder {
+    name: String,
+    on_connect: Option<Hook>,
+    on_disconnect: Option<Hook>,
+    accept_policy: Option<AcceptPolicy>,
+}
+
+impl ServerBuilder {
+    /// Runs `hook` on every new connection, before its handler
+    pub fn on_connect<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&ConnectionInfo) + Send + Sync + 'static,
+    {
+        self.on_connect = Some(Arc::new(hook));
+        self
+    }
+
+    /// Runs `hook` when a connection ends, however it ended
+    pub fn on_disconnect<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&ConnectionInfo) + Send + Sync + 'static,
+    {
+        self.on_disconnect = Some(Arc::new(hook));
+        self
+    }
+
+    /// How accept errors are handled, replacing the policy of an
+    /// [`Acceptor`] passed to [`start`](Self::start)
+    ///
+    /// The server stops accepting once the policy gives up; connections
+    /// already open carry on.
+    pub fn accept_policy(mut self, policy: AcceptPolicy) -> Self {
+        self.accept_policy = Some(policy);
+        self
+    }
+
+    /// Starts accepting on `listener`, running `handler` for each connection
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn start<H, Fut, E>(mut self, listener: impl Into<Acceptor>, handler: H) -> Server
+    where
+        H: Fn(TcpStream, ConnectionInfo, watch::Receiver<bool>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: std::fmt::Display + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            name: self.name.as_str().into(),
+            next_id: AtomicU64::new(0),
+            connections: Mutex::new(HashMap::new()),
+            closed: Notify::new(),
+        });
+        let mut listener = listener.into();
+        if let Some(policy) = self.accept_policy.take() {
+            listener = Acceptor::new(listener.into_inner(), policy);
+        }
+        let local_addr = listener.local_addr();
+        let stop_accepting = CancellationToken::new();
+        let retire = CancellationToken::new();
+        let (notify_tx, notify_rx) = watch::channel(false);
+
+        let accept_task = spawn_traced(
+            &format!("{} accept loop", self.name),
+            accept_loop(
+                listener,
+                shared.clone(),
+                self,
+                handler,
+                notify_rx,
+                stop_accepting.clone(),
+                retire.clone(),
+            ),
+        );
+
+        Server {
+            shared,
+            local_addr,
+            stop_accepting,
+            retire,
+            notify_tx,
+            accept_task: Some(accept_task),
+        }
+    }
+
+    /// Starts accepting on `listener`, decoding messages on each connection
+    /// with `codec` and answering each with `service`'s response
+    ///
+    /// Middleware for auth, logging or rate limiting goes in `service`'s
+    /// layers, where every [`Message`] carries its connection's details.
+    /// A connection stops reading when shutdown starts. A service error
+    /// closes the connection, so services that want to answer errors should
+    /// return them as responses.
+    pub fn serve<C, S, Req, Resp>(
+        self,
+        listener: impl Into<Acceptor>,
+        codec: C,
+        service: S,
+    ) -> Server
+    where
+        C: Decoder<Item = Req> + Encoder<Resp> + Clone + Send + 'static,
+        <C as Decoder>::Error: Into<BoxError>,
+        <C as Encoder<Resp>>::Error: Into<BoxError>,
+        S: Service<Message<Req>, Resp> + 'static,
+        S::Error: Into<BoxError>,
+        Req: Send + 'static,
+        Resp: Send + 'static,
+    {
+        let service = Arc::new(service);
+        self.start(listener, move |socket, connection, shutdown| {
+            serve_messages(
+                Framed::new(socket, codec.clone()),
+                connection,
+                service.clone(),
+                shutdown,
+            )
+        })
+    }
+}
+
+async fn serve_messages<C, S, Req, Resp>(
+    mut framed: Framed<TcpStream, C>,
+    connection: ConnectionInfo,
+    service: Arc<S>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), BoxError>
+where
+    C: Decoder<Item = Req> + Encoder<Resp>,
+    <C as Decoder>::Error: Into<BoxError>,
+    <C as Encoder<Resp>>::Error: Into<BoxError>,
+    S: Service<Message<Req>, Resp>,
+    S::Error: Into<BoxError>,
+{
+    loop {
+        let body = tokio::select! {
+            _ = shutdown.wait_for(|stopping| *stopping) => return Ok(()),
+            frame = framed.next() => match frame {
+                Some(frame) => frame.map_err(Into::into)?,
+                None => return Ok(()),
+            },
+        };
+        let response = service
+            .call(Message { connection, body })
+            .await
+            .map_err(Into::into)?;
+        framed.send(response).await.map_err(Into::into)?;
+    }
+}
+
+/// Accepts connections on a listener and runs a handler for each in its
+/// own task, until [`shutdown_phased`](Self::shutdown_phased)
+///
+/// Handlers get the stream, the peer address and a receiver that flips to
+/// `true` when shutdown starts. Connections run inside
+/// [`serve_traced`](super::serve_traced)-style spans.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use tokio::net::TcpListener;
+/// use tokio_tutorial_patterns::io::{Server, ShutdownConfig};
+///
+/// let listener = TcpListener::bind("127.0.0.1:7000").await?;
+/// let server = Server::start(listener, "echo", |mut socket, _peer, _shutdown| async move {
+///     let (mut reader, mut writer) = socket.split();
+///     tokio::io::copy(&mut reader, &mut writer).await.map(|_| ())
+/// });
+///
+/// let _ = tokio::signal::ctrl_c().await;
+/// let report = server
+///     .shutdown_phased(ShutdownConfig {
+///         goodbye: Some(b"server going away\n".to_vec()),
+///         drain_timeout: Duration::from_secs(10),
+///         ..Default::default()
+///     })
+///     .await;
+/// println!("{} drained, {} forced", report.drained, report.forced);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Server {
+    shared: Arc<Shared>,
+    local_addr: std::io::Result<SocketAddr>,
+    stop_accepting: CancellationToken,
+    /// Stops accepting once the listener's backlog is empty
+    retire: CancellationToken,
+    notify_tx: watch::Sender<bool>,
+    accept_task: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// A server named `name` in traces and logs, with hooks or a
+    /// per-message service to be configured
+    pub fn builder(name: &str) -> ServerBuilder {
+        ServerBuilder {
+            name: name.to_string(),
+            on_connect: None,
+            on_disconnect: None,
+            accept_policy: None,
+        }
+    }
+
+    /// Starts accepting on `listener`; `name` identifies the server in
+    /// traces and logs
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn start<H, Fut, E>(listener: impl Into<Acceptor>, name: &str, handler: H) -> Self
+    where
+        H: Fn(TcpStream, SocketAddr, watch::Receiver<bool>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: std::fmt::Display + Send + 'static,
+    {
+        Self::builder(name).start(listener, move |socket, connection, shutdown| {
+            handler(socket, connection.peer, shutdown)
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match &self.local_addr {
+            Ok(addr) => Ok(*addr),
+            Err(e) => Err(std::io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+
+    /// Connections currently open
+    pub fn connections(&self) -> usize {
+        self.shared.connections.lock().unwrap().len()
+    }
+
+    /// Accepts the connections already waiting on the listener, then stops
+    /// accepting and closes it, returning how many were accepted meanwhile
+    ///
+    /// Connections already open carry on, and
+    /// [`shutdown_phased`](Self::shutdown_phased) still drains them. With
+    /// another listener on the same address only connections that reach
+    /// this one after its last accept are refused; see
+    /// [`ListenerHandoff`](super::ListenerHandoff).
+    pub async fn stop_accepting(&mut self) -> usize {
+        let before = self.shared.next_id.load(Ordering::Relaxed);
+        self.retire.cancel();
+        if let Some(accept_task) = self.accept_task.take() {
+            let _ = accept_task.await;
+        }
+        (self.shared.next_id.load(Ordering::Relaxed) - before) as usize
+    }
+
+    /// Shuts the server down in phases:
+    ///
+    /// 1. stop accepting and close the listener
+    /// 2. notify every connection's handler, and send the goodbye frame
+    /// 3. wait up to `drain_timeout` for the connections to finish
+    /// 4. close the sockets of any still open and abort their handlers
+    /// 5. shut down the coordinator, if one is configured
+    pub async fn shutdown_phased(mut self, config: ShutdownConfig) -> ServerShutdownReport {
+        let started = Instant::now();
+
+        self.stop_accepting.cancel();
+        if let Some(accept_task) = self.accept_task.take() {
+            let _ = accept_task.await;
+        }
+
+        let open = {
+            let connections = self.shared.connections.lock().unwrap();
+            if let Some(goodbye) = &config.goodbye {
+                for tracked in connections.values() {
+                    // Best effort: a full send buffer means the peer isn't reading
+                    let _ = tracked.socket.send(goodbye);
+                }
+            }
+            connections.len()
+        };
+        self.notify_tx.send_replace(true);
+
+        let drained = tokio::time::timeout(config.drain_timeout, self.shared.wait_until_closed());
+        let forced = match drained.await {
+            Ok(()) => 0,
+            Err(_) => {
+                let forced = {
+                    let connections = self.shared.connections.lock().unwrap();
+                    for tracked in connections.values() {
+                        let _ = tracked.socket.shutdown(Shutdown::Both);
+                        if let Some(task) = &tracked.task {
+                            task.abort();
+                        }
+                    }
+                    connections.len()
+                };
+                self.shared.wait_until_closed().await;
+                forced
+            }
+        };
+
+        let flush = match &config.coordinator {
+            Some(coordinator) => Some(coordinator.shutdown(config.flush_timeout).await),
+            None => None,
+        };
+
+        ServerShutdownReport {
+            drained: open.saturating_sub(forced),
+            forced,
+            flush,
+            elapsed: started.elapsed(),
+        }
+    }
+}
+
+impl Drop for Server {
+    /// Stops accepting; connections already open carry on
+    fn drop(&mut self) {
+        self.stop_accepting.cancel();
+    }
+}
+
+async fn accept_loop<H, Fut, E>(
+    mut listener: Acceptor,
+    shared: Arc<Shared>,
+    hooks: ServerBuilder,
+    handler: H,
+    notify_rx: watch::Receiver<bool>,
+    stop: CancellationToken,
+    retire: CancellationToken,
+) where
+    H: Fn(TcpStream, ConnectionInfo, watch::Receiver<bool>) -> Fut,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: std::fmt::Display + Send + 'static,
+{
+    loop {
+        let (socket, peer) = tokio::select! {
+            biased;
+            _ = stop.cancelled() => return,
+            _ = retire.cancelled() => match listener.try_accept() {
+                Ok(Some(accepted)) => accepted,
+                _ => return,
+            },
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                // Already reported by the acceptor's policy
+                Err(_) => return,
+            },
+        };
+
+        let Ok(handle) = SockRef::from(&socket).try_clone() else {
+            continue;
+        };
+        let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+        shared.connections.lock().unwrap().insert(
+            id,
+            Tracked {
+                socket: handle,
+                task: None,
+            },
+        );
+        let info = ConnectionInfo {
+            id,
+            peer,
+            connected_at: Instant::now(),
+        };
+        let untrack = Untrack {
+            shared: shared.clone(),
+            info,
+            on_disconnect: hooks.on_disconnect.clone(),
+        };
+
+        if let Some(on_connect) = &hooks.on_connect {
+            on_connect(&info);
+        }
+        let conn = handler(socket, info, notify_rx.clone());
+        let name = shared.name.clone();
+        let task = tokio::spawn(async move {
+            let _untrack = untrack;
+            let result = traced_connection(&name, peer, conn).await;
+            #[cfg(not(feature = "tracing"))]
+            if let Err(e) = result {
+                eprintln!("{} client {} error: {}", name, peer, e);
+            }
+            #[cfg(feature = "tracing")]
+            let _ = result;
+        });
+        // Gone already if the connection has finished
+        if let Some(tracked) = shared.connections.lock().unwrap().get_mut(&id) {
+            tracked.task = Some(task.abort_handle());
+        }
+    }
+}
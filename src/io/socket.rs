@@ -0,0 +1,190 @@
+//! Socket option builder for TCP listeners and streams
+
+use socket2::{SockRef, TcpKeepalive};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Socket options applied when creating listeners and client connections
+///
+/// Options left unset keep the operating system defaults.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::io::SocketConfig;
+///
+/// let config = SocketConfig::new()
+///     .nodelay(true)
+///     .keepalive(Duration::from_secs(60))
+///     .reuse_address(true);
+///
+/// let listener = config.bind("127.0.0.1:8080".parse().unwrap())?;
+/// let (stream, _) = listener.accept().await?;
+/// config.apply(&stream)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SocketConfig {
+    nodelay: Option<bool>,
+    keepalive_time: Option<Duration>,
+    keepalive_interval: Option<Duration>,
+    keepalive_retries: Option<u32>,
+    reuse_address: Option<bool>,
+    reuse_port: Option<bool>,
+    linger: Option<Option<Duration>>,
+    send_buffer_size: Option<u32>,
+    recv_buffer_size: Option<u32>,
+    backlog: Option<u32>,
+}
+
+impl SocketConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Disables Nagle's algorithm (TCP_NODELAY)
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Enables TCP keepalive, probing after the connection is idle for `time`
+    pub fn keepalive(mut self, time: Duration) -> Self {
+        self.keepalive_time = Some(time);
+        self
+    }
+
+    /// Time between keepalive probes once probing has started
+    ///
+    /// Enables keepalive on its own, with the system's idle time unless
+    /// [`keepalive`](Self::keepalive) sets one.
+    pub fn keepalive_interval(mut self, interval: Duration) -> Self {
+        self.keepalive_interval = Some(interval);
+        self
+    }
+
+    /// Number of unanswered probes before the connection is dropped
+    ///
+    /// Like [`keepalive_interval`](Self::keepalive_interval), this enables
+    /// keepalive by itself.
+    pub fn keepalive_retries(mut self, retries: u32) -> Self {
+        self.keepalive_retries = Some(retries);
+        self
+    }
+
+    /// Sets SO_REUSEADDR
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.reuse_address = Some(reuse);
+        self
+    }
+
+    /// Sets SO_REUSEPORT (ignored on platforms without it)
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.reuse_port = Some(reuse);
+        self
+    }
+
+    /// Sets SO_LINGER; `None` disables lingering
+    pub fn linger(mut self, linger: Option<Duration>) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    /// Sets SO_SNDBUF
+    pub fn send_buffer_size(mut self, size: u32) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets SO_RCVBUF
+    pub fn recv_buffer_size(mut self, size: u32) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Length of the pending-connection queue used by [`bind`](Self::bind)
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /// Creates a listener bound to `addr` with these options applied
+    pub fn bind(&self, addr: SocketAddr) -> std::io::Result<TcpListener> {
+        let socket = self.socket_for(addr)?;
+        if let Some(reuse) = self.reuse_address {
+            socket.set_reuseaddr(reuse)?;
+        }
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        if let Some(reuse) = self.reuse_port {
+            socket.set_reuseport(reuse)?;
+        }
+        self.apply_common(&SockRef::from(&socket))?;
+        socket.bind(addr)?;
+        socket.listen(self.backlog.unwrap_or(1024))
+    }
+
+    /// Connects to `addr` with these options applied before the handshake
+    pub async fn connect(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+        let socket = self.socket_for(addr)?;
+        self.apply_common(&SockRef::from(&socket))?;
+        socket.connect(addr).await
+    }
+
+    /// Applies the per-connection options to an accepted or existing stream
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        self.apply_common(&SockRef::from(stream))
+    }
+
+    fn socket_for(&self, addr: SocketAddr) -> std::io::Result<TcpSocket> {
+        match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4(),
+            SocketAddr::V6(_) => TcpSocket::new_v6(),
+        }
+    }
+
+    fn apply_common(&self, socket: &SockRef<'_>) -> std::io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            socket.set_tcp_nodelay(nodelay)?;
+        }
+        if let Some(linger) = self.linger {
+            socket.set_linger(linger)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size as usize)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size as usize)?;
+        }
+        if let Some(keepalive) = self.tcp_keepalive() {
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+
+    fn tcp_keepalive(&self) -> Option<TcpKeepalive> {
+        if self.keepalive_time.is_none()
+            && self.keepalive_interval.is_none()
+            && self.keepalive_retries.is_none()
+        {
+            return None;
+        }
+        let mut keepalive = TcpKeepalive::new();
+
+        if let Some(time) = self.keepalive_time {
+            keepalive = keepalive.with_time(time);
+        }
+
+        #[cfg(any(unix, windows))]
+        if let Some(interval) = self.keepalive_interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        #[cfg(unix)]
+        if let Some(retries) = self.keepalive_retries {
+            keepalive = keepalive.with_retries(retries);
+        }
+
+        Some(keepalive)
+    }
+}
@@ -0,0 +1,123 @@
+//! Accept loops with per-connection tracing spans
+//!
+//! With the `tracing` feature every connection runs inside a `connection`
+//! span carrying the server name and peer address, and emits an event when
+//! it is accepted and when it closes. Without the feature the same code
+//! compiles down to the plain accept loop.
+
+use super::Acceptor;
+use std::future::Future;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// Runs one connection's future, wrapped in its lifecycle events
+pub(crate) async fn traced_connection<F, E>(
+    server: &str,
+    peer: SocketAddr,
+    conn: F,
+) -> Result<(), E>
+where
+    F: Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    #[cfg(feature = "tracing")]
+    {
+        use tracing::Instrument;
+
+        let span = tracing::info_span!("connection", server, %peer);
+        async move {
+            let started = tokio::time::Instant::now();
+            tracing::debug!("connection accepted");
+            let result = conn.await;
+            let elapsed = started.elapsed();
+            match &result {
+                Ok(()) => tracing::debug!(?elapsed, "connection closed"),
+                Err(e) => tracing::warn!(?elapsed, error = %e, "connection failed"),
+            }
+            result
+        }
+        .instrument(span)
+        .await
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (server, peer);
+        conn.await
+    }
+}
+
+/// Accepts connections on `listener` and runs `handler` for each one in its
+/// own task, until `shutdown` completes
+///
+/// `name` identifies the server in the spans and events. The handler gets
+/// a receiver that flips to `true` on shutdown; once `shutdown` resolves the
+/// listener is closed and the call returns when every handler has finished.
+/// It returns early, with the error, if accepting fails for good; see
+/// [`Acceptor`].
+/// Handler errors are logged, with `tracing` if enabled and on standard
+/// error otherwise.
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use tokio::io::copy;
+/// use tokio::net::TcpListener;
+/// use tokio_tutorial_patterns::io::serve_traced;
+///
+/// let listener = TcpListener::bind("127.0.0.1:7000").await?;
+/// serve_traced(listener, "echo", |mut socket, _peer, _shutdown| async move {
+///     let (mut reader, mut writer) = socket.split();
+///     copy(&mut reader, &mut writer).await.map(|_| ())
+/// }, async {
+///     let _ = tokio::signal::ctrl_c().await;
+/// })
+/// .await
+/// # }
+/// ```
+pub async fn serve_traced<H, Fut, E, F>(
+    listener: impl Into<Acceptor>,
+    name: &str,
+    handler: H,
+    shutdown: F,
+) -> std::io::Result<()>
+where
+    H: Fn(TcpStream, SocketAddr, watch::Receiver<bool>) -> Fut,
+    Fut: Future<Output = Result<(), E>> + Send + 'static,
+    E: std::fmt::Display + Send + 'static,
+    F: Future<Output = ()>,
+{
+    let mut listener = listener.into();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let name: std::sync::Arc<str> = name.into();
+
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer) = accepted?;
+                let conn = handler(socket, peer, shutdown_rx.clone());
+                let name = name.clone();
+                connections.spawn(async move {
+                    let result = traced_connection(&name, peer, conn).await;
+                    #[cfg(not(feature = "tracing"))]
+                    if let Err(e) = result {
+                        eprintln!("{} client {} error: {}", name, peer, e);
+                    }
+                    #[cfg(feature = "tracing")]
+                    let _ = result;
+                });
+            }
+            _ = &mut shutdown => break,
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+        }
+    }
+
+    drop(listener);
+    let _ = shutdown_tx.send(true);
+    while connections.join_next().await.is_some() {}
+
+    Ok(())
+}
@@ -0,0 +1,156 @@
+//! In-memory duplex transport with fault injection, for testing servers and
+//! clients without real sockets
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::time::Sleep;
+
+/// Buffer size of each direction of the in-memory pipe
+const TRANSPORT_BUFFER: usize = 64 * 1024;
+
+/// Faults injected on the write side of a [`FaultyStream`]
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    latency: Option<Duration>,
+    max_write: Option<usize>,
+    close_after: Option<usize>,
+}
+
+impl FaultConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delays every write by this long before it reaches the peer
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Accepts at most this many bytes per write call, forcing partial writes
+    pub fn max_write(mut self, bytes: usize) -> Self {
+        self.max_write = Some(bytes.max(1));
+        self
+    }
+
+    /// Closes the connection after this many bytes have been written; later
+    /// writes fail with `BrokenPipe` and the peer sees EOF
+    pub fn close_after(mut self, bytes: usize) -> Self {
+        self.close_after = Some(bytes);
+        self
+    }
+}
+
+/// One end of an in-memory connection, with optional injected faults
+pub struct FaultyStream<S = DuplexStream> {
+    inner: S,
+    config: FaultConfig,
+    delay: Option<Pin<Box<Sleep>>>,
+    written: usize,
+    closed: bool,
+}
+
+impl<S> FaultyStream<S> {
+    pub fn new(inner: S, config: FaultConfig) -> Self {
+        Self {
+            inner,
+            config,
+            delay: None,
+            written: 0,
+            closed: false,
+        }
+    }
+
+    /// Total bytes accepted by this end so far
+    pub fn bytes_written(&self) -> usize {
+        self.written
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// Returns two connected in-memory streams with no faults injected
+pub fn test_transport() -> (FaultyStream, FaultyStream) {
+    test_transport_with(FaultConfig::default(), FaultConfig::default())
+}
+
+/// Returns two connected in-memory streams, each with its own write faults
+pub fn test_transport_with(
+    client: FaultConfig,
+    server: FaultConfig,
+) -> (FaultyStream, FaultyStream) {
+    let (a, b) = tokio::io::duplex(TRANSPORT_BUFFER);
+    (FaultyStream::new(a, client), FaultyStream::new(b, server))
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FaultyStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.closed {
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FaultyStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+
+        if this.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        if let Some(latency) = this.config.latency {
+            let delay = this
+                .delay
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(latency)));
+            if delay.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        let mut len = buf.len();
+        if let Some(max) = this.config.max_write {
+            len = len.min(max);
+        }
+        if let Some(limit) = this.config.close_after {
+            len = len.min(limit - this.written);
+        }
+
+        match Pin::new(&mut this.inner).poll_write(cx, &buf[..len]) {
+            Poll::Ready(Ok(n)) => {
+                this.written += n;
+                this.delay = None;
+                if this.config.close_after == Some(this.written) {
+                    this.closed = true;
+                    // Shut the inner write half so the peer observes EOF
+                    let _ = Pin::new(&mut this.inner).poll_shutdown(cx);
+                }
+                Poll::Ready(Ok(n))
+            }
+            other => other,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
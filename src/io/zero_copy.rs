@@ -0,0 +1,120 @@
+//! Vectored writes and `sendfile`-style transfers for protocol servers
+
+use std::io::{IoSlice, SeekFrom};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Writes every buffer in `bufs`, e.g. a header and a body, without first
+/// concatenating them into one allocation
+///
+/// Writers that don't support vectored I/O still work; they just receive one
+/// buffer per write call.
+pub async fn write_all_vectored<W>(
+    writer: &mut W,
+    mut bufs: &mut [IoSlice<'_>],
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    // Skip leading empty slices so a zero-length write means the peer is gone
+    IoSlice::advance_slices(&mut bufs, 0);
+
+    while !bufs.is_empty() {
+        let n = writer.write_vectored(bufs).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut bufs, n);
+    }
+    Ok(())
+}
+
+/// Sends `len` bytes of `file` starting at `offset` to `stream`
+///
+/// On Linux this uses `sendfile(2)` so the bytes never pass through user
+/// space; elsewhere, or if the kernel refuses (e.g. on some filesystems), it
+/// falls back to an ordinary buffered copy. Returns the number of bytes sent,
+/// which is smaller than `len` only if the file ends first. The file's cursor
+/// position afterwards is unspecified.
+pub async fn send_file(
+    file: &mut File,
+    stream: &mut TcpStream,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<u64> {
+    #[cfg(target_os = "linux")]
+    match sendfile_linux(file, stream, offset, len).await {
+        Ok(sent) => return Ok(sent),
+        Err(e) if is_unsupported(&e) => {}
+        Err(e) => return Err(e),
+    }
+
+    copy_range(file, stream, offset, len).await
+}
+
+async fn copy_range(
+    file: &mut File,
+    stream: &mut TcpStream,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<u64> {
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut range = (&mut *file).take(len);
+    let copied = tokio::io::copy(&mut range, stream).await?;
+    stream.flush().await?;
+    Ok(copied)
+}
+
+#[cfg(target_os = "linux")]
+fn is_unsupported(e: &std::io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP)
+    )
+}
+
+#[cfg(target_os = "linux")]
+async fn sendfile_linux(
+    file: &File,
+    stream: &TcpStream,
+    offset: u64,
+    len: u64,
+) -> std::io::Result<u64> {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    // Linux transfers at most this many bytes per call
+    const MAX_CHUNK: u64 = 0x7fff_f000;
+
+    let in_fd = file.as_raw_fd();
+    let out_fd = stream.as_raw_fd();
+    let mut sent = 0u64;
+
+    while sent < len {
+        let chunk = (len - sent).min(MAX_CHUNK) as usize;
+        let n = stream
+            .async_io(Interest::WRITABLE, || {
+                let mut off = (offset + sent) as libc::off_t;
+                // SAFETY: both descriptors are owned by live objects borrowed
+                // for the duration of the call, and `off` is a valid pointer.
+                let n = unsafe { libc::sendfile(out_fd, in_fd, &mut off, chunk) };
+                if n < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(n as u64)
+                }
+            })
+            .await;
+
+        match n {
+            Ok(0) => break,
+            Ok(n) => sent += n,
+            // Bytes are already on the wire, so hide the errno to stop the caller falling back
+            Err(e) if sent > 0 && is_unsupported(&e) => return Err(std::io::Error::other(e)),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(sent)
+}
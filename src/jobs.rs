@@ -0,0 +1,540 @@
+//! An in-process job queue with priorities, delays, retries and dead letters
+//!
+//! Jobs added with [`JobQueue::enqueue`] run as soon as a slot is free,
+//! highest priority first and in enqueue order within a priority, or once
+//! their run-at time has passed if they were delayed. A failed job is
+//! rescheduled according to its [`RetryPolicy`]; once the policy gives up it
+//! becomes a [`DeadLetter`]. A handler that panics has failed the same way,
+//! with a [`JobPanicked`] error. A [`JobStore`] is told about every change, so
+//! jobs can be persisted and picked up again by the next process.
+
+use crate::select::RetryPolicy;
+use crate::spawning::spawn_traced;
+use futures::FutureExt;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+/// Identifies a job for the lifetime of the queue and its store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JobId(pub u64);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job-{}", self.0)
+    }
+}
+
+/// The persistent part of a job, as seen by a [`JobStore`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JobRecord<T> {
+    pub id: JobId,
+    pub payload: T,
+    /// Higher runs first
+    pub priority: i32,
+    /// Not run before this time
+    pub run_at: SystemTime,
+    /// Failed attempts so far
+    pub attempts: u32,
+}
+
+/// A job to enqueue, built with [`Job::new`]
+pub struct Job<T, E> {
+    payload: T,
+    priority: i32,
+    run_at: Option<SystemTime>,
+    retry: Option<RetryPolicy<E>>,
+}
+
+impl<T, E> Job<T, E> {
+    /// A job with priority 0 that can run at once and uses the queue's
+    /// default retry policy
+    pub fn new(payload: T) -> Self {
+        Self {
+            payload,
+            priority: 0,
+            run_at: None,
+            retry: None,
+        }
+    }
+
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn run_at(mut self, at: SystemTime) -> Self {
+        self.run_at = Some(at);
+        self
+    }
+
+    pub fn delay(self, delay: Duration) -> Self {
+        self.run_at(SystemTime::now() + delay)
+    }
+
+    pub fn retry(mut self, policy: RetryPolicy<E>) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+}
+
+/// A job whose retry policy gave up, with the error from its last attempt
+#[derive(Debug)]
+pub struct DeadLetter<T, E> {
+    pub job: JobRecord<T>,
+    pub error: E,
+}
+
+/// Persistence hooks for a [`JobQueue`]
+///
+/// Every method has a no-op default. They are called synchronously while
+/// the queue runs, so implementations that do slow I/O should hand the work
+/// off to a task of their own.
+pub trait JobStore<T, E>: Send + Sync + 'static {
+    /// Jobs to resume with, called once when the queue starts
+    fn recover(&self) -> Vec<JobRecord<T>> {
+        Vec::new()
+    }
+
+    /// A job was enqueued, or rescheduled after a failed attempt
+    fn saved(&self, _job: &JobRecord<T>) {}
+
+    /// A job succeeded and can be forgotten
+    fn completed(&self, _job: &JobRecord<T>) {}
+
+    /// A job ran out of retries
+    fn dead_lettered(&self, _job: &JobRecord<T>, _error: &E) {}
+}
+
+/// A [`JobStore`] that keeps nothing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoStore;
+
+impl<T, E> JobStore<T, E> for NoStore {}
+
+/// Settings for a [`JobQueue`]
+#[derive(Debug, Clone)]
+pub struct JobQueueConfig<E> {
+    /// Jobs running at the same time
+    pub concurrency: usize,
+    /// Used by jobs that don't set their own, including recovered ones
+    pub default_retry: RetryPolicy<E>,
+}
+
+impl<E> Default for JobQueueConfig<E> {
+    /// 4 concurrent jobs and no retries
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            default_retry: RetryPolicy::fixed(Duration::ZERO).max_attempts(Some(1)),
+        }
+    }
+}
+
+/// Returned by [`JobQueue::enqueue`] with the rejected payload once the
+/// queue has shut down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueClosed<T>(pub T);
+
+impl<T> fmt::Display for QueueClosed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job queue is shut down")
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for QueueClosed<T> {}
+
+/// The error an attempt fails with when the handler panics, converted to
+/// the queue's error type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobPanicked;
+
+impl fmt::Display for JobPanicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job handler panicked")
+    }
+}
+
+impl std::error::Error for JobPanicked {}
+
+impl From<JobPanicked> for String {
+    fn from(panicked: JobPanicked) -> Self {
+        panicked.to_string()
+    }
+}
+
+impl From<JobPanicked> for std::io::Error {
+    fn from(panicked: JobPanicked) -> Self {
+        std::io::Error::other(panicked)
+    }
+}
+
+/// Job counts, from [`JobQueue::stats`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Due and waiting for a free slot
+    pub ready: usize,
+    /// Waiting for their run-at time, including retries
+    pub delayed: usize,
+    pub running: usize,
+    pub completed: u64,
+    pub dead_lettered: u64,
+}
+
+struct Entry<T, E> {
+    record: JobRecord<T>,
+    retry: RetryPolicy<E>,
+    /// When the first attempt started, for the policy's elapsed-time limit
+    started: Option<Instant>,
+}
+
+struct State<T, E> {
+    /// Keyed so the first entry is the highest priority, oldest job
+    ready: BTreeMap<(std::cmp::Reverse<i32>, JobId), Entry<T, E>>,
+    delayed: BTreeMap<(Instant, JobId), Entry<T, E>>,
+    running: usize,
+    dead: Vec<DeadLetter<T, E>>,
+    next_id: u64,
+    closed: bool,
+}
+
+impl<T, E> State<T, E> {
+    fn schedule(&mut self, entry: Entry<T, E>) {
+        let id = entry.record.id;
+        let delay = entry
+            .record
+            .run_at
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO);
+
+        if delay.is_zero() {
+            self.ready
+                .insert((std::cmp::Reverse(entry.record.priority), id), entry);
+        } else {
+            self.delayed.insert((Instant::now() + delay, id), entry);
+        }
+    }
+
+    /// Moves delayed jobs that are due to the ready set
+    fn promote(&mut self) {
+        let now = Instant::now();
+        while let Some(entry) = self.delayed.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let entry = entry.remove();
+            self.ready.insert(
+                (std::cmp::Reverse(entry.record.priority), entry.record.id),
+                entry,
+            );
+        }
+    }
+}
+
+type Handler<T, E> =
+    Arc<dyn Fn(T) -> futures::future::BoxFuture<'static, Result<(), E>> + Send + Sync>;
+
+struct Shared<T, E> {
+    state: Mutex<State<T, E>>,
+    changed: Notify,
+    store: Arc<dyn JobStore<T, E>>,
+    handler: Handler<T, E>,
+    default_retry: RetryPolicy<E>,
+    completed: AtomicU64,
+    dead_lettered: AtomicU64,
+}
+
+/// A priority job queue run by a fixed number of concurrent slots
+///
+/// Clones share the same queue. Dropping the last one stops it like
+/// [`shutdown`](Self::shutdown): running jobs finish in the background and
+/// jobs that haven't started are dropped, so only a [`JobStore`] keeps them.
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::jobs::{Job, JobQueue, JobQueueConfig};
+/// use tokio_tutorial_patterns::select::RetryPolicy;
+///
+/// let queue = JobQueue::new(JobQueueConfig::default(), |email: String| async move {
+///     println!("sending {}", email);
+///     Ok::<_, std::io::Error>(())
+/// });
+///
+/// queue.enqueue(Job::new("welcome".to_string())).unwrap();
+/// queue
+///     .enqueue(
+///         Job::new("password reset".to_string())
+///             .priority(10)
+///             .retry(RetryPolicy::exponential(Duration::from_secs(1)).max_attempts(Some(5))),
+///     )
+///     .unwrap();
+/// queue.enqueue(Job::new("reminder".to_string()).delay(Duration::from_secs(3600))).unwrap();
+///
+/// // Jobs that haven't run yet are handed back, e.g. to persist elsewhere
+/// let unfinished = queue.shutdown().await;
+/// # let _ = unfinished;
+/// # }
+/// ```
+pub struct JobQueue<T, E> {
+    shared: Arc<Shared<T, E>>,
+    stop: CancellationToken,
+    dispatcher: Arc<Mutex<Option<JoinHandle<()>>>>,
+    /// Cancels `stop` once the last handle is dropped
+    _stop_on_drop: Arc<DropGuard>,
+}
+
+impl<T, E> Clone for JobQueue<T, E> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            stop: self.stop.clone(),
+            dispatcher: self.dispatcher.clone(),
+            _stop_on_drop: self._stop_on_drop.clone(),
+        }
+    }
+}
+
+impl<T, E> JobQueue<T, E>
+where
+    T: Clone + Send + 'static,
+    E: From<JobPanicked> + Send + 'static,
+{
+    /// Starts a queue that keeps nothing across restarts
+    ///
+    /// Must be called from within a Tokio runtime.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.concurrency` is zero.
+    pub fn new<F, Fut>(config: JobQueueConfig<E>, handler: F) -> Self
+    where
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        Self::with_store(config, NoStore, handler)
+    }
+
+    /// Starts a queue that reports to `store`, resuming the jobs it recovers
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.concurrency` is zero.
+    pub fn with_store<S, F, Fut>(config: JobQueueConfig<E>, store: S, handler: F) -> Self
+    where
+        S: JobStore<T, E>,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        assert!(config.concurrency > 0, "`concurrency` must be non-zero");
+
+        let mut state = State {
+            ready: BTreeMap::new(),
+            delayed: BTreeMap::new(),
+            running: 0,
+            dead: Vec::new(),
+            next_id: 0,
+            closed: false,
+        };
+        for record in store.recover() {
+            state.next_id = state.next_id.max(record.id.0 + 1);
+            state.schedule(Entry {
+                record,
+                retry: config.default_retry.clone(),
+                started: None,
+            });
+        }
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(state),
+            changed: Notify::new(),
+            store: Arc::new(store),
+            handler: Arc::new(move |payload| Box::pin(handler(payload))),
+            default_retry: config.default_retry,
+            completed: AtomicU64::new(0),
+            dead_lettered: AtomicU64::new(0),
+        });
+        let stop = CancellationToken::new();
+        let dispatcher = spawn_traced(
+            "job dispatcher",
+            dispatch(shared.clone(), config.concurrency, stop.clone()),
+        );
+
+        Self {
+            shared,
+            _stop_on_drop: Arc::new(stop.clone().drop_guard()),
+            stop,
+            dispatcher: Arc::new(Mutex::new(Some(dispatcher))),
+        }
+    }
+
+    /// Adds a job, returning the id it was given
+    pub fn enqueue(&self, job: Job<T, E>) -> Result<JobId, QueueClosed<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(QueueClosed(job.payload));
+        }
+
+        let id = JobId(state.next_id);
+        state.next_id += 1;
+        let record = JobRecord {
+            id,
+            payload: job.payload,
+            priority: job.priority,
+            run_at: job.run_at.unwrap_or_else(SystemTime::now),
+            attempts: 0,
+        };
+        self.shared.store.saved(&record);
+        state.schedule(Entry {
+            record,
+            retry: job
+                .retry
+                .unwrap_or_else(|| self.shared.default_retry.clone()),
+            started: None,
+        });
+        drop(state);
+
+        self.shared.changed.notify_one();
+        Ok(id)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.shared.state.lock().unwrap();
+        QueueStats {
+            ready: state.ready.len(),
+            delayed: state.delayed.len(),
+            running: state.running,
+            completed: self.shared.completed.load(Ordering::Relaxed),
+            dead_lettered: self.shared.dead_lettered.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Takes the jobs that have been dead-lettered since the last call
+    pub fn dead_letters(&self) -> Vec<DeadLetter<T, E>> {
+        std::mem::take(&mut self.shared.state.lock().unwrap().dead)
+    }
+
+    /// Stops starting jobs, waits for running ones to finish and returns
+    /// every job that never completed, in no particular order
+    ///
+    /// Failures during shutdown are rescheduled as usual and so are part of
+    /// the result. Only the first call returns anything.
+    pub async fn shutdown(&self) -> Vec<JobRecord<T>> {
+        self.shared.state.lock().unwrap().closed = true;
+        self.stop.cancel();
+
+        let dispatcher = self.dispatcher.lock().unwrap().take();
+        let Some(dispatcher) = dispatcher else {
+            return Vec::new();
+        };
+        let _ = dispatcher.await;
+
+        let mut state = self.shared.state.lock().unwrap();
+        let ready = std::mem::take(&mut state.ready).into_values();
+        let delayed = std::mem::take(&mut state.delayed).into_values();
+        ready.chain(delayed).map(|entry| entry.record).collect()
+    }
+}
+
+async fn dispatch<T, E>(shared: Arc<Shared<T, E>>, concurrency: usize, stop: CancellationToken)
+where
+    T: Clone + Send + 'static,
+    E: From<JobPanicked> + Send + 'static,
+{
+    let slots = Arc::new(Semaphore::new(concurrency));
+    let mut runs = JoinSet::new();
+
+    'dispatch: loop {
+        while runs.try_join_next().is_some() {}
+
+        let slot = tokio::select! {
+            _ = stop.cancelled() => break,
+            slot = slots.clone().acquire_owned() => slot.unwrap(),
+        };
+
+        let entry = loop {
+            let next_due = {
+                let mut state = shared.state.lock().unwrap();
+                state.promote();
+                if let Some(entry) = state.ready.pop_first().map(|(_, entry)| entry) {
+                    state.running += 1;
+                    break entry;
+                }
+                state.delayed.first_key_value().map(|((at, _), _)| *at)
+            };
+
+            let due = async {
+                match next_due {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = stop.cancelled() => break 'dispatch,
+                _ = shared.changed.notified() => {}
+                _ = due => {}
+            }
+        };
+
+        let shared = shared.clone();
+        runs.spawn(async move {
+            run(&shared, entry).await;
+            shared.state.lock().unwrap().running -= 1;
+            shared.changed.notify_one();
+            drop(slot);
+        });
+    }
+
+    while runs.join_next().await.is_some() {}
+}
+
+async fn run<T, E>(shared: &Shared<T, E>, mut entry: Entry<T, E>)
+where
+    T: Clone + 'static,
+    E: From<JobPanicked> + 'static,
+{
+    let started = *entry.started.get_or_insert_with(Instant::now);
+    let payload = entry.record.payload.clone();
+    let attempt = AssertUnwindSafe(async { (shared.handler)(payload).await });
+    let result = attempt
+        .catch_unwind()
+        .await
+        .unwrap_or_else(|_| Err(JobPanicked.into()));
+
+    let error = match result {
+        Ok(()) => {
+            shared.store.completed(&entry.record);
+            shared.completed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        Err(error) => error,
+    };
+
+    entry.record.attempts += 1;
+    match entry
+        .retry
+        .next_delay(entry.record.attempts, started, &error)
+    {
+        Some(delay) => {
+            entry.record.run_at = SystemTime::now() + delay;
+            shared.store.saved(&entry.record);
+            shared.state.lock().unwrap().schedule(entry);
+        }
+        None => {
+            shared.store.dead_lettered(&entry.record, &error);
+            shared.dead_lettered.fetch_add(1, Ordering::Relaxed);
+            shared.state.lock().unwrap().dead.push(DeadLetter {
+                job: entry.record,
+                error,
+            });
+        }
+    }
+}
@@ -8,13 +8,48 @@ pub mod basic_operations {
 
     use tokio::runtime::Runtime;
 
+    /// Starts serving `tokio-console` on its default port (6669), once per
+    /// process, returning whether it is being served
+    ///
+    /// The runtime factories below call this when the `console` feature is
+    /// enabled. The console needs the global `tracing` subscriber, so this
+    /// does nothing and returns `false` if another one was set first; set
+    /// yours before creating a runtime to keep it. The console only sees
+    /// tasks in binaries built with `RUSTFLAGS="--cfg tokio_unstable"`; tasks
+    /// started with [`spawn_traced`](crate::spawning::spawn_traced) show up
+    /// by name.
+    #[cfg(feature = "console")]
+    pub fn init_console() -> bool {
+        use tracing_subscriber::layer::SubscriberExt;
+        use tracing_subscriber::util::SubscriberInitExt;
+
+        static SERVING: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+        *SERVING.get_or_init(|| {
+            // Checked first so the console's server isn't started for nothing
+            if tracing::dispatcher::has_been_set() {
+                return false;
+            }
+            let console = console_subscriber::ConsoleLayer::builder()
+                .with_default_env()
+                .spawn();
+            tracing_subscriber::registry()
+                .with(console)
+                .try_init()
+                .is_ok()
+        })
+    }
+
     /// Creates a new multi-threaded Tokio runtime
     pub fn create_runtime() -> Runtime {
+        #[cfg(feature = "console")]
+        let _ = init_console();
         Runtime::new().expect("Failed to create runtime")
     }
 
     /// Creates a single-threaded Tokio runtime
     pub fn create_current_thread_runtime() -> Runtime {
+        #[cfg(feature = "console")]
+        let _ = init_console();
         tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
@@ -23,6 +58,8 @@ pub mod basic_operations {
 
     /// Creates a multi-threaded runtime with custom worker threads
     pub fn create_runtime_with_threads(num_threads: usize) -> Runtime {
+        #[cfg(feature = "console")]
+        let _ = init_console();
         tokio::runtime::Builder::new_multi_thread()
             .worker_threads(num_threads)
             .enable_all()
@@ -59,6 +96,49 @@ pub mod spawning {
         }
     }
 
+    /// Spawns a task that runs inside a `task` span named `name`
+    ///
+    /// With the `tracing` feature, every event the task emits carries its
+    /// name, and its start and end are logged at trace level. With the
+    /// `console` feature and `--cfg tokio_unstable`, the task is also given
+    /// `name` as its Tokio task name, which is what `tokio-console` lists.
+    /// Without either this is plain `tokio::spawn`.
+    ///
+    /// The crate's own background tasks are spawned this way, named after
+    /// what runs them, e.g. `health checker` or `pipeline stage parse`.
+    pub fn spawn_traced<F>(name: &str, future: F) -> JoinHandle<F::Output>
+    where
+        F: std::future::Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        #[cfg(feature = "tracing")]
+        let future = {
+            use tracing::Instrument;
+
+            let span = tracing::info_span!("task", task.name = name);
+            async move {
+                tracing::trace!("task started");
+                let output = future.await;
+                tracing::trace!("task finished");
+                output
+            }
+            .instrument(span)
+        };
+
+        #[cfg(all(feature = "console", tokio_unstable))]
+        {
+            tokio::task::Builder::new()
+                .name(name)
+                .spawn(future)
+                .expect("Failed to spawn task")
+        }
+        #[cfg(not(all(feature = "console", tokio_unstable)))]
+        {
+            let _ = name;
+            tokio::spawn(future)
+        }
+    }
+
     /// Demonstrates task cancellation
     pub async fn cancellable_task() -> JoinHandle<()> {
         tokio::spawn(async {
@@ -73,32 +153,105 @@ pub mod spawning {
 pub mod shared_state {
     //! Patterns for sharing state across async tasks
 
+    use std::collections::hash_map::RandomState;
+    use std::collections::HashMap;
+    use std::hash::{BuildHasher, Hash};
     use std::sync::Arc;
-    use tokio::sync::{Mutex, RwLock, Semaphore, Barrier, Notify};
+    use tokio::sync::{RwLock, Semaphore, Barrier, Notify};
+    #[cfg(not(tokio_patterns_loom))]
+    use tokio::sync::Mutex;
+
+    mod commit_barrier;
+
+    pub use commit_barrier::{CommitBarrier, CommitError, CommitProducer, Committed};
+
+    pub mod sync {
+        //! Blocking primitives that can be swapped for loom's
+        //!
+        //! With `RUSTFLAGS="--cfg tokio_patterns_loom"` these are loom's
+        //! model-checked versions. Code that imports its `Arc`, `Mutex` and
+        //! atomics from here is checked by loom models as written. The
+        //! crate's own async primitives are built on Tokio's locks, which
+        //! loom can't model under this cfg, so they aren't checked this way;
+        //! see [`Counter`](super::Counter). A crate-specific cfg is used
+        //! rather than plain `loom` because that one also changes Tokio,
+        //! which then leaves out `net` and `fs`.
+
+        #[cfg(tokio_patterns_loom)]
+        pub use loom::sync::{atomic, Arc, Mutex, MutexGuard};
+        #[cfg(not(tokio_patterns_loom))]
+        pub use std::sync::{atomic, Arc, Mutex, MutexGuard};
+    }
 
     /// A thread-safe counter using Arc and Mutex
+    ///
+    /// Under loom the Tokio lock is swapped for a blocking [`sync::Mutex`],
+    /// so the loom model checks that variant, not the lock shipped in normal
+    /// builds. It is a usage example for [`sync`] rather than a proof about
+    /// this type.
     #[derive(Clone)]
     pub struct Counter {
+        #[cfg(not(tokio_patterns_loom))]
         inner: Arc<Mutex<i32>>,
+        #[cfg(tokio_patterns_loom)]
+        inner: sync::Arc<sync::Mutex<i32>>,
     }
 
     impl Counter {
+        #[cfg(not(tokio_patterns_loom))]
         pub fn new(initial: i32) -> Self {
             Self {
                 inner: Arc::new(Mutex::new(initial)),
             }
         }
 
+        #[cfg(tokio_patterns_loom)]
+        pub fn new(initial: i32) -> Self {
+            Self {
+                inner: sync::Arc::new(sync::Mutex::new(initial)),
+            }
+        }
+
         pub async fn increment(&self) {
+            #[cfg(not(tokio_patterns_loom))]
             let mut count = self.inner.lock().await;
+            #[cfg(tokio_patterns_loom)]
+            let mut count = self.inner.lock().unwrap();
             *count += 1;
         }
 
         pub async fn get(&self) -> i32 {
-            *self.inner.lock().await
+            #[cfg(not(tokio_patterns_loom))]
+            let count = self.inner.lock().await;
+            #[cfg(tokio_patterns_loom)]
+            let count = self.inner.lock().unwrap();
+            *count
+        }
+
+        /// The counter's state, for [`restore`](Self::restore) after a restart
+        pub async fn snapshot(&self) -> CounterSnapshot {
+            CounterSnapshot {
+                value: self.get().await,
+            }
+        }
+
+        /// Sets the counter back to a snapshot, for every clone
+        pub async fn restore(&self, snapshot: &CounterSnapshot) {
+            #[cfg(not(tokio_patterns_loom))]
+            let mut count = self.inner.lock().await;
+            #[cfg(tokio_patterns_loom)]
+            let mut count = self.inner.lock().unwrap();
+            *count = snapshot.value;
         }
     }
 
+    /// A [`Counter`]'s state, serializable with the `serde` feature
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct CounterSnapshot {
+        pub value: i32,
+    }
+
     /// A read-write locked data structure
     pub struct SharedData<T> {
         inner: Arc<RwLock<T>>,
@@ -111,11 +264,11 @@ pub mod shared_state {
             }
         }
 
-        pub async fn read(&self) -> tokio::sync::RwLockReadGuard<T> {
+        pub async fn read(&self) -> tokio::sync::RwLockReadGuard<'_, T> {
             self.inner.read().await
         }
 
-        pub async fn write(&self) -> tokio::sync::RwLockWriteGuard<T> {
+        pub async fn write(&self) -> tokio::sync::RwLockWriteGuard<'_, T> {
             self.inner.write().await
         }
     }
@@ -142,6 +295,122 @@ pub mod shared_state {
     pub fn create_notify() -> Arc<Notify> {
         Arc::new(Notify::new())
     }
+
+    /// A concurrent hash map split into independently locked shards
+    ///
+    /// Keys hash to one shard, so tasks working on different keys rarely
+    /// contend on the same lock.
+    pub struct AsyncMap<K, V> {
+        shards: Arc<Vec<RwLock<HashMap<K, V>>>>,
+        hasher: RandomState,
+    }
+
+    impl<K, V> AsyncMap<K, V>
+    where
+        K: Hash + Eq,
+    {
+        pub fn new() -> Self {
+            Self::with_shards(16)
+        }
+
+        pub fn with_shards(shards: usize) -> Self {
+            assert!(shards > 0, "AsyncMap needs at least one shard");
+            Self {
+                shards: Arc::new((0..shards).map(|_| RwLock::new(HashMap::new())).collect()),
+                hasher: RandomState::new(),
+            }
+        }
+
+        fn shard_index(&self, key: &K) -> usize {
+            self.hasher.hash_one(key) as usize % self.shards.len()
+        }
+
+        fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+            &self.shards[self.shard_index(key)]
+        }
+
+        pub async fn get(&self, key: &K) -> Option<V>
+        where
+            V: Clone,
+        {
+            self.shard(key).read().await.get(key).cloned()
+        }
+
+        pub async fn insert(&self, key: K, value: V) -> Option<V> {
+            self.shard(&key).write().await.insert(key, value)
+        }
+
+        pub async fn remove(&self, key: &K) -> Option<V> {
+            self.shard(key).write().await.remove(key)
+        }
+
+        pub async fn contains_key(&self, key: &K) -> bool {
+            self.shard(key).read().await.contains_key(key)
+        }
+
+        /// Total number of entries, summed shard by shard
+        pub async fn len(&self) -> usize {
+            let mut len = 0;
+            for shard in self.shards.iter() {
+                len += shard.read().await.len();
+            }
+            len
+        }
+
+        pub async fn is_empty(&self) -> bool {
+            self.len().await == 0
+        }
+
+        /// Every entry at one point in time
+        ///
+        /// All shards are locked together, so no write lands halfway
+        /// through. With the `serde` feature the map can be serialized as is.
+        pub async fn snapshot(&self) -> HashMap<K, V>
+        where
+            K: Clone,
+            V: Clone,
+        {
+            let mut shards = Vec::with_capacity(self.shards.len());
+            // Always locked in the same order, so two snapshots can't deadlock
+            for shard in self.shards.iter() {
+                shards.push(shard.read().await);
+            }
+            shards
+                .iter()
+                .flat_map(|shard| shard.iter())
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        }
+
+        /// Replaces every entry with those in `snapshot`, at once for all
+        /// clones
+        pub async fn restore(&self, snapshot: HashMap<K, V>) {
+            let mut shards = Vec::with_capacity(self.shards.len());
+            for shard in self.shards.iter() {
+                let mut shard = shard.write().await;
+                shard.clear();
+                shards.push(shard);
+            }
+            for (key, value) in snapshot {
+                shards[self.shard_index(&key)].insert(key, value);
+            }
+        }
+    }
+
+    impl<K: Hash + Eq, V> Default for AsyncMap<K, V> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<K, V> Clone for AsyncMap<K, V> {
+        fn clone(&self) -> Self {
+            Self {
+                shards: Arc::clone(&self.shards),
+                hasher: self.hasher.clone(),
+            }
+        }
+    }
 }
 
 pub mod channels {
@@ -149,6 +418,19 @@ pub mod channels {
 
     use tokio::sync::{mpsc, oneshot, broadcast, watch};
 
+    mod adaptive;
+    mod batch;
+    mod deadline;
+    mod traced;
+
+    pub use adaptive::{
+        AdaptiveChannel, AdaptiveChannelConfig, AdaptiveReceiver, AdaptiveSender, ResizeEvent,
+        ResizeReason,
+    };
+    pub use batch::BatchReceiver;
+    pub use deadline::{SendOutcome, SenderExt};
+    pub use traced::{traced_channel, TracedReceiver, TracedSender};
+
     /// Creates an MPSC channel with the specified buffer size
     pub fn create_mpsc<T>(buffer: usize) -> (mpsc::Sender<T>, mpsc::Receiver<T>) {
         mpsc::channel(buffer)
@@ -192,7 +474,7 @@ pub mod channels {
         {
             let (tx, mut rx) = mpsc::channel::<(Req, oneshot::Sender<Resp>)>(32);
 
-            tokio::spawn(async move {
+            crate::spawning::spawn_traced("request handler", async move {
                 while let Some((req, response_tx)) = rx.recv().await {
                     let resp = handler(req).await;
                     let _ = response_tx.send(resp);
@@ -224,6 +506,67 @@ pub mod io {
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use std::path::Path;
 
+    mod accept;
+    mod batch;
+    mod chat;
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    mod compression;
+    mod discovery;
+    mod dns;
+    mod file_lock;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    mod handoff;
+    mod hashing;
+    mod heartbeat;
+    pub mod http_lite;
+    pub mod kv;
+    mod proxy;
+    #[cfg(any(feature = "ndjson", feature = "csv"))]
+    mod records;
+    mod repl;
+    mod rotating_log;
+    mod server;
+    mod socket;
+    mod traced;
+    mod transport;
+    mod zero_copy;
+
+    pub use accept::{AcceptAction, AcceptErrorKind, AcceptPolicy, Acceptor};
+    pub use batch::{process_files, process_files_with_progress, BatchReport, Progress};
+    pub use chat::{chat_server, serve_chat, serve_chat_with};
+    #[cfg(feature = "gzip")]
+    pub use compression::{gzip_reader, gzip_writer, read_file_gz, write_file_gz};
+    #[cfg(feature = "zstd")]
+    pub use compression::{read_file_zst, write_file_zst, zstd_reader, zstd_writer};
+    pub use discovery::{Beacon, DiscoveryConfig, DiscoveryEvent, DiscoveryListener, Peer};
+    pub use dns::{connect_addrs, connect_happy_eyeballs, resolve};
+    pub use file_lock::{FileLock, FileLockGuard};
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    pub use handoff::ListenerHandoff;
+    #[cfg(feature = "crc32")]
+    pub use hashing::Crc32;
+    #[cfg(feature = "sha256")]
+    pub use hashing::Sha256;
+    pub use hashing::{Digest, HashingReader, HashingWriter};
+    pub use heartbeat::{Heartbeat, HeartbeatError, HeartbeatFrame};
+    pub use proxy::copy_bidirectional_graceful;
+    #[cfg(feature = "csv")]
+    pub use records::csv_stream;
+    #[cfg(feature = "ndjson")]
+    pub use records::ndjson_stream;
+    #[cfg(any(feature = "ndjson", feature = "csv"))]
+    pub use records::{RecordError, RecordErrorKind};
+    pub use repl::{repl, repl_with, ReplExit};
+    pub use rotating_log::{LogError, RotatingLog, RotatingLogBuilder};
+    pub use server::{
+        ConnectionInfo, Goodbye, Message, Server, ServerBuilder, ServerShutdownReport,
+        ShutdownConfig,
+    };
+    pub use socket::SocketConfig;
+    pub use traced::serve_traced;
+    pub use transport::{test_transport, test_transport_with, FaultConfig, FaultyStream};
+    pub use zero_copy::{send_file, write_all_vectored};
+
     /// Asynchronously reads the entire contents of a file
     pub async fn read_file<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
         let mut file = tokio::fs::File::open(path).await?;
@@ -245,10 +588,13 @@ pub mod io {
     }
 
     /// Creates a TCP echo server on the given address
+    ///
+    /// Runs until accepting fails with an error the default [`AcceptPolicy`]
+    /// doesn't retry.
     pub async fn tcp_echo_server(addr: &str) -> std::io::Result<()> {
         use tokio::net::TcpListener;
 
-        let listener = TcpListener::bind(addr).await?;
+        let mut listener = Acceptor::from(TcpListener::bind(addr).await?);
         println!("Echo server listening on: {}", addr);
 
         loop {
@@ -276,37 +622,198 @@ pub mod io {
 pub mod select {
     //! Patterns using tokio::select! for concurrent operations
 
-    use tokio::time::{sleep, Duration};
+    use tokio::time::{sleep, sleep_until, Duration, Instant};
+
+    mod adaptive_timeout;
+    mod bulkhead;
+    mod circuit_breaker;
+    mod connect;
+    mod deadline;
+    mod debounce;
+    mod expiry;
+    mod future_set;
+    mod interval;
+    mod latency;
+    mod load_shed;
+    pub mod policy;
+    mod progress;
+    mod retry;
+    pub mod scheduler;
+    mod signal;
+    mod watchdog;
+
+    pub use adaptive_timeout::{AdaptiveTimeout, AdaptiveTimeoutConfig, AdaptiveTimeoutError};
+    pub use bulkhead::{Bulkhead, BulkheadFull, BulkheadMetrics};
+    pub use circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitError, CircuitState};
+    pub use connect::{retry_connect, ConnectAttempt, ConnectError, ConnectPolicy};
+    pub use deadline::{with_deadline, Deadline};
+    pub use debounce::{Debouncer, Throttler};
+    pub use expiry::ExpiryQueue;
+    pub use future_set::FutureSet;
+    pub use interval::{interval_jittered, IntervalHandle, JitteredInterval, PausableInterval};
+    pub use latency::{timed, LatencyRecorder, LatencySummary};
+    pub use load_shed::{LoadShed, LoadShedConfig, LoadShedMetrics, ShedError, ShedReason};
+    pub use progress::{
+        set_status, with_progress, with_progress_config, ProgressConfig, ProgressUpdate,
+    };
+    pub use retry::{retry, Backoff, Jitter, RetryPolicy};
+    pub use signal::{
+        shutdown_on_signal, wait_for_shutdown_signal, ShutdownSignal, ShutdownSignals,
+    };
+    pub use watchdog::{progress, with_watchdog, ProgressHandle, Stalled};
+
+    /// Error returned when an operation doesn't finish in time
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct TimeoutError {
+        /// How long the operation was given
+        pub timeout: Duration,
+    }
+
+    impl std::fmt::Display for TimeoutError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "operation timed out after {:?}", self.timeout)
+        }
+    }
+
+    impl std::error::Error for TimeoutError {}
 
     /// Demonstrates basic select pattern
     pub async fn select_with_timeout<T>(
         future: impl std::future::Future<Output = T>,
         timeout: Duration,
-    ) -> Result<T, ()> {
+    ) -> Result<T, TimeoutError> {
+        tokio::select! {
+            result = future => Ok(result),
+            _ = sleep(timeout) => Err(TimeoutError { timeout }),
+        }
+    }
+
+    /// Like [`select_with_timeout`], but bounded by a point in time
+    ///
+    /// Useful when several sequential steps share one overall budget.
+    pub async fn select_with_deadline<T>(
+        future: impl std::future::Future<Output = T>,
+        deadline: Instant,
+    ) -> Result<T, TimeoutError> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
         tokio::select! {
             result = future => Ok(result),
-            _ = sleep(timeout) => Err(()),
+            _ = sleep_until(deadline) => Err(TimeoutError { timeout }),
+        }
+    }
+
+    /// Runs all futures concurrently and returns the first `Ok`, dropping
+    /// (and so cancelling) the rest
+    ///
+    /// If every future fails, all errors are returned in input order.
+    pub async fn race_ok<I, F, T, E>(futures: I) -> Result<T, Vec<E>>
+    where
+        I: IntoIterator<Item = F>,
+        F: std::future::Future<Output = Result<T, E>>,
+    {
+        use futures::stream::{FuturesUnordered, StreamExt};
+
+        let mut pending: FuturesUnordered<_> = futures
+            .into_iter()
+            .enumerate()
+            .map(|(index, fut)| async move { (index, fut.await) })
+            .collect();
+        let mut errors = Vec::with_capacity(pending.len());
+
+        while let Some((index, result)) = pending.next().await {
+            match result {
+                Ok(value) => return Ok(value),
+                Err(e) => errors.push((index, e)),
+            }
+        }
+
+        errors.sort_by_key(|(index, _)| *index);
+        Err(errors.into_iter().map(|(_, e)| e).collect())
+    }
+
+    /// Runs two futures concurrently and returns whichever finishes first
+    ///
+    /// The loser is dropped, which cancels it at its current `.await`. When
+    /// both are ready in the same poll the winner is picked at random, as
+    /// with `tokio::select!`.
+    pub async fn race<A, B>(a: A, b: B) -> futures::future::Either<A::Output, B::Output>
+    where
+        A: std::future::Future,
+        B: std::future::Future,
+    {
+        use futures::future::Either;
+
+        tokio::select! {
+            out = a => Either::Left(out),
+            out = b => Either::Right(out),
+        }
+    }
+
+    /// Like [`race`], but always polls `a` first, so `a` wins ties
+    ///
+    /// Use this when `a` is a shutdown or cancellation signal that must take
+    /// priority over `b`.
+    pub async fn race_biased<A, B>(a: A, b: B) -> futures::future::Either<A::Output, B::Output>
+    where
+        A: std::future::Future,
+        B: std::future::Future,
+    {
+        use futures::future::Either;
+
+        tokio::select! {
+            biased;
+            out = a => Either::Left(out),
+            out = b => Either::Right(out),
+        }
+    }
+
+    /// Like [`race_biased`], but hands the loser back un-cancelled
+    ///
+    /// The losing future may have been polled already; awaiting it later
+    /// resumes where it left off.
+    pub async fn select_biased<A, B>(
+        mut a: A,
+        mut b: B,
+    ) -> futures::future::Either<(A::Output, B), (B::Output, A)>
+    where
+        A: std::future::Future + Unpin,
+        B: std::future::Future + Unpin,
+    {
+        use futures::future::Either;
+
+        tokio::select! {
+            biased;
+            out = &mut a => Either::Left((out, b)),
+            out = &mut b => Either::Right((out, a)),
         }
     }
 
     /// Graceful shutdown pattern
-    pub async fn graceful_shutdown<F, Fut>(
-        work: F,
-        mut shutdown_rx: tokio::sync::mpsc::Receiver<()>,
-    ) where
-        F: Fn() -> Fut,
-        Fut: std::future::Future<Output = ()>,
+    ///
+    /// Runs `work` every 100ms until `token` is cancelled. Any number of loops
+    /// can share one token (or child tokens of it). The first error returned
+    /// by `work` stops the loop and is handed back to the caller.
+    ///
+    /// Suits a single loop; services with several subsystems that must stop
+    /// in order should use [`crate::shutdown::Coordinator`] instead.
+    pub async fn graceful_shutdown<F, Fut, E>(
+        mut work: F,
+        token: tokio_util::sync::CancellationToken,
+    ) -> Result<(), E>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<(), E>>,
     {
         let mut interval = tokio::time::interval(Duration::from_millis(100));
 
         loop {
             tokio::select! {
                 _ = interval.tick() => {
-                    work().await;
+                    work().await?;
                 }
-                _ = shutdown_rx.recv() => {
+                _ = token.cancelled() => {
                     println!("Shutdown signal received");
-                    break;
+                    return Ok(());
                 }
             }
         }
@@ -316,19 +823,91 @@ pub mod select {
 pub mod streams {
     //! Stream processing patterns and utilities
 
-    use tokio_stream::{Stream, StreamExt};
+    mod cancel;
+    mod channels;
+    mod checkpoint;
+    mod chunks;
+    mod concurrent;
+    mod consume;
+    mod debounce;
+    mod dedup;
+    mod fallible;
+    mod framed;
+    mod generate;
+    mod instrument;
+    mod merge;
+    mod paginate;
+    mod partition;
+    mod prefetch;
+    mod rate_limit;
+    mod reorder;
+    mod retry;
+    mod sample;
+    mod scan;
+    pub mod sinks;
+    mod stream_fn;
+    mod tee;
+    mod timeout;
+    mod window;
+
+    pub use cancel::{take_until_cancelled, take_until_shutdown};
+    pub use channels::{
+        forward_to_sender, from_broadcast, from_receiver, from_unbounded_receiver, from_watch,
+        Lagged,
+    };
+    pub use checkpoint::{Checkpointed, Committer};
+    pub use chunks::{chunks, chunks_timeout};
+    pub use concurrent::{map_concurrent, map_concurrent_unordered};
+    pub use consume::{collect_n, drain_with, take_while_async};
+    pub use debounce::{debounce, throttle, Debounce, Edge, Throttle};
+    pub use dedup::{dedup_by_key, distinct_until_changed};
+    pub use fallible::{collect_results, ok_items, stop_on_error_threshold};
+    pub use framed::{frames, lines};
+    pub use generate::{generate, ticker};
+    pub use instrument::{instrument, instrument_with};
+    pub use merge::{merge, merge_sorted_by_key, MergeSortedByKey};
+    pub use paginate::{paginate, Page, Paginate};
+    pub use partition::{partition_by_key, Partition, PartitionByKey};
+    pub use prefetch::{prefetch, Prefetch, PrefetchMetrics};
+    pub use rate_limit::{rate_limit, rate_limit_with};
+    pub use reorder::reorder_by_sequence;
+    pub use retry::retry_stream;
+    pub use sample::{latest_every, sample_every, sample_probabilistic};
+    pub use scan::{fold_async, scan_async};
+    pub use stream_fn::{stream_fn, StreamFn, Yielder};
+    pub use tee::{tee, tee_with_config, SlowConsumer, Tee, TeeConfig};
+    pub use timeout::{take_until_deadline, timeout_per_item};
+    pub use window::{tumbling_window, windowed};
+
+    use tokio_stream::Stream;
     use std::pin::Pin;
     use std::task::{Context, Poll};
 
     /// A custom Fibonacci stream
+    ///
+    /// Ends after the last Fibonacci number that fits in a `u64` (the 94th)
+    /// instead of overflowing. Written with [`stream_fn`], so the state is
+    /// just two local variables.
     pub struct FibonacciStream {
-        curr: u64,
-        next: u64,
+        inner: Pin<Box<dyn Stream<Item = u64> + Send>>,
     }
 
     impl FibonacciStream {
         pub fn new() -> Self {
-            Self { curr: 0, next: 1 }
+            let numbers = stream_fn(|mut y| async move {
+                let (mut curr, mut next) = (0u64, Some(1u64));
+                loop {
+                    y.emit(curr).await;
+                    let Some(following) = next else {
+                        return;
+                    };
+                    next = curr.checked_add(following);
+                    curr = following;
+                }
+            });
+            Self {
+                inner: Box::pin(numbers),
+            }
         }
     }
 
@@ -341,38 +920,61 @@ pub mod streams {
     impl Stream for FibonacciStream {
         type Item = u64;
 
-        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            let current = self.curr;
-            let next = self.next;
-
-            self.curr = next;
-            self.next = current + next;
-
-            Poll::Ready(Some(current))
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.inner.as_mut().poll_next(cx)
         }
     }
 
-    /// Takes the first n items from a stream
-    pub async fn take_n<S>(mut stream: S, n: usize) -> Vec<S::Item>
+    /// A stream of the values produced by calling `f` repeatedly, ending
+    /// the first time it returns `None`
+    ///
+    /// The closure keeps whatever state the sequence needs:
+    ///
+    /// ```
+    /// # async fn example() {
+    /// use tokio_stream::StreamExt;
+    /// use tokio_tutorial_patterns::streams::sequence_stream;
+    ///
+    /// let mut power = 1u32;
+    /// let powers_of_two = sequence_stream(move || {
+    ///     let current = power;
+    ///     power = power.checked_mul(2)?;
+    ///     Some(current)
+    /// });
+    /// let powers: Vec<u32> = powers_of_two.collect().await;
+    /// assert_eq!(powers.len(), 31);
+    /// # }
+    /// ```
+    pub fn sequence_stream<T, F>(f: F) -> impl Stream<Item = T>
     where
-        S: Stream + Unpin,
+        F: FnMut() -> Option<T>,
     {
-        let mut items = Vec::new();
-        let mut count = 0;
-
-        while count < n {
-            if let Some(item) = stream.next().await {
-                items.push(item);
-                count += 1;
-            } else {
-                break;
-            }
-        }
-
-        items
+        tokio_stream::iter(std::iter::from_fn(f))
     }
 }
 
+pub mod actors;
+pub mod batching;
+pub mod bench;
+pub mod codec;
+pub mod config;
+pub mod coordination;
+pub mod crawl;
+pub mod dedup;
+pub mod eventlog;
+pub mod health;
+pub mod jobs;
+pub mod metrics;
+pub mod orchestration;
+pub mod pipeline;
+pub mod ratelimit;
+pub mod service;
+pub mod sessions;
+pub mod shutdown;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod workers;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -407,8 +1009,4687 @@ mod tests {
     #[tokio::test]
     async fn test_fibonacci_stream() {
         let stream = streams::FibonacciStream::new();
-        let items = streams::take_n(stream, 10).await;
+        let items = streams::collect_n(stream, 10).await;
 
         assert_eq!(items, vec![0, 1, 1, 2, 3, 5, 8, 13, 21, 34]);
     }
+
+    #[tokio::test]
+    async fn test_hashing_reader_writer() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        #[derive(Default)]
+        struct ByteSum(u64);
+
+        impl io::Digest for ByteSum {
+            type Output = u64;
+
+            fn update(&mut self, data: &[u8]) {
+                self.0 += data.iter().map(|&b| b as u64).sum::<u64>();
+            }
+
+            fn finalize(&self) -> u64 {
+                self.0
+            }
+        }
+
+        let data = b"hello hashing".to_vec();
+        let expected: u64 = data.iter().map(|&b| b as u64).sum();
+
+        let mut reader = io::HashingReader::new(&data[..], ByteSum::default());
+        let mut out = Vec::new();
+        assert_eq!(reader.hash(), None);
+        reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(reader.hash(), Some(expected));
+        assert_eq!(reader.bytes_read(), data.len() as u64);
+
+        let mut writer = io::HashingWriter::new(Vec::new(), ByteSum::default());
+        writer.write_all(&data).await.unwrap();
+        assert_eq!(writer.hash(), None);
+        writer.flush().await.unwrap();
+        assert_eq!(writer.hash(), Some(expected));
+    }
+
+    #[cfg(feature = "crc32")]
+    #[tokio::test]
+    async fn test_hashing_crc32() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut reader = io::HashingReader::new(&b"123456789"[..], io::Crc32::new());
+        reader.read_to_end(&mut Vec::new()).await.unwrap();
+        assert_eq!(reader.hash(), Some(0xCBF43926));
+
+        let mut writer = io::HashingWriter::new(Vec::new(), io::Crc32::new());
+        writer.write_all(b"123456789").await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(writer.hash(), Some(0xCBF43926));
+    }
+
+    #[cfg(feature = "sha256")]
+    #[tokio::test]
+    async fn test_hashing_sha256() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let expected = [
+            0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+            0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+            0xf2, 0x00, 0x15, 0xad,
+        ];
+
+        let mut reader = io::HashingReader::new(&b"abc"[..], io::Sha256::new());
+        reader.read_to_end(&mut Vec::new()).await.unwrap();
+        assert_eq!(reader.hash(), Some(expected));
+
+        let mut writer = io::HashingWriter::new(Vec::new(), io::Sha256::new());
+        writer.write_all(b"abc").await.unwrap();
+        writer.flush().await.unwrap();
+        assert_eq!(writer.hash(), Some(expected));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_gzip_file_roundtrip() {
+        let path = std::env::temp_dir().join(format!("tokio_patterns_{}.gz", std::process::id()));
+        let contents = b"line one\nline two\n".repeat(100);
+
+        io::write_file_gz(&path, &contents).await.unwrap();
+        assert!(io::read_file(&path).await.unwrap().len() < contents.len());
+        assert_eq!(io::read_file_gz(&path).await.unwrap(), contents);
+
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[tokio::test]
+    async fn test_chat_server_fan_out() {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(io::serve_chat(listener, async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let mut alice = BufReader::new(tokio::net::TcpStream::connect(addr).await.unwrap());
+        let mut bob = BufReader::new(tokio::net::TcpStream::connect(addr).await.unwrap());
+        let mut line = String::new();
+
+        alice.read_line(&mut line).await.unwrap();
+        alice.get_mut().write_all(b"alice\n").await.unwrap();
+        line.clear();
+        alice.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "* welcome, alice\n");
+
+        bob.read_line(&mut line).await.unwrap();
+        bob.get_mut().write_all(b"bob\n").await.unwrap();
+        bob.read_line(&mut line).await.unwrap();
+
+        line.clear();
+        alice.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "* bob joined\n");
+
+        bob.get_mut().write_all(b"hi all\n").await.unwrap();
+        line.clear();
+        alice.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "bob: hi all\n");
+
+        // A line with no end in sight gets the client dropped, not buffered.
+        // One byte over the limit, so the server has read it all when it closes
+        bob.get_mut()
+            .write_all(&vec![b'x'; 8 * 1024 + 1])
+            .await
+            .unwrap();
+        let mut rest = String::new();
+        bob.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "* disconnected: line too long\n");
+        line.clear();
+        alice.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "* bob left\n");
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_map_shards() {
+        let map = shared_state::AsyncMap::with_shards(4);
+
+        let mut handles = vec![];
+        for i in 0..20 {
+            let map = map.clone();
+            handles.push(tokio::spawn(async move {
+                map.insert(i, i * 10).await;
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        assert_eq!(map.len().await, 20);
+        assert_eq!(map.get(&7).await, Some(70));
+        assert_eq!(map.remove(&7).await, Some(70));
+        assert!(!map.contains_key(&7).await);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restore() {
+        use std::collections::HashMap;
+        use std::time::Duration;
+
+        let counter = shared_state::Counter::new(0);
+        counter.increment().await;
+        counter.increment().await;
+        let saved = counter.snapshot().await;
+        counter.increment().await;
+        counter.restore(&saved).await;
+        assert_eq!(counter.get().await, 2);
+
+        let map = shared_state::AsyncMap::with_shards(4);
+        for i in 0..10 {
+            map.insert(i, i * 10).await;
+        }
+        let saved = map.snapshot().await;
+        assert_eq!(saved.len(), 10);
+        map.insert(99, 0).await;
+        map.restore(saved.clone()).await;
+        assert_eq!(map.snapshot().await, saved);
+        assert!(!map.contains_key(&99).await);
+        map.restore(HashMap::new()).await;
+        assert!(map.is_empty().await);
+
+        let registry = metrics::Registry::new();
+        registry.counter("requests").add(5);
+        registry.gauge("load").set(0.5);
+        registry.latency("rtt").record(Duration::from_millis(3));
+        let saved = registry.snapshot();
+
+        // A fresh process starts from zero and picks up where it left off
+        let restarted = metrics::Registry::new();
+        let requests = restarted.counter("requests");
+        restarted.restore(&saved);
+        assert_eq!(requests.get(), 5);
+        assert_eq!(restarted.gauge("load").get(), 0.5);
+        assert!(!restarted.snapshot().contains_key("rtt"));
+
+        #[cfg(all(feature = "serde", feature = "json"))]
+        {
+            let json = serde_json::to_string(&saved).unwrap();
+            let parsed: std::collections::BTreeMap<String, metrics::MetricValue> =
+                serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, saved);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kv_server_roundtrip() {
+        use io::kv::{Client, ClientError, Command};
+
+        assert_eq!(
+            Command::parse("SET greeting hello world"),
+            Ok(Command::Set("greeting".into(), "hello world".into()))
+        );
+        assert!(Command::parse("GET").is_err());
+        assert!(Command::parse("GET a\tb").is_err());
+        assert!(Command::parse("SET a\tb value").is_err());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(io::kv::serve(listener, Default::default(), async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let mut client = Client::connect(addr).await.unwrap();
+        assert_eq!(client.get("greeting").await.unwrap(), None);
+        client.set("greeting", "hello world").await.unwrap();
+        assert_eq!(
+            client.get("greeting").await.unwrap().as_deref(),
+            Some("hello world")
+        );
+        assert!(client.del("greeting").await.unwrap());
+        assert!(!client.del("greeting").await.unwrap());
+
+        // Keys with whitespace and values with line breaks would reframe the
+        // request, so they never leave the client
+        assert!(matches!(
+            client.get("a b").await,
+            Err(ClientError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+        ));
+        assert!(matches!(
+            client.set("a\tb", "v").await,
+            Err(ClientError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+        ));
+        client.set("x", "keep").await.unwrap();
+        assert!(matches!(
+            client.set("a", "b\nDEL x").await,
+            Err(ClientError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+        ));
+        assert!(matches!(
+            client.set("a", "b\r").await,
+            Err(ClientError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+        ));
+        assert_eq!(client.get("x").await.unwrap().as_deref(), Some("keep"));
+
+        // An over-long line is rejected without closing the connection
+        let key = "k".repeat(70 * 1024);
+        assert!(matches!(
+            client.get(&key).await,
+            Err(ClientError::Server(_))
+        ));
+        client.set("a", "b").await.unwrap();
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_servers_say_goodbye() {
+        use io::kv::{Client, ClientError};
+        use io::Goodbye;
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let goodbye = Goodbye::new("maintenance").redirect("10.0.0.2:7000");
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(io::serve_chat_with(
+            listener,
+            async {
+                let _ = shutdown_rx.await;
+            },
+            goodbye.clone(),
+        ));
+        let mut alice = BufReader::new(tokio::net::TcpStream::connect(addr).await.unwrap());
+        let mut line = String::new();
+        alice.read_line(&mut line).await.unwrap();
+        alice.get_mut().write_all(b"alice\n").await.unwrap();
+        alice.read_line(&mut line).await.unwrap();
+
+        shutdown_tx.send(()).unwrap();
+        let mut rest = String::new();
+        alice.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(
+            rest,
+            "* server going away: maintenance\n* reconnect to 10.0.0.2:7000\n"
+        );
+        server.await.unwrap().unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(io::kv::serve_with(
+            listener,
+            Default::default(),
+            async {
+                let _ = shutdown_rx.await;
+            },
+            goodbye,
+        ));
+        let mut socket = BufReader::new(tokio::net::TcpStream::connect(addr).await.unwrap());
+        socket.get_mut().write_all(b"SET k v\n").await.unwrap();
+        line.clear();
+        socket.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "OK\n");
+
+        shutdown_tx.send(()).unwrap();
+        rest.clear();
+        socket.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "REDIRECT 10.0.0.2:7000 maintenance\n");
+        server.await.unwrap().unwrap();
+
+        // A server going away mid-call is reported as such
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            let mut request = String::new();
+            socket.read_line(&mut request).await.unwrap();
+            socket
+                .get_mut()
+                .write_all(b"BYE restarting\n")
+                .await
+                .unwrap();
+        });
+        let mut client = Client::connect(addr).await.unwrap();
+        let err = client.get("k").await.unwrap_err();
+        assert!(matches!(
+            &err,
+            ClientError::GoingAway { reason, redirect: None } if reason == "restarting"
+        ));
+        assert_eq!(err.to_string(), "server going away: restarting");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_servers_drain_stalled_clients() {
+        use io::Goodbye;
+        use std::time::Duration;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let goodbye = Goodbye {
+            flush_timeout: Duration::from_millis(100),
+            ..Goodbye::default()
+        };
+
+        // A kv client that pipelines requests but never reads the responses
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(io::kv::serve_with(
+            listener,
+            Default::default(),
+            async {
+                let _ = shutdown_rx.await;
+            },
+            goodbye.clone(),
+        ));
+        let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let value = "v".repeat(60 * 1024);
+        socket
+            .write_all(format!("SET big {}\n", value).as_bytes())
+            .await
+            .unwrap();
+        socket.write_all(&b"GET big\n".repeat(500)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("kv server stuck on a stalled client")
+            .unwrap()
+            .unwrap();
+        drop(socket);
+
+        // A chat client that stops reading while others keep talking
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(io::serve_chat_with(
+            listener,
+            async {
+                let _ = shutdown_rx.await;
+            },
+            goodbye,
+        ));
+        let mut alice = BufReader::new(tokio::net::TcpStream::connect(addr).await.unwrap());
+        // A small receive window so the server's writes to bob back up quickly
+        let bob = tokio::net::TcpSocket::new_v4().unwrap();
+        bob.set_recv_buffer_size(4096).unwrap();
+        let mut bob = BufReader::new(bob.connect(addr).await.unwrap());
+        let mut line = String::new();
+        alice.read_line(&mut line).await.unwrap();
+        alice.get_mut().write_all(b"alice\n").await.unwrap();
+        alice.read_line(&mut line).await.unwrap();
+        bob.read_line(&mut line).await.unwrap();
+        bob.get_mut().write_all(b"bob\n").await.unwrap();
+        bob.read_line(&mut line).await.unwrap();
+
+        // Paced so alice's own task keeps up with the broadcast
+        let text = format!("{}\n", "x".repeat(8000)).repeat(32);
+        for _ in 0..32 {
+            alice.get_mut().write_all(text.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("chat server stuck on a stalled client")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_lite_keep_alive() {
+        use io::http_lite::{Response, Router};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let router = Router::new()
+            .route("GET", "/healthz", |_| async { Response::text("ok") })
+            .route("POST", "/echo", |req| async move {
+                Response::new(200).body(req.body)
+            })
+            .route("GET", "/peer", |req| async move {
+                Response::text(req.peer.map(|peer| peer.to_string()).unwrap_or_default())
+            });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(io::http_lite::serve(listener, router, async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let n = socket.read(&mut buf).await.unwrap();
+        let response = String::from_utf8_lossy(&buf[..n]).to_string();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Connection: keep-alive"));
+        assert!(response.ends_with("ok"));
+
+        socket
+            .write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nping")
+            .await
+            .unwrap();
+        let mut rest = String::new();
+        socket.read_to_string(&mut rest).await.unwrap();
+        assert!(rest.contains("Connection: close"));
+        assert!(rest.ends_with("ping"));
+
+        let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(b"GET /peer HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with(&socket.local_addr().unwrap().to_string()));
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_lite_head_limit() {
+        use io::http_lite::{Response, Router};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let router = Router::new().route("GET", "/", |_| async { Response::text("ok") });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(io::http_lite::serve(
+            listener,
+            router,
+            std::future::pending(),
+        ));
+
+        // Exactly the 16 KiB head budget, never finishing a line or the head
+        let request_line = format!("GET /{}", "a".repeat(16 * 1024 - 5));
+        let header = format!("GET / HTTP/1.1\r\nX-Big: {}", "a".repeat(16 * 1024 - 23));
+        let full_header = format!(
+            "GET / HTTP/1.1\r\nX-Big: {}\r\n",
+            "a".repeat(16 * 1024 - 25)
+        );
+        // Conflicting lengths are refused rather than one of them winning
+        let conflicting = "POST / HTTP/1.1\r\nContent-Length: 4\r\nContent-Length: 5\r\n\r\nping";
+        for (head, status) in [
+            (request_line, "400"),
+            (header, "431"),
+            (full_header, "431"),
+            (conflicting.to_string(), "400"),
+        ] {
+            let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+            socket.write_all(head.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            socket.read_to_string(&mut response).await.unwrap();
+            assert!(
+                response.starts_with(&format!("HTTP/1.1 {}", status)),
+                "{}",
+                response
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_socket_config_applies_options() {
+        let config = io::SocketConfig::new()
+            .nodelay(true)
+            .reuse_address(true)
+            .keepalive(std::time::Duration::from_secs(30))
+            .recv_buffer_size(64 * 1024);
+
+        let listener = config.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (client, accepted) = tokio::join!(config.connect(addr), listener.accept());
+        let client = client.unwrap();
+        let (server, _) = accepted.unwrap();
+        config.apply(&server).unwrap();
+
+        assert!(client.nodelay().unwrap());
+        assert!(server.nodelay().unwrap());
+
+        // Probe settings alone still turn keepalive on
+        let probes = io::SocketConfig::new().keepalive_interval(std::time::Duration::from_secs(5));
+        probes.apply(&server).unwrap();
+        let server = socket2::SockRef::from(&server);
+        assert!(server.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            server.tcp_keepalive_interval().unwrap(),
+            std::time::Duration::from_secs(5)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rotating_log_rotates_and_prunes() {
+        let dir = std::env::temp_dir().join(format!("tokio_patterns_log_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("app.log");
+
+        let log = io::RotatingLog::builder(&path)
+            .max_size(20)
+            .keep(2)
+            .open()
+            .await
+            .unwrap();
+
+        for i in 0..8 {
+            log.append_line(&format!("record {:04}", i)).await.unwrap();
+        }
+        log.close().await.unwrap();
+        assert!(matches!(
+            log.append_line("late").await,
+            Err(io::LogError::Closed)
+        ));
+
+        let current = tokio::fs::read_to_string(&path).await.unwrap();
+        let newest = tokio::fs::read_to_string(dir.join("app.log.1"))
+            .await
+            .unwrap();
+        assert_eq!(current, "record 0007\n");
+        assert_eq!(newest, "record 0006\n");
+        assert!(tokio::fs::try_exists(dir.join("app.log.2")).await.unwrap());
+        assert!(!tokio::fs::try_exists(dir.join("app.log.3")).await.unwrap());
+
+        // A directory in the way of rotation: the record is lost and the
+        // next flush says so, once
+        let path = dir.join("blocked.log");
+        tokio::fs::create_dir_all(dir.join("blocked.log.1/occupied"))
+            .await
+            .unwrap();
+        let log = io::RotatingLog::builder(&path)
+            .max_size(5)
+            .keep(1)
+            .open()
+            .await
+            .unwrap();
+        log.append_line("first").await.unwrap();
+        log.append_line("second").await.unwrap();
+        assert!(log.flush().await.is_err());
+        log.flush().await.unwrap();
+        log.close().await.unwrap();
+        assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "first\n");
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_process_files_aggregates_errors() {
+        let dir = std::env::temp_dir().join(format!("tokio_patterns_batch_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        for i in 0..4 {
+            io::write_file(dir.join(format!("{}.txt", i)), b"abc")
+                .await
+                .unwrap();
+        }
+
+        let mut paths: Vec<_> = (0..4).map(|i| dir.join(format!("{}.txt", i))).collect();
+        paths.push(dir.join("missing.txt"));
+
+        let mut updates = Vec::new();
+        let report = io::process_files_with_progress(
+            paths,
+            2,
+            |path| async move {
+                let contents = io::read_file(&path).await?;
+                io::write_file(path.with_extension("out"), &contents.to_ascii_uppercase()).await?;
+                Ok(contents.len())
+            },
+            |progress| updates.push(progress),
+        )
+        .await;
+
+        assert_eq!(report.succeeded.len(), 4);
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.failed[0].0.ends_with("missing.txt"));
+        assert_eq!(updates.len(), 5);
+        assert_eq!(updates.last().unwrap().completed(), 5);
+        assert_eq!(io::read_file(dir.join("0.out")).await.unwrap(), b"ABC");
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test]
+    async fn test_vectored_write_and_send_file() {
+        use std::io::IoSlice;
+        use tokio::io::AsyncReadExt;
+
+        let mut out = Vec::new();
+        let header = b"HEADER ";
+        let body = b"body bytes";
+        let mut bufs = [IoSlice::new(b""), IoSlice::new(header), IoSlice::new(body)];
+        io::write_all_vectored(&mut out, &mut bufs).await.unwrap();
+        assert_eq!(out, b"HEADER body bytes");
+
+        let path =
+            std::env::temp_dir().join(format!("tokio_patterns_sendfile_{}", std::process::id()));
+        io::write_file(&path, b"0123456789").await.unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let reader = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut file = tokio::fs::File::open(&path).await.unwrap();
+        let sent = io::send_file(&mut file, &mut stream, 2, 100).await.unwrap();
+        drop(stream);
+
+        assert_eq!(sent, 8);
+        assert_eq!(reader.await.unwrap(), b"23456789");
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[tokio::test]
+    async fn test_repl_with_scripted_input() {
+        let input: &[u8] = b"one\ntwo\n";
+        let mut output = Vec::new();
+
+        let exit = io::repl_with(
+            input,
+            &mut output,
+            "> ",
+            |line| async move { (line != "two").then(|| line.to_uppercase()) },
+            std::future::pending(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(exit, io::ReplExit::Eof);
+        assert_eq!(String::from_utf8(output).unwrap(), "> ONE\n> > ");
+
+        let exit = io::repl_with(
+            tokio::io::empty(),
+            tokio::io::sink(),
+            "> ",
+            |_| async { None },
+            async {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(exit, io::ReplExit::Interrupted);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transport_fault_injection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client, mut server) = io::test_transport();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let faults = io::FaultConfig::new()
+            .latency(std::time::Duration::from_millis(50))
+            .max_write(3)
+            .close_after(5);
+        let (mut client, mut server) = io::test_transport_with(faults, Default::default());
+
+        let start = tokio::time::Instant::now();
+        assert_eq!(client.write(b"hello world").await.unwrap(), 3);
+        assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+        assert!(client.write_all(b"lo world").await.is_err());
+        assert_eq!(client.bytes_written(), 5);
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_file_lock_exclusion() {
+        use std::time::Duration;
+
+        let path = std::env::temp_dir().join(format!("tokio_patterns_lock_{}", std::process::id()));
+        let first = io::FileLock::open(&path).await.unwrap();
+        let second = io::FileLock::open(&path).await.unwrap();
+
+        let guard = first.lock_exclusive().await.unwrap();
+        assert!(second.try_lock_shared().unwrap().is_none());
+        let err = second
+            .lock_exclusive_timeout(Duration::from_millis(30))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        drop(guard);
+        let shared_a = first.lock_shared().await.unwrap();
+        let shared_b = second
+            .lock_shared_timeout(Duration::from_secs(1))
+            .await
+            .unwrap();
+        drop((shared_a, shared_b));
+
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[tokio::test]
+    async fn test_resolve_and_happy_eyeballs() {
+        use std::time::Duration;
+
+        let ips = io::resolve("localhost", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(ips.iter().any(|ip| ip.is_loopback()));
+
+        // A port nobody listens on any more refuses connections
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused_addr = refused.local_addr().unwrap();
+        drop(refused);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good_addr = listener.local_addr().unwrap();
+
+        let stream = io::connect_addrs(vec![refused_addr, good_addr], Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), good_addr);
+
+        let err = io::connect_addrs(vec![refused_addr], Duration::from_millis(10)).await;
+        assert!(err.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_copy_bidirectional_half_close() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut client, mut proxy_client_side) = tokio::io::duplex(1024);
+        let (mut proxy_server_side, mut server) = tokio::io::duplex(1024);
+
+        let proxy = tokio::spawn(async move {
+            io::copy_bidirectional_graceful(&mut proxy_client_side, &mut proxy_server_side).await
+        });
+
+        // The client finishes its request and half-closes
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        // The server still sees the full request followed by EOF...
+        let mut request = Vec::new();
+        server.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        // ...and can still answer over the other direction
+        server.write_all(b"response!").await.unwrap();
+        server.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response!");
+
+        assert_eq!(proxy.await.unwrap().unwrap(), (7, 9));
+    }
+
+    #[cfg(all(feature = "ndjson", feature = "csv"))]
+    #[tokio::test]
+    async fn test_record_streams_skip_bad_lines() {
+        use tokio_stream::StreamExt;
+
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Event {
+            id: u32,
+            name: String,
+        }
+
+        let ndjson: &[u8] = b"{\"id\":1,\"name\":\"a\"}\nnot json\n\n{\"id\":2,\"name\":\"b\"}\n";
+        let results: Vec<_> = io::ndjson_stream::<Event, _>(ndjson).collect().await;
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().id, 1);
+        assert_eq!(results[1].as_ref().unwrap_err().line, 2);
+        assert_eq!(results[2].as_ref().unwrap().name, "b");
+
+        // Records after an over-long one are still read
+        let mut ndjson = b"{\"id\":1,\"name\":\"a\"}\n".to_vec();
+        ndjson.extend(std::iter::repeat_n(b'x', 2 * 1024 * 1024));
+        ndjson.extend(b"\n{\"id\":2,\"name\":\"b\"}\r\n{\"id\":3,\"name\":\"c\"}");
+        let results: Vec<_> = io::ndjson_stream::<Event, _>(&ndjson[..]).collect().await;
+        assert_eq!(results.len(), 4);
+        let error = results[1].as_ref().unwrap_err();
+        assert!(matches!(error.kind, io::RecordErrorKind::TooLong));
+        assert_eq!(results[2].as_ref().unwrap().id, 2);
+        assert_eq!(results[3].as_ref().unwrap().id, 3);
+
+        let csv: &[u8] = b"id,name\n1,a\nx,b\n3,\"c, d\"\n";
+        let results: Vec<_> = io::csv_stream::<Event, _>(csv).collect().await;
+        assert_eq!(results.len(), 3);
+        assert!(results[1].is_err());
+        assert_eq!(
+            results[2].as_ref().unwrap(),
+            &Event {
+                id: 3,
+                name: "c, d".to_string()
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_detects_silent_peer() {
+        use futures::{SinkExt, StreamExt};
+        use std::time::Duration;
+        use tokio_util::codec::{Framed, LinesCodec};
+
+        let (a, b) = tokio::io::duplex(1024);
+        let (c, d) = tokio::io::duplex(1024);
+        let interval = Duration::from_secs(1);
+        let timeout = Duration::from_millis(500);
+
+        // Two heartbeat endpoints keep each other alive and still pass data
+        let mut left = io::Heartbeat::new(Framed::new(a, LinesCodec::new()), interval, timeout);
+        let mut right = io::Heartbeat::new(Framed::new(b, LinesCodec::new()), interval, timeout);
+        let echo = tokio::spawn(async move {
+            while let Some(Ok(line)) = right.next().await {
+                right.send(line).await.unwrap();
+            }
+        });
+
+        // Keep reading on the left while several heartbeat rounds pass
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+            item = left.next() => panic!("unexpected frame: {:?}", item.map(|r| r.is_ok())),
+        }
+        left.send("hello".to_string()).await.unwrap();
+        assert_eq!(left.next().await.unwrap().unwrap(), "hello");
+        echo.abort();
+
+        // A peer that never answers pings is reported dead
+        let mut lonely = io::Heartbeat::new(Framed::new(c, LinesCodec::new()), interval, timeout);
+        let _silent = d;
+        match lonely.next().await {
+            Some(Err(io::HeartbeatError::ConnectionDead { silent_for })) => {
+                assert!(silent_for >= interval + timeout);
+            }
+            other => panic!(
+                "expected ConnectionDead, got {:?}",
+                other.map(|r| r.is_ok())
+            ),
+        }
+        assert!(lonely.next().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_select_timeout_errors() {
+        use std::time::Duration;
+        use tokio::time::Instant;
+
+        let fast = select::select_with_timeout(async { 1 }, Duration::from_secs(1)).await;
+        assert_eq!(fast, Ok(1));
+
+        let slow = tokio::time::sleep(Duration::from_secs(10));
+        let err = select::select_with_timeout(slow, Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert_eq!(err.timeout, Duration::from_secs(1));
+        assert_eq!(err.to_string(), "operation timed out after 1s");
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let err = select::select_with_deadline(std::future::pending::<()>(), deadline)
+            .await
+            .unwrap_err();
+        assert_eq!(err.timeout, Duration::from_secs(2));
+        assert_eq!(Instant::now(), deadline);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_policies() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::time::Duration;
+
+        let policy = select::RetryPolicy::<&str>::exponential(Duration::from_millis(100));
+        assert_eq!(policy.delay_for(1), Duration::from_millis(100));
+        assert_eq!(policy.delay_for(3), Duration::from_millis(400));
+        assert_eq!(policy.delay_for(100), Duration::from_secs(30));
+
+        // Succeeds on the third attempt after 100ms + 200ms of backoff
+        let calls = AtomicU32::new(0);
+        let start = tokio::time::Instant::now();
+        let result = select::retry(policy.clone(), || async {
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err("transient"),
+                n => Ok(n),
+            }
+        })
+        .await;
+        assert_eq!(result, Ok(2));
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+
+        // Non-retryable errors fail immediately
+        let calls = AtomicU32::new(0);
+        let policy = policy.retry_if(|e| *e != "fatal");
+        let result: Result<(), _> = select::retry(policy, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err("fatal")
+        })
+        .await;
+        assert_eq!(result, Err("fatal"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Full jitter never exceeds the un-jittered delay
+        let jittered = select::RetryPolicy::<()>::fixed(Duration::from_secs(1)).full_jitter();
+        assert!((0..20).all(|_| jittered.delay_for(1) <= Duration::from_secs(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker_transitions() {
+        use select::{CircuitBreaker, CircuitBreakerConfig, CircuitError, CircuitState};
+        use std::time::Duration;
+
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failures: 3,
+            open_duration: Duration::from_secs(10),
+            half_open_probes: 2,
+            ..Default::default()
+        });
+        let mut states = breaker.subscribe();
+
+        for _ in 0..3 {
+            let result = breaker.call(|| async { Err::<(), _>("boom") }).await;
+            assert_eq!(result, Err(CircuitError::Inner("boom")));
+        }
+        assert_eq!(*states.borrow_and_update(), CircuitState::Open);
+
+        // Rejected without running the operation
+        let result = breaker.call(|| async { Ok::<_, &str>(()) }).await;
+        assert_eq!(result, Err(CircuitError::Open));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        // A failed probe re-opens, a successful one closes
+        let _ = breaker.call(|| async { Err::<(), _>("still down") }).await;
+        assert_eq!(breaker.state(), CircuitState::Open);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(breaker.call(|| async { Ok::<_, &str>(7) }).await, Ok(7));
+        assert_eq!(*states.borrow_and_update(), CircuitState::Closed);
+
+        // A probe that outlives its half-open period doesn't count in the next
+        for _ in 0..3 {
+            let _ = breaker.call(|| async { Err::<(), _>("boom") }).await;
+        }
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let slow_probe = breaker.call(|| async {
+            tokio::time::sleep(Duration::from_secs(20)).await;
+            Ok::<_, &str>(())
+        });
+        let failed_probe = breaker.call(|| async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Err::<(), _>("down")
+        });
+        let next_period = async {
+            tokio::time::sleep(Duration::from_secs(15)).await;
+            assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        };
+        let (slow, _, _) = tokio::join!(slow_probe, failed_probe, next_period);
+        assert_eq!(slow, Ok(()));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_race_ok() {
+        use std::time::Duration;
+
+        let replica = |delay: u64, result: Result<&'static str, &'static str>| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            result
+        };
+
+        let start = tokio::time::Instant::now();
+        let fastest_ok = select::race_ok(vec![
+            replica(10, Err("replica a down")),
+            replica(50, Ok("b")),
+            replica(500, Ok("c")),
+        ])
+        .await;
+        assert_eq!(fastest_ok, Ok("b"));
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+
+        let all_failed = select::race_ok(vec![replica(20, Err("a")), replica(10, Err("b"))]).await;
+        assert_eq!(all_failed, Err(vec!["a", "b"]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_coordinator_phases() {
+        use shutdown::{Coordinator, Phase};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let coordinator = Coordinator::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for (name, phase) in [
+            ("flusher", Phase::Flush),
+            ("listener", Phase::StopIntake),
+            ("worker", Phase::Drain),
+        ] {
+            let subsystem = coordinator.register(name, phase);
+            let order = Arc::clone(&order);
+            tokio::spawn(async move {
+                subsystem.cancelled().await;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                order.lock().unwrap().push(subsystem.name().to_string());
+                subsystem.ack();
+            });
+        }
+
+        let stuck = coordinator.register("stuck", Phase::Flush);
+
+        let report = coordinator.shutdown(Duration::from_secs(1)).await;
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec!["listener", "worker", "flusher"]
+        );
+        assert_eq!(report.completed, vec!["listener", "worker", "flusher"]);
+        assert_eq!(report.timed_out, vec!["stuck"]);
+        assert_eq!(report.elapsed, Duration::from_secs(1));
+        assert!(stuck.is_cancelled());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_shutdown_on_sigterm() {
+        use std::time::Duration;
+        use tokio::sync::oneshot;
+
+        let coordinator = shutdown::Coordinator::new();
+        let subsystem = coordinator.register("worker", shutdown::Phase::Drain);
+        let (installed_tx, installed_rx) = oneshot::channel();
+        let waiter = {
+            let coordinator = coordinator.clone();
+            tokio::spawn(async move {
+                let signals = select::ShutdownSignals::install().unwrap();
+                installed_tx.send(()).unwrap();
+                signals.shutdown(&coordinator, Duration::from_secs(1)).await
+            })
+        };
+        tokio::spawn(async move {
+            subsystem.cancelled().await;
+            subsystem.ack();
+        });
+
+        // Raising SIGTERM before tokio's handler is installed would kill the test
+        installed_rx.await.unwrap();
+        unsafe { libc::raise(libc::SIGTERM) };
+
+        let (signal, report) = waiter.await.unwrap();
+        assert_eq!(signal, select::ShutdownSignal::Terminate);
+        assert_eq!(report.completed, vec!["worker"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_graceful_shutdown_with_token() {
+        use std::time::Duration;
+        use tokio_util::sync::CancellationToken;
+
+        let token = CancellationToken::new();
+        let mut ticks = 0;
+        let canceller = {
+            let token = token.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(250)).await;
+                token.cancel();
+            })
+        };
+
+        let result: Result<(), &str> = select::graceful_shutdown(
+            || {
+                ticks += 1;
+                async { Ok(()) }
+            },
+            token.child_token(),
+        )
+        .await;
+        canceller.await.unwrap();
+        assert_eq!(result, Ok(()));
+        assert_eq!(ticks, 3);
+
+        let mut attempts = 0;
+        let result = select::graceful_shutdown(
+            || {
+                attempts += 1;
+                let fail = attempts == 2;
+                async move {
+                    if fail {
+                        Err("work failed")
+                    } else {
+                        Ok(())
+                    }
+                }
+            },
+            CancellationToken::new(),
+        )
+        .await;
+        assert_eq!(result, Err("work failed"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline_propagation() {
+        use select::{with_deadline, Deadline, TimeoutError};
+        use std::time::Duration;
+        use tokio::time::{sleep, Instant};
+
+        async fn slow_layer() -> Result<(), TimeoutError> {
+            // An inner scope asking for more time cannot extend the outer budget
+            Deadline::after(Duration::from_secs(10))
+                .scope(with_deadline(sleep(Duration::from_secs(1))))
+                .await
+        }
+
+        assert_eq!(Deadline::current(), None);
+        assert_eq!(with_deadline(async { 7 }).await, Ok(7));
+
+        let start = Instant::now();
+        let result = Deadline::after(Duration::from_millis(100))
+            .scope(slow_layer())
+            .await;
+        assert!(result.is_err());
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+
+        let reserved = Deadline::after(Duration::from_millis(100))
+            .scope(async {
+                let outer = Deadline::current().unwrap();
+                outer
+                    .shrink(Duration::from_millis(30))
+                    .scope(async { Deadline::current().unwrap().remaining() })
+                    .await
+            })
+            .await;
+        assert_eq!(reserved, Duration::from_millis(70));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_interval_jittered() {
+        use std::time::Duration;
+        use tokio::time::{Instant, MissedTickBehavior};
+
+        let period = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut interval = select::interval_jittered(period, 0.2, MissedTickBehavior::Skip);
+
+        for k in 0..5 {
+            let tick = interval.tick().await;
+            let base = start + period * k;
+            assert!(tick >= base && tick <= base + Duration::from_millis(200));
+        }
+
+        // Falling behind skips the missed periods instead of bursting
+        tokio::time::sleep(Duration::from_millis(3500)).await;
+        interval.tick().await;
+        let tick = interval.tick().await;
+        let base = start + period * 8;
+        assert!(tick >= base && tick <= base + Duration::from_millis(200));
+
+        // Even when more periods were missed than fit a u32
+        let period = Duration::from_millis(1);
+        let mut interval = select::interval_jittered(period, 0.0, MissedTickBehavior::Skip);
+        interval.tick().await;
+        tokio::time::sleep(Duration::from_secs(60 * 24 * 3600)).await;
+        let late = Instant::now();
+        interval.tick().await;
+        let tick = interval.tick().await;
+        assert!(tick > late && tick <= late + period);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_debouncer_and_throttler() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use tokio::time::{sleep, Instant};
+
+        let start = Instant::now();
+        let recorder = || {
+            let runs = Arc::new(Mutex::new(Vec::new()));
+            let action = {
+                let runs = runs.clone();
+                move || {
+                    runs.lock().unwrap().push(start.elapsed());
+                    async {}
+                }
+            };
+            (runs, action)
+        };
+
+        let (debounced, action) = recorder();
+        let (leading, leading_action) = recorder();
+        let (trailing, trailing_action) = recorder();
+        let debouncer = select::Debouncer::new(Duration::from_millis(200), action);
+        let throttler = select::Throttler::new(Duration::from_secs(1), leading_action);
+        let trailing_throttler =
+            select::Throttler::trailing(Duration::from_secs(1), trailing_action);
+
+        for _ in 0..5 {
+            debouncer.trigger();
+            throttler.trigger();
+            trailing_throttler.trigger();
+            sleep(Duration::from_millis(100)).await;
+        }
+        sleep(Duration::from_secs(2)).await;
+
+        assert_eq!(*debounced.lock().unwrap(), [Duration::from_millis(600)]);
+        assert_eq!(*leading.lock().unwrap(), [Duration::ZERO]);
+        assert_eq!(
+            *trailing.lock().unwrap(),
+            [Duration::ZERO, Duration::from_secs(1)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pausable_interval() {
+        use std::time::Duration;
+        use tokio::time::{sleep, Instant};
+
+        let start = Instant::now();
+        let mut interval = select::PausableInterval::new(Duration::from_secs(1));
+        let handle = interval.handle();
+
+        let controller = tokio::spawn(async move {
+            sleep(Duration::from_millis(1500)).await;
+            handle.pause();
+            sleep(Duration::from_millis(3500)).await;
+            handle.resume();
+            sleep(Duration::from_millis(1500)).await;
+            handle.reset();
+        });
+
+        let mut ticks = Vec::new();
+        for _ in 0..4 {
+            interval.tick().await;
+            ticks.push(start.elapsed());
+        }
+        controller.await.unwrap();
+
+        // Paused between 1.5s and 5s, resumed with a fresh period, then reset at 6.5s
+        let secs = |ms| Duration::from_millis(ms);
+        assert_eq!(ticks, [secs(0), secs(1000), secs(6000), secs(7500)]);
+        assert!(!interval.is_paused());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_adaptive_timeout() {
+        use select::{AdaptiveTimeout, AdaptiveTimeoutConfig, AdaptiveTimeoutError};
+        use std::time::Duration;
+        use tokio::time::sleep;
+
+        let ms = Duration::from_millis;
+        let timeout = AdaptiveTimeout::new(AdaptiveTimeoutConfig {
+            percentile: 0.9,
+            factor: 2.0,
+            min: ms(50),
+            max: ms(1000),
+            initial: ms(500),
+            window_size: 10,
+            min_samples: 5,
+        });
+        assert_eq!(timeout.current(), ms(500));
+
+        for latency in [10, 20, 30, 40, 50, 60, 70, 80, 90, 100] {
+            let result: Result<(), AdaptiveTimeoutError<()>> = timeout
+                .call(async move {
+                    sleep(ms(latency)).await;
+                    Ok(())
+                })
+                .await;
+            assert!(result.is_ok());
+        }
+        // p90 of the window is 90ms
+        assert_eq!(timeout.current(), ms(180));
+
+        let slow: Result<(), AdaptiveTimeoutError<()>> = timeout
+            .call(async {
+                sleep(ms(300)).await;
+                Ok(())
+            })
+            .await;
+        assert!(matches!(slow, Err(AdaptiveTimeoutError::Elapsed(e)) if e.timeout == ms(180)));
+
+        // Failures don't feed the histogram, and the clamps apply
+        let failed = timeout.call(async { Err::<(), _>("boom") }).await;
+        assert_eq!(failed, Err(AdaptiveTimeoutError::Inner("boom")));
+        for _ in 0..10 {
+            timeout.record(ms(1));
+        }
+        assert_eq!(timeout.current(), ms(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expiry_queue() {
+        use futures::StreamExt;
+        use std::time::Duration;
+        use tokio::time::Instant;
+
+        let start = Instant::now();
+        let mut queue = select::ExpiryQueue::new();
+        queue.insert("a", 1, Duration::from_secs(1));
+        queue.insert("b", 2, Duration::from_secs(2));
+        queue.insert("c", 3, Duration::from_secs(3));
+        assert_eq!(queue.insert("a", 10, Duration::from_secs(4)), Some(1));
+        assert!(queue.reset(&"b", Duration::from_secs(5)));
+        assert_eq!(queue.remove(&"c"), Some(3));
+        assert_eq!(queue.get(&"b"), Some(&2));
+
+        assert_eq!(queue.next().await, Some(("a", 10)));
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+        assert_eq!(queue.next().await, Some(("b", 2)));
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert!(queue.is_empty());
+
+        // An empty queue waits for the next insert instead of ending
+        let pending = tokio::time::timeout(Duration::from_secs(10), queue.next()).await;
+        assert!(pending.is_err());
+        queue.insert("d", 4, Duration::from_secs(1));
+        assert_eq!(queue.next().await, Some(("d", 4)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scheduler_overlap_and_history() {
+        use select::scheduler::{
+            CronSchedule, Job, OverlapPolicy, RunOutcome, Schedule, Scheduler,
+        };
+        use std::time::{Duration, UNIX_EPOCH};
+        use tokio::time::sleep;
+
+        // Weekdays 09:00-17:45 every 15 minutes; Saturday noon rolls to Monday 09:00
+        let cron: CronSchedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        let saturday_noon = UNIX_EPOCH + Duration::from_secs(1_704_542_400);
+        let monday_nine = UNIX_EPOCH + Duration::from_secs(1_704_704_400);
+        assert_eq!(cron.next_after(saturday_noon), Some(monday_nine));
+        // Day fields covering every day don't widen the match to weekends
+        let cron: CronSchedule = "*/15 9-17 */1 * 1-5".parse().unwrap();
+        assert_eq!(cron.next_after(saturday_noon), Some(monday_nine));
+        assert!(Schedule::cron("61 * * * *").is_err());
+        assert!(Schedule::cron("* * *").is_err());
+
+        let (mut scheduler, mut history) = Scheduler::new();
+        let slow = scheduler.add(
+            Job::new(
+                "slow",
+                Schedule::Interval(Duration::from_millis(100)),
+                || async {
+                    sleep(Duration::from_millis(250)).await;
+                    Ok::<_, String>(())
+                },
+            )
+            .overlap(OverlapPolicy::Skip),
+        );
+        let failing = scheduler.add(Job::new(
+            "failing",
+            Schedule::Interval(Duration::from_millis(300)),
+            || async { Err::<(), _>("disk full") },
+        ));
+        let panicking = scheduler.add(Job::new(
+            "panicking",
+            Schedule::Interval(Duration::from_millis(400)),
+            || async { panic!("corrupt state") as Result<(), String> },
+        ));
+
+        // slow: runs at 100ms, skips 200 and 300, runs again at 400
+        sleep(Duration::from_millis(450)).await;
+        slow.disable();
+        failing.disable();
+        panicking.disable();
+        sleep(Duration::from_secs(1)).await;
+        assert!(!slow.is_enabled());
+        scheduler.shutdown().await;
+
+        let mut slow_outcomes = Vec::new();
+        let mut failures = Vec::new();
+        while let Some(record) = history.recv().await {
+            match &*record.job {
+                "slow" => slow_outcomes.push(record.outcome),
+                _ => failures.push((record.job, record.outcome)),
+            }
+        }
+        assert_eq!(
+            slow_outcomes,
+            [
+                RunOutcome::Skipped,
+                RunOutcome::Skipped,
+                RunOutcome::Succeeded,
+                RunOutcome::Succeeded
+            ]
+        );
+        let failed = |job: &str, error: &str| (job.into(), RunOutcome::Failed(error.to_string()));
+        assert_eq!(
+            failures,
+            [
+                failed("failing", "disk full"),
+                failed("panicking", "run panicked")
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bulkhead_queue_and_rejection() {
+        use select::{Bulkhead, BulkheadFull};
+        use std::time::Duration;
+        use tokio::time::sleep;
+
+        let bulkhead = Bulkhead::new(2, 1);
+        let mut calls = Vec::new();
+        for i in 0..3 {
+            let bulkhead = bulkhead.clone();
+            calls.push(tokio::spawn(async move {
+                bulkhead
+                    .call(|| async move {
+                        sleep(Duration::from_millis(100)).await;
+                        i
+                    })
+                    .await
+            }));
+            tokio::task::yield_now().await;
+        }
+
+        let metrics = bulkhead.metrics();
+        assert_eq!((metrics.in_flight, metrics.waiting), (2, 1));
+        assert_eq!(bulkhead.call(|| async { 3 }).await, Err(BulkheadFull));
+
+        for (i, call) in calls.into_iter().enumerate() {
+            assert_eq!(call.await.unwrap(), Ok(i));
+        }
+        let metrics = bulkhead.metrics();
+        assert_eq!(
+            (metrics.executed, metrics.queued, metrics.rejected),
+            (3, 1, 1)
+        );
+        assert_eq!((metrics.in_flight, metrics.waiting), (0, 0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_policy_composition() {
+        use ratelimit::TokenBucket;
+        use select::policy::{self, Policy, PolicyError, PolicyExt};
+        use select::{Bulkhead, CircuitBreaker, CircuitBreakerConfig, RetryPolicy, TimeoutError};
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio::time::{sleep, Instant};
+
+        // The first two attempts hang past the per-attempt timeout, the third succeeds
+        let attempts = Arc::new(AtomicU32::new(0));
+        let op = {
+            let attempts = attempts.clone();
+            move || {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    if attempt < 3 {
+                        sleep(Duration::from_secs(10)).await;
+                    }
+                    Ok::<_, String>(attempt)
+                }
+            }
+        };
+        let resilient = policy::timeout(Duration::from_millis(100))
+            .retry(RetryPolicy::fixed(Duration::from_millis(10)))
+            .bulkhead(Bulkhead::new(4, 0));
+        assert_eq!(resilient.run(op).await, Ok(3));
+
+        // The breaker sees the final failure and opens
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failures: 1,
+            ..CircuitBreakerConfig::default()
+        });
+        let guarded = policy::timeout(Duration::from_millis(50)).circuit_break(breaker.clone());
+        let slow = || async {
+            sleep(Duration::from_secs(1)).await;
+            Ok::<(), String>(())
+        };
+        assert_eq!(
+            guarded.run(slow).await,
+            Err(PolicyError::Timeout(TimeoutError {
+                timeout: Duration::from_millis(50)
+            }))
+        );
+        assert_eq!(guarded.run(slow).await, Err(PolicyError::CircuitOpen));
+
+        // An open breaker's rejections are passed on, not retried
+        let start = Instant::now();
+        let retried =
+            policy::circuit_break(breaker).retry(RetryPolicy::fixed(Duration::from_millis(10)));
+        assert_eq!(retried.run(slow).await, Err(PolicyError::CircuitOpen));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // Rate limiting paces calls to 10 per second after a burst of 2
+        let limited = policy::rate_limit(TokenBucket::new(10.0, 2));
+        let start = Instant::now();
+        for _ in 0..4 {
+            limited.run(|| async { Ok::<_, String>(()) }).await.unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_future_set_concurrency_limit() {
+        use futures::future::BoxFuture;
+        use futures::StreamExt;
+        use std::time::Duration;
+        use tokio::time::{sleep, Instant};
+
+        let delayed = |ms: u64| -> BoxFuture<'static, u64> {
+            Box::pin(async move {
+                sleep(Duration::from_millis(ms)).await;
+                ms
+            })
+        };
+
+        let start = Instant::now();
+        let mut set = select::FutureSet::with_concurrency_limit(2);
+        set.extend([delayed(300), delayed(100), delayed(50)]);
+        assert_eq!((set.running(), set.queued()), (2, 1));
+
+        let mut finished = Vec::new();
+        while let Some(ms) = set.next().await {
+            finished.push((ms, start.elapsed().as_millis()));
+            if ms == 100 {
+                set.push(delayed(10));
+            }
+        }
+
+        // 50 only starts once 100 frees a slot, and 10 waits behind it
+        assert_eq!(finished, [(100, 100), (50, 150), (10, 160), (300, 300)]);
+        assert!(set.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_race_and_select_biased() {
+        use futures::future::Either;
+        use std::time::Duration;
+        use tokio::time::sleep;
+
+        let fast = async {
+            sleep(Duration::from_millis(10)).await;
+            "fast"
+        };
+        let slow = async {
+            sleep(Duration::from_millis(20)).await;
+            1
+        };
+        assert!(matches!(
+            select::race(slow, fast).await,
+            Either::Right("fast")
+        ));
+
+        // Ties go to the first future
+        for _ in 0..10 {
+            let tie = select::race_biased(async { 'a' }, async { 'b' }).await;
+            assert!(matches!(tie, Either::Left('a')));
+        }
+
+        // The loser is returned and can still be awaited to completion
+        let first = Box::pin(sleep(Duration::from_millis(5)));
+        let second = Box::pin(async {
+            sleep(Duration::from_millis(50)).await;
+            "finished"
+        });
+        match select::select_biased(first, second).await {
+            Either::Left(((), loser)) => assert_eq!(loser.await, "finished"),
+            Either::Right(_) => panic!("the shorter sleep should win"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_watchdog_slow_vs_hung() {
+        use select::{progress, with_watchdog, Stalled};
+        use std::time::Duration;
+        use tokio::time::sleep;
+
+        // Slow but progressing: 10 steps of 400ms under a 500ms stall window
+        let (handle, progress_rx) = progress();
+        let slow = async move {
+            for _ in 0..10 {
+                sleep(Duration::from_millis(400)).await;
+                handle.report();
+            }
+            "done"
+        };
+        let result = with_watchdog(slow, progress_rx, Duration::from_millis(500)).await;
+        assert_eq!(result, Ok("done"));
+
+        // Hung after three steps
+        let (handle, progress_rx) = progress();
+        let hung = async move {
+            for _ in 0..3 {
+                sleep(Duration::from_millis(100)).await;
+                handle.report();
+            }
+            std::future::pending::<()>().await;
+        };
+        let result = with_watchdog(hung, progress_rx, Duration::from_millis(500)).await;
+        assert_eq!(
+            result,
+            Err(Stalled {
+                stalled_for: Duration::from_millis(500),
+                reports: 3
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_with_progress() {
+        use select::{set_status, with_progress_config, ProgressConfig};
+        use std::time::Duration;
+
+        assert!(!set_status("outside"));
+
+        let mut updates = Vec::new();
+        let config = ProgressConfig {
+            interval: Duration::from_secs(1),
+            warn_after: Some(Duration::from_secs(2)),
+            name: "import".to_string(),
+        };
+        let job = async {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            assert!(set_status("halfway"));
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            42
+        };
+        let out = with_progress_config(job, config, |update| updates.push(update)).await;
+
+        assert_eq!(out, 42);
+        let seen: Vec<_> = updates
+            .iter()
+            .map(|update| (update.elapsed.as_secs(), update.status.as_deref()))
+            .collect();
+        assert_eq!(
+            seen,
+            [(1, None), (2, Some("halfway")), (3, Some("halfway"))]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timed_and_latency_recorder() {
+        use select::{timed, LatencyRecorder};
+        use std::time::Duration;
+        use tokio::time::sleep;
+
+        let ms = Duration::from_millis;
+        let (value, took) = timed(async {
+            sleep(ms(25)).await;
+            5
+        })
+        .await;
+        assert_eq!((value, took), (5, ms(25)));
+
+        let recorder = LatencyRecorder::new(100);
+        let mut summaries = recorder.subscribe();
+        for latency in 1..=100 {
+            recorder.time(sleep(ms(latency))).await;
+        }
+        assert!(summaries.has_changed().unwrap());
+
+        let summary = *summaries.borrow_and_update();
+        assert_eq!(summary.count, 100);
+        assert_eq!((summary.min, summary.max), (ms(1), ms(100)));
+        assert_eq!(summary.mean, Duration::from_micros(50_500));
+        assert_eq!(
+            (summary.p50, summary.p90, summary.p99),
+            (ms(50), ms(90), ms(99))
+        );
+        assert_eq!(recorder.summary(), summary);
+
+        // The window only keeps the most recent samples
+        recorder.record(ms(1000));
+        assert_eq!(recorder.summary().min, ms(2));
+
+        // Unsubscribed samples still count, and new subscribers see them
+        drop(summaries);
+        recorder.record(ms(2000));
+        assert_eq!(recorder.summary().max, ms(2000));
+        assert_eq!(recorder.subscribe().borrow().max, ms(2000));
+    }
+
+    #[tokio::test]
+    async fn test_retry_connect_diagnostics() {
+        use select::{retry_connect, ConnectPolicy, RetryPolicy};
+        use std::io::ErrorKind;
+        use std::time::Duration;
+        use tokio::net::TcpListener;
+        use tokio_util::sync::CancellationToken;
+
+        // Reserve a port, then leave it closed for a while
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let retry = || RetryPolicy::fixed(Duration::from_millis(20));
+
+        let err = retry_connect(addr, ConnectPolicy::new(retry().max_attempts(Some(2))))
+            .await
+            .unwrap_err();
+        assert!(!err.cancelled);
+        assert_eq!(err.attempts.len(), 2);
+        assert_eq!(err.attempts[1].attempt, 2);
+        assert!(err.attempts[1].started_after >= Duration::from_millis(20));
+        assert_eq!(
+            err.last_error().unwrap().kind(),
+            ErrorKind::ConnectionRefused
+        );
+
+        let token = CancellationToken::new();
+        let canceller = {
+            let token = token.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                token.cancel();
+            })
+        };
+        let policy = ConnectPolicy::new(retry().max_attempts(None)).cancel_on(token);
+        let err = retry_connect(addr, policy).await.unwrap_err();
+        assert!(err.cancelled && !err.attempts.is_empty());
+        canceller.await.unwrap();
+
+        // The server comes up while the client is retrying
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            let listener = TcpListener::bind(addr).await.unwrap();
+            listener.accept().await.unwrap();
+        });
+        let policy = ConnectPolicy::new(retry().max_attempts(Some(50)))
+            .attempt_timeout(Duration::from_secs(1));
+        let stream = retry_connect(addr, policy).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fibonacci_ends_before_overflow() {
+        use tokio_stream::StreamExt;
+
+        let all: Vec<u64> = streams::FibonacciStream::new().collect().await;
+        assert_eq!(all.len(), 94);
+        assert_eq!(all[93], 12_200_160_415_121_876_738);
+
+        let mut n = 0u64;
+        let squares = std::pin::pin!(streams::sequence_stream(move || {
+            n += 1;
+            (n <= 5).then_some(n * n)
+        }));
+        assert_eq!(streams::collect_n(squares, 10).await, [1, 4, 9, 16, 25]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ticker_and_generate() {
+        use std::time::Duration;
+        use tokio::time::{sleep, Instant};
+        use tokio_stream::StreamExt;
+
+        let start = Instant::now();
+        let ticks: Vec<u64> = streams::ticker(Duration::from_millis(100))
+            .take(4)
+            .collect()
+            .await;
+        assert_eq!(ticks, [0, 1, 2, 3]);
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+
+        // A 150ms producer on a 100ms period runs back to back, never in a burst
+        let start = Instant::now();
+        let mut calls = 0;
+        let stamps: Vec<(u32, Duration)> = streams::generate(Duration::from_millis(100), || {
+            calls += 1;
+            let call = calls;
+            async move {
+                sleep(Duration::from_millis(150)).await;
+                (call, start.elapsed())
+            }
+        })
+        .take(3)
+        .collect()
+        .await;
+        let ms = Duration::from_millis;
+        assert_eq!(stamps, [(1, ms(150)), (2, ms(300)), (3, ms(450))]);
+    }
+
+    #[tokio::test]
+    async fn test_channel_stream_adapters() {
+        use streams::{forward_to_sender, from_broadcast, from_receiver, from_watch, Lagged};
+        use tokio::sync::{broadcast, mpsc, watch};
+        use tokio_stream::StreamExt;
+
+        // stream -> channel -> stream
+        let (tx, rx) = mpsc::channel(2);
+        let forwarder =
+            tokio::spawn(async move { forward_to_sender(tokio_stream::iter(1..=5), &tx).await });
+        let received: Vec<i32> = from_receiver(rx).collect().await;
+        assert_eq!(received, [1, 2, 3, 4, 5]);
+        assert_eq!(forwarder.await.unwrap().unwrap(), 5);
+
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+        let err = forward_to_sender(tokio_stream::iter([7]), &tx)
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, 7);
+
+        // A subscriber that fell behind sees the lag, then the retained messages
+        let (tx, rx) = broadcast::channel(2);
+        for i in 0..5 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+        let items: Vec<Result<i32, Lagged>> = from_broadcast(rx).collect().await;
+        assert_eq!(items, [Err(Lagged { missed: 3 }), Ok(3), Ok(4)]);
+
+        let (tx, rx) = watch::channel("initial");
+        let mut values = Box::pin(from_watch(rx));
+        assert_eq!(values.next().await, Some("initial"));
+        tx.send("updated").unwrap();
+        assert_eq!(values.next().await, Some("updated"));
+        drop(tx);
+        assert_eq!(values.next().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chunks_and_batch_receiver() {
+        use std::time::Duration;
+        use tokio::sync::mpsc;
+        use tokio::time::sleep;
+        use tokio_stream::StreamExt;
+
+        let batches: Vec<Vec<u32>> = streams::chunks(tokio_stream::iter(1..=5), 2)
+            .collect()
+            .await;
+        assert_eq!(batches, [vec![1, 2], vec![3, 4], vec![5]]);
+
+        // Items 1-3 arrive quickly, 4 after a pause longer than the latency bound
+        let producer = |tx: mpsc::Sender<u32>| async move {
+            for i in 1..=4 {
+                if i == 4 {
+                    sleep(Duration::from_millis(200)).await;
+                }
+                tx.send(i).await.unwrap();
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let expected = [vec![1, 2], vec![3], vec![4]];
+
+        let (tx, rx) = mpsc::channel(8);
+        tokio::spawn(producer(tx));
+        let stream =
+            streams::chunks_timeout(streams::from_receiver(rx), 2, Duration::from_millis(50));
+        let batches: Vec<Vec<u32>> = stream.collect().await;
+        assert_eq!(batches, expected);
+
+        let (tx, rx) = mpsc::channel(8);
+        tokio::spawn(producer(tx));
+        let mut batcher = channels::BatchReceiver::new(rx, 2, Duration::from_millis(50));
+        let mut batches = Vec::new();
+        while let Some(batch) = batcher.recv_batch().await {
+            batches.push(batch);
+        }
+        assert_eq!(batches, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_windowed_and_tumbling_window() {
+        use std::time::Duration;
+        use tokio::time::sleep;
+        use tokio_stream::StreamExt;
+
+        let sliding: Vec<Vec<u32>> = streams::windowed(tokio_stream::iter(1..=5), 3, 1)
+            .collect()
+            .await;
+        assert_eq!(sliding, [vec![1, 2, 3], vec![2, 3, 4], vec![3, 4, 5]]);
+        let sparse: Vec<Vec<u32>> = streams::windowed(tokio_stream::iter(1..=7), 2, 3)
+            .collect()
+            .await;
+        assert_eq!(sparse, [vec![1, 2], vec![4, 5]]);
+
+        // Items at 10, 20, 150 and 350ms over 100ms windows; the 200-300ms window is empty
+        let source = futures::stream::unfold(0, |i| async move {
+            let delays = [10, 10, 130, 200];
+            let delay = *delays.get(i)?;
+            sleep(Duration::from_millis(delay)).await;
+            Some((i, i + 1))
+        });
+        let windows: Vec<Vec<usize>> = streams::tumbling_window(source, Duration::from_millis(100))
+            .collect()
+            .await;
+        assert_eq!(windows, [vec![0, 1], vec![2], vec![], vec![3]]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_debounce_and_throttle_streams() {
+        use std::time::Duration;
+        use streams::Edge;
+        use tokio_stream::{Stream, StreamExt};
+
+        // Yields 0, 1, 2, ... each after the matching delay in milliseconds
+        fn spaced(delays: &'static [u64]) -> impl Stream<Item = usize> {
+            futures::stream::unfold(0, move |i| async move {
+                let delay = *delays.get(i)?;
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                Some((i, i + 1))
+            })
+        }
+
+        // Bursts at 0-20ms and 200-210ms
+        const BURSTS: &[u64] = &[0, 10, 10, 180, 10];
+        let quiet = Duration::from_millis(50);
+        for (edge, expected) in [
+            (Edge::Trailing, vec![2, 4]),
+            (Edge::Leading, vec![0, 3]),
+            (Edge::Both, vec![0, 2, 3, 4]),
+        ] {
+            let items: Vec<usize> = streams::debounce(spaced(BURSTS), quiet)
+                .edge(edge)
+                .collect()
+                .await;
+            assert_eq!(items, expected, "debounce {:?}", edge);
+        }
+
+        // One item every 30ms, from 0ms to 270ms
+        const STEADY: &[u64] = &[0, 30, 30, 30, 30, 30, 30, 30, 30, 30];
+        let interval = Duration::from_millis(100);
+        for (edge, expected) in [
+            (Edge::Leading, vec![0, 4, 8]),
+            (Edge::Trailing, vec![3, 6, 9]),
+            (Edge::Both, vec![0, 3, 6, 9]),
+        ] {
+            let start = tokio::time::Instant::now();
+            let items: Vec<usize> = streams::throttle(spaced(STEADY), interval)
+                .edge(edge)
+                .collect()
+                .await;
+            assert_eq!(items, expected, "throttle {:?}", edge);
+            if edge != Edge::Leading {
+                // The trailing item is held until its interval ends
+                assert_eq!(start.elapsed(), Duration::from_millis(300));
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_map_concurrent() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio::time::{sleep, Instant};
+        use tokio_stream::StreamExt;
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        // Earlier items take longer, so completion order is the reverse of input order
+        let mapper = |i: u64| {
+            let in_flight = in_flight.clone();
+            let peak = peak.clone();
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                sleep(Duration::from_millis(100 - i * 10)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                i
+            }
+        };
+
+        let start = Instant::now();
+        let ordered: Vec<u64> = streams::map_concurrent(tokio_stream::iter(0..6), 3, mapper)
+            .collect()
+            .await;
+        assert_eq!(ordered, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        assert!(start.elapsed() < Duration::from_millis(6 * 75));
+
+        peak.store(0, Ordering::SeqCst);
+        let unordered: Vec<u64> =
+            streams::map_concurrent_unordered(tokio_stream::iter(0..3), 3, mapper)
+                .collect()
+                .await;
+        assert_eq!(unordered, [2, 1, 0]);
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_merge_and_merge_sorted_by_key() {
+        use std::time::Duration;
+        use tokio_stream::StreamExt;
+
+        let mut merged: Vec<u32> = streams::merge([
+            tokio_stream::iter(vec![1, 2, 3]),
+            tokio_stream::iter(vec![10, 20]),
+            tokio_stream::iter(vec![]),
+        ])
+        .collect()
+        .await;
+        merged.sort();
+        assert_eq!(merged, [1, 2, 3, 10, 20]);
+
+        // A slow source still has its items placed in key order
+        let slow =
+            tokio_stream::iter(vec![(2, "slow"), (5, "slow")]).throttle(Duration::from_secs(1));
+        let fast = tokio_stream::iter(vec![(1, "fast"), (2, "fast"), (3, "fast"), (6, "fast")])
+            .throttle(Duration::from_millis(1));
+        let events: Vec<(u32, &str)> = streams::merge_sorted_by_key([slow, fast], |(t, _)| *t)
+            .collect()
+            .await;
+        assert_eq!(
+            events,
+            [
+                (1, "fast"),
+                (2, "slow"),
+                (2, "fast"),
+                (3, "fast"),
+                (5, "slow"),
+                (6, "fast")
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partition_by_key() {
+        use std::time::Duration;
+        use tokio::time::sleep;
+        use tokio_stream::StreamExt;
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let mut partitions = streams::partition_by_key(
+            streams::from_receiver(rx),
+            |(user, _): &(char, u32)| *user,
+            4,
+        )
+        .idle_timeout(Duration::from_secs(10));
+
+        let mut consumers = Vec::new();
+        let collect = tokio::spawn(async move {
+            while let Some((user, events)) = partitions.next().await {
+                consumers.push(tokio::spawn(async move {
+                    let values: Vec<u32> = events.map(|(_, value)| value).collect().await;
+                    (user, values)
+                }));
+            }
+            let mut results = Vec::new();
+            for consumer in consumers {
+                results.push(consumer.await.unwrap());
+            }
+            results
+        });
+
+        for event in [('a', 1), ('b', 2), ('a', 3), ('b', 4)] {
+            tx.send(event).await.unwrap();
+        }
+        // 'a' goes idle and is closed; its next event opens a new partition
+        sleep(Duration::from_secs(30)).await;
+        tx.send(('a', 5)).await.unwrap();
+        drop(tx);
+
+        let results = collect.await.unwrap();
+        assert_eq!(
+            results,
+            [('a', vec![1, 3]), ('b', vec![2, 4]), ('a', vec![5])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tee() {
+        use futures::FutureExt;
+        use streams::{SlowConsumer, TeeConfig};
+        use tokio_stream::StreamExt;
+
+        let config = TeeConfig {
+            buffer: 2,
+            slow_consumer: SlowConsumer::Block,
+        };
+        let mut consumers = streams::tee_with_config(tokio_stream::iter(0..5), 2, config.clone());
+        let mut b = consumers.pop().unwrap();
+        let mut a = consumers.pop().unwrap();
+
+        // `a` can only get `buffer` items ahead of `b`
+        assert_eq!(a.next().now_or_never(), Some(Some(0)));
+        assert_eq!(a.next().now_or_never(), Some(Some(1)));
+        assert_eq!(a.next().now_or_never(), None);
+        assert_eq!(b.next().await, Some(0));
+        assert_eq!(a.next().await, Some(2));
+
+        // Once `b` is gone `a` runs freely
+        drop(b);
+        assert_eq!(a.collect::<Vec<_>>().await, [3, 4]);
+
+        let dropping = TeeConfig {
+            slow_consumer: SlowConsumer::Drop,
+            ..config
+        };
+        let mut consumers = streams::tee_with_config(tokio_stream::iter(0..5), 2, dropping);
+        let b = consumers.pop().unwrap();
+        let a = consumers.pop().unwrap();
+        assert_eq!(a.collect::<Vec<_>>().await, [0, 1, 2, 3, 4]);
+        assert_eq!(b.collect::<Vec<_>>().await, [0, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout_per_item_and_deadline() {
+        use std::time::Duration;
+        use tokio::time::{sleep, Instant};
+        use tokio_stream::StreamExt;
+
+        let ms = Duration::from_millis;
+        // Items after 10ms, 150ms and 10ms
+        let source = futures::stream::unfold(0, move |i| async move {
+            let delay = *[10, 150, 10].get(i)?;
+            sleep(ms(delay)).await;
+            Some((i, i + 1))
+        });
+
+        let items: Vec<_> = streams::timeout_per_item(source, ms(100)).collect().await;
+        assert_eq!(
+            items,
+            [
+                Ok(0),
+                Err(select::TimeoutError { timeout: ms(100) }),
+                Ok(1),
+                Ok(2)
+            ]
+        );
+
+        let start = Instant::now();
+        let ticks: Vec<u64> =
+            streams::take_until_deadline(streams::ticker(ms(100)), start + ms(350))
+                .collect()
+                .await;
+        assert_eq!(ticks, [0, 1, 2, 3]);
+        assert_eq!(start.elapsed(), ms(350));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_take_until_cancelled() {
+        use std::time::Duration;
+        use tokio_stream::StreamExt;
+        use tokio_util::sync::CancellationToken;
+
+        let ms = Duration::from_millis;
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ms(250)).await;
+            canceller.cancel();
+        });
+        let ticks: Vec<u64> = streams::take_until_cancelled(streams::ticker(ms(100)), token)
+            .collect()
+            .await;
+        assert_eq!(ticks, [0, 1, 2]);
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move {
+            tokio::time::sleep(ms(150)).await;
+            let _ = shutdown_tx.send(true);
+        });
+        let ticks: Vec<u64> = streams::take_until_shutdown(streams::ticker(ms(100)), shutdown_rx)
+            .collect()
+            .await;
+        assert_eq!(ticks, [0, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_stream() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio_stream::StreamExt;
+
+        // Subscription 1 fails to open, 2 yields one item then breaks, 3 ends cleanly
+        let opened = Arc::new(AtomicU32::new(0));
+        let factory = {
+            let opened = opened.clone();
+            move || {
+                let n = opened.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    match n {
+                        1 => Err("connection refused"),
+                        2 => Ok(tokio_stream::iter(vec![
+                            Ok(1),
+                            Err("connection reset"),
+                            Ok(99),
+                        ])),
+                        _ => Ok(tokio_stream::iter(vec![Ok(2), Ok(3)])),
+                    }
+                }
+            }
+        };
+        let policy = select::RetryPolicy::fixed(Duration::from_millis(10)).max_attempts(Some(2));
+        let items: Vec<Result<u32, &str>> = streams::retry_stream(factory, policy).collect().await;
+        assert_eq!(items, [Ok(1), Ok(2), Ok(3)]);
+        assert_eq!(opened.load(Ordering::SeqCst), 3);
+
+        // The policy's last error is surfaced
+        let policy = select::RetryPolicy::fixed(Duration::from_millis(10)).max_attempts(Some(3));
+        let failing = || async { Err::<futures::stream::Empty<Result<u32, &str>>, _>("down") };
+        let items: Vec<Result<u32, &str>> = streams::retry_stream(failing, policy).collect().await;
+        assert_eq!(items, [Err("down")]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limited_stream() {
+        use std::time::Duration;
+        use tokio::time::Instant;
+        use tokio_stream::StreamExt;
+
+        let start = Instant::now();
+        let mut paced = std::pin::pin!(streams::rate_limit(tokio_stream::iter(0..6), 10.0, 3));
+        let mut emitted_at = Vec::new();
+        while let Some(item) = paced.next().await {
+            emitted_at.push((item, start.elapsed().as_millis()));
+        }
+        // The burst goes straight through, then one item every 100ms
+        assert_eq!(
+            emitted_at,
+            [(0, 0), (1, 0), (2, 0), (3, 100), (4, 200), (5, 300)]
+        );
+
+        // Two streams sharing a bucket split its rate
+        let bucket = ratelimit::TokenBucket::new(10.0, 1);
+        let a = streams::rate_limit_with(tokio_stream::iter(0..5), bucket.clone());
+        let b = streams::rate_limit_with(tokio_stream::iter(0..5), bucket);
+        let start = Instant::now();
+        let both: Vec<u32> = streams::merge([a, b]).collect().await;
+        assert_eq!(both.len(), 10);
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_prefetch() {
+        use std::time::Duration;
+        use tokio::time::{sleep, Instant};
+        use tokio_stream::StreamExt;
+
+        let ms = Duration::from_millis;
+        // Producing and consuming each take 100ms per item
+        let source = || {
+            futures::stream::unfold(0, move |i| async move {
+                (i < 5).then_some(())?;
+                sleep(ms(100)).await;
+                Some((i, i + 1))
+            })
+        };
+
+        let start = Instant::now();
+        let mut plain = std::pin::pin!(source());
+        while plain.next().await.is_some() {
+            sleep(ms(100)).await;
+        }
+        assert_eq!(start.elapsed(), ms(1000));
+
+        let start = Instant::now();
+        let mut ahead = streams::prefetch(source(), 2);
+        while ahead.next().await.is_some() {
+            sleep(ms(100)).await;
+        }
+        // Production overlaps consumption
+        assert!(start.elapsed() < ms(700));
+
+        let metrics = ahead.metrics();
+        assert_eq!(metrics.produced, 5);
+        assert_eq!(metrics.buffered, 0);
+        assert!(metrics.peak_buffered <= 2);
+        assert!(metrics.consumer_waits >= 1);
+    }
+
+    #[tokio::test]
+    async fn test_dedup_adapters() {
+        use tokio_stream::StreamExt;
+
+        let values = tokio_stream::iter(vec![1, 1, 2, 2, 2, 1, 3, 3]);
+        let changes: Vec<u32> = streams::distinct_until_changed(values).collect().await;
+        assert_eq!(changes, [1, 2, 1, 3]);
+
+        // Message ids, with redeliveries; only the last two ids are remembered
+        let messages = tokio_stream::iter(vec![(1, "a"), (2, "b"), (1, "a"), (3, "c"), (1, "a")]);
+        let unique: Vec<(u32, &str)> = streams::dedup_by_key(messages, |(id, _)| *id, 2)
+            .collect()
+            .await;
+        assert_eq!(unique, [(1, "a"), (2, "b"), (3, "c"), (1, "a")]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sampling_adapters() {
+        use std::time::Duration;
+        use tokio::time::sleep;
+        use tokio_stream::StreamExt;
+
+        let every_third: Vec<u32> = streams::sample_every(tokio_stream::iter(0..10), 3)
+            .collect()
+            .await;
+        assert_eq!(every_third, [0, 3, 6, 9]);
+
+        let sampled = |p| streams::sample_probabilistic(tokio_stream::iter(0..10_000), p);
+        assert_eq!(sampled(1.0).collect::<Vec<_>>().await.len(), 10_000);
+        assert!(sampled(0.0).collect::<Vec<_>>().await.is_empty());
+        let half = sampled(0.5).collect::<Vec<_>>().await.len();
+        assert!((4_000..6_000).contains(&half));
+
+        // Items every 30ms for 120ms, then one more at 420ms
+        let ms = Duration::from_millis;
+        let source = futures::stream::unfold(0, move |i| async move {
+            let delay = *[30, 30, 30, 30, 300].get(i)?;
+            sleep(ms(delay)).await;
+            Some((i, i + 1))
+        });
+        let start = tokio::time::Instant::now();
+        let mut latest = std::pin::pin!(streams::latest_every(source, ms(100)));
+        let mut emitted = Vec::new();
+        while let Some(item) = latest.next().await {
+            emitted.push((item, start.elapsed().as_millis()));
+        }
+        assert_eq!(emitted, [(2, 100), (3, 200), (4, 420)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scan_and_fold_async() {
+        use std::time::Duration;
+        use tokio::time::sleep;
+        use tokio_stream::StreamExt;
+
+        // Exponential moving average, with an async step
+        let readings = tokio_stream::iter(vec![10.0, 20.0, 20.0]);
+        let ema = |avg: Option<f64>, x: f64| async move {
+            sleep(Duration::from_millis(10)).await;
+            let avg = avg.map_or(x, |avg| avg + 0.5 * (x - avg));
+            (Some(avg), avg)
+        };
+        let smoothed: Vec<f64> = streams::scan_async(readings, None, ema).collect().await;
+        assert_eq!(smoothed, [10.0, 15.0, 17.5]);
+
+        let write = |mut log: Vec<u32>, n: u32| async move {
+            sleep(Duration::from_millis(10)).await;
+            log.push(n * 2);
+            log
+        };
+        let written = streams::fold_async(tokio_stream::iter(0..5), Vec::new(), write).await;
+        assert_eq!(written, [0, 2, 4, 6, 8]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_paginate() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+        use streams::Page;
+        use tokio_stream::StreamExt;
+
+        // Three pages of three items; the second page fails on its first try
+        let calls = Arc::new(AtomicU32::new(0));
+        let fetch = {
+            let calls = calls.clone();
+            move |page: u32| {
+                let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    if call == 2 {
+                        return Err("503");
+                    }
+                    let items = (page * 3..page * 3 + 3).collect();
+                    Ok(Page {
+                        items,
+                        next: (page < 2).then_some(page + 1),
+                    })
+                }
+            }
+        };
+        let policy = select::RetryPolicy::fixed(Duration::from_millis(50)).max_attempts(Some(3));
+        let items: Vec<Result<u32, &str>> = streams::paginate(0, fetch.clone())
+            .retry(policy)
+            .collect()
+            .await;
+        assert_eq!(items, (0..9).map(Ok).collect::<Vec<_>>());
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // Without retries the failure ends the stream; the limit stops further fetches
+        calls.store(0, Ordering::SeqCst);
+        let items: Vec<Result<u32, &str>> = streams::paginate(0, fetch.clone()).collect().await;
+        assert_eq!(items, [Ok(0), Ok(1), Ok(2), Err("503")]);
+
+        calls.store(10, Ordering::SeqCst);
+        let items: Vec<Result<u32, &str>> =
+            streams::paginate(0, fetch).max_items(4).collect().await;
+        assert_eq!(items, [Ok(0), Ok(1), Ok(2), Ok(3)]);
+        assert_eq!(calls.load(Ordering::SeqCst), 12);
+    }
+
+    #[tokio::test]
+    async fn test_lines_and_frames() {
+        use tokio::io::AsyncWriteExt;
+        use tokio_stream::StreamExt;
+        use tokio_util::codec::LengthDelimitedCodec;
+
+        let input: &[u8] = b"first\r\nsecond\n\nlast";
+        let lines: Vec<String> = streams::lines(input).map(Result::unwrap).collect().await;
+        assert_eq!(lines, ["first", "second", "", "last"]);
+
+        // Length-prefixed frames arriving over a pipe
+        let (mut writer, reader) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            for payload in [&b"hello"[..], b"", b"world"] {
+                writer.write_u32(payload.len() as u32).await.unwrap();
+                writer.write_all(payload).await.unwrap();
+            }
+        });
+        let frames: Vec<Vec<u8>> = streams::frames(reader, LengthDelimitedCodec::new())
+            .map(|frame| frame.unwrap().to_vec())
+            .collect()
+            .await;
+        assert_eq!(frames, [b"hello".to_vec(), vec![], b"world".to_vec()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_instrumented_stream_metrics() {
+        use metrics::{MetricValue, Registry};
+        use std::time::Duration;
+        use tokio_stream::StreamExt;
+
+        let registry = Registry::new();
+        // Ten items per second for three seconds
+        let ticks = streams::ticker(Duration::from_millis(100)).skip(1).take(30);
+        let items: Vec<u64> = streams::instrument_with(ticks, "ticks", &registry)
+            .collect()
+            .await;
+        assert_eq!(items.len(), 30);
+
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot["ticks.items"], MetricValue::Counter(30));
+        assert!(matches!(
+            snapshot["ticks.items_per_sec"],
+            MetricValue::Gauge(rate) if (rate - 10.0).abs() < 0.5
+        ));
+        let MetricValue::Latency(gaps) = &snapshot["ticks.gap"] else {
+            panic!("gap should be a latency metric");
+        };
+        assert_eq!(gaps.count, 30);
+        assert_eq!(gaps.max, Duration::from_millis(100));
+
+        registry.counter("requests").add(2);
+        assert_eq!(registry.snapshot()["requests"], MetricValue::Counter(2));
+    }
+
+    #[tokio::test]
+    async fn test_checkpointed_resume() {
+        use streams::Checkpointed;
+        use tokio_stream::StreamExt;
+
+        let log = |from: u64| tokio_stream::iter((from..6).map(|n| n * 10));
+
+        // Process four items, committing all but the last before "crashing"
+        let mut first_run = Checkpointed::resume(log, None);
+        let committer = first_run.committer();
+        let mut persisted = committer.subscribe();
+        for expected in [(0, 0), (1, 10), (2, 20), (3, 30)] {
+            let (offset, item) = first_run.next().await.unwrap();
+            assert_eq!((offset, item), expected);
+            if offset < 3 {
+                committer.commit(offset);
+            }
+        }
+        committer.commit(1); // late, out-of-order commit is ignored
+        assert!(persisted.has_changed().unwrap());
+        let checkpoint = *persisted.borrow_and_update();
+        assert_eq!(checkpoint, Some(2));
+        drop(first_run);
+
+        // The uncommitted item is replayed after the restart
+        let second_run: Vec<(u64, u64)> = Checkpointed::resume(log, checkpoint).collect().await;
+        assert_eq!(second_run, [(3, 30), (4, 40), (5, 50)]);
+
+        let fresh: Vec<(u64, char)> = Checkpointed::new(tokio_stream::iter(['a', 'b']))
+            .collect()
+            .await;
+        assert_eq!(fresh, [(0, 'a'), (1, 'b')]);
+    }
+
+    #[tokio::test]
+    async fn test_fallible_stream_combinators() {
+        use tokio_stream::StreamExt;
+
+        let parsed = || tokio_stream::iter(["1", "x", "3", "y", "5", "z"]).map(str::parse::<u32>);
+
+        let oks: Vec<u32> = streams::ok_items(parsed()).collect().await;
+        assert_eq!(oks, [1, 3, 5]);
+
+        let (values, errors) = streams::collect_results(parsed()).await;
+        assert_eq!(values, [1, 3, 5]);
+        assert_eq!(errors.len(), 3);
+
+        let until_second_error: Vec<_> = streams::stop_on_error_threshold(parsed(), 2)
+            .collect()
+            .await;
+        assert_eq!(until_second_error.len(), 4);
+        assert!(until_second_error[3].is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_fn() {
+        use std::time::Duration;
+        use tokio_stream::StreamExt;
+
+        // Runs lazily, pausing at each emit until the next item is wanted
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut chunks = streams::stream_fn(|mut y| async move {
+            for word in "a stream written as a loop".split(' ') {
+                tx.send(word).unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                y.emit(word.len()).await;
+            }
+        });
+        assert!(rx.try_recv().is_err());
+        assert_eq!(chunks.next().await, Some(1));
+        assert_eq!(rx.try_recv(), Ok("a"));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(chunks.collect::<Vec<usize>>().await, [6, 7, 2, 1, 4]);
+
+        let empty: Vec<u8> = streams::stream_fn(|_| async {}).collect().await;
+        assert!(empty.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sinks_and_forward() {
+        use streams::sinks;
+        use tokio::io::AsyncReadExt;
+        use tokio_util::codec::LinesCodec;
+
+        // The channel is smaller than the stream, so forwarding waits on the consumer
+        let (tx, rx) = tokio::sync::mpsc::channel(2);
+        let consumer = tokio::spawn(tokio_stream::StreamExt::collect::<Vec<u32>>(
+            streams::from_receiver(rx),
+        ));
+        let sent = sinks::forward(tokio_stream::iter(0..5), sinks::sender(tx))
+            .await
+            .unwrap();
+        assert_eq!(sent, 5);
+        let received = consumer.await.unwrap();
+        assert_eq!(received, [0, 1, 2, 3, 4]);
+
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let lines = tokio_stream::iter(["alpha", "beta"]);
+        sinks::forward(lines, sinks::framed(writer, LinesCodec::new()))
+            .await
+            .unwrap();
+        let mut written = String::new();
+        reader.read_to_string(&mut written).await.unwrap();
+        assert_eq!(written, "alpha\nbeta\n");
+
+        let dir = std::env::temp_dir().join(format!("tokio_patterns_sink_{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("events.log");
+        let log = io::RotatingLog::builder(&path).open().await.unwrap();
+        let records = tokio_stream::iter(["one\n", "two\n"]);
+        sinks::forward(records, sinks::rotating_log(log.clone()))
+            .await
+            .unwrap();
+        // Forwarding flushed the log, which is still open
+        assert_eq!(
+            tokio::fs::read_to_string(&path).await.unwrap(),
+            "one\ntwo\n"
+        );
+        log.append_line("three").await.unwrap();
+        log.close().await.unwrap();
+
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_consumption_helpers() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+        use tokio::time::{sleep, Instant};
+        use tokio_stream::StreamExt;
+        use tokio_util::sync::CancellationToken;
+
+        let first: Vec<u64> = streams::collect_n(streams::ticker(Duration::from_secs(1)), 3).await;
+        assert_eq!(first, [0, 1, 2]);
+
+        let small = streams::take_while_async(tokio_stream::iter([1, 2, 3, 10, 4]), |n| {
+            let n = *n;
+            async move { n < 5 }
+        });
+        assert_eq!(small.collect::<Vec<u32>>().await, [1, 2, 3]);
+
+        // Four 100ms handlers, two at a time
+        let start = Instant::now();
+        let sum = AtomicUsize::new(0);
+        let handled = streams::drain_with(
+            tokio_stream::iter(1..=4),
+            |n| {
+                let sum = &sum;
+                async move {
+                    sleep(Duration::from_millis(100)).await;
+                    sum.fetch_add(n, Ordering::SeqCst);
+                }
+            },
+            2,
+        )
+        .await;
+        assert_eq!(handled, 4);
+        assert_eq!(sum.load(Ordering::SeqCst), 10);
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+
+        // A cancelled token ends the drain after the items already taken
+        let token = CancellationToken::new();
+        let ticks = streams::ticker(Duration::from_millis(100));
+        let ticks = streams::take_until_cancelled(ticks, token.clone());
+        let slow = |_| async { sleep(Duration::from_millis(150)).await };
+        let drained = tokio::spawn(async move { streams::drain_with(ticks, slow, 4).await });
+        sleep(Duration::from_millis(250)).await;
+        token.cancel();
+        assert_eq!(drained.await.unwrap(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reorder_by_sequence() {
+        use std::time::Duration;
+        use tokio::time::sleep;
+        use tokio_stream::StreamExt;
+
+        // Later items finish first when processed concurrently
+        let tagged = tokio_stream::iter(0..6u64).map(|seq| (seq, seq * 10));
+        let scrambled = streams::map_concurrent_unordered(tagged, 6, |(seq, value)| async move {
+            sleep(Duration::from_millis(100 - seq * 10)).await;
+            (seq, value)
+        });
+        let ordered: Vec<(u64, u64)> = streams::reorder_by_sequence(scrambled, 8).collect().await;
+        assert_eq!(
+            ordered,
+            (0..6).map(|seq| (seq, seq * 10)).collect::<Vec<_>>()
+        );
+
+        // Sequence 1 never arrives: once two items are waiting the gap is skipped,
+        // and the late duplicate of 0 is dropped
+        let gappy = tokio_stream::iter(vec![(0, 'a'), (2, 'c'), (3, 'd'), (4, 'e'), (0, 'a')]);
+        let items: Vec<(u64, char)> = streams::reorder_by_sequence(gappy, 2).collect().await;
+        assert_eq!(items, [(0, 'a'), (2, 'c'), (3, 'd'), (4, 'e')]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pipeline() {
+        use pipeline::Pipeline;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = Pipeline::builder()
+            .capacity(2)
+            .on_error({
+                let failures = failures.clone();
+                move |e| failures.lock().unwrap().push(e.to_string())
+            })
+            .stage(
+                "parse",
+                1,
+                |s: &'static str| async move { s.parse::<u64>() },
+            )
+            .map("delay", 4, |n| async move {
+                // Later items finish first, but order is kept
+                tokio::time::sleep(Duration::from_millis(100 - n * 10)).await;
+                n * 2
+            })
+            .build();
+
+        let producer = tokio::spawn({
+            let input = pipeline.sender();
+            async move {
+                for s in ["1", "2", "oops", "3", "4"] {
+                    input.send(s).await.unwrap();
+                }
+            }
+        });
+        pipeline.close();
+        assert!(pipeline.send("5").await.is_err());
+
+        assert_eq!(pipeline.recv().await, Some(2));
+        let metrics = pipeline.metrics();
+        assert_eq!(metrics[0].name, "parse");
+        assert_eq!(metrics[1].concurrency, 4);
+
+        let rest = pipeline.drain().await;
+        assert_eq!(rest, [4, 6, 8]);
+        producer.await.unwrap();
+
+        let failures = failures.lock().unwrap().clone();
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].starts_with("stage `parse` failed"));
+
+        // Nobody reads the output, so input is soon refused
+        let pipeline = Pipeline::builder()
+            .capacity(1)
+            .map("identity", 1, |n: u32| async move { n })
+            .build();
+        let mut accepted = 0;
+        while tokio::time::timeout(Duration::from_secs(1), pipeline.send(accepted))
+            .await
+            .is_ok()
+        {
+            accepted += 1;
+        }
+        assert!(accepted > 0 && accepted < 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pipeline_backpressure() {
+        use pipeline::{BackpressureConfig, BackpressureEvent, Mitigation, Pipeline};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let registry = metrics::Registry::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let record = |events: &Arc<Mutex<Vec<BackpressureEvent>>>| {
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(event)
+        };
+
+        // Nobody reads the output, so everything after the first is shed
+        let mut pipeline = Pipeline::builder()
+            .capacity(1)
+            .backpressure(BackpressureConfig {
+                stall_threshold: Duration::from_millis(100),
+                mitigation: Mitigation::Shed,
+                registry: Some(registry.clone()),
+            })
+            .on_backpressure(record(&events))
+            .map("double", 1, |n: u32| async move { n * 2 })
+            .build();
+        for n in 0..10 {
+            pipeline.send(n).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        pipeline.close();
+        assert_eq!(pipeline.recv().await, Some(0));
+        assert_eq!(pipeline.recv().await, None);
+        assert_eq!(pipeline.metrics()[0].stalls, 1);
+        assert_eq!(pipeline.metrics()[0].shed, 9);
+        assert_eq!(registry.counter("pipeline.double.shed").get(), 9);
+        // The stall ends with the stage
+        assert_eq!(
+            events.lock().unwrap()[0],
+            BackpressureEvent::Stalled {
+                stage: "double".to_string(),
+                downstream: "output".to_string(),
+            }
+        );
+        assert!(matches!(
+            events.lock().unwrap()[1],
+            BackpressureEvent::Recovered { .. }
+        ));
+
+        // A stalled stage holds back the source until the output is read
+        events.lock().unwrap().clear();
+        let mut pipeline = Pipeline::builder()
+            .capacity(1)
+            .backpressure(BackpressureConfig {
+                stall_threshold: Duration::from_millis(100),
+                mitigation: Mitigation::PauseSource,
+                registry: None,
+            })
+            .on_backpressure(record(&events))
+            .map("first", 1, |n: u32| async move { n })
+            .map("second", 1, |n: u32| async move { n })
+            .build();
+        for n in 0..3 {
+            pipeline.send(n).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(pipeline.is_paused());
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), pipeline.send(3))
+                .await
+                .is_err()
+        );
+
+        assert_eq!(pipeline.recv().await, Some(0));
+        tokio::task::yield_now().await;
+        assert!(!pipeline.is_paused());
+        let events = events.lock().unwrap().clone();
+        assert_eq!(
+            events[0],
+            BackpressureEvent::Stalled {
+                stage: "second".to_string(),
+                downstream: "output".to_string(),
+            }
+        );
+        assert!(matches!(
+            &events[1],
+            BackpressureEvent::Recovered { stage, stalled_for, .. }
+                if stage == "second" && *stalled_for >= Duration::from_secs(1)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_worker_pool_scaling() {
+        use std::time::Duration;
+        use workers::{ScalingPolicy, WorkerPool};
+
+        let policy = ScalingPolicy {
+            min_workers: 1,
+            max_workers: 4,
+            scale_up_depth: 2,
+            scale_down_depth: 0,
+            check_interval: Duration::from_millis(100),
+            queue_capacity: 64,
+        };
+        let pool = WorkerPool::new(policy, |_job: u32| async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+        assert_eq!(pool.metrics().workers, 1);
+
+        for job in 0..20 {
+            pool.submit(job).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let busy = pool.metrics();
+        assert_eq!(busy.workers, 4);
+        assert_eq!(busy.utilization, 1.0);
+
+        // The backlog is gone well before this, and the pool shrinks back
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let idle = pool.metrics();
+        assert_eq!((idle.workers, idle.queued, idle.completed), (1, 0, 20));
+
+        pool.submit(20).await.unwrap();
+        pool.drain_and_stop().await;
+        assert_eq!(pool.metrics().completed, 21);
+        assert!(pool.submit(21).await.is_err());
+
+        // A panicking job neither kills its worker nor leaves it busy
+        let policy = ScalingPolicy {
+            min_workers: 1,
+            max_workers: 1,
+            ..ScalingPolicy::default()
+        };
+        let pool = WorkerPool::new(policy, |job: u32| async move {
+            assert_ne!(job, 0, "job 0 is poisoned");
+        });
+        for job in 0..3 {
+            pool.submit(job).await.unwrap();
+        }
+        pool.drain_and_stop().await;
+        let metrics = pool.metrics();
+        assert_eq!(
+            (metrics.completed, metrics.panicked, metrics.busy),
+            (3, 1, 0)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_job_queue() {
+        use jobs::{Job, JobId, JobQueue, JobQueueConfig, JobRecord, JobStore};
+        use select::RetryPolicy;
+        use std::sync::{Arc, Mutex};
+        use std::time::{Duration, SystemTime};
+
+        #[derive(Clone, Default)]
+        struct Recording(Arc<Mutex<Vec<String>>>);
+
+        impl JobStore<u32, String> for Recording {
+            fn recover(&self) -> Vec<JobRecord<u32>> {
+                let left_over = JobRecord {
+                    id: JobId(10),
+                    payload: 50,
+                    priority: 100,
+                    run_at: SystemTime::now(),
+                    attempts: 0,
+                };
+                vec![left_over]
+            }
+
+            fn completed(&self, job: &JobRecord<u32>) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("completed {}", job.payload));
+            }
+
+            fn dead_lettered(&self, job: &JobRecord<u32>, error: &String) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("dead {}: {}", job.payload, error));
+            }
+        }
+
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let store = Recording::default();
+        let config = JobQueueConfig {
+            concurrency: 1,
+            ..Default::default()
+        };
+        let queue = JobQueue::with_store(config, store.clone(), {
+            let runs = runs.clone();
+            move |n: u32| {
+                let mut runs = runs.lock().unwrap();
+                runs.push(n);
+                let attempts = runs.iter().filter(|&&r| r == n).count();
+                async move {
+                    match n {
+                        7 if attempts == 1 => Err("flaky".to_string()),
+                        13 if attempts == 1 => panic!("poisoned"),
+                        99 => Err("broken".to_string()),
+                        _ => Ok(()),
+                    }
+                }
+            }
+        });
+
+        let retry = |delay| RetryPolicy::fixed(Duration::from_millis(delay));
+        queue.enqueue(Job::new(1)).unwrap();
+        queue.enqueue(Job::new(2).priority(5)).unwrap();
+        queue
+            .enqueue(Job::new(3).delay(Duration::from_secs(1)))
+            .unwrap();
+        queue.enqueue(Job::new(7).retry(retry(100))).unwrap();
+        let doomed = Job::new(99).retry(retry(10).max_attempts(Some(2)));
+        assert_eq!(queue.enqueue(doomed).unwrap(), JobId(15));
+        assert_eq!(queue.stats().delayed, 1);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(*runs.lock().unwrap(), [50, 2, 1, 7, 99, 99, 7, 3]);
+        let stats = queue.stats();
+        assert_eq!(
+            (stats.completed, stats.dead_lettered, stats.ready),
+            (5, 1, 0)
+        );
+
+        let dead = queue.dead_letters();
+        assert_eq!((dead[0].job.payload, dead[0].job.attempts), (99, 2));
+        assert!(store
+            .0
+            .lock()
+            .unwrap()
+            .contains(&"dead 99: broken".to_string()));
+
+        // A panic is a failed attempt like any other, and frees its slot
+        queue.enqueue(Job::new(13).retry(retry(10))).unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(runs.lock().unwrap().ends_with(&[13, 13]));
+        let stats = queue.stats();
+        assert_eq!((stats.completed, stats.running), (6, 0));
+
+        queue
+            .enqueue(Job::new(4).delay(Duration::from_secs(3600)))
+            .unwrap();
+        let unfinished = queue.shutdown().await;
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].payload, 4);
+        assert!(queue.enqueue(Job::new(5)).is_err());
+
+        // Dropping every handle stops the dispatcher, which drops the handler
+        let handler_alive = Arc::new(());
+        let queue = JobQueue::new(JobQueueConfig::default(), {
+            let handler_alive = handler_alive.clone();
+            move |_: u32| {
+                let _ = &handler_alive;
+                async { Ok::<_, String>(()) }
+            }
+        });
+        let clone = queue.clone();
+        drop(queue);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(Arc::strong_count(&handler_alive), 2);
+        drop(clone);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(Arc::strong_count(&handler_alive), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_supervision_tree() {
+        use actors::{Actor, ActorError, ActorRef, Exit, Strategy, SupervisorSpec};
+        use std::time::Duration;
+        use tokio::sync::oneshot;
+
+        #[derive(Debug)]
+        enum Msg {
+            Add(u32),
+            Get(oneshot::Sender<u32>),
+            Crash,
+        }
+
+        struct Tally(u32);
+
+        impl Actor for Tally {
+            type Message = Msg;
+
+            async fn handle(&mut self, message: Msg) -> Result<(), ActorError> {
+                match message {
+                    Msg::Add(n) => self.0 += n,
+                    Msg::Get(reply) => {
+                        let _ = reply.send(self.0);
+                    }
+                    Msg::Crash => return Err("crashed".into()),
+                }
+                Ok(())
+            }
+        }
+
+        async fn get(actor: &ActorRef<Msg>) -> u32 {
+            let (tx, rx) = oneshot::channel();
+            let _ = actor.send(Msg::Get(tx)).await;
+            rx.await.unwrap()
+        }
+
+        // One-for-all: a failure in `a` restarts `b` too
+        let (a, a_mailbox) = actors::mailbox(8);
+        let (b, b_mailbox) = actors::mailbox(8);
+        let supervisor = SupervisorSpec::new("pair", Strategy::OneForAll)
+            .actor("a", a_mailbox, || Tally(0))
+            .actor("b", b_mailbox, || Tally(0))
+            .start();
+        a.send(Msg::Add(1)).await.unwrap();
+        b.send(Msg::Add(5)).await.unwrap();
+        assert_eq!(get(&b).await, 5);
+        a.send(Msg::Crash).await.unwrap();
+        assert_eq!(get(&a).await, 0);
+        assert_eq!(get(&b).await, 0);
+        assert_eq!(supervisor.restarts(), 1);
+        assert_eq!(supervisor.shutdown().await, Exit::Normal);
+
+        // The inner supervisor allows one restart; the second failure escalates
+        // and the outer supervisor restarts the whole subtree
+        let (c, c_mailbox) = actors::mailbox(8);
+        let inner = SupervisorSpec::new("inner", Strategy::OneForOne)
+            .intensity(1, Duration::from_secs(10))
+            .actor("c", c_mailbox, || Tally(0));
+        let root = SupervisorSpec::new("root", Strategy::OneForOne)
+            .intensity(5, Duration::from_secs(10))
+            .supervisor(inner)
+            .start();
+        c.send(Msg::Crash).await.unwrap();
+        c.send(Msg::Add(2)).await.unwrap();
+        assert_eq!(get(&c).await, 2);
+        assert_eq!(root.restarts(), 0);
+        c.send(Msg::Crash).await.unwrap();
+        assert_eq!(get(&c).await, 0);
+        assert_eq!(root.restarts(), 1);
+
+        // Too many failures at the top level stop the tree
+        let (d, d_mailbox) = actors::mailbox(8);
+        let fragile = SupervisorSpec::new("fragile", Strategy::OneForOne)
+            .intensity(1, Duration::from_secs(1))
+            .actor("d", d_mailbox, || Tally(0))
+            .start();
+        d.send(Msg::Crash).await.unwrap();
+        d.send(Msg::Crash).await.unwrap();
+        assert!(matches!(fragile.join().await, Exit::Failed(reason) if reason.contains("`d`")));
+        assert!(d.send(Msg::Add(1)).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batcher() {
+        use batching::{BatchConfig, Batcher};
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let batcher = Batcher::new(BatchConfig::default(), {
+            let calls = calls.clone();
+            move |mut keys: Vec<u32>| {
+                keys.sort_unstable();
+                calls.lock().unwrap().push(keys.clone());
+                async move {
+                    // Odd keys don't exist in the backend
+                    keys.into_iter()
+                        .filter(|k| k % 2 == 0)
+                        .map(|k| (k, k * 100))
+                        .collect::<HashMap<_, _>>()
+                }
+            }
+        });
+
+        let results = futures::future::join_all([2, 4, 4, 5].map(|k| batcher.get(k))).await;
+        let results: Vec<_> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(results, [Some(200), Some(400), Some(400), None]);
+        assert_eq!(*calls.lock().unwrap(), [vec![2, 4, 5]]);
+
+        // A request after the window has closed goes in a batch of its own
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(batcher.get(6).await, Ok(Some(600)));
+        assert_eq!(calls.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_single_flight() {
+        use dedup::SingleFlight;
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let runs = Arc::new(AtomicU32::new(0));
+        let fetch = || {
+            let runs = runs.clone();
+            move || async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                runs.fetch_add(1, Ordering::SeqCst) + 1
+            }
+        };
+
+        let flights = SingleFlight::new();
+        let results = futures::future::join_all((0..10).map(|_| flights.get("k", fetch()))).await;
+        assert_eq!(results, [1; 10]);
+        assert_eq!(flights.in_flight(), 0);
+        // Without caching, a later call runs again
+        assert_eq!(flights.get("k", fetch()).await, 2);
+
+        let cached = SingleFlight::new().cache_for(Duration::from_secs(1));
+        assert_eq!(cached.get("k", fetch()).await, 3);
+        assert_eq!(cached.get("k", fetch()).await, 3);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(cached.get("k", fetch()).await, 4);
+        cached.forget(&"k");
+        assert_eq!(cached.get("k", fetch()).await, 5);
+
+        // Expired results are pruned when another is cached
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(cached.get("other", fetch()).await, 6);
+        assert_eq!(cached.cached(), 1);
+
+        // Work that panics isn't handed to later callers
+        let flights = SingleFlight::new();
+        let panicked = tokio::spawn({
+            let flights = flights.clone();
+            async move { flights.get("k", || async { panic!("render failed") }).await }
+        });
+        assert!(panicked.await.unwrap_err().is_panic());
+        assert_eq!(flights.in_flight(), 0);
+        assert_eq!(flights.get("k", fetch()).await, 7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_load_shed() {
+        use io::http_lite::{Request, Response, Router};
+        use select::{LoadShed, LoadShedConfig, ShedError, ShedReason};
+        use std::time::Duration;
+
+        let shed = LoadShed::new(LoadShedConfig {
+            max_queue_depth: 2,
+            max_latency: Duration::from_secs(1),
+            latency_percentile: 0.9,
+            latency_window: Duration::from_secs(10),
+            min_samples: 2,
+        });
+        let slow = |secs| {
+            let shed = shed.clone();
+            tokio::spawn(async move {
+                shed.call(|| async move {
+                    tokio::time::sleep(Duration::from_secs(secs)).await;
+                    Ok::<_, String>(())
+                })
+                .await
+            })
+        };
+
+        let first = slow(5);
+        tokio::task::yield_now().await;
+        let quick = shed.call(|| async { Ok::<_, String>(1) }).await;
+        assert_eq!(quick, Ok(1));
+        let failed = shed
+            .call(|| async { Err::<u32, _>("boom".to_string()) })
+            .await;
+        assert_eq!(failed, Err(ShedError::Failed("boom".to_string())));
+
+        let second = slow(5);
+        tokio::task::yield_now().await;
+        let rejected = shed.call(|| async { Ok::<_, String>(1) }).await;
+        assert_eq!(rejected, Err(ShedError::Overloaded(ShedReason::QueueDepth)));
+
+        // A long-running request alone sheds nothing...
+        second.abort();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(shed.call(|| async { Ok::<_, String>(1) }).await, Ok(1));
+
+        // ...but once it completes, over a tenth of recent completions were slow
+        first.await.unwrap().unwrap();
+        let rejected = shed.call(|| async { Ok::<_, String>(1) }).await;
+        assert_eq!(rejected, Err(ShedError::Overloaded(ShedReason::Latency)));
+
+        // Until the slow completion leaves the window
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(shed.call(|| async { Ok::<_, String>(1) }).await, Ok(1));
+        let metrics = shed.metrics();
+        assert_eq!(
+            (metrics.admitted, metrics.failed, metrics.in_flight),
+            (6, 1, 0)
+        );
+        assert_eq!((metrics.shed_queue_depth, metrics.shed_latency), (1, 1));
+
+        // The HTTP router answers 503 instead of queueing more work
+        let router = Router::new()
+            .route("GET", "/slow", |_| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Response::text("done")
+            })
+            .load_shed(LoadShed::new(LoadShedConfig {
+                max_queue_depth: 1,
+                ..Default::default()
+            }));
+        let request = Request {
+            method: "GET".to_string(),
+            path: "/slow".to_string(),
+            query: None,
+            version: "HTTP/1.1".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            peer: None,
+        };
+        let busy = tokio::spawn({
+            let router = router.clone();
+            let request = request.clone();
+            async move { router.handle(request).await }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(router.handle(request).await.status, 503);
+        assert_eq!(busy.await.unwrap().status, 200);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_service_layers() {
+        use select::RetryPolicy;
+        use service::{service_fn, BoxError, Service, ServiceBuilder};
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let calls = Arc::new(AtomicU32::new(0));
+        let flaky = service_fn({
+            let calls = calls.clone();
+            move |n: u32| {
+                let attempt = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                async move {
+                    tokio::time::sleep(Duration::from_millis(n.into())).await;
+                    if attempt < 3 {
+                        Err::<u32, BoxError>("not yet".into())
+                    } else {
+                        Ok(n * 2)
+                    }
+                }
+            }
+        });
+
+        let stack = ServiceBuilder::new()
+            .logging("flaky")
+            .concurrency_limit(1)
+            .timeout(Duration::from_secs(1))
+            .retry(RetryPolicy::fixed(Duration::from_millis(10)));
+        let svc = stack.service(flaky);
+        assert_eq!(svc.call(100).await.unwrap(), 200);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let err = svc.call(1500).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+
+        // Calls queue for the single slot
+        let started = tokio::time::Instant::now();
+        let (a, b) = tokio::join!(svc.call(100), svc.call(100));
+        assert_eq!((a.unwrap(), b.unwrap()), (200, 200));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        // The crate's request/response handler is a service too
+        let doubler = channels::RequestHandler::new(|n: u32| async move { n * 2 });
+        let svc = ServiceBuilder::new().logging("doubler").service(doubler);
+        assert_eq!(svc.call(21).await, Ok(42));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_health_checks() {
+        use health::HealthChecker;
+        use io::http_lite::{Request, Router};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let db_up = Arc::new(AtomicBool::new(true));
+        let hung = Arc::new(AtomicBool::new(false));
+        let monitor = HealthChecker::new(Duration::from_secs(1))
+            .readiness("db", Duration::from_millis(100), {
+                let db_up = db_up.clone();
+                move || {
+                    let up = db_up.load(Ordering::SeqCst);
+                    async move {
+                        if up {
+                            Ok(())
+                        } else {
+                            Err("connection refused")
+                        }
+                    }
+                }
+            })
+            .liveness("event loop", Duration::from_millis(100), {
+                let hung = hung.clone();
+                move || {
+                    let hung = hung.load(Ordering::SeqCst);
+                    async move {
+                        if hung {
+                            std::future::pending::<()>().await;
+                        }
+                        Ok::<_, String>(())
+                    }
+                }
+            })
+            .start();
+        assert!(!monitor.report().ready);
+
+        let mut updates = monitor.subscribe();
+        updates.changed().await.unwrap();
+        let report = monitor.report();
+        assert!(report.live && report.ready);
+        assert_eq!(report.checks.len(), 2);
+
+        db_up.store(false, Ordering::SeqCst);
+        hung.store(true, Ordering::SeqCst);
+        updates.changed().await.unwrap();
+        let report = updates.borrow().clone();
+        assert!(!report.live && !report.ready);
+        assert_eq!(
+            report.checks["db"].error.as_deref(),
+            Some("connection refused")
+        );
+        assert!(report.checks["event loop"]
+            .error
+            .as_ref()
+            .unwrap()
+            .contains("timed out"));
+
+        let router = monitor.routes(Router::new());
+        let get = |path: &str| Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            query: None,
+            version: "HTTP/1.1".to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            peer: None,
+        };
+        let response = router.handle(get("/readyz")).await;
+        assert_eq!(response.status, 503);
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains("db: failing (connection refused)"));
+
+        hung.store(false, Ordering::SeqCst);
+        updates.changed().await.unwrap();
+        assert_eq!(router.handle(get("/healthz")).await.status, 200);
+
+        // Once the checks stop, the last report no longer stands
+        drop(monitor);
+        updates.changed().await.unwrap();
+        assert_eq!(router.handle(get("/healthz")).await.status, 503);
+
+        // A panicking probe fails without stopping the other checks
+        let monitor = HealthChecker::new(Duration::from_secs(1))
+            .liveness("cache", Duration::from_millis(100), || async {
+                panic!("corrupt index") as Result<(), String>
+            })
+            .start();
+        let mut updates = monitor.subscribe();
+        updates.changed().await.unwrap();
+        updates.changed().await.unwrap();
+        let report = monitor.report();
+        assert!(!report.live);
+        assert_eq!(
+            report.checks["cache"].error.as_deref(),
+            Some("probe panicked")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_traced_spawn_and_serve() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (tx, mut rx) = channels::traced_channel::<u32>("numbers", 4);
+        let producer = spawning::spawn_traced("producer", async move {
+            for i in 0..3 {
+                tx.send(i).await.unwrap();
+            }
+            tx.queued()
+        });
+        assert_eq!(producer.await.unwrap(), 3);
+        assert_eq!(rx.recv().await, Some(0));
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(rx.recv().await, Some(2));
+        assert_eq!(rx.recv().await, None);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(io::serve_traced(
+            listener,
+            "echo",
+            |mut socket, _peer, _shutdown| async move {
+                let (mut reader, mut writer) = socket.split();
+                tokio::io::copy(&mut reader, &mut writer).await.map(|_| ())
+            },
+            async {
+                let _ = shutdown_rx.await;
+            },
+        ));
+
+        let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        socket.write_all(b"ping").await.unwrap();
+        socket.shutdown().await.unwrap();
+        let mut echoed = String::new();
+        socket.read_to_string(&mut echoed).await.unwrap();
+        assert_eq!(echoed, "ping");
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_config_reload() {
+        use config::{ConfigError, ConfigLoader};
+        use std::time::Duration;
+
+        let path =
+            std::env::temp_dir().join(format!("tokio_patterns_config_{}", std::process::id()));
+        let loader = || {
+            ConfigLoader::new(path.clone(), |s: &str| s.trim().parse::<u32>())
+                .validate(|n: &u32| {
+                    if *n == 0 {
+                        Err("must be positive")
+                    } else {
+                        Ok(())
+                    }
+                })
+                .poll_interval(Duration::from_millis(10))
+        };
+        async fn settle<T: Send + Sync + 'static>(config: &config::ConfigWatch<T>) {
+            for _ in 0..200 {
+                if config.last_error().is_some() {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        assert!(matches!(loader().watch().await, Err(ConfigError::Io(_))));
+
+        tokio::fs::write(&path, "3").await.unwrap();
+        let config = loader().watch().await.unwrap();
+        let mut updates = config.subscribe();
+        assert_eq!(*config.current(), 3);
+        assert_eq!(config.version(), 1);
+
+        tokio::fs::write(&path, "5").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), updates.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(**updates.borrow_and_update(), 5);
+        assert_eq!(config.version(), 2);
+
+        // Bad versions are reported and the last good one stays current
+        tokio::fs::write(&path, "oops").await.unwrap();
+        settle(&config).await;
+        assert!(matches!(config.last_error(), Some(ConfigError::Parse(_))));
+        tokio::fs::write(&path, "0").await.unwrap();
+        assert!(matches!(
+            config.reload().await,
+            Err(ConfigError::Invalid(_))
+        ));
+        assert_eq!(*config.current(), 5);
+        assert_eq!(config.version(), 2);
+
+        tokio::fs::write(&path, "7").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), updates.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*config.current(), 7);
+        assert!(config.last_error().is_none());
+
+        // A file that goes missing and comes back unchanged clears the error
+        tokio::fs::remove_file(&path).await.unwrap();
+        settle(&config).await;
+        assert!(matches!(config.last_error(), Some(ConfigError::Io(_))));
+        tokio::fs::write(&path, "7").await.unwrap();
+        for _ in 0..200 {
+            if config.last_error().is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(config.last_error().is_none());
+        assert_eq!(*config.current(), 7);
+
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+
+    #[test]
+    fn test_testing_utilities() {
+        use futures::FutureExt;
+        use std::time::Duration;
+        use testing::{Script, Step};
+
+        testing::run_test(async {
+            let started = tokio::time::Instant::now();
+            let slow = tokio::time::sleep(Duration::from_secs(60)).map(|_| "done");
+            let mut slow = Box::pin(slow);
+            testing::assert_pending_for(Duration::from_secs(59), &mut slow).await;
+            let done = testing::assert_completes_within(Duration::from_secs(2), slow).await;
+            assert_eq!(done, "done");
+            assert_eq!(started.elapsed(), Duration::from_secs(60));
+
+            let script = Script::new().step(Step::Pass).drop().cycle();
+            let items = testing::scripted_stream(tokio_stream::iter(0..6), script);
+            assert_eq!(
+                tokio_stream::StreamExt::collect::<Vec<_>>(items).await,
+                vec![0, 2, 4]
+            );
+
+            let script = Script::new().delay(Duration::from_secs(5)).pass(1).end();
+            let (tx, mut rx) = testing::mock_channel(4, script);
+            let producer = tokio::spawn(async move {
+                tx.send(1).await.unwrap();
+                tx.send(2).await.unwrap();
+                assert!(tx.send(3).await.is_err());
+                assert!(tx.send(4).await.is_err());
+            });
+            assert_eq!(rx.recv().await, Some(1));
+            assert_eq!(started.elapsed(), Duration::from_secs(65));
+            assert_eq!(rx.recv().await, Some(2));
+            assert_eq!(rx.recv().await, None);
+            producer.await.unwrap();
+
+            // Dropping a message fails once the channel is closed
+            let (tx, _rx) = testing::mock_channel(4, Script::new().end().drop());
+            assert!(tx.send(1).await.is_err());
+            assert!(tx.send(2).await.is_err());
+            assert_eq!(tx.dropped(), 0);
+            let (tx, rx) = testing::mock_channel(4, Script::new().drop());
+            drop(rx);
+            assert!(tx.send(3).await.is_err());
+            assert_eq!(tx.dropped(), 0);
+        });
+    }
+
+    // An example of a loom model over `shared_state::sync`. It checks the
+    // blocking-lock variant of `Counter` built under this cfg.
+    #[cfg(tokio_patterns_loom)]
+    #[test]
+    fn loom_counter_increments() {
+        loom::model(|| {
+            let counter = shared_state::Counter::new(0);
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let counter = counter.clone();
+                    loom::thread::spawn(move || loom::future::block_on(counter.increment()))
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(loom::future::block_on(counter.get()), 2);
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_saga_compensation() {
+        use orchestration::{Saga, SagaEvent, SagaStep};
+        use select::RetryPolicy;
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let attempts = Arc::new(AtomicU32::new(0));
+        let record = |log: &Arc<Mutex<Vec<String>>>, entry: String| log.lock().unwrap().push(entry);
+
+        let (events_tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+        let (l1, l2, l3) = (log.clone(), log.clone(), log.clone());
+        let flaky = attempts.clone();
+        let saga = Saga::new()
+            .step(SagaStep::new(
+                "flight",
+                |trip: &'static str| async move { Ok::<_, String>(format!("{} flight", trip)) },
+                move |_, booking: String| {
+                    record(&l1, format!("cancel {}", booking));
+                    async { Ok(()) }
+                },
+            ))
+            .step(
+                SagaStep::new(
+                    "hotel",
+                    move |trip: &'static str| {
+                        let n = flaky.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            if n < 2 {
+                                return Err("busy".to_string());
+                            }
+                            Ok(format!("{} hotel", trip))
+                        }
+                    },
+                    move |_, booking: String| {
+                        record(&l2, format!("cancel {}", booking));
+                        async { Err::<(), _>("hotel unreachable".to_string()) }
+                    },
+                )
+                .retry(RetryPolicy::fixed(Duration::from_secs(1)).max_attempts(Some(3))),
+            )
+            .step(SagaStep::new(
+                "car",
+                |_trip| async { Err::<(), _>("no cars".to_string()) },
+                move |_, ()| {
+                    record(&l3, "cancel car".to_string());
+                    async { Ok(()) }
+                },
+            ))
+            .events(events_tx);
+
+        let failed = saga.run("paris").await.unwrap_err();
+        assert_eq!(failed.step, "car");
+        assert_eq!(failed.error, "no cars");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        let hotel_failure = ("hotel".to_string(), "hotel unreachable".to_string());
+        assert_eq!(failed.compensation_failures, vec![hotel_failure]);
+        // Completed steps are undone newest first; the failed step isn't
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["cancel paris hotel", "cancel paris flight"]
+        );
+
+        drop(saga);
+        let mut seen = Vec::new();
+        while let Some(event) = events.recv().await {
+            seen.push(event);
+        }
+        assert_eq!(seen[0], SagaEvent::Started("flight".into()));
+        assert_eq!(
+            seen[5],
+            SagaEvent::Failed {
+                step: "car".into(),
+                error: "no cars".into()
+            }
+        );
+        assert_eq!(seen.last(), Some(&SagaEvent::Compensated("flight".into())));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_event_log() {
+        use eventlog::{Compacted, EventLog};
+        use tokio_stream::StreamExt;
+
+        let log = EventLog::with_snapshots(0, |total: &mut i64, amount: &i64| *total += amount);
+        for amount in [10, 20, 30] {
+            log.append(amount);
+        }
+
+        let mut replay = Box::pin(log.subscribe_from(1));
+        assert_eq!(replay.next().await, Some(Ok((1, 20))));
+        assert_eq!(replay.next().await, Some(Ok((2, 30))));
+
+        let appender = log.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            appender.append(40);
+        });
+        assert_eq!(replay.next().await, Some(Ok((3, 40))));
+
+        // Compacted events are folded into the snapshot
+        log.compact_before(2);
+        assert_eq!(log.snapshot(), Some((2, 30)));
+        assert_eq!(
+            log.get(1),
+            Err(Compacted {
+                requested: 1,
+                first_available: 2
+            })
+        );
+        let mut stale = Box::pin(log.subscribe_from(0));
+        assert!(matches!(stale.next().await, Some(Err(_))));
+        assert_eq!(stale.next().await, None);
+
+        let log = log.retain(1);
+        assert_eq!(log.append(50), 4);
+        assert_eq!(log.snapshot(), Some((4, 100)));
+        assert_eq!(log.first_offset(), 4);
+        assert_eq!(log.next_offset(), 5);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_bench_comparisons() {
+        let config = bench::BenchConfig {
+            operations: 2_000,
+            tasks: 4,
+        };
+        let comparisons = bench::run_all(&config).await;
+        assert_eq!(comparisons.len(), 4);
+        for comparison in &comparisons {
+            assert!(comparison.baseline.operations > 0);
+            assert_eq!(
+                comparison.baseline.operations,
+                comparison.candidate.operations
+            );
+            assert!(comparison.speedup().is_finite());
+            assert!(comparison.to_string().contains("speedup"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_shutdown_phased() {
+        use io::{Server, ShutdownConfig};
+        use shutdown::{Coordinator, Phase};
+        use std::time::Duration;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Server::start(
+            listener,
+            "test",
+            |mut socket, _peer, mut shutdown| async move {
+                let mut kind = [0u8; 1];
+                socket.read_exact(&mut kind).await?;
+                if kind[0] == b'p' {
+                    // Polite: leaves as soon as it's asked to
+                    let _ = shutdown.wait_for(|stopping| *stopping).await;
+                    return Ok::<_, std::io::Error>(());
+                }
+                // Stubborn: ignores shutdown until the peer hangs up
+                while socket.read(&mut kind).await? > 0 {}
+                Ok(())
+            },
+        );
+        let addr = server.local_addr().unwrap();
+
+        let mut polite = tokio::net::TcpStream::connect(addr).await.unwrap();
+        polite.write_all(b"p").await.unwrap();
+        let mut stubborn = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stubborn.write_all(b"s").await.unwrap();
+        while server.connections() < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let coordinator = Coordinator::new();
+        let metrics = coordinator.register("metrics", Phase::Flush);
+        let flusher = tokio::spawn(async move {
+            metrics.cancelled().await;
+            metrics.ack();
+        });
+
+        let report = server
+            .shutdown_phased(ShutdownConfig {
+                goodbye: Some(b"bye".to_vec()),
+                drain_timeout: Duration::from_millis(100),
+                coordinator: Some(coordinator),
+                ..Default::default()
+            })
+            .await;
+        flusher.await.unwrap();
+        assert_eq!((report.drained, report.forced), (1, 1));
+        assert!(report.flush.as_ref().unwrap().is_clean());
+        assert!(!report.is_clean());
+
+        // Both got the goodbye; the stubborn one was then closed on
+        let mut received = Vec::new();
+        stubborn.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"bye");
+        received.clear();
+        polite.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"bye");
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_leader_election() {
+        use coordination::{
+            ElectionConfig, LeaderElection, LeaseBackend, LockFileLease, MemoryLease,
+        };
+        use std::time::Duration;
+
+        let path =
+            std::env::temp_dir().join(format!("tokio_patterns_leader_{}", std::process::id()));
+        let first = LockFileLease::open(&path).await.unwrap();
+        let second = LockFileLease::open(&path).await.unwrap();
+        assert!(first.try_acquire("a", Duration::ZERO).unwrap());
+        assert!(!second.try_acquire("b", Duration::ZERO).unwrap());
+        first.release("a").unwrap();
+        assert!(second.try_acquire("b", Duration::ZERO).unwrap());
+        drop(second);
+        let _ = std::fs::remove_file(&path);
+
+        tokio::time::pause();
+        let config = ElectionConfig {
+            lease: Duration::from_secs(10),
+            renew_interval: Duration::from_secs(3),
+            retry_interval: Duration::from_secs(1),
+        };
+        let lease = MemoryLease::new();
+        let a = LeaderElection::start("a", lease.clone(), config.clone());
+        a.wait_for_leadership().await;
+        let b = LeaderElection::start("b", lease.clone(), config);
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert!(a.is_leader());
+        assert!(!b.is_leader());
+        assert_eq!(lease.holder().as_deref(), Some("a"));
+
+        // b's work waits for leadership, which it gets once a resigns
+        let mut b_leader = b.subscribe();
+        let (work, ()) = tokio::join!(b.run_while_leader(async { "scheduled" }), async {
+            a.resign().await.unwrap();
+        });
+        assert_eq!(work, Some("scheduled"));
+        assert!(*b_leader.borrow_and_update());
+        assert_eq!(lease.holder().as_deref(), Some("b"));
+
+        // A lease that was stolen is noticed on the next renewal
+        let mut lost = b.subscribe();
+        let unfinished =
+            tokio::spawn(async move { b.run_while_leader(std::future::pending::<()>()).await });
+        tokio::task::yield_now().await;
+        lease.release("b").unwrap();
+        assert!(lease
+            .try_acquire("intruder", Duration::from_secs(60))
+            .unwrap());
+        lost.wait_for(|leader| !*leader).await.unwrap();
+        assert_eq!(unfinished.await.unwrap(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_session_expiry() {
+        use sessions::{SessionEvent, SessionManager, SessionToken};
+        use std::time::Duration;
+
+        let sessions = SessionManager::new(Duration::from_secs(60));
+        let mut events = sessions.subscribe();
+        let active = sessions.create(0u32);
+        let idle = sessions.create(0u32);
+        let ended = sessions.create(0u32);
+        assert_ne!(active.token(), idle.token());
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SessionEvent::Created(active.token().clone())
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SessionEvent::Created(idle.token().clone())
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SessionEvent::Created(ended.token().clone())
+        );
+
+        assert!(sessions.end(ended.token()));
+        assert!(!sessions.end(ended.token()));
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SessionEvent::Ended(ended.token().clone())
+        );
+
+        // Lookups keep a session alive and share its state
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_secs(40)).await;
+            let session = sessions.get(active.token()).unwrap();
+            *session.lock().await += 1;
+        }
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SessionEvent::Expired(idle.token().clone())
+        );
+        assert!(sessions.get(idle.token()).is_none());
+        assert_eq!(*active.lock().await, 3);
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions.get(&SessionToken::from("forged")).is_none());
+
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            SessionEvent::Expired(active.token().clone())
+        );
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pool_lanes() {
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use workers::{BlockingError, BlockingPool, BlockingPoolConfig, Lane};
+
+        let pool = Arc::new(BlockingPool::new(BlockingPoolConfig {
+            threads: 1,
+            queue_capacity: 2,
+            thread_name: "test-pool".to_string(),
+        }));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // Occupy the only thread until released
+        let (release, blocked) = std::sync::mpsc::channel::<()>();
+        let blocker = tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                pool.run(Lane::Normal, move || blocked.recv().unwrap())
+                    .await
+            }
+        });
+        let submit = |lane: Lane, name: &'static str| {
+            let (pool, order) = (pool.clone(), order.clone());
+            tokio::spawn(async move {
+                pool.run(lane, move || order.lock().unwrap().push(name))
+                    .await
+            })
+        };
+        async fn wait_queued(pool: &BlockingPool, n: usize) {
+            while pool.queued() != n {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+
+        wait_queued(&pool, 0).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let low = submit(Lane::Low, "low");
+        wait_queued(&pool, 1).await;
+        let high = submit(Lane::High, "high");
+        wait_queued(&pool, 2).await;
+        // The queue is full, so this one waits for room
+        let normal = submit(Lane::Normal, "normal");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(pool.queued(), 2);
+
+        release.send(()).unwrap();
+        let blocked = blocker.await.unwrap().unwrap();
+        assert!(blocked.ran >= Duration::from_millis(20));
+        let high = high.await.unwrap().unwrap();
+        assert!(high.waited >= Duration::from_millis(20));
+        low.await.unwrap().unwrap();
+        normal.await.unwrap().unwrap();
+        // "normal" may overtake "low" once it gets room, but never "high"
+        let mut order = order.lock().unwrap().clone();
+        assert_eq!(order[0], "high");
+        order.sort();
+        assert_eq!(order, ["high", "low", "normal"]);
+
+        let panicked = pool.run(Lane::High, || panic!("job failed")).await;
+        assert_eq!(panicked.unwrap_err(), BlockingError::Panicked);
+        assert_eq!(pool.run(Lane::Low, || 7).await.unwrap().output, 7);
+
+        pool.drain_and_stop().await;
+        assert_eq!(
+            pool.run(Lane::High, || ()).await.unwrap_err(),
+            BlockingError::Closed
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keyed_limiter() {
+        use io::http_lite::{Request, Response, Router};
+        use ratelimit::KeyedLimiter;
+        use std::time::Duration;
+
+        let limiter = KeyedLimiter::new(1.0, 2).max_keys(2);
+        assert!(limiter.check(&"alice").is_ok());
+        assert!(limiter.check(&"alice").is_ok());
+        let limited = limiter.check(&"alice").unwrap_err();
+        assert_eq!(limited.retry_after, Duration::from_secs(1));
+        // Other keys have quotas of their own
+        assert!(limiter.check(&"bob").is_ok());
+
+        let started = tokio::time::Instant::now();
+        limiter.acquire(&"alice").await;
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+
+        // A third key evicts the least recently used, alice, who starts over
+        limiter.check(&"bob").unwrap();
+        limiter.check(&"carol").unwrap();
+        assert_eq!(limiter.tracked_keys(), 2);
+        limiter.check(&"alice").unwrap();
+        limiter.check(&"alice").unwrap();
+
+        let router = Router::new()
+            .route("GET", "/", |_| async { Response::text("ok") })
+            .rate_limit(KeyedLimiter::new(0.5, 1), |req| {
+                req.peer
+                    .map(|peer| peer.ip().to_string())
+                    .unwrap_or_default()
+            });
+        let request = |peer: &str| Request {
+            method: "GET".into(),
+            path: "/".into(),
+            query: None,
+            version: "HTTP/1.1".into(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            peer: Some(peer.parse().unwrap()),
+        };
+        assert_eq!(router.handle(request("192.0.2.1:5000")).await.status, 200);
+        // Another connection from the same address shares its quota
+        let rejected = router.handle(request("192.0.2.1:5001")).await;
+        assert_eq!(rejected.status, 429);
+        assert!(rejected
+            .headers
+            .contains(&("Retry-After".into(), "2".into())));
+        assert_eq!(router.handle(request("192.0.2.2:5000")).await.status, 200);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_signal_bus_acks() {
+        use shutdown::SignalBus;
+        use std::time::Duration;
+
+        let bus = SignalBus::new();
+        for (name, work) in [("cache", 100), ("metrics", 300)] {
+            let signal = bus.subscribe(name);
+            tokio::spawn(async move {
+                let ack = signal.recv().await;
+                tokio::time::sleep(Duration::from_millis(work)).await;
+                drop(ack);
+            });
+        }
+        let exited_early = bus.subscribe("exited early");
+        drop(exited_early);
+        let stuck = bus.subscribe("stuck");
+        let _stuck_ack = tokio::spawn(stuck.recv());
+
+        let report = bus.initiate(Duration::from_secs(1)).await;
+        assert!(bus.is_initiated());
+        assert_eq!(report.completed, vec!["cache", "metrics"]);
+        assert_eq!(report.timed_out, vec!["stuck"]);
+        assert_eq!(report.elapsed, Duration::from_secs(1));
+
+        // Late subscribers see shutdown at once
+        let late = bus.subscribe("late");
+        let ack = late.recv().await;
+        assert_eq!(ack.name(), "late");
+        drop(ack);
+
+        let report = bus.initiate(Duration::from_secs(1)).await;
+        assert_eq!(report.timed_out, vec!["stuck"]);
+        assert!(report.completed.contains(&"late".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_signal_bus_forgets_dropped_subscribers() {
+        use shutdown::SignalBus;
+        use std::time::Duration;
+
+        let bus = SignalBus::new();
+        for i in 0..3 {
+            drop(bus.subscribe(format!("short-lived {}", i)));
+        }
+        let worker = bus.subscribe("worker");
+        tokio::spawn(async move {
+            let _ack = worker.recv().await;
+        });
+
+        let report = bus.initiate(Duration::from_secs(1)).await;
+        assert_eq!(report.completed, vec!["worker"]);
+        assert!(report.timed_out.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_signal_bus_finishes_early() {
+        use shutdown::SignalBus;
+        use std::time::Duration;
+
+        let bus = SignalBus::new();
+        let signal = bus.subscribe("worker");
+        tokio::spawn(async move {
+            let _ack = signal.recv().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        });
+        let report = bus.initiate(Duration::from_secs(10)).await;
+        assert!(report.is_clean());
+        assert_eq!(report.elapsed, Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_codecs() {
+        use bytes::{Bytes, BytesMut};
+        use codec::{CodecError, Delimited, LengthPrefixed};
+        use futures::{SinkExt, StreamExt};
+        use tokio_util::codec::Decoder;
+
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = codec::framed(client, LengthPrefixed::new().max_frame_len(16));
+        let mut server = codec::length_prefixed(server);
+        client.send(Bytes::from_static(b"one")).await.unwrap();
+        client.send(Bytes::new()).await.unwrap();
+        assert!(matches!(
+            client.send(Bytes::from(vec![0; 17])).await,
+            Err(CodecError::FrameTooLarge { len: 17, max: 16 })
+        ));
+        assert_eq!(&server.next().await.unwrap().unwrap()[..], b"one");
+        assert!(server.next().await.unwrap().unwrap().is_empty());
+
+        // A delimiter split across reads is still found
+        let mut crlf = Delimited::new(b"\r\n".to_vec()).max_frame_len(8);
+        let mut buf = BytesMut::from(&b"GET /\r"[..]);
+        assert!(crlf.decode(&mut buf).unwrap().is_none());
+        buf.extend_from_slice(b"\nPING\r\ntail");
+        assert_eq!(&crlf.decode(&mut buf).unwrap().unwrap()[..], b"GET /");
+        assert_eq!(&crlf.decode(&mut buf).unwrap().unwrap()[..], b"PING");
+        assert!(crlf.decode(&mut buf).unwrap().is_none());
+        assert_eq!(&crlf.decode_eof(&mut buf).unwrap().unwrap()[..], b"tail");
+        buf.extend_from_slice(b"far too long");
+        assert!(matches!(
+            crlf.decode(&mut buf),
+            Err(CodecError::FrameTooLarge { .. })
+        ));
+
+        let mut truncated = BytesMut::from(&[0, 0, 0, 5, b'a'][..]);
+        assert!(matches!(
+            LengthPrefixed::new().decode_eof(&mut truncated),
+            Err(CodecError::Truncated)
+        ));
+
+        #[cfg(feature = "json")]
+        {
+            let (a, b) = tokio::io::duplex(256);
+            let mut a = codec::json::<_, Vec<u32>>(a);
+            let mut b = codec::json::<_, Vec<u32>>(b);
+            a.send(vec![1, 2, 3]).await.unwrap();
+            assert_eq!(b.next().await.unwrap().unwrap(), vec![1, 2, 3]);
+        }
+        #[cfg(feature = "bincode")]
+        {
+            let (a, b) = tokio::io::duplex(256);
+            let mut a = codec::bincode::<_, (String, u8)>(a);
+            let mut b = codec::bincode::<_, (String, u8)>(b);
+            a.send(("id".to_string(), 7)).await.unwrap();
+            assert_eq!(b.next().await.unwrap().unwrap(), ("id".to_string(), 7));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_hooks_and_services() {
+        use io::{Message, Server};
+        use service::{service_fn, BoxError, ServiceBuilder};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio_util::codec::LinesCodec;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let service = ServiceBuilder::new()
+            .concurrency_limit(4)
+            .service(service_fn(|msg: Message<String>| async move {
+                if msg.body == "quit" {
+                    return Err::<String, BoxError>("client quit".into());
+                }
+                Ok(format!(
+                    "#{} {}",
+                    msg.connection.id,
+                    msg.body.to_uppercase()
+                ))
+            }));
+        let server = Server::builder("upper")
+            .on_connect({
+                let events = events.clone();
+                move |conn| events.lock().unwrap().push(format!("connect {}", conn.id))
+            })
+            .on_disconnect({
+                let events = events.clone();
+                move |conn| {
+                    events
+                        .lock()
+                        .unwrap()
+                        .push(format!("disconnect {}", conn.id))
+                }
+            })
+            .serve(
+                tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap(),
+                LinesCodec::new(),
+                service,
+            );
+        let addr = server.local_addr().unwrap();
+
+        let mut client = BufReader::new(tokio::net::TcpStream::connect(addr).await.unwrap());
+        client.write_all(b"hello\n").await.unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "#0 HELLO\n");
+
+        // A service error closes the connection
+        client.write_all(b"quit\n").await.unwrap();
+        line.clear();
+        assert_eq!(client.read_line(&mut line).await.unwrap(), 0);
+        while server.connections() > 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(*events.lock().unwrap(), ["connect 0", "disconnect 0"]);
+
+        let mut idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        while server.connections() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let report = server.shutdown_phased(Default::default()).await;
+        assert!(report.is_clean());
+        let mut rest = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut idle, &mut rest)
+            .await
+            .unwrap();
+        assert_eq!(events.lock().unwrap()[2..], ["connect 1", "disconnect 1"]);
+    }
+
+    #[tokio::test]
+    async fn test_discovery_beacon() {
+        use futures::StreamExt;
+        use io::{Beacon, DiscoveryConfig, DiscoveryEvent, DiscoveryListener};
+        use std::time::Duration;
+
+        async fn next(listener: &mut DiscoveryListener) -> DiscoveryEvent {
+            let event = tokio::time::timeout(Duration::from_secs(5), listener.next()).await;
+            event.unwrap().unwrap().unwrap()
+        }
+
+        let mut config = DiscoveryConfig {
+            group: "127.0.0.1:0".parse().unwrap(),
+            interval: Duration::from_millis(20),
+            ttl: Duration::from_millis(200),
+        };
+        let mut listener = DiscoveryListener::bind(config.clone()).await.unwrap();
+        config.group = listener.local_addr().unwrap();
+
+        // Repeated announcements report the peer once, and dropping the
+        // beacon takes it down without waiting for the TTL
+        let beacon = Beacon::start("kv store", 6379, config.clone())
+            .await
+            .unwrap();
+        let DiscoveryEvent::Up(peer) = next(&mut listener).await else {
+            panic!("expected the peer to come up");
+        };
+        assert_eq!(peer.name, "kv store");
+        assert_eq!(peer.addr, "127.0.0.1:6379".parse().unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(beacon);
+        assert_eq!(next(&mut listener).await, DiscoveryEvent::Down(peer));
+
+        // A peer that goes quiet expires after the TTL
+        let quiet = DiscoveryConfig {
+            interval: Duration::from_secs(60),
+            ..config
+        };
+        let _beacon = Beacon::start("cache", 11211, quiet).await.unwrap();
+        let DiscoveryEvent::Up(peer) = next(&mut listener).await else {
+            panic!("expected the peer to come up");
+        };
+        let started = tokio::time::Instant::now();
+        assert_eq!(next(&mut listener).await, DiscoveryEvent::Down(peer));
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(listener.live(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_crawl() {
+        use crawl::{crawl, Crawler, Fetched};
+        use futures::StreamExt;
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        // 1 -> 2 -> 4 -> 5 with a cycle back from 2 and a diamond through 3
+        let graph: Arc<HashMap<u32, Vec<u32>>> = Arc::new(HashMap::from([
+            (1, vec![2, 3]),
+            (2, vec![4, 1]),
+            (3, vec![4]),
+            (4, vec![5]),
+        ]));
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let fetch = {
+            let (graph, peak) = (graph.clone(), peak.clone());
+            move |n: u32| {
+                let (graph, running, peak) = (graph.clone(), running.clone(), peak.clone());
+                async move {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    match graph.get(&n) {
+                        Some(links) => Ok(Fetched::new(n * 10, links.clone())),
+                        None => Err(format!("{} not found", n)),
+                    }
+                }
+            }
+        };
+
+        let mut results: Vec<_> = crawl([1], 2, fetch.clone()).collect().await;
+        results.sort_by_key(|found| found.item);
+        let found: Vec<_> = results
+            .iter()
+            .map(|found| (found.item, found.depth, found.result.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (1, 0, Ok(10)),
+                (2, 1, Ok(20)),
+                (3, 1, Ok(30)),
+                (4, 2, Ok(40)),
+                (5, 3, Err("5 not found".to_string())),
+            ]
+        );
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+
+        let revisits = Arc::new(Mutex::new(Vec::new()));
+        let crawl = Crawler::new(fetch)
+            .max_depth(2)
+            .follow(|&n, _| n != 3)
+            .on_revisit({
+                let revisits = revisits.clone();
+                move |&n, depth| revisits.lock().unwrap().push((n, depth))
+            })
+            .run([1]);
+        let mut items: Vec<_> = crawl.map(|found| found.item).collect().await;
+        items.sort();
+        assert_eq!(items, [1, 2, 4]);
+        // 2 links back to the seed, and 5 is too deep
+        assert_eq!(*revisits.lock().unwrap(), [(1, 2)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sender_ext() {
+        use channels::{SendOutcome, SenderExt};
+        use select::Deadline;
+        use std::time::Duration;
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        assert!(tx.send_or_drop(1).is_sent());
+        assert_eq!(tx.send_or_drop(2), SendOutcome::Full(2));
+
+        let deadline = Deadline::after(Duration::from_millis(50));
+        assert_eq!(tx.send_deadline(3, deadline).await, SendOutcome::Full(3));
+
+        // Room made before the deadline is used
+        let reader = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let first = rx.recv().await;
+            (first, rx)
+        });
+        let deadline = Deadline::after(Duration::from_millis(50));
+        assert_eq!(
+            tx.send_deadline(4, deadline).await,
+            SendOutcome::Sent {
+                waited: Duration::from_millis(10)
+            }
+        );
+        let (first, mut rx) = reader.await.unwrap();
+        assert_eq!(first, Some(1));
+        assert_eq!(rx.recv().await, Some(4));
+
+        // An expired deadline still sends when there is room
+        let expired = Deadline::after(Duration::ZERO);
+        assert!(tx.send_deadline(5, expired).await.is_sent());
+        drop(rx);
+        assert_eq!(tx.send_or_drop(6).into_inner(), Some(6));
+        assert_eq!(tx.send_deadline(7, expired).await, SendOutcome::Closed(7));
+
+        let (traced, _rx) = channels::traced_channel("traced", 1);
+        assert!(traced.send_or_drop("a").is_sent());
+        let deadline = Deadline::after(Duration::from_millis(5));
+        assert_eq!(
+            traced.send_deadline("b", deadline).await,
+            SendOutcome::Full("b")
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_adaptive_channel() {
+        use channels::{AdaptiveChannel, AdaptiveChannelConfig, ResizeReason};
+        use std::sync::{Arc, Mutex};
+        use std::time::Duration;
+
+        let config = AdaptiveChannelConfig {
+            initial: 2,
+            min: 2,
+            max: 8,
+            window: Duration::from_millis(10),
+            grow_after_stall: Duration::from_millis(1),
+            shrink_below: 0.5,
+        };
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        let (tx, mut rx) = AdaptiveChannel::new(config)
+            .on_resize(move |event| seen.lock().unwrap().push(event.clone()))
+            .build();
+
+        // A slow consumer stalls the producer until the queue has grown
+        let producer = tx.clone();
+        tokio::spawn(async move {
+            for i in 0..60 {
+                producer.send(i).await.unwrap();
+            }
+        });
+        for expected in 0..60 {
+            assert_eq!(rx.recv().await, Some(expected));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(tx.capacity(), 8);
+        let grown: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| (e.from, e.to))
+            .collect();
+        assert_eq!(grown, vec![(2, 4), (4, 8)]);
+        assert!(events
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|e| matches!(e.reason, ResizeReason::Stalled { .. })));
+
+        // A trickle shrinks it back down
+        events.lock().unwrap().clear();
+        for i in 0..4 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            tx.send(i).await.unwrap();
+            assert_eq!(rx.recv().await, Some(i));
+        }
+        assert_eq!(tx.capacity(), 2);
+        let shrunk: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| (e.from, e.to))
+            .collect();
+        assert_eq!(shrunk, vec![(8, 4), (4, 2)]);
+
+        drop(tx);
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_commit_barrier() {
+        use futures::FutureExt;
+        use shared_state::{CommitBarrier, CommitError, Committed};
+        use std::time::Duration;
+
+        let barrier = CommitBarrier::new(Duration::from_secs(1));
+        let mut producers: Vec<_> = (0..3).map(|_| barrier.register()).collect();
+        let mut straggler = producers.pop().unwrap();
+        assert_eq!(barrier.producers(), 3);
+
+        // Every producer stages, so the round commits without waiting
+        let staging: Vec<_> = (0..3)
+            .zip(producers.drain(..).chain([straggler]))
+            .map(|(i, mut producer)| {
+                tokio::spawn(async move { (producer.stage(i).await, producer) })
+            })
+            .collect();
+        let started = tokio::time::Instant::now();
+        let mut batch = barrier
+            .commit(|items| async move { Ok::<_, ()>(items) })
+            .await
+            .unwrap();
+        batch.sort();
+        assert_eq!(batch, vec![0, 1, 2]);
+        assert!(started.elapsed() < Duration::from_millis(10));
+        let mut producers = Vec::new();
+        for staging in staging {
+            let (outcome, producer) = staging.await.unwrap();
+            assert_eq!(outcome, Ok(Committed { round: 1, items: 3 }));
+            producers.push(producer);
+        }
+
+        // A straggler holds the round up for the timeout only, counted from
+        // the first item staged, and a failed commit aborts the round for
+        // everyone in it
+        straggler = producers.pop().unwrap();
+        let staging: Vec<_> = producers
+            .drain(..)
+            .map(|mut producer| tokio::spawn(async move { producer.stage(10).await }))
+            .collect();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        let started = tokio::time::Instant::now();
+        let result = barrier
+            .commit(|items| async move { Err::<(), _>(items.len()) })
+            .await;
+        assert_eq!(result, Err(2));
+        assert_eq!(started.elapsed(), Duration::from_millis(400));
+        for staging in staging {
+            assert_eq!(
+                staging.await.unwrap(),
+                Err(CommitError::Aborted { round: 2 })
+            );
+        }
+        assert_eq!(barrier.producers(), 1);
+
+        // Staging again in the same round replaces the earlier item
+        assert!(straggler.stage(20).now_or_never().is_none());
+        let staging = tokio::spawn(async move { (straggler.stage(21).await, straggler) });
+        tokio::task::yield_now().await;
+        assert_eq!(barrier.staged(), 1);
+        let batch = barrier
+            .commit(|items| async move { Ok::<_, ()>(items) })
+            .await
+            .unwrap();
+        assert_eq!(batch, vec![21]);
+        let (outcome, mut straggler) = staging.await.unwrap();
+        assert_eq!(outcome, Ok(Committed { round: 3, items: 1 }));
+
+        // Dropping the barrier fails what is staged
+        let staged = tokio::spawn(async move { straggler.stage(30).await });
+        tokio::task::yield_now().await;
+        assert_eq!(barrier.staged(), 1);
+        drop(barrier);
+        assert_eq!(staged.await.unwrap(), Err(CommitError::Abandoned));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_accept_policy() {
+        use io::{AcceptAction, AcceptErrorKind, AcceptPolicy, Acceptor};
+        use std::io::{Error, ErrorKind};
+        use std::sync::{Arc, Mutex};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let kind = |e: Error| AcceptErrorKind::of(&e);
+        assert_eq!(
+            kind(Error::from_raw_os_error(libc::EMFILE)),
+            AcceptErrorKind::Resources
+        );
+        assert_eq!(
+            kind(Error::from_raw_os_error(libc::ECONNABORTED)),
+            AcceptErrorKind::Connection
+        );
+        assert_eq!(
+            kind(Error::from_raw_os_error(libc::EBADF)),
+            AcceptErrorKind::Fatal
+        );
+        assert_eq!(kind(ErrorKind::InvalidInput.into()), AcceptErrorKind::Fatal);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = socket2::SockRef::from(&listener).try_clone().unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let seen = errors.clone();
+        let policy = AcceptPolicy::new().on_error(move |e, action| {
+            seen.lock().unwrap().push((AcceptErrorKind::of(e), action));
+        });
+        let server = tokio::spawn(io::serve_traced(
+            Acceptor::new(listener, policy),
+            "echo",
+            |mut socket, _peer, _shutdown| async move {
+                let (mut reader, mut writer) = socket.split();
+                tokio::io::copy(&mut reader, &mut writer).await.map(|_| ())
+            },
+            std::future::pending(),
+        ));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        // A listener shut down under the server fails every accept for good
+        handle.shutdown(std::net::Shutdown::Both).unwrap();
+        let err = server.await.unwrap().unwrap_err();
+        assert_eq!(AcceptErrorKind::of(&err), AcceptErrorKind::Fatal);
+        assert_eq!(
+            *errors.lock().unwrap(),
+            vec![(AcceptErrorKind::Fatal, AcceptAction::Abort)]
+        );
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn test_listener_handoff() {
+        use io::{ListenerHandoff, Server, ShutdownConfig};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        fn named(
+            name: &'static str,
+        ) -> impl Fn(
+            tokio::net::TcpStream,
+            std::net::SocketAddr,
+            tokio::sync::watch::Receiver<bool>,
+        ) -> futures::future::BoxFuture<'static, std::io::Result<()>> {
+            move |mut socket, _peer, _shutdown| {
+                Box::pin(async move {
+                    socket.write_all(name.as_bytes()).await?;
+                    let mut buf = [0; 4];
+                    while socket.read(&mut buf).await? > 0 {}
+                    Ok(())
+                })
+            }
+        }
+        async fn greeting(addr: std::net::SocketAddr) -> std::io::Result<[u8; 3]> {
+            let mut socket = tokio::net::TcpStream::connect(addr).await?;
+            let mut name = [0; 3];
+            socket.read_exact(&mut name).await?;
+            Ok(name)
+        }
+
+        let (handoff, listener) = ListenerHandoff::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = handoff.local_addr();
+        let mut old = Server::start(listener, "old", named("old"));
+        let mut kept = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut name = [0; 3];
+        kept.read_exact(&mut name).await.unwrap();
+        assert_eq!(&name, b"old");
+
+        // Clients connecting during the handoff are served by one or the
+        // other. The handoff isn't zero-downtime: those landing in the old
+        // queue just as it closes are reset, unless the kernel migrates them.
+        let clients: Vec<_> = (0..50).map(|_| tokio::spawn(greeting(addr))).collect();
+        let new = handoff
+            .replace(&mut old, |listener| {
+                Server::start(listener, "new", named("new"))
+            })
+            .await
+            .unwrap();
+        let mut resets = 0;
+        for client in clients {
+            match client.await.unwrap() {
+                Ok(name) => assert!(&name == b"old" || &name == b"new"),
+                Err(e) => {
+                    assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset);
+                    resets += 1;
+                }
+            }
+        }
+        let migrated = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_migrate_req")
+            .is_ok_and(|enabled| enabled.trim() == "1");
+        let allowed = if migrated { 0 } else { 5 };
+        assert!(resets <= allowed, "{} of 50 clients were reset", resets);
+        assert_eq!(&greeting(addr).await.unwrap(), b"new");
+
+        // The old server still has its connections until it shuts down
+        assert!(old.connections() >= 1);
+        kept.write_all(b"ping").await.unwrap();
+        drop(kept);
+        let report = old.shutdown_phased(ShutdownConfig::default()).await;
+        assert_eq!(report.forced, 0);
+        new.shutdown_phased(ShutdownConfig::default()).await;
+    }
 }
@@ -0,0 +1,159 @@
+//! A minimal in-process metrics registry
+//!
+//! Metrics are registered by name and handed out as cheap clone handles:
+//! a [`Counter`] only goes up, a [`Gauge`] holds the latest value, and
+//! latencies reuse [`LatencyRecorder`]. [`Registry::snapshot`] reads them
+//! all at once, e.g. for a stats endpoint or a periodic log line, and
+//! [`Registry::restore`] loads counters and gauges back from one, so a
+//! service can carry them across a restart. With the `serde` feature
+//! snapshots can be serialized. Patterns that report metrics of their own
+//! use [`Registry::global`].
+
+use crate::select::{LatencyRecorder, LatencySummary};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Samples kept by latency metrics created through a registry
+const LATENCY_WINDOW: usize = 1024;
+
+/// A monotonically increasing count
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+}
+
+/// A value that can go up and down
+#[derive(Debug, Clone, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Clone)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Latency(LatencyRecorder),
+}
+
+/// The value of one metric at the time of a [`Registry::snapshot`]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MetricValue {
+    Counter(u64),
+    Gauge(f64),
+    Latency(LatencySummary),
+}
+
+/// Named metrics shared by all clones of the registry
+#[derive(Clone, Default)]
+pub struct Registry {
+    metrics: Arc<Mutex<BTreeMap<String, Metric>>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The process-wide registry
+    pub fn global() -> &'static Registry {
+        static GLOBAL: OnceLock<Registry> = OnceLock::new();
+        GLOBAL.get_or_init(Registry::new)
+    }
+
+    /// The counter called `name`, created on first use
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is already registered as a different kind of metric,
+    /// as do [`gauge`](Self::gauge) and [`latency`](Self::latency).
+    pub fn counter(&self, name: &str) -> Counter {
+        match self.get_or_insert(name, || Metric::Counter(Counter::default())) {
+            Metric::Counter(counter) => counter,
+            _ => panic!("metric `{}` is not a counter", name),
+        }
+    }
+
+    pub fn gauge(&self, name: &str) -> Gauge {
+        match self.get_or_insert(name, || Metric::Gauge(Gauge::default())) {
+            Metric::Gauge(gauge) => gauge,
+            _ => panic!("metric `{}` is not a gauge", name),
+        }
+    }
+
+    /// A latency recorder keeping the most recent 1024 samples
+    pub fn latency(&self, name: &str) -> LatencyRecorder {
+        let new = || Metric::Latency(LatencyRecorder::new(LATENCY_WINDOW));
+        match self.get_or_insert(name, new) {
+            Metric::Latency(recorder) => recorder,
+            _ => panic!("metric `{}` is not a latency", name),
+        }
+    }
+
+    /// Current values of every metric, by name
+    pub fn snapshot(&self) -> BTreeMap<String, MetricValue> {
+        let metrics = self.metrics.lock().unwrap();
+        metrics
+            .iter()
+            .map(|(name, metric)| {
+                let value = match metric {
+                    Metric::Counter(counter) => MetricValue::Counter(counter.get()),
+                    Metric::Gauge(gauge) => MetricValue::Gauge(gauge.get()),
+                    Metric::Latency(recorder) => MetricValue::Latency(recorder.summary()),
+                };
+                (name.clone(), value)
+            })
+            .collect()
+    }
+
+    /// Sets counters and gauges to their values in `snapshot`, creating
+    /// any that don't exist yet
+    ///
+    /// Handles already given out see the restored values. Latencies are
+    /// skipped, as a summary can't be turned back into samples, and metrics
+    /// missing from `snapshot` keep their values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a name in `snapshot` is registered as a different kind of
+    /// metric.
+    pub fn restore(&self, snapshot: &BTreeMap<String, MetricValue>) {
+        for (name, value) in snapshot {
+            match value {
+                MetricValue::Counter(count) => self.counter(name).set(*count),
+                MetricValue::Gauge(value) => self.gauge(name).set(*value),
+                MetricValue::Latency(_) => {}
+            }
+        }
+    }
+
+    fn get_or_insert(&self, name: &str, new: impl FnOnce() -> Metric) -> Metric {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.entry(name.to_string()).or_insert_with(new).clone()
+    }
+}
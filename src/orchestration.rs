@@ -0,0 +1,264 @@
+//! Multi-step workflows that undo themselves when a step fails
+//!
+//! A [`Saga`] runs a fixed list of steps in order. Each step is an action
+//! plus a compensation that undoes it: book a flight / cancel the booking,
+//! charge a card / refund it. If a step fails, after its own retries, the
+//! steps that already completed are compensated in reverse order, leaving
+//! the system as it was as far as each compensation can manage. The
+//! compensation gets whatever its action returned, e.g. a reservation id.
+
+use crate::select::{retry, RetryPolicy};
+use futures::future::BoxFuture;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Progress reported by a running [`Saga`], named by step
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SagaEvent {
+    Started(String),
+    Completed(String),
+    /// The action failed for good; compensation starts next
+    Failed {
+        step: String,
+        error: String,
+    },
+    Compensating(String),
+    Compensated(String),
+    CompensationFailed {
+        step: String,
+        error: String,
+    },
+}
+
+/// Why a [`Saga`] did not complete
+#[derive(Debug)]
+pub struct SagaError<E> {
+    /// The step whose action failed
+    pub step: String,
+    pub error: E,
+    /// Completed steps whose compensation also failed, in the order they
+    /// were attempted
+    pub compensation_failures: Vec<(String, E)>,
+}
+
+impl<E> SagaError<E> {
+    /// Whether every completed step was undone
+    pub fn is_fully_compensated(&self) -> bool {
+        self.compensation_failures.is_empty()
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for SagaError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step `{}` failed: {}", self.step, self.error)?;
+        if !self.is_fully_compensated() {
+            write!(
+                f,
+                " ({} compensations failed)",
+                self.compensation_failures.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for SagaError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+type Undo<E> = Arc<dyn Fn() -> BoxFuture<'static, Result<(), E>> + Send + Sync>;
+type Action<C, E> = Box<dyn Fn(C) -> BoxFuture<'static, Result<Undo<E>, E>> + Send + Sync>;
+
+/// One step of a [`Saga`]: an action and the compensation that undoes it
+pub struct SagaStep<C, E> {
+    name: String,
+    action: Action<C, E>,
+    retry: RetryPolicy<E>,
+    compensation_retry: RetryPolicy<E>,
+}
+
+impl<C, E> SagaStep<C, E>
+where
+    C: Clone + Send + Sync + 'static,
+    E: Send + 'static,
+{
+    /// A step that runs `action` once, and `compensate` with its output
+    /// once, should a later step fail
+    pub fn new<A, AFut, T, U, UFut>(name: impl Into<String>, action: A, compensate: U) -> Self
+    where
+        A: Fn(C) -> AFut + Send + Sync + 'static,
+        AFut: Future<Output = Result<T, E>> + Send + 'static,
+        T: Clone + Send + Sync + 'static,
+        U: Fn(C, T) -> UFut + Send + Sync + 'static,
+        UFut: Future<Output = Result<(), E>> + Send + 'static,
+    {
+        let compensate = Arc::new(compensate);
+        let action: Action<C, E> = Box::new(move |ctx: C| {
+            let running = action(ctx.clone());
+            let compensate = compensate.clone();
+            Box::pin(async move {
+                let output = running.await?;
+                let undo: Undo<E> = Arc::new(move || {
+                    Box::pin(compensate(ctx.clone(), output.clone())) as BoxFuture<'static, _>
+                });
+                Ok(undo)
+            })
+        });
+
+        let once = RetryPolicy::fixed(Duration::ZERO).max_attempts(Some(1));
+        Self {
+            name: name.into(),
+            action,
+            retry: once.clone(),
+            compensation_retry: once,
+        }
+    }
+
+    /// Retries the action according to `policy` before giving up on it
+    pub fn retry(mut self, policy: RetryPolicy<E>) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Retries the compensation according to `policy`
+    pub fn compensation_retry(mut self, policy: RetryPolicy<E>) -> Self {
+        self.compensation_retry = policy;
+        self
+    }
+}
+
+/// An ordered list of compensable steps over a shared context `C`
+///
+/// The context, e.g. an order id and the clients the steps need, is cloned
+/// into every action and compensation. A saga can be [run](Saga::run) any
+/// number of times.
+///
+/// ```
+/// # async fn example() {
+/// use tokio_tutorial_patterns::orchestration::{Saga, SagaStep};
+///
+/// let saga = Saga::new()
+///     .step(SagaStep::new(
+///         "reserve stock",
+///         |order: u64| async move { Ok::<_, String>(format!("reservation-{}", order)) },
+///         |_order, reservation: String| async move {
+///             println!("releasing {}", reservation);
+///             Ok(())
+///         },
+///     ))
+///     .step(SagaStep::new(
+///         "charge card",
+///         |_order: u64| async { Err::<(), _>("card declined".to_string()) },
+///         |_order, ()| async { Ok(()) },
+///     ));
+///
+/// let failed = saga.run(42).await.unwrap_err();
+/// assert_eq!(failed.step, "charge card");
+/// assert!(failed.is_fully_compensated()); // the reservation was released
+/// # }
+/// ```
+pub struct Saga<C, E> {
+    steps: Vec<SagaStep<C, E>>,
+    events: Option<mpsc::UnboundedSender<SagaEvent>>,
+}
+
+impl<C, E> Saga<C, E>
+where
+    C: Clone + Send + Sync + 'static,
+    E: fmt::Display + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self {
+            steps: Vec::new(),
+            events: None,
+        }
+    }
+
+    /// Adds a step after the ones added so far
+    pub fn step(mut self, step: SagaStep<C, E>) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Reports progress of every run on `events`
+    pub fn events(mut self, events: mpsc::UnboundedSender<SagaEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Runs every step in order, compensating the completed ones in reverse
+    /// if one fails
+    ///
+    /// Compensation carries on past a failed compensation, so one stuck
+    /// undo doesn't stop the others; the failures are collected in the
+    /// returned [`SagaError`].
+    pub async fn run(&self, ctx: C) -> Result<(), SagaError<E>> {
+        let mut completed: Vec<(&str, Undo<E>, &RetryPolicy<E>)> = Vec::new();
+
+        for step in &self.steps {
+            self.emit(SagaEvent::Started(step.name.clone()));
+            let result = retry(step.retry.clone(), || (step.action)(ctx.clone())).await;
+            match result {
+                Ok(undo) => {
+                    self.emit(SagaEvent::Completed(step.name.clone()));
+                    completed.push((&step.name, undo, &step.compensation_retry));
+                }
+                Err(error) => {
+                    self.emit(SagaEvent::Failed {
+                        step: step.name.clone(),
+                        error: error.to_string(),
+                    });
+                    let compensation_failures = self.compensate(completed).await;
+                    return Err(SagaError {
+                        step: step.name.clone(),
+                        error,
+                        compensation_failures,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn compensate(
+        &self,
+        completed: Vec<(&str, Undo<E>, &RetryPolicy<E>)>,
+    ) -> Vec<(String, E)> {
+        let mut failures = Vec::new();
+        for (name, undo, policy) in completed.into_iter().rev() {
+            self.emit(SagaEvent::Compensating(name.to_string()));
+            match retry(policy.clone(), || undo()).await {
+                Ok(()) => self.emit(SagaEvent::Compensated(name.to_string())),
+                Err(error) => {
+                    self.emit(SagaEvent::CompensationFailed {
+                        step: name.to_string(),
+                        error: error.to_string(),
+                    });
+                    failures.push((name.to_string(), error));
+                }
+            }
+        }
+        failures
+    }
+
+    fn emit(&self, event: SagaEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+}
+
+impl<C, E> Default for Saga<C, E>
+where
+    C: Clone + Send + Sync + 'static,
+    E: fmt::Display + Send + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,614 @@
+//! Multi-stage processing pipelines with typed stages
+//!
+//! A [`Pipeline`] is a chain of stages, each an async transform run by its
+//! own task with a fixed concurrency limit and linked to the next by a
+//! bounded channel. A slow stage fills the channel in front of it, which
+//! stalls the stages before it and finally [`Pipeline::send`], so memory use
+//! stays bounded end to end. Stages keep items in order. Items that fail are
+//! counted and handed to an error handler instead of being passed on, and
+//! closing the input lets everything already inside finish before the
+//! output ends.
+//!
+//! A stage whose output channel stays full for longer than the
+//! [`BackpressureConfig`] threshold is stalled: it reports a
+//! [`BackpressureEvent`] and applies the configured [`Mitigation`], which
+//! can drop or sample its output, or hold back the source until the stall
+//! clears.
+
+use crate::metrics::{Counter, Registry};
+use crate::spawning::spawn_traced;
+use futures::StreamExt;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Error type stages can fail with
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An item that failed in a stage
+#[derive(Debug)]
+pub struct StageError {
+    pub stage: String,
+    pub error: BoxError,
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stage `{}` failed: {}", self.stage, self.error)
+    }
+}
+
+impl std::error::Error for StageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// Returned by [`Pipeline::send`] with the rejected item once the pipeline no
+/// longer accepts input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineClosed<T>(pub T);
+
+impl<T> fmt::Display for PipelineClosed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pipeline is closed")
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for PipelineClosed<T> {}
+
+/// What a stalled stage does with its output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mitigation {
+    /// Keep waiting; the stall is only reported
+    Wait,
+    /// Drop every item until the next stage has room again
+    Shed,
+    /// Pass on one item in every `n`, waiting for room for it, and drop the
+    /// rest until the next stage has room again
+    Sample(u32),
+    /// Keep waiting, and hold back [`Pipeline::send`] until the stall
+    /// clears
+    PauseSource,
+}
+
+/// When a stage counts as stalled and what it does about it
+#[derive(Clone)]
+pub struct BackpressureConfig {
+    /// How long a stage may wait for room in the next stage before it is
+    /// stalled
+    pub stall_threshold: Duration,
+    pub mitigation: Mitigation,
+    /// Also counts stalls and shed items here, as
+    /// `pipeline.<stage>.stalls` and `pipeline.<stage>.shed`
+    pub registry: Option<Registry>,
+}
+
+impl Default for BackpressureConfig {
+    /// Reports stalls longer than a second and keeps waiting
+    fn default() -> Self {
+        Self {
+            stall_threshold: Duration::from_secs(1),
+            mitigation: Mitigation::Wait,
+            registry: None,
+        }
+    }
+}
+
+/// A change in a stage's ability to pass items on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackpressureEvent {
+    /// `stage` has waited longer than the threshold for room in
+    /// `downstream`, the next stage or `"output"`
+    Stalled { stage: String, downstream: String },
+    /// `stage` can pass items on again, or has finished
+    Recovered {
+        stage: String,
+        downstream: String,
+        stalled_for: Duration,
+    },
+}
+
+/// Counters for one stage, from [`Pipeline::metrics`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageMetrics {
+    pub name: String,
+    pub concurrency: usize,
+    /// Items taken from the previous stage and not yet passed on
+    pub in_flight: usize,
+    pub processed: u64,
+    pub failed: u64,
+    /// Times the stage has stalled
+    pub stalls: u64,
+    /// Items dropped by [`Mitigation::Shed`] or [`Mitigation::Sample`]
+    pub shed: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    in_flight: AtomicUsize,
+    processed: AtomicU64,
+    failed: AtomicU64,
+    stalls: AtomicU64,
+    shed: AtomicU64,
+}
+
+struct StageInfo {
+    name: String,
+    concurrency: usize,
+    counters: Arc<Counters>,
+}
+
+type ErrorHandler = Arc<dyn Fn(StageError) + Send + Sync>;
+type BackpressureHandler = Arc<dyn Fn(BackpressureEvent) + Send + Sync>;
+
+/// Backpressure settings shared by every stage
+struct Backpressure {
+    config: BackpressureConfig,
+    on_event: BackpressureHandler,
+    /// Stages currently holding back the source
+    pausing: watch::Sender<usize>,
+}
+
+struct Links {
+    capacity: usize,
+    on_error: ErrorHandler,
+    backpressure: Arc<Backpressure>,
+    /// Every stage's name, in order
+    names: Vec<String>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+type Connect<I, O> = Box<dyn FnOnce(mpsc::Receiver<I>, &mut Links) -> mpsc::Receiver<O> + Send>;
+
+/// Builds a [`Pipeline`] one stage at a time
+///
+/// Created by [`Pipeline::builder`]. Each stage takes the previous stage's
+/// output type, so the chain is type checked as it is written.
+pub struct PipelineBuilder<I, O> {
+    capacity: usize,
+    on_error: ErrorHandler,
+    backpressure: BackpressureConfig,
+    on_backpressure: BackpressureHandler,
+    stages: Vec<StageInfo>,
+    connect: Connect<I, O>,
+}
+
+impl<I: Send + 'static, O: Send + 'static> PipelineBuilder<I, O> {
+    /// Capacity of the input and of the channel after each stage; defaults
+    /// to 16
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "`capacity` must be non-zero");
+        self.capacity = capacity;
+        self
+    }
+
+    /// Called with every item that fails in any stage; by default failures
+    /// are only counted
+    pub fn on_error(mut self, handler: impl Fn(StageError) + Send + Sync + 'static) -> Self {
+        self.on_error = Arc::new(handler);
+        self
+    }
+
+    /// How stalls are detected and handled; by default stalls longer than
+    /// a second are reported and stages keep waiting
+    pub fn backpressure(mut self, config: BackpressureConfig) -> Self {
+        self.backpressure = config;
+        self
+    }
+
+    /// Called whenever a stage stalls or recovers; by default stalls are
+    /// only counted
+    pub fn on_backpressure(
+        mut self,
+        handler: impl Fn(BackpressureEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_backpressure = Arc::new(handler);
+        self
+    }
+
+    /// Appends a stage that runs `f` on up to `concurrency` items at once
+    ///
+    /// Items for which `f` returns an error go to the error handler and are
+    /// not passed on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `concurrency` is zero.
+    pub fn stage<U, F, Fut, E>(
+        mut self,
+        name: impl Into<String>,
+        concurrency: usize,
+        f: F,
+    ) -> PipelineBuilder<I, U>
+    where
+        U: Send + 'static,
+        F: Fn(O) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<U, E>> + Send + 'static,
+        E: Into<BoxError> + Send + 'static,
+    {
+        assert!(concurrency > 0, "`concurrency` must be non-zero");
+
+        let name = name.into();
+        let index = self.stages.len();
+        let counters = Arc::new(Counters::default());
+        self.stages.push(StageInfo {
+            name: name.clone(),
+            concurrency,
+            counters: counters.clone(),
+        });
+
+        let previous = self.connect;
+        let connect: Connect<I, U> = Box::new(move |input, links| {
+            let input = previous(input, links);
+            let (tx, output) = mpsc::channel(links.capacity);
+            let downstream = links.names.get(index + 1).map_or("output", String::as_str);
+            let stage = Stage {
+                downstream: downstream.to_string(),
+                name,
+                concurrency,
+                counters,
+                on_error: links.on_error.clone(),
+                backpressure: links.backpressure.clone(),
+            };
+            let task_name = format!("pipeline stage {}", stage.name);
+            links
+                .tasks
+                .push(spawn_traced(&task_name, stage.run(input, tx, f)));
+            output
+        });
+
+        PipelineBuilder {
+            capacity: self.capacity,
+            on_error: self.on_error,
+            backpressure: self.backpressure,
+            on_backpressure: self.on_backpressure,
+            stages: self.stages,
+            connect,
+        }
+    }
+
+    /// Appends a stage that can't fail
+    ///
+    /// # Panics
+    ///
+    /// Panics if `concurrency` is zero.
+    pub fn map<U, F, Fut>(
+        self,
+        name: impl Into<String>,
+        concurrency: usize,
+        f: F,
+    ) -> PipelineBuilder<I, U>
+    where
+        U: Send + 'static,
+        F: Fn(O) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = U> + Send + 'static,
+    {
+        let f = Arc::new(f);
+        self.stage(name, concurrency, move |item| {
+            let f = f.clone();
+            async move { Ok::<_, std::convert::Infallible>(f(item).await) }
+        })
+    }
+
+    /// Spawns a task per stage and starts accepting input
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn build(self) -> Pipeline<I, O> {
+        let (input, rx) = mpsc::channel(self.capacity);
+        let (pausing, paused) = watch::channel(0);
+        let mut links = Links {
+            capacity: self.capacity,
+            on_error: self.on_error,
+            backpressure: Arc::new(Backpressure {
+                config: self.backpressure,
+                on_event: self.on_backpressure,
+                pausing,
+            }),
+            names: self.stages.iter().map(|stage| stage.name.clone()).collect(),
+            tasks: Vec::new(),
+        };
+        let output = (self.connect)(rx, &mut links);
+
+        Pipeline {
+            input: Some(input),
+            output,
+            paused,
+            stages: self.stages,
+            tasks: links.tasks,
+        }
+    }
+}
+
+struct Stage {
+    name: String,
+    downstream: String,
+    concurrency: usize,
+    counters: Arc<Counters>,
+    on_error: ErrorHandler,
+    backpressure: Arc<Backpressure>,
+}
+
+/// A stage's view of its own stall
+struct Stall {
+    since: Instant,
+    /// Items seen while stalled, for [`Mitigation::Sample`]
+    seen: u32,
+    stalls: Option<Counter>,
+    shed: Option<Counter>,
+}
+
+impl Stage {
+    /// Passes `item` on, detecting and mitigating stalls; returns false
+    /// once the rest of the pipeline is gone
+    async fn deliver<U>(
+        &self,
+        output: &mpsc::Sender<U>,
+        item: U,
+        stall: &mut Option<Stall>,
+    ) -> bool {
+        let item = match output.try_send(item) {
+            Ok(()) => {
+                self.recover(stall);
+                return true;
+            }
+            Err(TrySendError::Closed(_)) => return false,
+            Err(TrySendError::Full(item)) => item,
+        };
+
+        match stall {
+            Some(current) => current.seen += 1,
+            None => {
+                let threshold = self.backpressure.config.stall_threshold;
+                match tokio::time::timeout(threshold, output.reserve()).await {
+                    Ok(Ok(permit)) => {
+                        permit.send(item);
+                        return true;
+                    }
+                    Ok(Err(_)) => return false,
+                    Err(_) => self.stall(stall),
+                }
+            }
+        }
+
+        let current = stall.as_ref().expect("stage is stalled");
+        let keep = match self.backpressure.config.mitigation {
+            Mitigation::Wait | Mitigation::PauseSource => {
+                let sent = output.send(item).await.is_ok();
+                self.recover(stall);
+                return sent;
+            }
+            Mitigation::Shed => false,
+            Mitigation::Sample(n) => current.seen.is_multiple_of(n.max(1)),
+        };
+        if keep {
+            return output.send(item).await.is_ok();
+        }
+        self.counters.shed.fetch_add(1, Ordering::Relaxed);
+        if let Some(shed) = &current.shed {
+            shed.inc();
+        }
+        true
+    }
+
+    fn stall(&self, stall: &mut Option<Stall>) {
+        let registry = self.backpressure.config.registry.as_ref();
+        let counter =
+            |kind: &str| registry.map(|r| r.counter(&format!("pipeline.{}.{}", self.name, kind)));
+        let current = stall.insert(Stall {
+            since: Instant::now(),
+            seen: 0,
+            stalls: counter("stalls"),
+            shed: counter("shed"),
+        });
+        self.counters.stalls.fetch_add(1, Ordering::Relaxed);
+        if let Some(stalls) = &current.stalls {
+            stalls.inc();
+        }
+        if self.backpressure.config.mitigation == Mitigation::PauseSource {
+            self.backpressure
+                .pausing
+                .send_modify(|pausing| *pausing += 1);
+        }
+        (self.backpressure.on_event)(BackpressureEvent::Stalled {
+            stage: self.name.clone(),
+            downstream: self.downstream.clone(),
+        });
+    }
+
+    fn recover(&self, stall: &mut Option<Stall>) {
+        let Some(current) = stall.take() else {
+            return;
+        };
+        if self.backpressure.config.mitigation == Mitigation::PauseSource {
+            self.backpressure
+                .pausing
+                .send_modify(|pausing| *pausing -= 1);
+        }
+        (self.backpressure.on_event)(BackpressureEvent::Recovered {
+            stage: self.name.clone(),
+            downstream: self.downstream.clone(),
+            stalled_for: current.since.elapsed(),
+        });
+    }
+
+    async fn run<T, U, F, Fut, E>(self, input: mpsc::Receiver<T>, output: mpsc::Sender<U>, f: F)
+    where
+        F: Fn(T) -> Fut,
+        Fut: Future<Output = Result<U, E>>,
+        E: Into<BoxError>,
+    {
+        let counters = &self.counters;
+        let results = ReceiverStream::new(input)
+            .map(|item| {
+                counters.in_flight.fetch_add(1, Ordering::Relaxed);
+                f(item)
+            })
+            .buffered(self.concurrency);
+        let mut results = std::pin::pin!(results);
+        let mut stall = None;
+
+        while let Some(result) = results.next().await {
+            counters.in_flight.fetch_sub(1, Ordering::Relaxed);
+            match result {
+                Ok(item) => {
+                    counters.processed.fetch_add(1, Ordering::Relaxed);
+                    // The rest of the pipeline is gone, so nothing can use the output
+                    if !self.deliver(&output, item, &mut stall).await {
+                        break;
+                    }
+                }
+                Err(error) => {
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                    (self.on_error)(StageError {
+                        stage: self.name.clone(),
+                        error: error.into(),
+                    });
+                }
+            }
+        }
+        // Don't leave the source paused behind
+        self.recover(&mut stall);
+    }
+}
+
+/// A running chain of stages taking `I`s and producing `O`s
+///
+/// ```
+/// # async fn example() {
+/// use tokio_tutorial_patterns::pipeline::Pipeline;
+///
+/// let mut pipeline = Pipeline::builder()
+///     .stage("parse", 2, |line: String| async move { line.trim().parse::<u64>() })
+///     .map("square", 4, |n| async move { n * n })
+///     .build();
+///
+/// tokio::spawn({
+///     let input = pipeline.sender();
+///     async move {
+///         for line in ["1", "2", "x", "3"] {
+///             let _ = input.send(line.to_string()).await;
+///         }
+///     }
+/// });
+/// pipeline.close();
+///
+/// // "x" fails to parse and is counted rather than passed on
+/// assert_eq!(pipeline.drain().await, [1, 4, 9]);
+/// # }
+/// ```
+pub struct Pipeline<I, O> {
+    input: Option<mpsc::Sender<I>>,
+    output: mpsc::Receiver<O>,
+    /// How many stages are holding back the source
+    paused: watch::Receiver<usize>,
+    stages: Vec<StageInfo>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl<I: Send + 'static> Pipeline<I, I> {
+    pub fn builder() -> PipelineBuilder<I, I> {
+        PipelineBuilder {
+            capacity: 16,
+            on_error: Arc::new(|_| {}),
+            backpressure: BackpressureConfig::default(),
+            on_backpressure: Arc::new(|_| {}),
+            stages: Vec::new(),
+            connect: Box::new(|input, _| input),
+        }
+    }
+}
+
+impl<I, O> Pipeline<I, O> {
+    /// Feeds an item in, waiting while the first stage is backed up or a
+    /// stalled stage is pausing the source
+    pub async fn send(&self, item: I) -> Result<(), PipelineClosed<I>> {
+        let mut paused = self.paused.clone();
+        // The stages hold the sender until they finish, and then it's 0
+        let _ = paused.wait_for(|pausing| *pausing == 0).await;
+        match &self.input {
+            Some(input) => input.send(item).await.map_err(|e| PipelineClosed(e.0)),
+            None => Err(PipelineClosed(item)),
+        }
+    }
+
+    /// Whether a stalled stage is holding back the source
+    ///
+    /// Only [`send`](Self::send) waits for a pause to end; producers using a
+    /// [`sender`](Self::sender) can check this or
+    /// [`wait_unpaused`](Self::wait_unpaused) themselves.
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow() > 0
+    }
+
+    /// Waits until no stage is holding back the source
+    pub async fn wait_unpaused(&self) {
+        let mut paused = self.paused.clone();
+        let _ = paused.wait_for(|pausing| *pausing == 0).await;
+    }
+
+    /// A handle for feeding items in from other tasks
+    ///
+    /// The pipeline only sees the end of its input once
+    /// [`close`](Self::close) has been called and every such handle is
+    /// dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pipeline has already been closed.
+    pub fn sender(&self) -> mpsc::Sender<I> {
+        self.input.clone().expect("pipeline is closed")
+    }
+
+    /// Stops accepting input; items already sent still go through
+    pub fn close(&mut self) {
+        self.input = None;
+    }
+
+    /// The next item out of the last stage, or `None` once the pipeline is
+    /// closed and empty
+    pub async fn recv(&mut self) -> Option<O> {
+        self.output.recv().await
+    }
+
+    /// Closes the pipeline, collects everything still inside and waits for
+    /// every stage to finish
+    pub async fn drain(mut self) -> Vec<O> {
+        self.close();
+        let mut rest = Vec::new();
+        while let Some(item) = self.output.recv().await {
+            rest.push(item);
+        }
+        for task in self.tasks.drain(..) {
+            let _ = task.await;
+        }
+        rest
+    }
+
+    /// Current counters for every stage, in pipeline order
+    pub fn metrics(&self) -> Vec<StageMetrics> {
+        self.stages
+            .iter()
+            .map(|stage| StageMetrics {
+                name: stage.name.clone(),
+                concurrency: stage.concurrency,
+                in_flight: stage.counters.in_flight.load(Ordering::Relaxed),
+                processed: stage.counters.processed.load(Ordering::Relaxed),
+                failed: stage.counters.failed.load(Ordering::Relaxed),
+                stalls: stage.counters.stalls.load(Ordering::Relaxed),
+                shed: stage.counters.shed.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,241 @@
+//! Rate limiting primitives
+//!
+//! A [`TokenBucket`] holds up to `burst` tokens and refills at a steady rate.
+//! Each permitted operation takes one token, so short bursts pass straight
+//! through while the long-run rate stays bounded. A [`KeyedLimiter`] keeps
+//! one such bucket per key, so each user or client address gets a quota of
+//! its own.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn full(burst: f64) -> Self {
+        Self {
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last_refill = now;
+    }
+
+    /// Takes a token, or returns how long until one is available
+    fn take(&mut self, rate: f64, burst: f64) -> Result<(), Duration> {
+        self.refill(rate, burst);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+fn assert_quota(rate_per_sec: f64, burst: u32) {
+    assert!(
+        rate_per_sec.is_finite() && rate_per_sec > 0.0,
+        "`rate_per_sec` must be positive"
+    );
+    assert!(burst > 0, "`burst` must be non-zero");
+}
+
+/// A token bucket shared by all of its clones
+#[derive(Clone)]
+pub struct TokenBucket {
+    bucket: Arc<Mutex<Bucket>>,
+    rate: f64,
+    burst: f64,
+}
+
+impl TokenBucket {
+    /// Allows `rate_per_sec` operations per second on average and up to
+    /// `burst` at once; the bucket starts full
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate_per_sec` isn't positive and finite, or `burst` is zero.
+    pub fn new(rate_per_sec: f64, burst: u32) -> Self {
+        assert_quota(rate_per_sec, burst);
+
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket::full(burst as f64))),
+            rate: rate_per_sec,
+            burst: burst as f64,
+        }
+    }
+
+    /// Takes a token if one is available right now
+    pub fn try_acquire(&self) -> bool {
+        self.take().is_ok()
+    }
+
+    /// Waits until a token is available and takes it
+    pub async fn acquire(&self) {
+        while let Err(wait) = self.take() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Tokens currently in the bucket
+    pub fn available(&self) -> f64 {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(self.rate, self.burst);
+        bucket.tokens
+    }
+
+    fn take(&self) -> Result<(), Duration> {
+        self.bucket.lock().unwrap().take(self.rate, self.burst)
+    }
+}
+
+/// Returned by [`KeyedLimiter::check`] when a key is over its quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited {
+    /// How long until the key has a token again
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limited; retry after {:?}", self.retry_after)
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+struct Keys<K> {
+    /// Each key's bucket and when it was last used
+    buckets: HashMap<K, (Bucket, u64)>,
+    /// Keys by last use, oldest first
+    recency: BTreeMap<u64, K>,
+    next_use: u64,
+}
+
+/// A token bucket per key, such as a user or client address; clones share
+/// the same keys
+///
+/// Only the `max_keys` most recently used keys are tracked. A key that was
+/// evicted starts over with a full bucket, so `max_keys` should comfortably
+/// exceed the number of keys active at once.
+///
+/// ```
+/// # async fn example() {
+/// use std::net::IpAddr;
+/// use tokio_tutorial_patterns::ratelimit::KeyedLimiter;
+///
+/// // 5 requests per second per address, bursts of up to 10
+/// let limiter = KeyedLimiter::<IpAddr>::new(5.0, 10);
+/// let peer: IpAddr = "192.0.2.7".parse().unwrap();
+/// if let Err(limited) = limiter.check(&peer) {
+///     println!("{} must wait {:?}", peer, limited.retry_after);
+/// }
+/// # }
+/// ```
+pub struct KeyedLimiter<K> {
+    keys: Arc<Mutex<Keys<K>>>,
+    rate: f64,
+    burst: f64,
+    max_keys: usize,
+}
+
+impl<K> Clone for KeyedLimiter<K> {
+    fn clone(&self) -> Self {
+        Self {
+            keys: self.keys.clone(),
+            rate: self.rate,
+            burst: self.burst,
+            max_keys: self.max_keys,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> KeyedLimiter<K> {
+    /// Allows each key `rate_per_sec` operations per second on average and
+    /// up to `burst` at once, tracking at most 10,000 keys
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate_per_sec` isn't positive and finite, or `burst` is zero.
+    pub fn new(rate_per_sec: f64, burst: u32) -> Self {
+        assert_quota(rate_per_sec, burst);
+
+        Self {
+            keys: Arc::new(Mutex::new(Keys {
+                buckets: HashMap::new(),
+                recency: BTreeMap::new(),
+                next_use: 0,
+            })),
+            rate: rate_per_sec,
+            burst: burst as f64,
+            max_keys: 10_000,
+        }
+    }
+
+    /// Tracks at most `max_keys` keys, evicting the least recently used
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_keys` is zero.
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        assert!(max_keys > 0, "`max_keys` must be non-zero");
+        self.max_keys = max_keys;
+        self
+    }
+
+    /// Takes a token for `key` if it has one right now
+    pub fn check(&self, key: &K) -> Result<(), RateLimited> {
+        self.take(key)
+            .map_err(|retry_after| RateLimited { retry_after })
+    }
+
+    /// Waits until `key` has a token and takes it
+    pub async fn acquire(&self, key: &K) {
+        while let Err(wait) = self.take(key) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Keys currently tracked
+    pub fn tracked_keys(&self) -> usize {
+        self.keys.lock().unwrap().buckets.len()
+    }
+
+    fn take(&self, key: &K) -> Result<(), Duration> {
+        let mut keys = self.keys.lock().unwrap();
+        let keys = &mut *keys;
+        let used = keys.next_use;
+        keys.next_use += 1;
+
+        if let Some((_, last_used)) = keys.buckets.get(key) {
+            keys.recency.remove(last_used);
+        } else {
+            while keys.buckets.len() >= self.max_keys {
+                let Some((_, oldest)) = keys.recency.pop_first() else {
+                    break;
+                };
+                keys.buckets.remove(&oldest);
+            }
+        }
+        keys.recency.insert(used, key.clone());
+        let (bucket, last_used) = keys
+            .buckets
+            .entry(key.clone())
+            .or_insert_with(|| (Bucket::full(self.burst), used));
+        *last_used = used;
+        bucket.take(self.rate, self.burst)
+    }
+}
@@ -0,0 +1,135 @@
+//! Timeouts derived from the latency of recent successful calls
+
+use super::{LatencyRecorder, TimeoutError};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How an [`AdaptiveTimeout`] turns observed latencies into a timeout
+#[derive(Debug, Clone)]
+pub struct AdaptiveTimeoutConfig {
+    /// Latency percentile the timeout is based on (0.0-1.0)
+    pub percentile: f64,
+    /// Multiplier applied to the percentile latency
+    pub factor: f64,
+    pub min: Duration,
+    pub max: Duration,
+    /// Timeout used until `min_samples` latencies have been recorded
+    pub initial: Duration,
+    /// Number of most recent latencies kept
+    pub window_size: usize,
+    pub min_samples: usize,
+}
+
+impl Default for AdaptiveTimeoutConfig {
+    fn default() -> Self {
+        Self {
+            percentile: 0.99,
+            factor: 2.0,
+            min: Duration::from_millis(10),
+            max: Duration::from_secs(30),
+            initial: Duration::from_secs(1),
+            window_size: 100,
+            min_samples: 20,
+        }
+    }
+}
+
+/// Error returned by [`AdaptiveTimeout::call`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum AdaptiveTimeoutError<E> {
+    /// The operation didn't finish within the current timeout
+    Elapsed(TimeoutError),
+    /// The operation finished and failed
+    Inner(E),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for AdaptiveTimeoutError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdaptiveTimeoutError::Elapsed(e) => write!(f, "{}", e),
+            AdaptiveTimeoutError::Inner(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: std::fmt::Debug + std::fmt::Display> std::error::Error for AdaptiveTimeoutError<E> {}
+
+struct Shared {
+    config: AdaptiveTimeoutConfig,
+    latencies: LatencyRecorder,
+}
+
+/// A timeout that follows the latency distribution of the calls it guards
+///
+/// With the default config the timeout is twice the p99 of the last 100
+/// successful calls, kept between 10ms and 30s. Clones share their samples.
+#[derive(Clone)]
+pub struct AdaptiveTimeout {
+    shared: Arc<Shared>,
+}
+
+impl AdaptiveTimeout {
+    /// # Panics
+    ///
+    /// Panics if `config.min` is greater than `config.max`.
+    pub fn new(config: AdaptiveTimeoutConfig) -> Self {
+        assert!(
+            config.min <= config.max,
+            "`min` must not be greater than `max`"
+        );
+        Self {
+            shared: Arc::new(Shared {
+                latencies: LatencyRecorder::new(config.window_size),
+                config,
+            }),
+        }
+    }
+
+    /// The timeout the next call will get
+    pub fn current(&self) -> Duration {
+        let config = &self.shared.config;
+        let latencies = &self.shared.latencies;
+        let latency = match latencies.percentile(config.percentile) {
+            Some(latency) if latencies.len() >= config.min_samples => latency,
+            _ => return config.initial.clamp(config.min, config.max),
+        };
+
+        let secs = latency.as_secs_f64() * config.factor;
+        if secs.is_finite() && secs < config.max.as_secs_f64() {
+            Duration::from_secs_f64(secs.max(0.0)).clamp(config.min, config.max)
+        } else {
+            config.max
+        }
+    }
+
+    /// Adds a latency observed outside [`call`](Self::call)
+    pub fn record(&self, latency: Duration) {
+        self.shared.latencies.record(latency);
+    }
+
+    /// The recorder holding the latencies this timeout is derived from
+    pub fn latencies(&self) -> &LatencyRecorder {
+        &self.shared.latencies
+    }
+
+    /// Runs `fut` under the current timeout, recording its latency if it
+    /// succeeds
+    pub async fn call<T, E, Fut>(&self, fut: Fut) -> Result<T, AdaptiveTimeoutError<E>>
+    where
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let timeout = self.current();
+        let started = Instant::now();
+
+        match tokio::time::timeout(timeout, fut).await {
+            Ok(Ok(value)) => {
+                self.record(started.elapsed());
+                Ok(value)
+            }
+            Ok(Err(e)) => Err(AdaptiveTimeoutError::Inner(e)),
+            Err(_) => Err(AdaptiveTimeoutError::Elapsed(TimeoutError { timeout })),
+        }
+    }
+}
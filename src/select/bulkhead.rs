@@ -0,0 +1,117 @@
+//! Bulkhead isolation: capping how many calls to one dependency run at once
+
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Error returned when a [`Bulkhead`] has no free slot and its queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkheadFull;
+
+impl std::fmt::Display for BulkheadFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bulkhead is full")
+    }
+}
+
+impl std::error::Error for BulkheadFull {}
+
+/// Counters describing what a [`Bulkhead`] has done so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BulkheadMetrics {
+    /// Calls that got a slot and ran
+    pub executed: u64,
+    /// Calls that had to wait for a slot
+    pub queued: u64,
+    /// Calls turned away because the queue was full
+    pub rejected: u64,
+    pub in_flight: usize,
+    pub waiting: usize,
+}
+
+struct Shared {
+    slots: Semaphore,
+    max_concurrent: usize,
+    max_queued: usize,
+    waiting: AtomicUsize,
+    executed: AtomicU64,
+    queued: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// Limits concurrent executions of an operation, with a bounded wait queue
+///
+/// A slow dependency can then only tie up `max_concurrent` tasks; further
+/// calls wait in line up to `max_queued` deep and are rejected immediately
+/// beyond that. Clones share the same limits and counters.
+#[derive(Clone)]
+pub struct Bulkhead {
+    shared: Arc<Shared>,
+}
+
+impl Bulkhead {
+    pub fn new(max_concurrent: usize, max_queued: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                slots: Semaphore::new(max_concurrent),
+                max_concurrent,
+                max_queued,
+                waiting: AtomicUsize::new(0),
+                executed: AtomicU64::new(0),
+                queued: AtomicU64::new(0),
+                rejected: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Runs `op` once a slot is free, or fails fast if the queue is full
+    pub async fn call<T, F, Fut>(&self, op: F) -> Result<T, BulkheadFull>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let shared = &self.shared;
+        let _permit = match shared.slots.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if shared.waiting.fetch_add(1, Ordering::SeqCst) >= shared.max_queued {
+                    shared.waiting.fetch_sub(1, Ordering::SeqCst);
+                    shared.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err(BulkheadFull);
+                }
+                shared.queued.fetch_add(1, Ordering::Relaxed);
+
+                let _waiting = WaitingGuard(&shared.waiting);
+                shared
+                    .slots
+                    .acquire()
+                    .await
+                    .expect("bulkhead semaphore is never closed")
+            }
+        };
+
+        shared.executed.fetch_add(1, Ordering::Relaxed);
+        Ok(op().await)
+    }
+
+    pub fn metrics(&self) -> BulkheadMetrics {
+        let shared = &self.shared;
+        BulkheadMetrics {
+            executed: shared.executed.load(Ordering::Relaxed),
+            queued: shared.queued.load(Ordering::Relaxed),
+            rejected: shared.rejected.load(Ordering::Relaxed),
+            in_flight: shared.max_concurrent - shared.slots.available_permits(),
+            waiting: shared.waiting.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Leaves the wait queue when the slot is acquired or the call is cancelled
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,297 @@
+//! Circuit breaker that stops calling an operation which keeps failing
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// The externally visible state of a [`CircuitBreaker`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls flow through normally
+    Closed,
+    /// Calls are rejected without running
+    Open,
+    /// A limited number of probe calls decide whether to close again
+    HalfOpen,
+}
+
+/// Error returned by [`CircuitBreaker::call`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum CircuitError<E> {
+    /// The breaker rejected the call without running it
+    Open,
+    /// The operation ran and failed
+    Inner(E),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for CircuitError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitError::Open => write!(f, "circuit breaker is open"),
+            CircuitError::Inner(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: std::fmt::Debug + std::fmt::Display> std::error::Error for CircuitError<E> {}
+
+/// Thresholds controlling when a [`CircuitBreaker`] trips and recovers
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Trip after this many failures in a row
+    pub consecutive_failures: u32,
+    /// Trip when the failure ratio over the window reaches this value (0.0-1.0)
+    pub failure_rate: f64,
+    /// Number of most recent calls the failure rate is computed over
+    pub window_size: usize,
+    /// The failure rate is ignored until the window has this many calls
+    pub minimum_calls: usize,
+    /// How long to stay open before letting probes through
+    pub open_duration: Duration,
+    /// Concurrent probe calls allowed while half-open
+    pub half_open_probes: u32,
+    /// Successful probes needed to close again
+    pub half_open_successes: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            consecutive_failures: 5,
+            failure_rate: 0.5,
+            window_size: 20,
+            minimum_calls: 10,
+            open_duration: Duration::from_secs(30),
+            half_open_probes: 1,
+            half_open_successes: 1,
+        }
+    }
+}
+
+struct BreakerState {
+    state: CircuitState,
+    window: VecDeque<bool>,
+    consecutive_failures: u32,
+    opened_at: Instant,
+    probes_in_flight: u32,
+    probe_successes: u32,
+    /// Bumped on every transition, so results of calls started in an
+    /// earlier state can be told apart
+    epoch: u64,
+}
+
+/// What [`CircuitBreaker::try_acquire`] let a call start as
+#[derive(Clone, Copy)]
+struct Permit {
+    probe: bool,
+    epoch: u64,
+}
+
+struct Shared {
+    config: CircuitBreakerConfig,
+    state: Mutex<BreakerState>,
+    state_tx: watch::Sender<CircuitState>,
+}
+
+/// Wraps async operations, failing fast while the protected dependency is
+/// unhealthy
+///
+/// State transitions are published on a watch channel, see
+/// [`subscribe`](Self::subscribe).
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    shared: Arc<Shared>,
+}
+
+impl CircuitBreaker {
+    /// # Panics
+    ///
+    /// Panics if any count or `open_duration` in `config` is zero, or
+    /// `failure_rate` isn't in `(0.0, 1.0]`.
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        assert!(
+            config.consecutive_failures > 0,
+            "`consecutive_failures` must be non-zero"
+        );
+        assert!(
+            config.failure_rate > 0.0 && config.failure_rate <= 1.0,
+            "`failure_rate` must be in (0.0, 1.0]"
+        );
+        assert!(config.window_size > 0, "`window_size` must be non-zero");
+        assert!(
+            !config.open_duration.is_zero(),
+            "`open_duration` must be non-zero"
+        );
+        assert!(
+            config.half_open_probes > 0,
+            "`half_open_probes` must be non-zero"
+        );
+        assert!(
+            config.half_open_successes > 0,
+            "`half_open_successes` must be non-zero"
+        );
+        let (state_tx, _) = watch::channel(CircuitState::Closed);
+        Self {
+            shared: Arc::new(Shared {
+                config,
+                state: Mutex::new(BreakerState {
+                    state: CircuitState::Closed,
+                    window: VecDeque::new(),
+                    consecutive_failures: 0,
+                    opened_at: Instant::now(),
+                    probes_in_flight: 0,
+                    probe_successes: 0,
+                    epoch: 0,
+                }),
+                state_tx,
+            }),
+        }
+    }
+
+    /// Current state, moving from open to half-open if the open period is over
+    pub fn state(&self) -> CircuitState {
+        let mut state = self.shared.state.lock().unwrap();
+        self.refresh(&mut state);
+        state.state
+    }
+
+    /// Receives every state change
+    pub fn subscribe(&self) -> watch::Receiver<CircuitState> {
+        self.shared.state_tx.subscribe()
+    }
+
+    /// Runs `op` unless the breaker is open, recording its outcome
+    pub async fn call<T, E, F, Fut>(&self, op: F) -> Result<T, CircuitError<E>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let permit = self.try_acquire()?;
+        let mut guard = CallGuard {
+            breaker: self,
+            permit,
+            finished: false,
+        };
+
+        let result = op().await;
+        guard.finished = true;
+        self.record(permit, result.is_ok());
+
+        result.map_err(CircuitError::Inner)
+    }
+
+    /// Checks whether a call may run now, and whether it is a probe
+    fn try_acquire<E>(&self) -> Result<Permit, CircuitError<E>> {
+        let mut state = self.shared.state.lock().unwrap();
+        self.refresh(&mut state);
+
+        let probe = match state.state {
+            CircuitState::Closed => false,
+            CircuitState::Open => return Err(CircuitError::Open),
+            CircuitState::HalfOpen => {
+                if state.probes_in_flight >= self.shared.config.half_open_probes {
+                    return Err(CircuitError::Open);
+                }
+                state.probes_in_flight += 1;
+                true
+            }
+        };
+        Ok(Permit {
+            probe,
+            epoch: state.epoch,
+        })
+    }
+
+    fn record(&self, permit: Permit, success: bool) {
+        let config = &self.shared.config;
+        let mut state = self.shared.state.lock().unwrap();
+
+        // Results of calls started in an earlier state don't count
+        if permit.epoch != state.epoch {
+            return;
+        }
+        if permit.probe {
+            state.probes_in_flight = state.probes_in_flight.saturating_sub(1);
+        }
+
+        match state.state {
+            CircuitState::Closed => {
+                state.window.push_back(success);
+                if state.window.len() > config.window_size {
+                    state.window.pop_front();
+                }
+                if success {
+                    state.consecutive_failures = 0;
+                    return;
+                }
+
+                state.consecutive_failures += 1;
+                let failures = state.window.iter().filter(|ok| !**ok).count();
+                let rate_tripped = state.window.len() >= config.minimum_calls
+                    && failures as f64 / state.window.len() as f64 >= config.failure_rate;
+
+                if state.consecutive_failures >= config.consecutive_failures || rate_tripped {
+                    self.transition(&mut state, CircuitState::Open);
+                }
+            }
+            CircuitState::HalfOpen if permit.probe => {
+                if success {
+                    state.probe_successes += 1;
+                    if state.probe_successes >= config.half_open_successes {
+                        self.transition(&mut state, CircuitState::Closed);
+                    }
+                } else {
+                    self.transition(&mut state, CircuitState::Open);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn refresh(&self, state: &mut BreakerState) {
+        if state.state == CircuitState::Open
+            && state.opened_at.elapsed() >= self.shared.config.open_duration
+        {
+            self.transition(state, CircuitState::HalfOpen);
+        }
+    }
+
+    fn transition(&self, state: &mut BreakerState, to: CircuitState) {
+        state.state = to;
+        state.epoch += 1;
+        match to {
+            CircuitState::Closed => {
+                state.window.clear();
+                state.consecutive_failures = 0;
+            }
+            CircuitState::Open => state.opened_at = Instant::now(),
+            CircuitState::HalfOpen => {
+                state.probes_in_flight = 0;
+                state.probe_successes = 0;
+            }
+        }
+        self.shared.state_tx.send_replace(to);
+    }
+}
+
+/// Frees a half-open probe slot if the call is cancelled mid-flight
+struct CallGuard<'a> {
+    breaker: &'a CircuitBreaker,
+    permit: Permit,
+    finished: bool,
+}
+
+impl Drop for CallGuard<'_> {
+    fn drop(&mut self) {
+        if self.permit.probe && !self.finished {
+            let mut state = self.breaker.shared.state.lock().unwrap();
+            // A slot from an earlier half-open period was already reset
+            if state.epoch == self.permit.epoch {
+                state.probes_in_flight = state.probes_in_flight.saturating_sub(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Establishing TCP connections with retries
+
+use super::RetryPolicy;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// How [`retry_connect`] retries, times out and gets cancelled
+#[derive(Debug, Clone)]
+pub struct ConnectPolicy {
+    retry: RetryPolicy<io::Error>,
+    attempt_timeout: Duration,
+    token: Option<CancellationToken>,
+}
+
+impl ConnectPolicy {
+    /// Retries according to `retry`, giving each attempt 10s
+    pub fn new(retry: RetryPolicy<io::Error>) -> Self {
+        Self {
+            retry,
+            attempt_timeout: Duration::from_secs(10),
+            token: None,
+        }
+    }
+
+    /// Timeout for each individual attempt; a timed-out attempt fails with
+    /// `ErrorKind::TimedOut` and may be retried
+    pub fn attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.attempt_timeout = timeout;
+        self
+    }
+
+    /// Gives up as soon as `token` is cancelled, even mid-attempt
+    pub fn cancel_on(mut self, token: CancellationToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+}
+
+/// One failed connection attempt
+#[derive(Debug)]
+pub struct ConnectAttempt {
+    /// 1 for the first attempt
+    pub attempt: u32,
+    /// When the attempt started, relative to the first one
+    pub started_after: Duration,
+    pub duration: Duration,
+    pub error: io::Error,
+}
+
+/// Error returned by [`retry_connect`], with every failed attempt
+#[derive(Debug)]
+pub struct ConnectError {
+    pub attempts: Vec<ConnectAttempt>,
+    /// Whether the cancellation token stopped the retries
+    pub cancelled: bool,
+}
+
+impl ConnectError {
+    /// The error of the most recent attempt, if any ran
+    pub fn last_error(&self) -> Option<&io::Error> {
+        self.attempts.last().map(|attempt| &attempt.error)
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.cancelled {
+            write!(
+                f,
+                "connect cancelled after {} attempts",
+                self.attempts.len()
+            )?;
+        } else {
+            write!(f, "connect failed after {} attempts", self.attempts.len())?;
+        }
+        match self.last_error() {
+            Some(e) => write!(f, ", last error: {}", e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.last_error()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// Connects to `addr`, retrying failed attempts as `policy` allows
+///
+/// ```no_run
+/// # async fn example() -> Result<(), tokio_tutorial_patterns::select::ConnectError> {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::select::{retry_connect, ConnectPolicy, RetryPolicy};
+///
+/// let policy = ConnectPolicy::new(
+///     RetryPolicy::exponential(Duration::from_millis(100)).max_attempts(Some(5)),
+/// )
+/// .attempt_timeout(Duration::from_secs(2));
+///
+/// let stream = retry_connect("127.0.0.1:6379", policy).await?;
+/// # let _ = stream;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn retry_connect<A>(addr: A, policy: ConnectPolicy) -> Result<TcpStream, ConnectError>
+where
+    A: ToSocketAddrs + Clone,
+{
+    let token = policy.token.unwrap_or_default();
+    let started = Instant::now();
+    let mut attempts = Vec::new();
+
+    loop {
+        let attempt_started = Instant::now();
+        let result = tokio::select! {
+            _ = token.cancelled() => None,
+            result = tokio::time::timeout(policy.attempt_timeout, TcpStream::connect(addr.clone())) => {
+                Some(result.unwrap_or_else(|_| {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "connect attempt timed out"))
+                }))
+            }
+        };
+
+        let error = match result {
+            Some(Ok(stream)) => return Ok(stream),
+            Some(Err(e)) => e,
+            None => {
+                return Err(ConnectError {
+                    attempts,
+                    cancelled: true,
+                })
+            }
+        };
+
+        let delay = policy
+            .retry
+            .next_delay(attempts.len() as u32 + 1, started, &error);
+        attempts.push(ConnectAttempt {
+            attempt: attempts.len() as u32 + 1,
+            started_after: attempt_started - started,
+            duration: attempt_started.elapsed(),
+            error,
+        });
+
+        let Some(delay) = delay else {
+            return Err(ConnectError {
+                attempts,
+                cancelled: false,
+            });
+        };
+        tokio::select! {
+            _ = token.cancelled() => {
+                return Err(ConnectError {
+                    attempts,
+                    cancelled: true,
+                })
+            }
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+}
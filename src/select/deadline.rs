@@ -0,0 +1,95 @@
+//! Request deadlines carried in task-local context
+//!
+//! A [`Deadline`] set with [`Deadline::scope`] is visible to everything the
+//! scoped future awaits. Inner layers call [`with_deadline`] to bound their
+//! work by whatever budget is left, instead of stacking their own timeouts.
+
+use super::TimeoutError;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+tokio::task_local! {
+    static CURRENT: Deadline;
+}
+
+/// A point in time by which an operation must finish
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Deadline {
+    at: Instant,
+}
+
+impl Deadline {
+    pub fn at(at: Instant) -> Self {
+        Self { at }
+    }
+
+    pub fn after(budget: Duration) -> Self {
+        Self::at(Instant::now() + budget)
+    }
+
+    /// The deadline of the enclosing [`scope`](Self::scope), if any
+    pub fn current() -> Option<Deadline> {
+        CURRENT.try_with(|deadline| *deadline).ok()
+    }
+
+    pub fn instant(&self) -> Instant {
+        self.at
+    }
+
+    /// Time left, zero once expired
+    pub fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.at
+    }
+
+    /// An earlier deadline that leaves `reserve` for the caller's own work
+    /// once the inner operation returns
+    pub fn shrink(self, reserve: Duration) -> Self {
+        Self::at(self.at.checked_sub(reserve).unwrap_or(self.at))
+    }
+
+    /// Runs `fut` with this deadline as the current one
+    ///
+    /// An enclosing deadline that is earlier still wins, so nested scopes can
+    /// only tighten the budget.
+    pub async fn scope<F: Future>(self, fut: F) -> F::Output {
+        let effective = match Self::current() {
+            Some(outer) => outer.min(self),
+            None => self,
+        };
+        CURRENT.scope(effective, fut).await
+    }
+}
+
+/// Bounds `fut` by the current task-local deadline
+///
+/// Without an enclosing [`Deadline::scope`] the future runs unbounded.
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::select::{with_deadline, Deadline};
+///
+/// async fn query() -> Result<u32, tokio_tutorial_patterns::select::TimeoutError> {
+///     with_deadline(async { 42 }).await
+/// }
+///
+/// let result = Deadline::after(Duration::from_millis(500)).scope(query()).await;
+/// assert_eq!(result, Ok(42));
+/// # }
+/// ```
+pub async fn with_deadline<F: Future>(fut: F) -> Result<F::Output, TimeoutError> {
+    match Deadline::current() {
+        Some(deadline) => {
+            let timeout = deadline.remaining();
+            tokio::time::timeout_at(deadline.instant(), fut)
+                .await
+                .map_err(|_| TimeoutError { timeout })
+        }
+        None => Ok(fut.await),
+    }
+}
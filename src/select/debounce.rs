@@ -0,0 +1,153 @@
+//! Coalescing bursts of triggers into fewer runs of an async action
+
+use crate::spawning::spawn_traced;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, sleep_until, Instant};
+
+/// Runs an action once triggers have stopped arriving for a quiet period
+///
+/// Every [`trigger`](Self::trigger) restarts the quiet period. The action
+/// runs on a background task; clones share that task, which exits once every
+/// handle is dropped. A run still pending at that point happens immediately.
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::select::Debouncer;
+///
+/// let saver = Debouncer::new(Duration::from_millis(500), || async {
+///     println!("saving document");
+/// });
+/// for _ in 0..10 {
+///     saver.trigger(); // one save, 500ms after the last edit
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Debouncer {
+    tx: mpsc::Sender<()>,
+}
+
+impl Debouncer {
+    pub fn new<F, Fut>(quiet: Duration, action: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(1);
+        spawn_traced("debounce", debounce(quiet, action, rx));
+        Self { tx }
+    }
+
+    /// Requests a run once things have been quiet for the configured period
+    pub fn trigger(&self) {
+        // A full channel already holds a wake-up for the task
+        let _ = self.tx.try_send(());
+    }
+}
+
+async fn debounce<F, Fut>(quiet: Duration, mut action: F, mut rx: mpsc::Receiver<()>)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    while rx.recv().await.is_some() {
+        loop {
+            tokio::select! {
+                trigger = rx.recv() => {
+                    if trigger.is_none() {
+                        action().await;
+                        return;
+                    }
+                }
+                _ = sleep(quiet) => {
+                    action().await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Runs an action at most once per period
+///
+/// The first trigger runs the action right away and starts the period.
+/// Triggers during the period are dropped, or with [`trailing`](Self::trailing)
+/// collapsed into a single run when the period ends.
+#[derive(Debug, Clone)]
+pub struct Throttler {
+    tx: mpsc::Sender<()>,
+}
+
+impl Throttler {
+    /// Leading-edge only: triggers during the period are discarded
+    pub fn new<F, Fut>(period: Duration, action: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self::spawn(period, false, action)
+    }
+
+    /// Leading and trailing edge: triggers during the period cause one more
+    /// run as soon as it ends
+    pub fn trailing<F, Fut>(period: Duration, action: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self::spawn(period, true, action)
+    }
+
+    fn spawn<F, Fut>(period: Duration, trailing: bool, action: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(1);
+        spawn_traced("throttle", throttle(period, trailing, action, rx));
+        Self { tx }
+    }
+
+    pub fn trigger(&self) {
+        let _ = self.tx.try_send(());
+    }
+}
+
+async fn throttle<F, Fut>(
+    period: Duration,
+    trailing: bool,
+    mut action: F,
+    mut rx: mpsc::Receiver<()>,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut closed = false;
+
+    while !closed && rx.recv().await.is_some() {
+        let mut run = true;
+
+        while run {
+            let period_end = Instant::now() + period;
+            action().await;
+
+            let mut pending = false;
+            while !closed {
+                tokio::select! {
+                    trigger = rx.recv() => match trigger {
+                        Some(()) => pending = true,
+                        None => closed = true,
+                    },
+                    _ = sleep_until(period_end) => break,
+                }
+            }
+            if closed && pending && trailing {
+                sleep_until(period_end).await;
+            }
+            run = pending && trailing;
+        }
+    }
+}
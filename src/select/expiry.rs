@@ -0,0 +1,134 @@
+//! Items that expire after a time-to-live
+//!
+//! Built on `tokio_util::time::DelayQueue`, with values kept alongside so
+//! they can be looked up, refreshed or removed by key before they expire.
+
+use futures::Stream;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio_util::time::{delay_queue, DelayQueue};
+
+/// A keyed collection whose entries are yielded once their TTL runs out
+///
+/// As a [`Stream`] it produces `(key, value)` pairs in expiry order and never
+/// ends: while empty it waits for the next insert.
+///
+/// ```
+/// # async fn example() {
+/// use futures::StreamExt;
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::select::ExpiryQueue;
+///
+/// let mut sessions = ExpiryQueue::new();
+/// sessions.insert("alice", "token-1", Duration::from_secs(30));
+/// sessions.reset(&"alice", Duration::from_secs(60)); // activity extends the session
+///
+/// while let Some((user, _token)) = sessions.next().await {
+///     println!("session for {} expired", user);
+/// }
+/// # }
+/// ```
+pub struct ExpiryQueue<K, V> {
+    queue: DelayQueue<K>,
+    entries: HashMap<K, (V, delay_queue::Key)>,
+}
+
+impl<K, V> ExpiryQueue<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            queue: DelayQueue::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Inserts `value` to expire after `ttl`, returning the value it replaced
+    ///
+    /// Replacing an entry restarts its TTL.
+    pub fn insert(&mut self, key: K, value: V, ttl: Duration) -> Option<V> {
+        match self.entries.get_mut(&key) {
+            Some((existing, delay)) => {
+                self.queue.reset(delay, ttl);
+                Some(std::mem::replace(existing, value))
+            }
+            None => {
+                let delay = self.queue.insert(key.clone(), ttl);
+                self.entries.insert(key, (value, delay));
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Gives `key` a fresh `ttl` from now, returning false if it isn't present
+    pub fn reset(&mut self, key: &K, ttl: Duration) -> bool {
+        match self.entries.get(key) {
+            Some((_, delay)) => {
+                self.queue.reset(delay, ttl);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes `key` before it expires
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, delay) = self.entries.remove(key)?;
+        self.queue.remove(&delay);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Polls for the next expired entry
+    pub fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<(K, V)> {
+        match self.queue.poll_expired(cx) {
+            Poll::Ready(Some(expired)) => {
+                let key = expired.into_inner();
+                let (value, _) = self.entries.remove(&key).expect("expired key has an entry");
+                Poll::Ready((key, value))
+            }
+            // The queue registered our waker, so the next insert wakes us
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<K, V> Default for ExpiryQueue<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Stream for ExpiryQueue<K, V>
+where
+    K: Eq + Hash + Clone + Unpin,
+    V: Unpin,
+{
+    type Item = (K, V);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_expired(cx).map(Some)
+    }
+}
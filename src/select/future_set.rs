@@ -0,0 +1,125 @@
+//! A growable set of futures consumed as a stream of completions
+
+use futures::stream::FuturesUnordered;
+use futures::Stream;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Futures that can be added at any time, yielding outputs as they finish
+///
+/// Unlike `join_all` the set can keep growing while it is being drained,
+/// and with [`with_concurrency_limit`](Self::with_concurrency_limit) only a
+/// bounded number of futures are polled at once; the rest wait in
+/// insertion order. Like `FuturesUnordered`, the stream yields `None`
+/// whenever the set is empty and picks up again after the next push.
+///
+/// ```
+/// # async fn example() {
+/// use futures::StreamExt;
+/// use tokio_tutorial_patterns::select::FutureSet;
+///
+/// async fn fetch(page: u32) -> Vec<u32> {
+///     vec![page + 1]
+/// }
+///
+/// let mut set = FutureSet::with_concurrency_limit(4);
+/// set.push(fetch(0));
+/// while let Some(links) = set.next().await {
+///     for page in links.into_iter().filter(|page| *page < 10) {
+///         set.push(fetch(page)); // follow-up work discovered along the way
+///     }
+/// }
+/// # }
+/// ```
+pub struct FutureSet<F> {
+    running: FuturesUnordered<F>,
+    queued: VecDeque<F>,
+    limit: Option<usize>,
+}
+
+// Queued futures are never pinned, only moved into `FuturesUnordered`
+impl<F> Unpin for FutureSet<F> {}
+
+impl<F: Future> FutureSet<F> {
+    pub fn new() -> Self {
+        Self {
+            running: FuturesUnordered::new(),
+            queued: VecDeque::new(),
+            limit: None,
+        }
+    }
+
+    /// Polls at most `limit` futures at a time
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn with_concurrency_limit(limit: usize) -> Self {
+        assert!(limit > 0, "concurrency limit must be non-zero");
+        Self {
+            limit: Some(limit),
+            ..Self::new()
+        }
+    }
+
+    pub fn push(&mut self, fut: F) {
+        // Go through the queue so earlier pushes start first
+        self.queued.push_back(fut);
+        self.promote();
+    }
+
+    /// Futures not yet finished, whether running or queued
+    pub fn len(&self) -> usize {
+        self.running.len() + self.queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty() && self.queued.is_empty()
+    }
+
+    /// Futures currently being polled
+    pub fn running(&self) -> usize {
+        self.running.len()
+    }
+
+    /// Futures waiting for a free slot
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+
+    fn promote(&mut self) {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        while self.running.len() < limit {
+            match self.queued.pop_front() {
+                Some(fut) => self.running.push(fut),
+                None => break,
+            }
+        }
+    }
+}
+
+impl<F: Future> Default for FutureSet<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Future> Extend<F> for FutureSet<F> {
+    fn extend<I: IntoIterator<Item = F>>(&mut self, iter: I) {
+        for fut in iter {
+            self.push(fut);
+        }
+    }
+}
+
+impl<F: Future> Stream for FutureSet<F> {
+    type Item = F::Output;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.promote();
+        Pin::new(&mut this.running).poll_next(cx)
+    }
+}
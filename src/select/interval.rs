@@ -0,0 +1,216 @@
+//! Intervals with randomized ticks or external control
+//!
+//! Tasks started together with a plain `tokio::time::interval` wake together
+//! forever after. Jittering each tick spreads them out. A plain interval also
+//! can't be paused from another task, which [`PausableInterval`] allows.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Interval, MissedTickBehavior, Sleep};
+
+/// An interval whose ticks land at a random offset after each period boundary
+///
+/// Created by [`interval_jittered`].
+#[derive(Debug)]
+pub struct JitteredInterval {
+    period: Duration,
+    jitter: f64,
+    behavior: MissedTickBehavior,
+    /// Unjittered time of the pending tick
+    base: Instant,
+    sleep: Pin<Box<Sleep>>,
+}
+
+/// Creates an interval that ticks once per `period`, each tick delayed by a
+/// random amount of up to `jitter_fraction * period`
+///
+/// The first tick is jittered too, so intervals created at the same moment
+/// don't fire together. `jitter_fraction` is clamped to `0.0..=1.0`, and
+/// `missed_tick_behavior` works as it does for `tokio::time::Interval`.
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+pub fn interval_jittered(
+    period: Duration,
+    jitter_fraction: f64,
+    missed_tick_behavior: MissedTickBehavior,
+) -> JitteredInterval {
+    assert!(period > Duration::ZERO, "`period` must be non-zero");
+
+    let jitter = if jitter_fraction.is_nan() {
+        0.0
+    } else {
+        jitter_fraction.clamp(0.0, 1.0)
+    };
+    let base = Instant::now();
+    let mut interval = JitteredInterval {
+        period,
+        jitter,
+        behavior: missed_tick_behavior,
+        base,
+        sleep: Box::pin(tokio::time::sleep_until(base)),
+    };
+    let first = base + interval.offset();
+    interval.sleep.as_mut().reset(first);
+    interval
+}
+
+impl JitteredInterval {
+    /// Waits for the next tick, returning the time it was scheduled for
+    pub async fn tick(&mut self) -> Instant {
+        std::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Instant> {
+        futures::ready!(self.sleep.as_mut().poll(cx));
+
+        let fired = self.sleep.deadline();
+        let now = Instant::now();
+        let mut next = self.base + self.period;
+
+        if now >= next {
+            next = match self.behavior {
+                MissedTickBehavior::Burst => next,
+                MissedTickBehavior::Delay => now + self.period,
+                MissedTickBehavior::Skip => {
+                    // The next multiple of the period after now, without
+                    // counting the missed ticks, which may not fit a u32
+                    let into = (now - self.base).as_nanos() % self.period.as_nanos();
+                    now + (self.period - Duration::from_nanos(into as u64))
+                }
+            };
+        }
+
+        self.base = next;
+        let target = next + self.offset();
+        self.sleep.as_mut().reset(target);
+        Poll::Ready(fired)
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn missed_tick_behavior(&self) -> MissedTickBehavior {
+        self.behavior
+    }
+
+    fn offset(&self) -> Duration {
+        self.period.mul_f64(self.jitter * rand::random::<f64>())
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Pause,
+    Resume,
+    Reset,
+}
+
+/// An interval that other tasks can pause, resume and reset
+///
+/// Control happens through [`IntervalHandle`]s; their commands take effect
+/// the next time [`tick`](Self::tick) is awaited.
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::select::PausableInterval;
+///
+/// let mut interval = PausableInterval::new(Duration::from_secs(1));
+/// let handle = interval.handle();
+///
+/// tokio::spawn(async move {
+///     loop {
+///         interval.tick().await;
+///         println!("refreshing");
+///     }
+/// });
+///
+/// handle.pause(); // e.g. while the upstream is in maintenance
+/// handle.resume();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PausableInterval {
+    interval: Interval,
+    paused: bool,
+    tx: mpsc::UnboundedSender<Command>,
+    rx: mpsc::UnboundedReceiver<Command>,
+}
+
+/// Controls a [`PausableInterval`] from any task
+///
+/// Commands sent after the interval is dropped are ignored.
+#[derive(Debug, Clone)]
+pub struct IntervalHandle {
+    tx: mpsc::UnboundedSender<Command>,
+}
+
+impl PausableInterval {
+    /// Ticks immediately, then once per `period`, like `tokio::time::interval`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    pub fn new(period: Duration) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            interval: tokio::time::interval(period),
+            paused: false,
+            tx,
+            rx,
+        }
+    }
+
+    pub fn handle(&self) -> IntervalHandle {
+        IntervalHandle {
+            tx: self.tx.clone(),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Waits for the next tick, never completing while paused
+    pub async fn tick(&mut self) -> Instant {
+        loop {
+            tokio::select! {
+                biased;
+                Some(command) = self.rx.recv() => match command {
+                    Command::Pause => self.paused = true,
+                    Command::Resume => {
+                        if self.paused {
+                            self.paused = false;
+                            self.interval.reset();
+                        }
+                    }
+                    Command::Reset => self.interval.reset(),
+                },
+                instant = self.interval.tick(), if !self.paused => return instant,
+            }
+        }
+    }
+}
+
+impl IntervalHandle {
+    /// Stops ticks until [`resume`](Self::resume)
+    pub fn pause(&self) {
+        let _ = self.tx.send(Command::Pause);
+    }
+
+    /// Restarts ticking, the first tick one full period after resuming
+    pub fn resume(&self) {
+        let _ = self.tx.send(Command::Resume);
+    }
+
+    /// Restarts the current period, so the next tick is one period from now
+    pub fn reset(&self) {
+        let _ = self.tx.send(Command::Reset);
+    }
+}
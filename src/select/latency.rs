@@ -0,0 +1,145 @@
+//! Measuring how long futures take
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Awaits `fut` and returns its output with the time it took
+pub async fn timed<F: Future>(fut: F) -> (F::Output, Duration) {
+    let started = Instant::now();
+    let output = fut.await;
+    (output, started.elapsed())
+}
+
+/// Percentiles over the samples currently held by a [`LatencyRecorder`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LatencySummary {
+    /// Samples in the window
+    pub count: usize,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+struct Shared {
+    window_size: usize,
+    samples: Mutex<VecDeque<Duration>>,
+    /// Only kept up to date while someone is subscribed
+    summary: watch::Sender<LatencySummary>,
+}
+
+/// Keeps the most recent latencies and summarizes them on demand
+///
+/// Recording a sample only stores it; percentiles are computed when asked
+/// for, which sorts the window. While anyone is
+/// [subscribed](Self::subscribe), every sample publishes a fresh summary and
+/// so pays for that sort.
+///
+/// Clones share samples and subscribers.
+#[derive(Clone)]
+pub struct LatencyRecorder {
+    shared: Arc<Shared>,
+}
+
+impl LatencyRecorder {
+    /// Summarizes the last `window_size` samples
+    pub fn new(window_size: usize) -> Self {
+        let (summary, _) = watch::channel(LatencySummary::default());
+        Self {
+            shared: Arc::new(Shared {
+                window_size,
+                samples: Mutex::new(VecDeque::new()),
+                summary,
+            }),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let summary = {
+            let mut samples = self.shared.samples.lock().unwrap();
+            samples.push_back(latency);
+            if samples.len() > self.shared.window_size {
+                samples.pop_front();
+            }
+            if self.shared.summary.is_closed() {
+                return;
+            }
+            summarize(&sorted(&samples))
+        };
+        self.shared.summary.send_replace(summary);
+    }
+
+    /// Awaits `fut`, recording how long it took
+    pub async fn time<F: Future>(&self, fut: F) -> F::Output {
+        let (output, latency) = timed(fut).await;
+        self.record(latency);
+        output
+    }
+
+    /// The latency at or below which `p` (0.0-1.0) of the samples fall
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let samples = self.shared.samples.lock().unwrap();
+        percentile(&sorted(&samples), p)
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let samples = self.shared.samples.lock().unwrap();
+        summarize(&sorted(&samples))
+    }
+
+    /// Receives a fresh summary after every recorded sample
+    pub fn subscribe(&self) -> watch::Receiver<LatencySummary> {
+        let samples = self.shared.samples.lock().unwrap();
+        // Nothing was published while nobody was listening
+        let summary = summarize(&sorted(&samples));
+        self.shared.summary.send_replace(summary);
+        self.shared.summary.subscribe()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.samples.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn sorted(samples: &VecDeque<Duration>) -> Vec<Duration> {
+    let mut sorted: Vec<_> = samples.iter().copied().collect();
+    sorted.sort_unstable();
+    sorted
+}
+
+/// Nearest-rank percentile of already sorted samples
+fn percentile(sorted: &[Duration], p: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p.clamp(0.0, 1.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+fn summarize(sorted: &[Duration]) -> LatencySummary {
+    let Some((&min, &max)) = sorted.first().zip(sorted.last()) else {
+        return LatencySummary::default();
+    };
+    let total: Duration = sorted.iter().sum();
+
+    LatencySummary {
+        count: sorted.len(),
+        min,
+        max,
+        mean: total / sorted.len() as u32,
+        p50: percentile(sorted, 0.5).unwrap_or_default(),
+        p90: percentile(sorted, 0.9).unwrap_or_default(),
+        p99: percentile(sorted, 0.99).unwrap_or_default(),
+    }
+}
@@ -0,0 +1,262 @@
+//! Load shedding: turning requests away early when a service is overloaded
+//!
+//! Shedding works per request, around [`LoadShed::call`] or a
+//! [`RequestHandler`], and in the [`http_lite`](crate::io::http_lite)
+//! router. The other TCP servers in [`io`](crate::io) hand whole
+//! connections to their handlers and don't shed on their own; a handler
+//! can wrap each request it reads in [`LoadShed::call`].
+
+use crate::channels::RequestHandler;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// When a [`LoadShed`] starts rejecting requests
+#[derive(Debug, Clone)]
+pub struct LoadShedConfig {
+    /// Requests that may be admitted and unfinished at once
+    pub max_queue_depth: usize,
+    /// Reject while the `latency_percentile` of recently completed requests
+    /// is above this
+    pub max_latency: Duration,
+    /// Latency percentile compared with `max_latency` (0.0-1.0)
+    pub latency_percentile: f64,
+    /// How long a completed request's latency counts for
+    ///
+    /// Also how long latency shedding lasts at most once requests speed up
+    /// again, since shed requests complete nothing.
+    pub latency_window: Duration,
+    /// Completed requests needed within the window before latency sheds
+    /// anything
+    pub min_samples: usize,
+}
+
+impl Default for LoadShedConfig {
+    /// Sheds above 128 unfinished requests, or while the p90 of the last
+    /// 10s of completions is above 1s
+    fn default() -> Self {
+        Self {
+            max_queue_depth: 128,
+            max_latency: Duration::from_secs(1),
+            latency_percentile: 0.9,
+            latency_window: Duration::from_secs(10),
+            min_samples: 20,
+        }
+    }
+}
+
+/// Why a request was shed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShedReason {
+    QueueDepth,
+    Latency,
+}
+
+/// Error returned by [`LoadShed::call`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShedError<E> {
+    /// Rejected without running; safe to retry elsewhere or later
+    Overloaded(ShedReason),
+    /// Admitted, but the operation itself failed
+    Failed(E),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for ShedError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShedError::Overloaded(ShedReason::QueueDepth) => write!(f, "shed: too many requests"),
+            ShedError::Overloaded(ShedReason::Latency) => write!(f, "shed: requests too slow"),
+            ShedError::Failed(e) => write!(f, "request failed: {}", e),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for ShedError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ShedError::Overloaded(_) => None,
+            ShedError::Failed(e) => Some(e),
+        }
+    }
+}
+
+/// Counters describing what a [`LoadShed`] has done so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LoadShedMetrics {
+    pub admitted: u64,
+    pub shed_queue_depth: u64,
+    pub shed_latency: u64,
+    /// Admitted requests that returned an error
+    pub failed: u64,
+    pub in_flight: usize,
+}
+
+/// Completions kept at most, however many the window holds
+const MAX_SAMPLES: usize = 1024;
+
+struct State {
+    in_flight: usize,
+    /// Completion times and latencies, oldest first
+    samples: VecDeque<(Instant, Duration)>,
+    /// Whether the samples are over the latency limit, until they change
+    too_slow: Option<bool>,
+}
+
+impl State {
+    fn too_slow(&mut self, config: &LoadShedConfig) -> bool {
+        let cutoff = Instant::now().checked_sub(config.latency_window);
+        while let Some(&(completed, _)) = self.samples.front() {
+            if cutoff.is_none_or(|cutoff| completed > cutoff) {
+                break;
+            }
+            self.samples.pop_front();
+            self.too_slow = None;
+        }
+        if self.samples.len() < config.min_samples.max(1) {
+            return false;
+        }
+
+        let samples = &self.samples;
+        *self.too_slow.get_or_insert_with(|| {
+            let mut latencies: Vec<_> = samples.iter().map(|&(_, latency)| latency).collect();
+            let p = config.latency_percentile.clamp(0.0, 1.0);
+            let rank = (p * latencies.len() as f64).ceil() as usize;
+            let index = rank.clamp(1, latencies.len()) - 1;
+            *latencies.select_nth_unstable(index).1 > config.max_latency
+        })
+    }
+
+    fn record(&mut self, latency: Duration) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((Instant::now(), latency));
+        self.too_slow = None;
+    }
+}
+
+struct Shared {
+    config: LoadShedConfig,
+    state: Mutex<State>,
+    admitted: AtomicU64,
+    shed_queue_depth: AtomicU64,
+    shed_latency: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// Rejects requests immediately while too many are unfinished or recent
+/// ones took too long
+///
+/// Latency is judged by requests that completed, so a single stuck request
+/// doesn't shed anything; enough of them still fill the queue depth.
+///
+/// Unlike a [`Bulkhead`](super::Bulkhead), nothing ever waits: a request is
+/// either admitted straight away or shed, which keeps latency bounded for
+/// the requests that do get in. Clones share the same limits and counters.
+#[derive(Clone)]
+pub struct LoadShed {
+    shared: Arc<Shared>,
+}
+
+impl LoadShed {
+    pub fn new(config: LoadShedConfig) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                config,
+                state: Mutex::new(State {
+                    in_flight: 0,
+                    samples: VecDeque::new(),
+                    too_slow: None,
+                }),
+                admitted: AtomicU64::new(0),
+                shed_queue_depth: AtomicU64::new(0),
+                shed_latency: AtomicU64::new(0),
+                failed: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Runs `op` if the service isn't overloaded
+    pub async fn call<T, E, F, Fut>(&self, op: F) -> Result<T, ShedError<E>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let admitted = self.admit().map_err(ShedError::Overloaded)?;
+        let result = op().await;
+        admitted.complete();
+        result.map_err(|e| {
+            self.shared.failed.fetch_add(1, Ordering::Relaxed);
+            ShedError::Failed(e)
+        })
+    }
+
+    /// Sends `req` to `handler` if the service isn't overloaded
+    pub async fn request<Req, Resp>(
+        &self,
+        handler: &RequestHandler<Req, Resp>,
+        req: Req,
+    ) -> Result<Resp, ShedError<oneshot::error::RecvError>>
+    where
+        Req: Send + 'static,
+        Resp: Send + 'static,
+    {
+        self.call(|| handler.request(req)).await
+    }
+
+    pub fn metrics(&self) -> LoadShedMetrics {
+        let shared = &self.shared;
+        LoadShedMetrics {
+            admitted: shared.admitted.load(Ordering::Relaxed),
+            shed_queue_depth: shared.shed_queue_depth.load(Ordering::Relaxed),
+            shed_latency: shared.shed_latency.load(Ordering::Relaxed),
+            failed: shared.failed.load(Ordering::Relaxed),
+            in_flight: shared.state.lock().unwrap().in_flight,
+        }
+    }
+
+    fn admit(&self) -> Result<Admitted<'_>, ShedReason> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+
+        if state.in_flight >= shared.config.max_queue_depth {
+            shared.shed_queue_depth.fetch_add(1, Ordering::Relaxed);
+            return Err(ShedReason::QueueDepth);
+        }
+        if state.too_slow(&shared.config) {
+            shared.shed_latency.fetch_add(1, Ordering::Relaxed);
+            return Err(ShedReason::Latency);
+        }
+
+        state.in_flight += 1;
+        shared.admitted.fetch_add(1, Ordering::Relaxed);
+        Ok(Admitted {
+            shared,
+            started: Instant::now(),
+        })
+    }
+}
+
+/// Marks a request finished when it completes or is cancelled
+struct Admitted<'a> {
+    shared: &'a Shared,
+    started: Instant,
+}
+
+impl Admitted<'_> {
+    /// Counts the request's latency; cancelled requests have none
+    fn complete(self) {
+        let latency = self.started.elapsed();
+        self.shared.state.lock().unwrap().record(latency);
+    }
+}
+
+impl Drop for Admitted<'_> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().in_flight -= 1;
+    }
+}
@@ -0,0 +1,279 @@
+//! Composable resilience policies
+//!
+//! Timeouts, retries, circuit breakers, bulkheads and rate limits all
+//! implement [`Policy`], and the [`PolicyExt`] combinators wrap one policy
+//! in another. Each combinator wraps everything built so far, so below every
+//! attempt gets its own 2s timeout, timed-out or failed attempts are retried,
+//! and the breaker only sees the outcome after retries.
+//!
+//! ```
+//! # async fn example() {
+//! use std::time::Duration;
+//! use tokio_tutorial_patterns::select::policy::{self, Policy, PolicyError, PolicyExt};
+//! use tokio_tutorial_patterns::select::{CircuitBreaker, CircuitBreakerConfig, RetryPolicy};
+//!
+//! let breaker = CircuitBreaker::new(CircuitBreakerConfig::default());
+//! let resilient = policy::timeout(Duration::from_secs(2))
+//!     .retry(RetryPolicy::exponential(Duration::from_millis(50)))
+//!     .circuit_break(breaker);
+//!
+//! let result: Result<String, PolicyError<std::io::Error>> = resilient
+//!     .run(|| async { tokio::fs::read_to_string("/etc/hostname").await })
+//!     .await;
+//! # let _ = result;
+//! # }
+//! ```
+
+use super::{Bulkhead, CircuitBreaker, CircuitError, RetryPolicy, TimeoutError};
+use crate::ratelimit::TokenBucket;
+use futures::future::BoxFuture;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Error returned by an operation run under a [`Policy`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum PolicyError<E> {
+    /// A timeout layer gave up on the operation
+    Timeout(TimeoutError),
+    /// A circuit breaker layer rejected the call
+    CircuitOpen,
+    /// A bulkhead layer had no room for the call
+    BulkheadFull,
+    /// The operation itself failed
+    Inner(E),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for PolicyError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyError::Timeout(e) => write!(f, "{}", e),
+            PolicyError::CircuitOpen => write!(f, "circuit breaker is open"),
+            PolicyError::BulkheadFull => write!(f, "bulkhead is full"),
+            PolicyError::Inner(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E: std::fmt::Debug + std::fmt::Display> std::error::Error for PolicyError<E> {}
+
+/// A re-runnable operation as seen by policies
+pub type Operation<T, E> =
+    Arc<dyn Fn() -> BoxFuture<'static, Result<T, PolicyError<E>>> + Send + Sync>;
+
+/// Behaviour that can be layered around an async operation
+pub trait Policy<T, E>: Send + Sync
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    /// Returns an operation that runs `op` under this policy
+    fn wrap(&self, op: Operation<T, E>) -> Operation<T, E>;
+
+    /// Runs `op` under this policy
+    fn run<F, Fut>(&self, op: F) -> BoxFuture<'static, Result<T, PolicyError<E>>>
+    where
+        Self: Sized,
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<T, E>> + Send + 'static,
+    {
+        let op: Operation<T, E> = Arc::new(move || {
+            let fut = op();
+            Box::pin(async move { fut.await.map_err(PolicyError::Inner) })
+        });
+        (self.wrap(op))()
+    }
+}
+
+/// Combinators that wrap a policy in another
+///
+/// Each wraps everything built so far, so the last combinator is outermost.
+pub trait PolicyExt: Sized {
+    /// Bounds everything wrapped so far by `duration`
+    fn timeout(self, duration: Duration) -> Wrap<Self, Timeout> {
+        Wrap::new(self, timeout(duration))
+    }
+
+    /// Retries everything wrapped so far according to `policy`
+    ///
+    /// [`PolicyError::CircuitOpen`] and [`PolicyError::BulkheadFull`] are
+    /// returned at once rather than retried.
+    fn retry<E>(self, policy: RetryPolicy<E>) -> Wrap<Self, RetryPolicy<E>> {
+        Wrap::new(self, policy)
+    }
+
+    /// Guards everything wrapped so far with `breaker`
+    fn circuit_break(self, breaker: CircuitBreaker) -> Wrap<Self, CircuitBreaker> {
+        Wrap::new(self, breaker)
+    }
+
+    /// Limits concurrent runs of everything wrapped so far
+    fn bulkhead(self, bulkhead: Bulkhead) -> Wrap<Self, Bulkhead> {
+        Wrap::new(self, bulkhead)
+    }
+
+    /// Waits for a token from `bucket` before everything wrapped so far
+    fn rate_limit(self, bucket: TokenBucket) -> Wrap<Self, TokenBucket> {
+        Wrap::new(self, bucket)
+    }
+}
+
+impl PolicyExt for Timeout {}
+impl<E> PolicyExt for RetryPolicy<E> {}
+impl PolicyExt for CircuitBreaker {}
+impl PolicyExt for Bulkhead {}
+impl PolicyExt for TokenBucket {}
+impl<I, O> PolicyExt for Wrap<I, O> {}
+
+/// `outer` applied around `inner`, built by the [`PolicyExt`] combinators
+pub struct Wrap<I, O> {
+    inner: I,
+    outer: O,
+}
+
+impl<I, O> Wrap<I, O> {
+    pub fn new(inner: I, outer: O) -> Self {
+        Self { inner, outer }
+    }
+}
+
+impl<T, E, I, O> Policy<T, E> for Wrap<I, O>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+    I: Policy<T, E>,
+    O: Policy<T, E>,
+{
+    fn wrap(&self, op: Operation<T, E>) -> Operation<T, E> {
+        self.outer.wrap(self.inner.wrap(op))
+    }
+}
+
+/// Starts a policy with a timeout
+pub fn timeout(duration: Duration) -> Timeout {
+    Timeout { duration }
+}
+
+/// Starts a policy with retries
+pub fn retry<E>(policy: RetryPolicy<E>) -> RetryPolicy<E> {
+    policy
+}
+
+/// Starts a policy with a circuit breaker
+pub fn circuit_break(breaker: CircuitBreaker) -> CircuitBreaker {
+    breaker
+}
+
+/// Starts a policy with a bulkhead
+pub fn bulkhead(bulkhead: Bulkhead) -> Bulkhead {
+    bulkhead
+}
+
+/// Starts a policy with a rate limit
+pub fn rate_limit(bucket: TokenBucket) -> TokenBucket {
+    bucket
+}
+
+/// Fails an operation that runs longer than a fixed duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    duration: Duration,
+}
+
+impl<T, E> Policy<T, E> for Timeout
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    fn wrap(&self, op: Operation<T, E>) -> Operation<T, E> {
+        let timeout = self.duration;
+        Arc::new(move || {
+            let fut = op();
+            Box::pin(async move {
+                tokio::time::timeout(timeout, fut)
+                    .await
+                    .unwrap_or(Err(PolicyError::Timeout(TimeoutError { timeout })))
+            })
+        })
+    }
+}
+
+impl<T, E> Policy<T, E> for RetryPolicy<PolicyError<E>>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    /// Rejections from a circuit breaker or bulkhead are never retried:
+    /// backing off and trying again would only hammer the layer that is
+    /// shedding load
+    fn wrap(&self, op: Operation<T, E>) -> Operation<T, E> {
+        let configured = self.clone();
+        let policy = self.clone().retry_if(move |e: &PolicyError<E>| {
+            !matches!(e, PolicyError::CircuitOpen | PolicyError::BulkheadFull)
+                && configured.is_retryable(e)
+        });
+        Arc::new(move || {
+            let op = op.clone();
+            let policy = policy.clone();
+            Box::pin(async move { super::retry(policy, || op()).await })
+        })
+    }
+}
+
+impl<T, E> Policy<T, E> for CircuitBreaker
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    fn wrap(&self, op: Operation<T, E>) -> Operation<T, E> {
+        let breaker = self.clone();
+        Arc::new(move || {
+            let op = op.clone();
+            let breaker = breaker.clone();
+            Box::pin(async move {
+                breaker.call(|| op()).await.map_err(|e| match e {
+                    CircuitError::Open => PolicyError::CircuitOpen,
+                    CircuitError::Inner(e) => e,
+                })
+            })
+        })
+    }
+}
+
+impl<T, E> Policy<T, E> for Bulkhead
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    fn wrap(&self, op: Operation<T, E>) -> Operation<T, E> {
+        let bulkhead = self.clone();
+        Arc::new(move || {
+            let op = op.clone();
+            let bulkhead = bulkhead.clone();
+            Box::pin(async move {
+                bulkhead
+                    .call(|| op())
+                    .await
+                    .unwrap_or(Err(PolicyError::BulkheadFull))
+            })
+        })
+    }
+}
+
+impl<T, E> Policy<T, E> for TokenBucket
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    fn wrap(&self, op: Operation<T, E>) -> Operation<T, E> {
+        let bucket = self.clone();
+        Arc::new(move || {
+            let op = op.clone();
+            let bucket = bucket.clone();
+            Box::pin(async move {
+                bucket.acquire().await;
+                op().await
+            })
+        })
+    }
+}
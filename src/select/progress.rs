@@ -0,0 +1,150 @@
+//! Periodic progress reports from long-running operations
+//!
+//! [`with_progress`] calls back every interval while a future runs, with
+//! the time so far and whatever status the operation last set through
+//! [`set_status`], and logs a warning once the operation takes longer than
+//! expected. Nothing is cancelled; for that see
+//! [`with_watchdog`](super::with_watchdog).
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{sleep_until, Instant, MissedTickBehavior};
+
+tokio::task_local! {
+    static STATUS: Arc<Mutex<Option<String>>>;
+}
+
+/// What a progress callback is told on every tick
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressUpdate {
+    /// Time since the operation started
+    pub elapsed: Duration,
+    /// The last status passed to [`set_status`], if any
+    pub status: Option<String>,
+}
+
+/// Settings for [`with_progress_config`]
+#[derive(Debug, Clone)]
+pub struct ProgressConfig {
+    /// How often the callback runs
+    pub interval: Duration,
+    /// Log a warning once the operation has run this long
+    pub warn_after: Option<Duration>,
+    /// Names the operation in the warning
+    pub name: String,
+}
+
+impl Default for ProgressConfig {
+    /// Reports every second and warns after 30 seconds
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            warn_after: Some(Duration::from_secs(30)),
+            name: "operation".to_string(),
+        }
+    }
+}
+
+/// Sets the status reported by the enclosing [`with_progress`], returning
+/// false when called outside one
+///
+/// The status is task-local, so it can only be set from the future being
+/// reported on, not from tasks it spawns.
+pub fn set_status(status: impl Into<String>) -> bool {
+    STATUS
+        .try_with(|current| *current.lock().unwrap() = Some(status.into()))
+        .is_ok()
+}
+
+/// Runs `fut`, calling `callback` every `interval` until it finishes
+///
+/// Warns after the default 30 seconds; see [`with_progress_config`] to
+/// change that.
+///
+/// # Panics
+///
+/// Panics if `interval` is zero.
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::select::{set_status, with_progress};
+///
+/// let migrate = async {
+///     for table in ["users", "orders", "invoices"] {
+///         set_status(format!("migrating {}", table));
+///         // ... migrate the table ...
+///     }
+/// };
+/// with_progress(migrate, Duration::from_secs(5), |update| {
+///     println!("{:?} in: {:?}", update.elapsed, update.status);
+/// })
+/// .await;
+/// # }
+/// ```
+pub async fn with_progress<F, C>(fut: F, interval: Duration, callback: C) -> F::Output
+where
+    F: Future,
+    C: FnMut(ProgressUpdate),
+{
+    let config = ProgressConfig {
+        interval,
+        ..ProgressConfig::default()
+    };
+    with_progress_config(fut, config, callback).await
+}
+
+/// [`with_progress`] with every setting explicit
+///
+/// # Panics
+///
+/// Panics if `config.interval` is zero.
+pub async fn with_progress_config<F, C>(
+    fut: F,
+    config: ProgressConfig,
+    mut callback: C,
+) -> F::Output
+where
+    F: Future,
+    C: FnMut(ProgressUpdate),
+{
+    assert!(!config.interval.is_zero(), "`interval` must be non-zero");
+
+    let status = Arc::new(Mutex::new(None));
+    let mut fut = std::pin::pin!(STATUS.scope(status.clone(), fut));
+    let started = Instant::now();
+    let mut ticks = tokio::time::interval_at(started + config.interval, config.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let warn_at = config.warn_after.map(|after| started + after);
+    let mut warned = false;
+
+    loop {
+        tokio::select! {
+            biased;
+            out = &mut fut => return out,
+            _ = ticks.tick() => callback(ProgressUpdate {
+                elapsed: started.elapsed(),
+                status: status.lock().unwrap().clone(),
+            }),
+            _ = sleep_until(warn_at.unwrap_or(started)), if warn_at.is_some() && !warned => {
+                warned = true;
+                let status = status.lock().unwrap().clone();
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    operation = %config.name,
+                    elapsed = ?started.elapsed(),
+                    ?status,
+                    "operation is taking too long"
+                );
+                #[cfg(not(feature = "tracing"))]
+                eprintln!(
+                    "{} is taking too long: {:?} so far, status {:?}",
+                    config.name,
+                    started.elapsed(),
+                    status
+                );
+            }
+        }
+    }
+}
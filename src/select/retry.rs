@@ -0,0 +1,207 @@
+//! Retrying fallible async operations with backoff and jitter
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How the delay between attempts grows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+    /// The same delay before every retry
+    Fixed(Duration),
+    /// `initial * multiplier^(retry - 1)`, capped at `max`
+    Exponential {
+        initial: Duration,
+        multiplier: f64,
+        max: Duration,
+    },
+}
+
+/// How randomness is applied to the computed delay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jitter {
+    None,
+    /// A uniformly random delay between zero and the computed delay
+    Full,
+}
+
+/// When and how often [`retry`] tries again
+///
+/// Policies start with no jitter, no elapsed-time limit, every error
+/// considered retryable, and at most 3 attempts.
+pub struct RetryPolicy<E> {
+    backoff: Backoff,
+    jitter: Jitter,
+    max_attempts: Option<u32>,
+    max_elapsed: Option<Duration>,
+    retryable: Arc<dyn Fn(&E) -> bool + Send + Sync>,
+}
+
+impl<E> Clone for RetryPolicy<E> {
+    fn clone(&self) -> Self {
+        Self {
+            backoff: self.backoff,
+            jitter: self.jitter,
+            max_attempts: self.max_attempts,
+            max_elapsed: self.max_elapsed,
+            retryable: Arc::clone(&self.retryable),
+        }
+    }
+}
+
+impl<E> std::fmt::Debug for RetryPolicy<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("backoff", &self.backoff)
+            .field("jitter", &self.jitter)
+            .field("max_attempts", &self.max_attempts)
+            .field("max_elapsed", &self.max_elapsed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<E> RetryPolicy<E> {
+    fn with_backoff(backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            jitter: Jitter::None,
+            max_attempts: Some(3),
+            max_elapsed: None,
+            retryable: Arc::new(|_| true),
+        }
+    }
+
+    /// Waits the same `delay` between attempts
+    pub fn fixed(delay: Duration) -> Self {
+        Self::with_backoff(Backoff::Fixed(delay))
+    }
+
+    /// Doubles the delay after every failure, starting at `initial`, up to 30s
+    pub fn exponential(initial: Duration) -> Self {
+        Self::with_backoff(Backoff::Exponential {
+            initial,
+            multiplier: 2.0,
+            max: Duration::from_secs(30),
+        })
+    }
+
+    /// Replaces the backoff strategy
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Randomizes every delay between zero and its computed value
+    pub fn full_jitter(mut self) -> Self {
+        self.jitter = Jitter::Full;
+        self
+    }
+
+    /// Total attempts including the first one; `None` retries forever
+    pub fn max_attempts(mut self, attempts: Option<u32>) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Stops retrying once the next attempt would start after this much time
+    pub fn max_elapsed(mut self, elapsed: Duration) -> Self {
+        self.max_elapsed = Some(elapsed);
+        self
+    }
+
+    /// Only errors for which `predicate` returns true are retried
+    pub fn retry_if<P>(mut self, predicate: P) -> Self
+    where
+        P: Fn(&E) -> bool + Send + Sync + 'static,
+    {
+        self.retryable = Arc::new(predicate);
+        self
+    }
+
+    /// Whether `error` should be retried at all
+    pub fn is_retryable(&self, error: &E) -> bool {
+        (self.retryable)(error)
+    }
+
+    /// Delay before retry number `retry` (1 for the first retry), jitter included
+    pub fn delay_for(&self, retry: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential {
+                initial,
+                multiplier,
+                max,
+            } => {
+                let exponent = retry.saturating_sub(1).min(i32::MAX as u32) as i32;
+                let secs = initial.as_secs_f64() * multiplier.powi(exponent);
+                // Large exponents overflow to infinity, which simply means "capped"
+                if secs.is_finite() && secs < max.as_secs_f64() {
+                    Duration::from_secs_f64(secs)
+                } else {
+                    max
+                }
+            }
+        };
+
+        match self.jitter {
+            Jitter::None => delay,
+            Jitter::Full => delay.mul_f64(rand::random::<f64>()),
+        }
+    }
+
+    /// Decides whether to try again after `attempts` attempts have failed,
+    /// returning the delay to wait first
+    pub fn next_delay(&self, attempts: u32, started: Instant, error: &E) -> Option<Duration> {
+        if !self.is_retryable(error) {
+            return None;
+        }
+        if self.max_attempts.is_some_and(|max| attempts >= max) {
+            return None;
+        }
+
+        let delay = self.delay_for(attempts);
+        if let Some(max_elapsed) = self.max_elapsed {
+            if started.elapsed() + delay > max_elapsed {
+                return None;
+            }
+        }
+        Some(delay)
+    }
+}
+
+/// Runs `op` until it succeeds or `policy` gives up, returning the last error
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::select::{retry, RetryPolicy};
+///
+/// let policy = RetryPolicy::exponential(Duration::from_millis(10))
+///     .full_jitter()
+///     .max_attempts(Some(5))
+///     .retry_if(|e: &std::io::Error| e.kind() != std::io::ErrorKind::NotFound);
+///
+/// let contents = retry(policy, || tokio::fs::read("/etc/hostname")).await;
+/// # let _ = contents;
+/// # }
+/// ```
+pub async fn retry<T, E, F, Fut>(policy: RetryPolicy<E>, mut op: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let started = Instant::now();
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) => match policy.next_delay(attempts, started, &e) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(e),
+            },
+        }
+    }
+}
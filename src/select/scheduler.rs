@@ -0,0 +1,506 @@
+//! Running jobs on cron expressions or fixed intervals
+//!
+//! Each job added to a [`Scheduler`] gets its own driver task that sleeps
+//! until the next fire time and starts a run according to the job's
+//! [`OverlapPolicy`]. Every run, skipped run and failure is reported as a
+//! [`RunRecord`] on the channel returned by [`Scheduler::new`].
+//!
+//! Cron expressions use the classic five fields (minute, hour, day of month,
+//! month, day of week) and are evaluated in UTC.
+//!
+//! ```
+//! # async fn example() -> Result<(), tokio_tutorial_patterns::select::scheduler::CronError> {
+//! use std::time::Duration;
+//! use tokio_tutorial_patterns::select::scheduler::{Job, OverlapPolicy, Schedule, Scheduler};
+//!
+//! let (mut scheduler, mut history) = Scheduler::new();
+//!
+//! let report = scheduler.add(Job::new("report", Schedule::cron("0 6 * * 1-5")?, || async {
+//!     Ok::<_, std::io::Error>(())
+//! }));
+//! scheduler.add(
+//!     Job::new("refresh", Schedule::Interval(Duration::from_secs(30)), || async {
+//!         Ok::<_, std::io::Error>(())
+//!     })
+//!     .overlap(OverlapPolicy::Skip),
+//! );
+//!
+//! report.disable(); // e.g. during a holiday freeze
+//! while let Some(record) = history.recv().await {
+//!     println!("{}: {:?}", record.job, record.outcome);
+//! }
+//! scheduler.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Error returned for a malformed cron expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronError(String);
+
+impl fmt::Display for CronError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid cron expression: {}", self.0)
+    }
+}
+
+impl std::error::Error for CronError {}
+
+/// A parsed five-field cron expression
+///
+/// Fields accept `*`, numbers, ranges (`1-5`), lists (`1,15`) and steps
+/// (`*/10`, `0-30/5`). Day of week runs from 0 (Sunday) to 6, with 7 also
+/// meaning Sunday. The shorthands `@hourly`, `@daily`, `@weekly`,
+/// `@monthly` and `@yearly` are accepted too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Cron matches either day field when both are restricted
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, CronError> {
+        let expanded = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+
+        let fields: Vec<_> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(CronError(format!(
+                "expected 5 fields, found {} in {:?}",
+                fields.len(),
+                expression
+            )));
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        // 7 is an alias for Sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays & !(1 << 7)) | 1;
+        }
+
+        let days = parse_field(day, 1, 31)?;
+        // A field is restricted by what it matches, so `*/1` is still `*`
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            days_restricted: days != parse_field("*", 1, 31)?,
+            weekdays_restricted: weekdays != parse_field("*", 0, 6)?,
+        })
+    }
+
+    /// The first matching minute strictly after `time`, if any within five years
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let mut minute = secs / 60 + 1;
+        let limit = minute + 5 * 366 * 24 * 60;
+
+        while minute < limit {
+            let days = minute / (24 * 60);
+            let (year, month, day) = civil_from_days(days);
+
+            if !bit(self.months, month) {
+                let (year, month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                minute = days_from_civil(year, month, 1) * 24 * 60;
+                continue;
+            }
+            if !self.day_matches(day, (days + 4) % 7) {
+                minute = (days + 1) * 24 * 60;
+                continue;
+            }
+            if !bit(self.hours, minute / 60 % 24) {
+                minute = (minute / 60 + 1) * 60;
+                continue;
+            }
+            if !bit(self.minutes, minute % 60) {
+                minute += 1;
+                continue;
+            }
+            return Some(UNIX_EPOCH + Duration::from_secs(minute * 60));
+        }
+        None
+    }
+
+    fn day_matches(&self, day: u64, weekday: u64) -> bool {
+        let by_day = bit(self.days, day);
+        let by_weekday = bit(self.weekdays, weekday);
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => by_day || by_weekday,
+            (true, false) => by_day,
+            (false, true) => by_weekday,
+            (false, false) => true,
+        }
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = CronError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+fn bit(mask: u64, value: u64) -> bool {
+    mask & (1 << value) != 0
+}
+
+/// Parses one cron field into a bitmask of allowed values
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, CronError> {
+    let mut mask = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u64 = step
+                    .parse()
+                    .map_err(|_| CronError(format!("bad step in {:?}", part)))?;
+                if step == 0 {
+                    return Err(CronError(format!("zero step in {:?}", part)));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else {
+            let number = |s: &str| {
+                s.parse::<u64>()
+                    .map_err(|_| CronError(format!("bad value {:?} in {:?}", s, field)))
+            };
+            match range.split_once('-') {
+                Some((start, end)) => (number(start)?, number(end)?),
+                None if step > 1 => (number(range)?, max),
+                None => {
+                    let value = number(range)?;
+                    (value, value)
+                }
+            }
+        };
+
+        if start < min || end > max || start > end {
+            return Err(CronError(format!(
+                "{:?} is outside {}-{} in {:?}",
+                range, min, max, field
+            )));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+/// Days since 1970-01-01 to (year, month, day), from Howard Hinnant's algorithm
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// When a job fires
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Every period, starting one period after the job is added or enabled
+    Interval(Duration),
+    Cron(CronSchedule),
+}
+
+impl Schedule {
+    pub fn cron(expression: &str) -> Result<Self, CronError> {
+        CronSchedule::parse(expression).map(Schedule::Cron)
+    }
+
+    fn next_fire(&self, after: Instant) -> Option<Instant> {
+        match self {
+            Schedule::Interval(period) => Some(after + *period),
+            Schedule::Cron(cron) => {
+                let now = SystemTime::now();
+                let next = cron.next_after(now)?;
+                let wait = next.duration_since(now).unwrap_or_default();
+                Some(Instant::now() + wait)
+            }
+        }
+    }
+}
+
+/// What to do when a job fires while its previous run is still going
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Drop the new run, reporting it as [`RunOutcome::Skipped`]
+    Skip,
+    /// Start the new run once the running one finishes
+    Queue,
+    /// Start the new run right away
+    Concurrent,
+}
+
+/// How a run ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunOutcome {
+    Succeeded,
+    /// The job returned an error, or panicked
+    Failed(String),
+    /// The job fired while a previous run was active under [`OverlapPolicy::Skip`]
+    Skipped,
+}
+
+/// One entry in the run history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunRecord {
+    pub job: Arc<str>,
+    pub started: Instant,
+    pub duration: Duration,
+    pub outcome: RunOutcome,
+}
+
+type JobFn = Arc<dyn Fn() -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// A named unit of work and when to run it
+pub struct Job {
+    name: Arc<str>,
+    schedule: Schedule,
+    overlap: OverlapPolicy,
+    run: JobFn,
+}
+
+impl Job {
+    /// A job running `f`, whose errors are recorded by their `Display` output
+    ///
+    /// Jobs allow concurrent runs unless configured otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `schedule` is a zero [`Schedule::Interval`].
+    pub fn new<F, Fut, E>(name: impl Into<Arc<str>>, schedule: Schedule, f: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), E>> + Send + 'static,
+        E: fmt::Display,
+    {
+        if let Schedule::Interval(period) = schedule {
+            assert!(period > Duration::ZERO, "`period` must be non-zero");
+        }
+        Self {
+            name: name.into(),
+            schedule,
+            overlap: OverlapPolicy::Concurrent,
+            run: Arc::new(move || {
+                let fut = f();
+                Box::pin(async move { fut.await.map_err(|e| e.to_string()) })
+            }),
+        }
+    }
+
+    pub fn overlap(mut self, policy: OverlapPolicy) -> Self {
+        self.overlap = policy;
+        self
+    }
+}
+
+/// Enables or disables a scheduled job at runtime
+///
+/// Disabling stops new runs; runs already in progress finish normally.
+#[derive(Debug, Clone)]
+pub struct JobHandle {
+    name: Arc<str>,
+    enabled: Arc<watch::Sender<bool>>,
+}
+
+impl JobHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn enable(&self) {
+        self.enabled.send_replace(true);
+    }
+
+    pub fn disable(&self) {
+        self.enabled.send_replace(false);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self.enabled.borrow()
+    }
+}
+
+/// Owns the driver tasks of every added job
+pub struct Scheduler {
+    jobs: JoinSet<()>,
+    token: CancellationToken,
+    history: mpsc::UnboundedSender<RunRecord>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler and the channel its run history is reported on
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<RunRecord>) {
+        let (history, rx) = mpsc::unbounded_channel();
+        let scheduler = Self {
+            jobs: JoinSet::new(),
+            token: CancellationToken::new(),
+            history,
+        };
+        (scheduler, rx)
+    }
+
+    /// Starts scheduling `job`, initially enabled
+    pub fn add(&mut self, job: Job) -> JobHandle {
+        let (enabled, enabled_rx) = watch::channel(true);
+        let handle = JobHandle {
+            name: job.name.clone(),
+            enabled: Arc::new(enabled),
+        };
+
+        self.jobs.spawn(drive(
+            job,
+            enabled_rx,
+            self.token.child_token(),
+            self.history.clone(),
+        ));
+        handle
+    }
+
+    /// Stops firing jobs and waits for the runs in progress to finish
+    pub async fn shutdown(mut self) {
+        self.token.cancel();
+        while self.jobs.join_next().await.is_some() {}
+    }
+}
+
+async fn drive(
+    job: Job,
+    mut enabled: watch::Receiver<bool>,
+    token: CancellationToken,
+    history: mpsc::UnboundedSender<RunRecord>,
+) {
+    let mut runs = JoinSet::new();
+    let mut queued = 0usize;
+    let mut next_fire = None;
+    let mut is_enabled = *enabled.borrow_and_update();
+    // False once every JobHandle is dropped
+    let mut controllable = true;
+
+    loop {
+        if is_enabled && next_fire.is_none() {
+            next_fire = job.schedule.next_fire(Instant::now());
+            // A cron expression that never matches again ends the job
+            if next_fire.is_none() && runs.is_empty() && queued == 0 {
+                break;
+            }
+        }
+        if !is_enabled && !controllable {
+            break;
+        }
+        let deadline = next_fire.filter(|_| is_enabled);
+
+        tokio::select! {
+            _ = token.cancelled() => break,
+            changed = enabled.changed(), if controllable => match changed {
+                Ok(()) => {
+                    is_enabled = *enabled.borrow_and_update();
+                    next_fire = None;
+                }
+                Err(_) => controllable = false,
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let fired = deadline.unwrap();
+                let now = Instant::now();
+                // Falling behind restarts the schedule rather than bursting
+                next_fire = job.schedule.next_fire(fired).filter(|next| *next > now);
+
+                if runs.is_empty() || job.overlap == OverlapPolicy::Concurrent {
+                    spawn_run(&mut runs, &job, &history);
+                } else if job.overlap == OverlapPolicy::Queue {
+                    queued += 1;
+                } else {
+                    let _ = history.send(RunRecord {
+                        job: job.name.clone(),
+                        started: now,
+                        duration: Duration::ZERO,
+                        outcome: RunOutcome::Skipped,
+                    });
+                }
+            }
+            Some(_) = runs.join_next(), if !runs.is_empty() => {
+                if queued > 0 && runs.is_empty() {
+                    queued -= 1;
+                    spawn_run(&mut runs, &job, &history);
+                }
+            }
+        }
+    }
+
+    while runs.join_next().await.is_some() {}
+}
+
+fn spawn_run(runs: &mut JoinSet<()>, job: &Job, history: &mpsc::UnboundedSender<RunRecord>) {
+    let run = job.run.clone();
+    let name = job.name.clone();
+    let history = history.clone();
+
+    runs.spawn(async move {
+        let started = Instant::now();
+        let outcome = match AssertUnwindSafe(async { run().await }).catch_unwind().await {
+            Ok(Ok(())) => RunOutcome::Succeeded,
+            Ok(Err(e)) => RunOutcome::Failed(e),
+            Err(_) => RunOutcome::Failed("run panicked".to_string()),
+        };
+        let _ = history.send(RunRecord {
+            job: name,
+            started,
+            duration: started.elapsed(),
+            outcome,
+        });
+    });
+}
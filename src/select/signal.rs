@@ -0,0 +1,118 @@
+//! Waiting for OS shutdown signals
+
+use crate::shutdown::{Coordinator, ShutdownReport};
+use std::time::Duration;
+
+/// The signal that requested shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownSignal {
+    /// SIGINT on Unix, Ctrl-C on Windows
+    Interrupt,
+    /// SIGTERM on Unix
+    Terminate,
+    /// Ctrl-Break on Windows
+    CtrlBreak,
+}
+
+/// Handlers for the platform's shutdown signals
+///
+/// A signal delivered before the handlers are installed is not seen, so
+/// install them before anything that might send one, e.g. before reporting
+/// the service ready.
+///
+/// Handles SIGINT and SIGTERM on Unix, and Ctrl-C and Ctrl-Break on Windows.
+pub struct ShutdownSignals {
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+    #[cfg(windows)]
+    interrupt: tokio::signal::windows::CtrlC,
+    #[cfg(windows)]
+    brk: tokio::signal::windows::CtrlBreak,
+}
+
+impl ShutdownSignals {
+    /// Installs the handlers; fails only if they cannot be installed
+    #[cfg(unix)]
+    pub fn install() -> std::io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+        })
+    }
+
+    /// Installs the handlers; fails only if they cannot be installed
+    #[cfg(windows)]
+    pub fn install() -> std::io::Result<Self> {
+        use tokio::signal::windows::{ctrl_break, ctrl_c};
+
+        Ok(Self {
+            interrupt: ctrl_c()?,
+            brk: ctrl_break()?,
+        })
+    }
+
+    /// Waits for the next shutdown signal
+    #[cfg(unix)]
+    pub async fn recv(&mut self) -> ShutdownSignal {
+        tokio::select! {
+            _ = self.interrupt.recv() => ShutdownSignal::Interrupt,
+            _ = self.terminate.recv() => ShutdownSignal::Terminate,
+        }
+    }
+
+    /// Waits for the next shutdown signal
+    #[cfg(windows)]
+    pub async fn recv(&mut self) -> ShutdownSignal {
+        tokio::select! {
+            _ = self.interrupt.recv() => ShutdownSignal::Interrupt,
+            _ = self.brk.recv() => ShutdownSignal::CtrlBreak,
+        }
+    }
+
+    /// Waits for a shutdown signal, then runs the coordinator's phased
+    /// shutdown
+    pub async fn shutdown(
+        mut self,
+        coordinator: &Coordinator,
+        timeout: Duration,
+    ) -> (ShutdownSignal, ShutdownReport) {
+        let signal = self.recv().await;
+        let report = coordinator.shutdown(timeout).await;
+        (signal, report)
+    }
+}
+
+/// Waits for the first shutdown signal the platform delivers
+///
+/// Handles SIGINT and SIGTERM on Unix, and Ctrl-C and Ctrl-Break on Windows.
+/// Fails only if the signal handlers cannot be installed.
+pub async fn wait_for_shutdown_signal() -> std::io::Result<ShutdownSignal> {
+    Ok(ShutdownSignals::install()?.recv().await)
+}
+
+/// Waits for a shutdown signal, then runs the coordinator's phased shutdown
+///
+/// ```no_run
+/// # async fn example() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::{select, shutdown::Coordinator};
+///
+/// let coordinator = Coordinator::new();
+/// // ... register subsystems and start the service ...
+/// let (signal, report) = select::shutdown_on_signal(&coordinator, Duration::from_secs(30)).await?;
+/// println!("{:?} received, laggards: {:?}", signal, report.timed_out);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn shutdown_on_signal(
+    coordinator: &Coordinator,
+    timeout: Duration,
+) -> std::io::Result<(ShutdownSignal, ShutdownReport)> {
+    Ok(ShutdownSignals::install()?
+        .shutdown(coordinator, timeout)
+        .await)
+}
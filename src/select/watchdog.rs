@@ -0,0 +1,101 @@
+//! Aborting operations that stop making progress
+//!
+//! A timeout can't tell a long job that is still moving from one that is
+//! hung. With [`with_watchdog`] the job reports progress through a
+//! [`ProgressHandle`] and is only aborted when it goes quiet for too long.
+
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::{sleep_until, Instant};
+
+/// Error returned when a watched operation stops reporting progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stalled {
+    /// How long the operation went without reporting
+    pub stalled_for: Duration,
+    /// Progress reports received before it stalled
+    pub reports: u64,
+}
+
+impl std::fmt::Display for Stalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "operation stalled: no progress for {:?} after {} reports",
+            self.stalled_for, self.reports
+        )
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+/// Lets a watched operation report that it is still making progress
+#[derive(Debug, Clone)]
+pub struct ProgressHandle {
+    tx: watch::Sender<u64>,
+}
+
+impl ProgressHandle {
+    pub fn report(&self) {
+        self.tx.send_modify(|reports| *reports += 1);
+    }
+}
+
+/// Creates a progress handle for the operation and the receiver to pass
+/// to [`with_watchdog`]
+pub fn progress() -> (ProgressHandle, watch::Receiver<u64>) {
+    let (tx, rx) = watch::channel(0);
+    (ProgressHandle { tx }, rx)
+}
+
+/// Runs `fut`, dropping it if `stall_timeout` passes without a progress report
+///
+/// The stall window starts when this is called and restarts on every
+/// report. Once every [`ProgressHandle`] is gone no more reports can arrive,
+/// so the current window is the last one.
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::select::{progress, with_watchdog};
+///
+/// let (handle, progress_rx) = progress();
+/// let copy = async move {
+///     for _chunk in 0..100 {
+///         // ... copy one chunk ...
+///         handle.report();
+///     }
+/// };
+/// match with_watchdog(copy, progress_rx, Duration::from_secs(5)).await {
+///     Ok(()) => println!("copy finished"),
+///     Err(stalled) => println!("copy aborted: {}", stalled),
+/// }
+/// # }
+/// ```
+pub async fn with_watchdog<F: Future>(
+    fut: F,
+    mut progress_rx: watch::Receiver<u64>,
+    stall_timeout: Duration,
+) -> Result<F::Output, Stalled> {
+    let mut fut = std::pin::pin!(fut);
+    let mut last_progress = Instant::now();
+    let mut open = true;
+
+    loop {
+        tokio::select! {
+            biased;
+            out = &mut fut => return Ok(out),
+            changed = progress_rx.changed(), if open => match changed {
+                Ok(()) => last_progress = Instant::now(),
+                Err(_) => open = false,
+            },
+            _ = sleep_until(last_progress + stall_timeout) => {
+                return Err(Stalled {
+                    stalled_for: last_progress.elapsed(),
+                    reports: *progress_rx.borrow(),
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,358 @@
+//! Composable request/response middleware, without depending on tower
+//!
+//! A [`Service`] turns a request into a response asynchronously. A
+//! [`Layer`] wraps a service in another one that adds behaviour around each
+//! call: a timeout, retries, a concurrency limit or logging. Layers are
+//! stacked with a [`ServiceBuilder`], outermost first, so a stack reads in
+//! the order a request travels through it.
+//!
+//! Layers keep the wrapped service's error type. The timeout layer needs
+//! that type to implement `From<TimeoutError>`, as
+//! `Box<dyn Error + Send + Sync>` does; an application error enum needs an
+//! impl of its own, usually mapping [`TimeoutError`] to a variant.
+//!
+//! ```
+//! # async fn example() {
+//! use std::time::Duration;
+//! use tokio_tutorial_patterns::select::RetryPolicy;
+//! use tokio_tutorial_patterns::service::{service_fn, BoxError, Service, ServiceBuilder};
+//!
+//! let lookup = ServiceBuilder::new()
+//!     .logging("lookup")
+//!     .concurrency_limit(16)
+//!     .timeout(Duration::from_secs(2))
+//!     .retry(RetryPolicy::exponential(Duration::from_millis(50)))
+//!     .service(service_fn(|id: u64| async move {
+//!         Ok::<_, BoxError>(format!("user {}", id))
+//!     }));
+//!
+//! let user = lookup.call(7).await;
+//! # let _ = user;
+//! # }
+//! ```
+
+use crate::channels::RequestHandler;
+use crate::select::{retry, RetryPolicy, TimeoutError};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Semaphore};
+use tokio::time::Instant;
+
+/// Error type for services that don't need a specific one
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An async function from requests to responses
+pub trait Service<Req, Resp>: Send + Sync {
+    type Error;
+
+    fn call(&self, req: Req) -> impl Future<Output = Result<Resp, Self::Error>> + Send;
+}
+
+/// Wraps a service in another one
+pub trait Layer<S> {
+    type Service;
+
+    fn layer(&self, inner: S) -> Self::Service;
+}
+
+/// A service backed by a closure, created with [`service_fn`]
+#[derive(Clone)]
+pub struct ServiceFn<F> {
+    f: F,
+}
+
+/// Turns an async closure into a [`Service`]
+pub fn service_fn<F>(f: F) -> ServiceFn<F> {
+    ServiceFn { f }
+}
+
+impl<F, Fut, Req, Resp, E> Service<Req, Resp> for ServiceFn<F>
+where
+    F: Fn(Req) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Resp, E>> + Send,
+{
+    type Error = E;
+
+    fn call(&self, req: Req) -> impl Future<Output = Result<Resp, E>> + Send {
+        (self.f)(req)
+    }
+}
+
+impl<Req, Resp> Service<Req, Resp> for RequestHandler<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    type Error = oneshot::error::RecvError;
+
+    fn call(&self, req: Req) -> impl Future<Output = Result<Resp, Self::Error>> + Send {
+        self.request(req)
+    }
+}
+
+/// Fails calls that take longer than a fixed duration
+#[derive(Debug, Clone)]
+pub struct Timeout<S> {
+    inner: S,
+    timeout: Duration,
+}
+
+impl<S, Req, Resp> Service<Req, Resp> for Timeout<S>
+where
+    S: Service<Req, Resp>,
+    S::Error: From<TimeoutError>,
+{
+    type Error = S::Error;
+
+    fn call(&self, req: Req) -> impl Future<Output = Result<Resp, S::Error>> + Send {
+        let call = self.inner.call(req);
+        let timeout = self.timeout;
+        async move {
+            match tokio::time::timeout(timeout, call).await {
+                Ok(result) => result,
+                Err(_) => Err(TimeoutError { timeout }.into()),
+            }
+        }
+    }
+}
+
+/// Retries failed calls with a clone of the request, according to a
+/// [`RetryPolicy`]
+#[derive(Debug, Clone)]
+pub struct Retry<S, E> {
+    inner: S,
+    policy: RetryPolicy<E>,
+}
+
+impl<S, Req, Resp> Service<Req, Resp> for Retry<S, S::Error>
+where
+    S: Service<Req, Resp>,
+    Req: Clone + Send + Sync,
+    Resp: Send,
+    S::Error: Send,
+{
+    type Error = S::Error;
+
+    async fn call(&self, req: Req) -> Result<Resp, S::Error> {
+        retry(self.policy.clone(), || self.inner.call(req.clone())).await
+    }
+}
+
+/// Lets at most a fixed number of calls run at once; the rest wait
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit<S> {
+    inner: S,
+    slots: Arc<Semaphore>,
+}
+
+impl<S, Req, Resp> Service<Req, Resp> for ConcurrencyLimit<S>
+where
+    S: Service<Req, Resp>,
+    Req: Send,
+{
+    type Error = S::Error;
+
+    async fn call(&self, req: Req) -> Result<Resp, S::Error> {
+        let _slot = self
+            .slots
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        self.inner.call(req).await
+    }
+}
+
+/// Logs the outcome and duration of calls
+///
+/// With the `tracing` feature every call is logged, successes at debug
+/// level. Without it only failures are, to standard error.
+#[derive(Debug, Clone)]
+pub struct Logging<S> {
+    inner: S,
+    name: Arc<str>,
+}
+
+impl<S, Req, Resp> Service<Req, Resp> for Logging<S>
+where
+    S: Service<Req, Resp>,
+    S::Error: std::fmt::Display,
+{
+    type Error = S::Error;
+
+    fn call(&self, req: Req) -> impl Future<Output = Result<Resp, S::Error>> + Send {
+        let call = self.inner.call(req);
+        let name = self.name.clone();
+        async move {
+            let started = Instant::now();
+            let result = call.await;
+            let elapsed = started.elapsed();
+            match &result {
+                #[cfg(feature = "tracing")]
+                Ok(_) => tracing::debug!(service = %name, ?elapsed, "call succeeded"),
+                #[cfg(feature = "tracing")]
+                Err(e) => tracing::warn!(service = %name, ?elapsed, error = %e, "call failed"),
+                #[cfg(not(feature = "tracing"))]
+                Ok(_) => {}
+                #[cfg(not(feature = "tracing"))]
+                Err(e) => eprintln!("{}: failed in {:?}: {}", name, elapsed, e),
+            }
+            result
+        }
+    }
+}
+
+/// Adds a [`Timeout`]
+#[derive(Debug, Clone)]
+pub struct TimeoutLayer(pub Duration);
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = Timeout<S>;
+
+    fn layer(&self, inner: S) -> Timeout<S> {
+        Timeout {
+            inner,
+            timeout: self.0,
+        }
+    }
+}
+
+/// Adds a [`Retry`]
+#[derive(Debug, Clone)]
+pub struct RetryLayer<E>(pub RetryPolicy<E>);
+
+impl<S, E> Layer<S> for RetryLayer<E> {
+    type Service = Retry<S, E>;
+
+    fn layer(&self, inner: S) -> Retry<S, E> {
+        Retry {
+            inner,
+            policy: self.0.clone(),
+        }
+    }
+}
+
+/// Adds a [`ConcurrencyLimit`]; services built from the same layer share
+/// its limit
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimitLayer {
+    slots: Arc<Semaphore>,
+}
+
+impl ConcurrencyLimitLayer {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+        }
+    }
+}
+
+impl<S> Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimit<S>;
+
+    fn layer(&self, inner: S) -> ConcurrencyLimit<S> {
+        ConcurrencyLimit {
+            inner,
+            slots: self.slots.clone(),
+        }
+    }
+}
+
+/// Adds [`Logging`] under `name`
+#[derive(Debug, Clone)]
+pub struct LoggingLayer(pub Arc<str>);
+
+impl<S> Layer<S> for LoggingLayer {
+    type Service = Logging<S>;
+
+    fn layer(&self, inner: S) -> Logging<S> {
+        Logging {
+            inner,
+            name: self.0.clone(),
+        }
+    }
+}
+
+/// The layer that adds nothing, where a [`ServiceBuilder`] starts
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl<S> Layer<S> for Identity {
+    type Service = S;
+
+    fn layer(&self, inner: S) -> S {
+        inner
+    }
+}
+
+/// Two layers applied one inside the other
+#[derive(Debug, Clone)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<S, Inner, Outer> Layer<S> for Stack<Inner, Outer>
+where
+    Inner: Layer<S>,
+    Outer: Layer<Inner::Service>,
+{
+    type Service = Outer::Service;
+
+    fn layer(&self, service: S) -> Outer::Service {
+        self.outer.layer(self.inner.layer(service))
+    }
+}
+
+/// Stacks layers around a service; layers added first are outermost
+///
+/// A builder can be cloned and reused to wrap several services the same way.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceBuilder<L> {
+    layer: L,
+}
+
+impl ServiceBuilder<Identity> {
+    pub fn new() -> Self {
+        Self { layer: Identity }
+    }
+}
+
+impl<L> ServiceBuilder<L> {
+    /// Adds `layer` inside the layers added so far
+    pub fn layer<T>(self, layer: T) -> ServiceBuilder<Stack<T, L>> {
+        ServiceBuilder {
+            layer: Stack {
+                inner: layer,
+                outer: self.layer,
+            },
+        }
+    }
+
+    pub fn timeout(self, timeout: Duration) -> ServiceBuilder<Stack<TimeoutLayer, L>> {
+        self.layer(TimeoutLayer(timeout))
+    }
+
+    pub fn retry<E>(self, policy: RetryPolicy<E>) -> ServiceBuilder<Stack<RetryLayer<E>, L>> {
+        self.layer(RetryLayer(policy))
+    }
+
+    pub fn concurrency_limit(
+        self,
+        max_concurrent: usize,
+    ) -> ServiceBuilder<Stack<ConcurrencyLimitLayer, L>> {
+        self.layer(ConcurrencyLimitLayer::new(max_concurrent))
+    }
+
+    pub fn logging(self, name: &str) -> ServiceBuilder<Stack<LoggingLayer, L>> {
+        self.layer(LoggingLayer(name.into()))
+    }
+
+    /// Wraps `service` in every layer
+    pub fn service<S>(&self, service: S) -> L::Service
+    where
+        L: Layer<S>,
+    {
+        self.layer.layer(service)
+    }
+}
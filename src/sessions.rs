@@ -0,0 +1,220 @@
+//! Token-keyed sessions that expire when left idle
+//!
+//! A [`SessionManager`] hands out a random [`SessionToken`] for every
+//! session it creates, which clients present to find their session again.
+//! Each session's state sits behind its own async lock, so connections
+//! working on different sessions never wait on each other. Sessions nobody
+//! has looked up for the idle timeout are dropped by a background task built
+//! on [`ExpiryQueue`], and every session created, ended or expired is
+//! announced as a [`SessionEvent`].
+
+use crate::select::ExpiryQueue;
+use crate::spawning::spawn_traced;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, MutexGuard};
+use tokio::task::JoinHandle;
+
+/// Events buffered per subscriber before it starts missing them
+const EVENT_CAPACITY: usize = 64;
+
+/// Identifies a session; unguessable when generated by the manager
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionToken(String);
+
+impl SessionToken {
+    fn generate() -> Self {
+        Self(format!("{:032x}", rand::random::<u128>()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A token as presented by a client
+impl From<&str> for SessionToken {
+    fn from(token: &str) -> Self {
+        Self(token.to_string())
+    }
+}
+
+impl From<String> for SessionToken {
+    fn from(token: String) -> Self {
+        Self(token)
+    }
+}
+
+/// A change to the set of live sessions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    Created(SessionToken),
+    /// Ended explicitly with [`SessionManager::end`]
+    Ended(SessionToken),
+    /// Dropped after sitting idle for the timeout
+    Expired(SessionToken),
+}
+
+/// A live session's token and state; clones share the state
+///
+/// A handle kept after its session ends or expires still works, but the
+/// session can no longer be looked up.
+pub struct Session<S> {
+    token: SessionToken,
+    state: Arc<tokio::sync::Mutex<S>>,
+}
+
+impl<S> Clone for Session<S> {
+    fn clone(&self) -> Self {
+        Self {
+            token: self.token.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<S> Session<S> {
+    pub fn token(&self) -> &SessionToken {
+        &self.token
+    }
+
+    /// Locks this session's state, waiting for other users of the same
+    /// session only
+    pub async fn lock(&self) -> MutexGuard<'_, S> {
+        self.state.lock().await
+    }
+}
+
+struct Shared<S> {
+    sessions: Mutex<ExpiryQueue<SessionToken, Arc<tokio::sync::Mutex<S>>>>,
+    events: broadcast::Sender<SessionEvent>,
+}
+
+/// Creates, finds and expires sessions holding state of type `S`
+///
+/// Share one manager between connections with an `Arc`; dropping it stops
+/// expiry.
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::sessions::SessionManager;
+///
+/// let sessions = SessionManager::new(Duration::from_secs(300));
+/// let token = sessions.create(Vec::<String>::new()).token().clone();
+///
+/// // Later, on a request carrying the token
+/// if let Some(session) = sessions.get(&token) {
+///     session.lock().await.push("viewed cart".to_string());
+/// }
+/// # }
+/// ```
+pub struct SessionManager<S> {
+    shared: Arc<Shared<S>>,
+    idle_timeout: Duration,
+    task: JoinHandle<()>,
+}
+
+impl<S: Send + 'static> SessionManager<S> {
+    /// Expires sessions that haven't been looked up for `idle_timeout`
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn new(idle_timeout: Duration) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let shared = Arc::new(Shared {
+            sessions: Mutex::new(ExpiryQueue::new()),
+            events,
+        });
+        let task = spawn_traced("session expiry", expire(shared.clone()));
+
+        Self {
+            shared,
+            idle_timeout,
+            task,
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Starts a session holding `state` under a freshly generated token
+    pub fn create(&self, state: S) -> Session<S> {
+        let session = Session {
+            token: SessionToken::generate(),
+            state: Arc::new(tokio::sync::Mutex::new(state)),
+        };
+        self.shared.sessions.lock().unwrap().insert(
+            session.token.clone(),
+            session.state.clone(),
+            self.idle_timeout,
+        );
+        let _ = self
+            .shared
+            .events
+            .send(SessionEvent::Created(session.token.clone()));
+        session
+    }
+
+    /// Finds a live session, counting the lookup as activity
+    pub fn get(&self, token: &SessionToken) -> Option<Session<S>> {
+        let mut sessions = self.shared.sessions.lock().unwrap();
+        let state = sessions.get(token)?.clone();
+        sessions.reset(token, self.idle_timeout);
+        Some(Session {
+            token: token.clone(),
+            state,
+        })
+    }
+
+    /// Restarts a session's idle timer without looking it up, returning
+    /// false if it isn't live
+    pub fn touch(&self, token: &SessionToken) -> bool {
+        let mut sessions = self.shared.sessions.lock().unwrap();
+        sessions.reset(token, self.idle_timeout)
+    }
+
+    /// Ends a session before it expires, returning false if it wasn't live
+    pub fn end(&self, token: &SessionToken) -> bool {
+        let removed = self.shared.sessions.lock().unwrap().remove(token);
+        if removed.is_some() {
+            let _ = self.shared.events.send(SessionEvent::Ended(token.clone()));
+        }
+        removed.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Events from now on; a subscriber that falls more than 64 behind
+    /// misses the oldest
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.shared.events.subscribe()
+    }
+}
+
+impl<S> Drop for SessionManager<S> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn expire<S>(shared: Arc<Shared<S>>) {
+    loop {
+        let (token, _state) =
+            std::future::poll_fn(|cx| shared.sessions.lock().unwrap().poll_expired(cx)).await;
+        let _ = shared.events.send(SessionEvent::Expired(token));
+    }
+}
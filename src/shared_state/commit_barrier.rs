@@ -0,0 +1,271 @@
+//! Gathering work from several producers and committing it as one batch
+//!
+//! The two-phase pattern batch pipelines use to keep a set of outputs
+//! consistent: every producer stages its part of a round, a coordinator
+//! waits until all of them have (or gives up on the stragglers after a
+//! timeout), then commits everything staged in one step and tells each
+//! producer whether its item made it.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+use tokio::time::{sleep_until, Instant};
+
+/// A committed round, as seen by a producer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Committed {
+    /// Rounds are numbered from 1
+    pub round: u64,
+    /// Items committed in the round, from every producer
+    pub items: usize,
+}
+
+/// Why a staged item wasn't committed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitError {
+    /// The commit function failed, so the whole round was dropped
+    Aborted { round: u64 },
+    /// The barrier, or the coordinator mid-commit, was dropped first
+    Abandoned,
+}
+
+impl std::fmt::Display for CommitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommitError::Aborted { round } => write!(f, "commit round {} was aborted", round),
+            CommitError::Abandoned => write!(f, "commit abandoned before it finished"),
+        }
+    }
+}
+
+impl std::error::Error for CommitError {}
+
+type Waiter = oneshot::Sender<Result<Committed, CommitError>>;
+
+struct State<T> {
+    /// Ids of the producers currently registered
+    registered: HashSet<u64>,
+    next_id: u64,
+    round: u64,
+    /// The barrier was dropped, so nothing will be committed again
+    closed: bool,
+    /// At most one item per producer, in the order they were first staged
+    staged: Vec<(u64, T, Waiter)>,
+    /// When the next round's first item was staged
+    first_staged: Option<Instant>,
+}
+
+impl<T> State<T> {
+    /// Every registered producer has staged an item
+    fn gathered(&self) -> bool {
+        let staged = self.staged.iter();
+        let staged = staged.filter(|(id, ..)| self.registered.contains(id));
+        staged.count() >= self.registered.len()
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Signalled whenever an item is staged or a producer leaves
+    changed: Notify,
+    timeout: Duration,
+}
+
+/// Collects one item per registered producer, then commits them together
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::shared_state::CommitBarrier;
+///
+/// let barrier = CommitBarrier::new(Duration::from_secs(5));
+/// for shard in 0..4 {
+///     let mut producer = barrier.register();
+///     tokio::spawn(async move {
+///         let summary = format!("shard {} done", shard);
+///         match producer.stage(summary).await {
+///             Ok(committed) => println!("shard {} is in round {}", shard, committed.round),
+///             Err(e) => println!("shard {} must retry: {}", shard, e),
+///         }
+///     });
+/// }
+///
+/// let written = barrier
+///     .commit(|summaries| async move {
+///         // ... write the summaries in one transaction ...
+///         Ok::<_, std::io::Error>(summaries.len())
+///     })
+///     .await;
+/// # let _ = written;
+/// # }
+/// ```
+pub struct CommitBarrier<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> CommitBarrier<T> {
+    /// Commits without the stragglers once `timeout` has passed since a
+    /// round's first item was staged
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    registered: HashSet::new(),
+                    next_id: 0,
+                    round: 0,
+                    closed: false,
+                    staged: Vec::new(),
+                    first_staged: None,
+                }),
+                changed: Notify::new(),
+                timeout,
+            }),
+        }
+    }
+
+    /// Adds a producer that every round waits for until it is dropped
+    pub fn register(&self) -> CommitProducer<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.registered.insert(id);
+        CommitProducer {
+            shared: self.shared.clone(),
+            id,
+        }
+    }
+
+    /// Producers currently registered
+    pub fn producers(&self) -> usize {
+        self.shared.state.lock().unwrap().registered.len()
+    }
+
+    /// Items staged for the next round so far
+    pub fn staged(&self) -> usize {
+        self.shared.state.lock().unwrap().staged.len()
+    }
+
+    /// Waits for a round to gather, then commits it with `commit`
+    ///
+    /// The round is complete once every producer has staged an item, or
+    /// the timeout passes after its first item. Every producer in the round
+    /// is then told the outcome: committed if `commit` returns `Ok`,
+    /// aborted if it returns an error, which is passed back here.
+    ///
+    /// Not cancel safe: dropping the future after the round has gathered
+    /// loses its items, and their producers see
+    /// [`CommitError::Abandoned`].
+    pub async fn commit<F, Fut, R, E>(&self, commit: F) -> Result<R, E>
+    where
+        F: FnOnce(Vec<T>) -> Fut,
+        Fut: Future<Output = Result<R, E>>,
+    {
+        loop {
+            let changed = self.shared.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            let deadline = {
+                let state = self.shared.state.lock().unwrap();
+                match state.first_staged {
+                    Some(_) if state.gathered() => break,
+                    Some(first) => Some(first + self.shared.timeout),
+                    None => None,
+                }
+            };
+            match deadline {
+                Some(deadline) => tokio::select! {
+                    _ = changed => {}
+                    _ = sleep_until(deadline) => break,
+                },
+                None => changed.await,
+            }
+        }
+
+        let (round, staged) = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.round += 1;
+            state.first_staged = None;
+            (state.round, std::mem::take(&mut state.staged))
+        };
+        let (items, waiters): (Vec<_>, Vec<_>) = staged
+            .into_iter()
+            .map(|(_, item, waiter)| (item, waiter))
+            .unzip();
+        let count = items.len();
+        let result = commit(items).await;
+        let outcome = match &result {
+            Ok(_) => Ok(Committed {
+                round,
+                items: count,
+            }),
+            Err(_) => Err(CommitError::Aborted { round }),
+        };
+        for waiter in waiters {
+            let _ = waiter.send(outcome);
+        }
+        result
+    }
+}
+
+/// Fails the items staged for the next round, and any staged later, with
+/// [`CommitError::Abandoned`]
+impl<T> Drop for CommitBarrier<T> {
+    fn drop(&mut self) {
+        let staged = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.closed = true;
+            state.first_staged = None;
+            std::mem::take(&mut state.staged)
+        };
+        // Outside the lock, as items may do anything when dropped
+        drop(staged);
+    }
+}
+
+/// A producer registered with a [`CommitBarrier`]
+///
+/// Dropping it unregisters it, so rounds stop waiting for it.
+pub struct CommitProducer<T> {
+    shared: Arc<Shared<T>>,
+    id: u64,
+}
+
+impl<T> CommitProducer<T> {
+    /// Stages `item` for the next round and waits for the round's outcome
+    ///
+    /// Dropping the future leaves the item staged; it is committed all the
+    /// same. Staging again before the round commits replaces that item.
+    pub async fn stage(&mut self, item: T) -> Result<Committed, CommitError> {
+        let (tx, rx) = oneshot::channel();
+        let replaced = {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed {
+                return Err(CommitError::Abandoned);
+            }
+            let id = self.id;
+            match state.staged.iter_mut().find(|(staged, ..)| *staged == id) {
+                Some(entry) => Some(std::mem::replace(entry, (id, item, tx))),
+                None => {
+                    state.first_staged.get_or_insert_with(Instant::now);
+                    state.staged.push((id, item, tx));
+                    None
+                }
+            }
+        };
+        // Outside the lock, as items may do anything when dropped
+        drop(replaced);
+        self.shared.changed.notify_waiters();
+        rx.await.unwrap_or(Err(CommitError::Abandoned))
+    }
+}
+
+impl<T> Drop for CommitProducer<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.registered.remove(&self.id);
+        drop(state);
+        self.shared.changed.notify_waiters();
+    }
+}
@@ -0,0 +1,399 @@
+//! Coordinated, phased shutdown of the subsystems in a service
+//!
+//! Subsystems register with a [`Coordinator`] for one [`Phase`]. When
+//! shutdown starts, phases run in order: every subsystem in `StopIntake` is
+//! cancelled and awaited, then `Drain`, then `Flush`. The whole sequence is
+//! bounded by a single timeout; subsystems that haven't acknowledged by then
+//! are reported as laggards.
+//!
+//! A [`SignalBus`] is the flat version: one broadcast reaches every
+//! subscriber at once, and each acknowledges by dropping a guard.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, oneshot, Notify};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Shutdown phases, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    /// Stop accepting new work: listeners, queue consumers, schedulers
+    StopIntake,
+    /// Finish work already in progress
+    Drain,
+    /// Persist state: logs, metrics, caches
+    Flush,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 3] = [Phase::StopIntake, Phase::Drain, Phase::Flush];
+}
+
+struct Registration {
+    name: String,
+    done: oneshot::Receiver<()>,
+}
+
+struct Inner {
+    phases: BTreeMap<Phase, (CancellationToken, Vec<Registration>)>,
+    started: bool,
+}
+
+/// Orders and times the shutdown of registered subsystems
+#[derive(Clone)]
+pub struct Coordinator {
+    inner: Arc<Mutex<Inner>>,
+    token: CancellationToken,
+}
+
+/// What happened during [`Coordinator::shutdown`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Subsystems that acknowledged in time, in phase and registration order
+    pub completed: Vec<String>,
+    /// Subsystems still running when the timeout expired
+    pub timed_out: Vec<String>,
+    pub elapsed: Duration,
+}
+
+impl ShutdownReport {
+    pub fn is_clean(&self) -> bool {
+        self.timed_out.is_empty()
+    }
+}
+
+impl Coordinator {
+    pub fn new() -> Self {
+        let phases = Phase::ALL
+            .iter()
+            .map(|phase| (*phase, (CancellationToken::new(), Vec::new())))
+            .collect();
+
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                phases,
+                started: false,
+            })),
+            token: CancellationToken::new(),
+        }
+    }
+
+    /// Registers a subsystem that will be stopped during `phase`
+    pub fn register(&self, name: impl Into<String>, phase: Phase) -> Subsystem {
+        let name = name.into();
+        let (done_tx, done_rx) = oneshot::channel();
+        let mut inner = self.inner.lock().unwrap();
+        let (token, registrations) = inner.phases.get_mut(&phase).unwrap();
+
+        registrations.push(Registration {
+            name: name.clone(),
+            done: done_rx,
+        });
+
+        Subsystem {
+            name,
+            phase,
+            token: token.clone(),
+            done: Some(done_tx),
+        }
+    }
+
+    /// Cancelled as soon as shutdown begins, before any phase runs
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Runs every phase in order, waiting at most `timeout` overall
+    ///
+    /// Calling this more than once returns an empty report.
+    pub async fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+        let started = Instant::now();
+        let deadline = started + timeout;
+        let phases: Vec<_> = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.started {
+                Vec::new()
+            } else {
+                inner.started = true;
+                inner
+                    .phases
+                    .iter_mut()
+                    .map(|(phase, (token, registrations))| {
+                        (*phase, token.clone(), std::mem::take(registrations))
+                    })
+                    .collect()
+            }
+        };
+
+        self.token.cancel();
+
+        let mut report = ShutdownReport {
+            completed: Vec::new(),
+            timed_out: Vec::new(),
+            elapsed: Duration::ZERO,
+        };
+
+        for (_phase, token, registrations) in phases {
+            token.cancel();
+
+            for registration in registrations {
+                // Dropping the Subsystem counts as an acknowledgement too
+                match tokio::time::timeout_at(deadline, registration.done).await {
+                    Ok(_) => report.completed.push(registration.name),
+                    Err(_) => report.timed_out.push(registration.name),
+                }
+            }
+        }
+
+        report.elapsed = started.elapsed();
+        report
+    }
+}
+
+impl Default for Coordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A registered subsystem's view of shutdown
+///
+/// Wait on [`cancelled`](Self::cancelled), wind down, then call
+/// [`ack`](Self::ack). Dropping the handle acknowledges as well, so a
+/// subsystem that exits early never holds shutdown up.
+pub struct Subsystem {
+    name: String,
+    phase: Phase,
+    token: CancellationToken,
+    done: Option<oneshot::Sender<()>>,
+}
+
+impl Subsystem {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Cancelled when this subsystem's phase starts
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves when this subsystem's phase starts
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Reports that this subsystem has finished shutting down
+    pub fn ack(mut self) {
+        if let Some(done) = self.done.take() {
+            let _ = done.send(());
+        }
+    }
+}
+
+struct BusState {
+    /// The name of every live subscriber, and of every one that was still
+    /// live when shutdown began, in subscription order
+    names: BTreeMap<u64, String>,
+    /// Subscribers that haven't acknowledged yet
+    pending: BTreeSet<u64>,
+    next_id: u64,
+    initiated: bool,
+}
+
+struct Bus {
+    state: Mutex<BusState>,
+    signal: broadcast::Sender<()>,
+    acked: Notify,
+}
+
+/// Broadcasts shutdown to every subscriber and waits for their
+/// acknowledgements; clones share the same subscribers
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_tutorial_patterns::shutdown::SignalBus;
+///
+/// let bus = SignalBus::new();
+/// let signal = bus.subscribe("cache writer");
+/// tokio::spawn(async move {
+///     let _ack = signal.recv().await;
+///     // flush the cache; dropping `_ack` reports completion
+/// });
+///
+/// let report = bus.initiate(Duration::from_secs(5)).await;
+/// println!("laggards: {:?}", report.timed_out);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SignalBus {
+    bus: Arc<Bus>,
+}
+
+impl SignalBus {
+    pub fn new() -> Self {
+        Self {
+            bus: Arc::new(Bus {
+                state: Mutex::new(BusState {
+                    names: BTreeMap::new(),
+                    pending: BTreeSet::new(),
+                    next_id: 0,
+                    initiated: false,
+                }),
+                signal: broadcast::channel(1).0,
+                acked: Notify::new(),
+            }),
+        }
+    }
+
+    /// Subscribes `name`, which shutdown will wait for
+    pub fn subscribe(&self, name: impl Into<String>) -> ShutdownSubscriber {
+        let mut state = self.bus.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.names.insert(id, name.into());
+        state.pending.insert(id);
+
+        ShutdownSubscriber {
+            rx: self.bus.signal.subscribe(),
+            initiated: state.initiated,
+            guard: AckGuard {
+                bus: self.bus.clone(),
+                id,
+            },
+        }
+    }
+
+    pub fn is_initiated(&self) -> bool {
+        self.bus.state.lock().unwrap().initiated
+    }
+
+    /// Signals every subscriber and waits at most `timeout` for all of them
+    /// to acknowledge
+    ///
+    /// Subscribers dropped before shutdown began are left out of the report;
+    /// ones that never received the signal but were dropped after it count as
+    /// acknowledged. Calling this again waits for the remaining laggards.
+    pub async fn initiate(&self, timeout: Duration) -> ShutdownReport {
+        let started = Instant::now();
+        let deadline = started + timeout;
+        {
+            let mut state = self.bus.state.lock().unwrap();
+            if !state.initiated {
+                state.initiated = true;
+                let _ = self.bus.signal.send(());
+            }
+        }
+
+        loop {
+            let acked = self.bus.acked.notified();
+            tokio::pin!(acked);
+            acked.as_mut().enable();
+            if self.bus.state.lock().unwrap().pending.is_empty() {
+                break;
+            }
+            if tokio::time::timeout_at(deadline, acked).await.is_err() {
+                break;
+            }
+        }
+
+        let state = self.bus.state.lock().unwrap();
+        let (timed_out, completed) = state
+            .names
+            .iter()
+            .map(|(id, name)| (id, name.clone()))
+            .partition::<Vec<_>, _>(|(id, _)| state.pending.contains(id));
+        let names = |subscribers: Vec<_>| subscribers.into_iter().map(|(_, name)| name).collect();
+        ShutdownReport {
+            completed: names(completed),
+            timed_out: names(timed_out),
+            elapsed: started.elapsed(),
+        }
+    }
+}
+
+impl Default for SignalBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Acknowledges its subscriber when dropped
+struct AckGuard {
+    bus: Arc<Bus>,
+    id: u64,
+}
+
+impl AckGuard {
+    fn name(&self) -> String {
+        let state = self.bus.state.lock().unwrap();
+        state.names.get(&self.id).cloned().unwrap_or_default()
+    }
+}
+
+impl Drop for AckGuard {
+    fn drop(&mut self) {
+        let mut state = self.bus.state.lock().unwrap();
+        state.pending.remove(&self.id);
+        // Before shutdown nobody will ask about this subscriber again
+        if !state.initiated {
+            state.names.remove(&self.id);
+        }
+        drop(state);
+        self.bus.acked.notify_waiters();
+    }
+}
+
+/// A subscriber's end of a [`SignalBus`]
+///
+/// Dropping it before shutdown acknowledges at once, so a subsystem that
+/// exits early never holds shutdown up.
+pub struct ShutdownSubscriber {
+    rx: broadcast::Receiver<()>,
+    /// Shutdown had begun before this subscribed
+    initiated: bool,
+    guard: AckGuard,
+}
+
+impl ShutdownSubscriber {
+    pub fn name(&self) -> String {
+        self.guard.name()
+    }
+
+    /// Waits for shutdown, returning the guard that acknowledges it when
+    /// dropped
+    pub async fn recv(mut self) -> ShutdownAck {
+        if !self.initiated {
+            // The guard keeps the sender alive and it sends once, so this
+            // only returns with the signal
+            let _ = self.rx.recv().await;
+        }
+        ShutdownAck { guard: self.guard }
+    }
+}
+
+/// Acknowledges shutdown when dropped; hold it while winding down
+pub struct ShutdownAck {
+    guard: AckGuard,
+}
+
+impl ShutdownAck {
+    pub fn name(&self) -> String {
+        self.guard.name()
+    }
+}
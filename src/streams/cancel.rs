@@ -0,0 +1,49 @@
+//! Ending streams on shutdown
+
+use tokio::sync::watch;
+use tokio_stream::Stream;
+use tokio_util::sync::CancellationToken;
+
+/// Ends the stream as soon as `token` is cancelled
+///
+/// The source is dropped at that point, along with any item it was in the
+/// middle of producing, so a pipeline reading from it finishes its loop
+/// normally and can flush. Pairs with the tokens handed out by
+/// [`crate::shutdown::Coordinator`].
+///
+/// ```
+/// # async fn example(subsystem: tokio_tutorial_patterns::shutdown::Subsystem) {
+/// use std::time::Duration;
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::{take_until_cancelled, ticker};
+///
+/// let mut ticks = std::pin::pin!(take_until_cancelled(
+///     ticker(Duration::from_secs(1)),
+///     subsystem.token().clone(),
+/// ));
+/// while let Some(tick) = ticks.next().await {
+///     println!("tick {}", tick);
+/// }
+/// subsystem.ack();
+/// # }
+/// ```
+pub fn take_until_cancelled<S: Stream>(
+    stream: S,
+    token: CancellationToken,
+) -> impl Stream<Item = S::Item> {
+    futures::StreamExt::take_until(stream, token.cancelled_owned())
+}
+
+/// Ends the stream once the watched flag becomes `true` or its sender is
+/// dropped
+///
+/// For code that signals shutdown with a `watch::channel(false)`, as the
+/// `io` servers do.
+pub fn take_until_shutdown<S: Stream>(
+    stream: S,
+    mut shutdown: watch::Receiver<bool>,
+) -> impl Stream<Item = S::Item> {
+    futures::StreamExt::take_until(stream, async move {
+        let _ = shutdown.wait_for(|stop| *stop).await;
+    })
+}
@@ -0,0 +1,77 @@
+//! Adapters between tokio channels and streams
+
+use std::fmt;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{
+    BroadcastStream, ReceiverStream, UnboundedReceiverStream, WatchStream,
+};
+use tokio_stream::{Stream, StreamExt};
+
+/// Yields every message sent on the channel, ending once all senders are gone
+pub fn from_receiver<T>(rx: mpsc::Receiver<T>) -> impl Stream<Item = T> {
+    ReceiverStream::new(rx)
+}
+
+pub fn from_unbounded_receiver<T>(rx: mpsc::UnboundedReceiver<T>) -> impl Stream<Item = T> {
+    UnboundedReceiverStream::new(rx)
+}
+
+/// Reported in place of the messages a slow broadcast subscriber missed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lagged {
+    pub missed: u64,
+}
+
+impl fmt::Display for Lagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "subscriber lagged behind by {} messages", self.missed)
+    }
+}
+
+impl std::error::Error for Lagged {}
+
+/// Yields broadcast messages, reporting overruns as `Err(Lagged)`
+///
+/// After a lag the stream carries on with the oldest message still
+/// retained, so it only ends once every sender is gone. Use
+/// `.filter_map(Result::ok)` to skip lags silently.
+pub fn from_broadcast<T>(rx: broadcast::Receiver<T>) -> impl Stream<Item = Result<T, Lagged>>
+where
+    T: Clone + Send + 'static,
+{
+    BroadcastStream::new(rx)
+        .map(|item| item.map_err(|BroadcastStreamRecvError::Lagged(missed)| Lagged { missed }))
+}
+
+/// Yields the current value, then the latest value after every change
+///
+/// Intermediate values may be skipped if they change faster than the stream
+/// is polled, as with any watch receiver.
+pub fn from_watch<T>(rx: watch::Receiver<T>) -> impl Stream<Item = T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    WatchStream::new(rx)
+}
+
+/// Sends every item of `stream` on `tx`, waiting for capacity as needed
+///
+/// Returns how many items were sent, or the first item that couldn't be
+/// because the receiver was dropped.
+pub async fn forward_to_sender<S>(
+    stream: S,
+    tx: &mpsc::Sender<S::Item>,
+) -> Result<usize, mpsc::error::SendError<S::Item>>
+where
+    S: Stream,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut sent = 0;
+
+    while let Some(item) = stream.next().await {
+        tx.send(item).await?;
+        sent += 1;
+    }
+    Ok(sent)
+}
@@ -0,0 +1,121 @@
+//! Offset tracking and resumption for replayable sources
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::watch;
+use tokio_stream::Stream;
+
+/// Yields `(offset, item)` pairs and tracks which offsets have been
+/// committed as processed
+///
+/// Offsets count up from 0 across restarts: after [`resume`](Self::resume)
+/// the first item gets the offset after the last committed one. Commit an
+/// offset only once everything up to it is done; persisting the value seen
+/// by [`Committer::subscribe`] and passing it to `resume` after a restart
+/// then gives at-least-once processing.
+///
+/// ```
+/// # async fn example() {
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::Checkpointed;
+///
+/// // A source that can start reading at any offset, like a log file or topic
+/// fn events_from(offset: u64) -> impl tokio_stream::Stream<Item = String> {
+///     tokio_stream::iter((offset..100).map(|n| format!("event {}", n)))
+/// }
+///
+/// let last_committed = None; // loaded from disk in a real service
+/// let mut events = Checkpointed::resume(events_from, last_committed);
+/// let committer = events.committer();
+/// while let Some((offset, event)) = events.next().await {
+///     println!("{}", event);
+///     committer.commit(offset);
+/// }
+/// # }
+/// ```
+pub struct Checkpointed<S> {
+    stream: Pin<Box<S>>,
+    next_offset: u64,
+    committed: Arc<watch::Sender<Option<u64>>>,
+}
+
+/// Commits offsets for a [`Checkpointed`] stream, from any task
+#[derive(Clone)]
+pub struct Committer {
+    committed: Arc<watch::Sender<Option<u64>>>,
+}
+
+impl<S: Stream> Checkpointed<S> {
+    /// Tags the items of `stream` with offsets starting at 0
+    pub fn new(stream: S) -> Self {
+        Self::resume(|_| stream, None)
+    }
+
+    /// Reopens a source after the last committed offset
+    ///
+    /// `open` is called with the offset of the first item it should produce:
+    /// 0 if nothing was committed, otherwise `committed + 1`.
+    pub fn resume<F>(open: F, committed: Option<u64>) -> Self
+    where
+        F: FnOnce(u64) -> S,
+    {
+        let next_offset = committed.map_or(0, |offset| offset + 1);
+        Self {
+            stream: Box::pin(open(next_offset)),
+            next_offset,
+            committed: Arc::new(watch::Sender::new(committed)),
+        }
+    }
+}
+
+impl<S> Checkpointed<S> {
+    pub fn committer(&self) -> Committer {
+        Committer {
+            committed: self.committed.clone(),
+        }
+    }
+
+    /// The highest committed offset, if any
+    pub fn committed(&self) -> Option<u64> {
+        *self.committed.borrow()
+    }
+}
+
+impl Committer {
+    /// Marks every offset up to and including `offset` as processed
+    ///
+    /// Committing an offset at or below the current one has no effect, so
+    /// workers finishing out of order can't move the checkpoint backwards.
+    pub fn commit(&self, offset: u64) {
+        self.committed.send_if_modified(|committed| {
+            if committed.is_some_and(|current| current >= offset) {
+                return false;
+            }
+            *committed = Some(offset);
+            true
+        });
+    }
+
+    pub fn committed(&self) -> Option<u64> {
+        *self.committed.borrow()
+    }
+
+    /// Notified on every commit, e.g. to persist the offset
+    pub fn subscribe(&self) -> watch::Receiver<Option<u64>> {
+        self.committed.subscribe()
+    }
+}
+
+impl<S: Stream> Stream for Checkpointed<S> {
+    type Item = (u64, S::Item);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = std::task::ready!(self.stream.as_mut().poll_next(cx));
+        Poll::Ready(item.map(|item| {
+            let offset = self.next_offset;
+            self.next_offset += 1;
+            (offset, item)
+        }))
+    }
+}
@@ -0,0 +1,29 @@
+//! Grouping stream items into batches
+
+use std::time::Duration;
+use tokio_stream::Stream;
+
+/// Yields items in `Vec`s of `size`, the last one possibly shorter
+///
+/// # Panics
+///
+/// Panics if `size` is zero.
+pub fn chunks<S: Stream>(stream: S, size: usize) -> impl Stream<Item = Vec<S::Item>> {
+    futures::StreamExt::chunks(stream, size)
+}
+
+/// Yields a batch once it holds `size` items or `max_latency` has passed
+/// since its first item, whichever comes first
+///
+/// The stream-side counterpart of [`crate::channels::BatchReceiver`].
+///
+/// # Panics
+///
+/// Panics if `size` is zero.
+pub fn chunks_timeout<S: Stream>(
+    stream: S,
+    size: usize,
+    max_latency: Duration,
+) -> impl Stream<Item = Vec<S::Item>> {
+    tokio_stream::StreamExt::chunks_timeout(stream, size, max_latency)
+}
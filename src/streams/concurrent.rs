@@ -0,0 +1,58 @@
+//! Running an async mapper on several stream items at once
+
+use std::future::Future;
+use tokio_stream::Stream;
+
+/// Maps items with `f`, running up to `limit` calls concurrently and
+/// yielding results in the order of the source
+///
+/// A slow item holds back the results behind it (though not their work), so
+/// at most `limit` results are ever buffered.
+///
+/// ```
+/// # async fn example() {
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::map_concurrent;
+///
+/// async fn fetch(id: u32) -> String {
+///     format!("user {}", id)
+/// }
+///
+/// let users: Vec<String> = map_concurrent(tokio_stream::iter(1..=100), 8, fetch)
+///     .collect()
+///     .await;
+/// # let _ = users;
+/// # }
+/// ```
+///
+/// # Panics
+///
+/// Panics if `limit` is zero.
+pub fn map_concurrent<S, F, Fut>(stream: S, limit: usize, f: F) -> impl Stream<Item = Fut::Output>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> Fut,
+    Fut: Future,
+{
+    assert!(limit > 0, "concurrency limit must be non-zero");
+    futures::StreamExt::buffered(futures::StreamExt::map(stream, f), limit)
+}
+
+/// Like [`map_concurrent`], but yields results as soon as they are ready
+///
+/// # Panics
+///
+/// Panics if `limit` is zero.
+pub fn map_concurrent_unordered<S, F, Fut>(
+    stream: S,
+    limit: usize,
+    f: F,
+) -> impl Stream<Item = Fut::Output>
+where
+    S: Stream,
+    F: FnMut(S::Item) -> Fut,
+    Fut: Future,
+{
+    assert!(limit > 0, "concurrency limit must be non-zero");
+    futures::StreamExt::buffer_unordered(futures::StreamExt::map(stream, f), limit)
+}
@@ -0,0 +1,56 @@
+//! Consuming streams
+
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio_stream::{Stream, StreamExt};
+
+/// Collects at most the first `n` items
+///
+/// Stops pulling from the source as soon as it has `n` items, so it is
+/// safe to use on endless streams.
+pub async fn collect_n<S: Stream>(stream: S, n: usize) -> Vec<S::Item> {
+    stream.take(n).collect().await
+}
+
+/// Yields items while the async predicate holds, ending at the first item
+/// it rejects
+pub fn take_while_async<S, P, Fut>(stream: S, predicate: P) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+    P: FnMut(&S::Item) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    futures::StreamExt::take_while(stream, predicate)
+}
+
+/// Runs `handler` on every item, up to `concurrency` at a time, and
+/// returns how many items were handled
+///
+/// Dropping the returned future cancels the drain, including any handlers
+/// still running. To stop gracefully instead, end the stream with
+/// [`take_until_cancelled`](super::take_until_cancelled): items already
+/// taken are then handled before this returns.
+///
+/// # Panics
+///
+/// Panics if `concurrency` is zero.
+pub async fn drain_with<S, F, Fut>(stream: S, mut handler: F, concurrency: usize) -> usize
+where
+    S: Stream,
+    F: FnMut(S::Item) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    assert!(concurrency > 0, "`concurrency` must be non-zero");
+
+    let handled = AtomicUsize::new(0);
+    futures::StreamExt::for_each_concurrent(stream, concurrency, |item| {
+        let handling = handler(item);
+        let handled = &handled;
+        async move {
+            handling.await;
+            handled.fetch_add(1, Ordering::Relaxed);
+        }
+    })
+    .await;
+    handled.into_inner()
+}
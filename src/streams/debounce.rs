@@ -0,0 +1,208 @@
+//! Debouncing and throttling streams
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{sleep, Instant, Sleep};
+use tokio_stream::Stream;
+
+/// Which items of a burst [`Debounce`] and [`Throttle`] let through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    /// The first item, as soon as it arrives
+    Leading,
+    /// The latest item, once the quiet period or interval is over
+    Trailing,
+    /// Both of the above; a burst of one item is only yielded once
+    Both,
+}
+
+impl Edge {
+    fn leading(self) -> bool {
+        matches!(self, Edge::Leading | Edge::Both)
+    }
+
+    fn trailing(self) -> bool {
+        matches!(self, Edge::Trailing | Edge::Both)
+    }
+}
+
+/// Stream returned by [`debounce`]
+pub struct Debounce<S: Stream> {
+    stream: Pin<Box<S>>,
+    quiet: Duration,
+    edge: Edge,
+    timer: Pin<Box<Sleep>>,
+    in_burst: bool,
+    pending: Option<S::Item>,
+    done: bool,
+}
+
+/// Yields the last item of every burst, once nothing has arrived for `quiet`
+///
+/// Each item restarts the quiet period. Use [`Debounce::edge`] to yield the
+/// first item of a burst instead or as well. An item still held back when
+/// the source ends is yielded straight away.
+///
+/// ```
+/// # async fn example(keystrokes: impl tokio_stream::Stream<Item = String>) {
+/// use std::time::Duration;
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::debounce;
+///
+/// // Search once the user stops typing for 300ms
+/// let mut queries = std::pin::pin!(debounce(keystrokes, Duration::from_millis(300)));
+/// while let Some(query) = queries.next().await {
+///     println!("searching for {}", query);
+/// }
+/// # }
+/// ```
+pub fn debounce<S: Stream>(stream: S, quiet: Duration) -> Debounce<S> {
+    Debounce {
+        stream: Box::pin(stream),
+        quiet,
+        edge: Edge::Trailing,
+        timer: Box::pin(sleep(Duration::ZERO)),
+        in_burst: false,
+        pending: None,
+        done: false,
+    }
+}
+
+impl<S: Stream> Debounce<S> {
+    /// Defaults to [`Edge::Trailing`]
+    pub fn edge(mut self, edge: Edge) -> Self {
+        self.edge = edge;
+        self
+    }
+}
+
+// Items are only ever moved, never pinned
+impl<S: Stream> Unpin for Debounce<S> {}
+
+impl<S: Stream> Stream for Debounce<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = self.get_mut();
+
+        while !this.done {
+            match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    let starts_burst = !this.in_burst;
+                    this.in_burst = true;
+                    this.timer.as_mut().reset(Instant::now() + this.quiet);
+
+                    if starts_burst && this.edge.leading() {
+                        return Poll::Ready(Some(item));
+                    }
+                    if this.edge.trailing() {
+                        this.pending = Some(item);
+                    }
+                }
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => break,
+            }
+        }
+        if this.done {
+            return Poll::Ready(this.pending.take());
+        }
+
+        if this.in_burst && this.timer.as_mut().poll(cx).is_ready() {
+            this.in_burst = false;
+            if let Some(item) = this.pending.take() {
+                return Poll::Ready(Some(item));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Stream returned by [`throttle`]
+pub struct Throttle<S: Stream> {
+    stream: Pin<Box<S>>,
+    min_interval: Duration,
+    edge: Edge,
+    timer: Pin<Box<Sleep>>,
+    in_interval: bool,
+    pending: Option<S::Item>,
+    done: bool,
+}
+
+/// Yields at most one item per `min_interval`
+///
+/// By default the first item is yielded right away and the rest of the
+/// interval is dropped. With [`Edge::Trailing`] or [`Edge::Both`] the latest
+/// dropped item is delayed to the end of the interval instead, which starts
+/// the next one; such an item is still yielded, on schedule, if the source
+/// ends first.
+pub fn throttle<S: Stream>(stream: S, min_interval: Duration) -> Throttle<S> {
+    Throttle {
+        stream: Box::pin(stream),
+        min_interval,
+        edge: Edge::Leading,
+        timer: Box::pin(sleep(Duration::ZERO)),
+        in_interval: false,
+        pending: None,
+        done: false,
+    }
+}
+
+impl<S: Stream> Throttle<S> {
+    /// Defaults to [`Edge::Leading`]
+    pub fn edge(mut self, edge: Edge) -> Self {
+        self.edge = edge;
+        self
+    }
+}
+
+impl<S: Stream> Unpin for Throttle<S> {}
+
+impl<S: Stream> Stream for Throttle<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = self.get_mut();
+
+        while !this.done {
+            match this.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    if !this.in_interval {
+                        this.in_interval = true;
+                        this.timer
+                            .as_mut()
+                            .reset(Instant::now() + this.min_interval);
+                        if this.edge.leading() {
+                            return Poll::Ready(Some(item));
+                        }
+                    }
+                    if this.edge.trailing() {
+                        this.pending = Some(item);
+                    }
+                }
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => break,
+            }
+        }
+
+        if this.done && this.pending.is_none() {
+            return Poll::Ready(None);
+        }
+        if this.in_interval {
+            if this.timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.in_interval = false;
+            if let Some(item) = this.pending.take() {
+                this.in_interval = true;
+                this.timer
+                    .as_mut()
+                    .reset(Instant::now() + this.min_interval);
+                return Poll::Ready(Some(item));
+            }
+        }
+
+        Poll::Pending
+    }
+}
@@ -0,0 +1,68 @@
+//! Suppressing repeated stream items
+
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+use tokio_stream::{Stream, StreamExt};
+
+/// Skips items equal to the one before them
+///
+/// Handy on top of a polling loop, so only actual changes are reacted to.
+///
+/// ```
+/// # async fn example() {
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::distinct_until_changed;
+///
+/// let readings = tokio_stream::iter(vec!["up", "up", "down", "down", "up"]);
+/// let changes: Vec<_> = distinct_until_changed(readings).collect().await;
+/// assert_eq!(changes, ["up", "down", "up"]);
+/// # }
+/// ```
+pub fn distinct_until_changed<S>(stream: S) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+    S::Item: PartialEq + Clone,
+{
+    let mut last = None;
+    stream.filter(move |item| {
+        if last.as_ref() == Some(item) {
+            return false;
+        }
+        last = Some(item.clone());
+        true
+    })
+}
+
+/// Skips items whose key matches one of the last `window` keys let through
+///
+/// Unlike [`distinct_until_changed`] this also catches repeats that aren't
+/// adjacent, such as redelivered messages, while only remembering a bounded
+/// number of keys.
+///
+/// # Panics
+///
+/// Panics if `window` is zero.
+pub fn dedup_by_key<S, F, K>(stream: S, mut key_fn: F, window: usize) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+    F: FnMut(&S::Item) -> K,
+    K: Hash + Eq + Clone,
+{
+    assert!(window > 0, "`window` must be non-zero");
+
+    let mut seen = HashSet::with_capacity(window);
+    let mut order = VecDeque::with_capacity(window);
+    stream.filter(move |item| {
+        let key = key_fn(item);
+        if !seen.insert(key.clone()) {
+            return false;
+        }
+        order.push_back(key);
+        if order.len() > window {
+            if let Some(oldest) = order.pop_front() {
+                seen.remove(&oldest);
+            }
+        }
+        true
+    })
+}
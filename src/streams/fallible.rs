@@ -0,0 +1,72 @@
+//! Error policies for streams of `Result`s
+
+use std::fmt::Display;
+use tokio_stream::{Stream, StreamExt};
+
+/// Yields the `Ok` values, logging and skipping every error
+///
+/// Errors go to `tracing` at `WARN` level with the `tracing` feature, or to
+/// stderr without it.
+pub fn ok_items<S, T, E>(stream: S) -> impl Stream<Item = T>
+where
+    S: Stream<Item = Result<T, E>>,
+    E: Display,
+{
+    stream.filter_map(|item| match item {
+        Ok(value) => Some(value),
+        Err(e) => {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %e, "skipping failed stream item");
+            #[cfg(not(feature = "tracing"))]
+            eprintln!("skipping failed stream item: {}", e);
+            None
+        }
+    })
+}
+
+/// Drains the stream, separating values from errors
+pub async fn collect_results<S, T, E>(stream: S) -> (Vec<T>, Vec<E>)
+where
+    S: Stream<Item = Result<T, E>>,
+{
+    let mut stream = std::pin::pin!(stream);
+    let (mut values, mut errors) = (Vec::new(), Vec::new());
+    while let Some(item) = stream.next().await {
+        match item {
+            Ok(value) => values.push(value),
+            Err(e) => errors.push(e),
+        }
+    }
+    (values, errors)
+}
+
+/// Passes items through until the `max_errors`th error, which is the last
+/// item yielded
+///
+/// Tolerates the occasional bad record while still giving up on a source
+/// that is clearly broken.
+///
+/// # Panics
+///
+/// Panics if `max_errors` is zero.
+pub fn stop_on_error_threshold<S, T, E>(
+    stream: S,
+    max_errors: usize,
+) -> impl Stream<Item = Result<T, E>>
+where
+    S: Stream<Item = Result<T, E>>,
+{
+    assert!(max_errors > 0, "`max_errors` must be non-zero");
+
+    let mut errors = 0;
+    futures::StreamExt::scan(stream, false, move |stopped, item| {
+        if *stopped {
+            return futures::future::ready(None);
+        }
+        if item.is_err() {
+            errors += 1;
+            *stopped = errors >= max_errors;
+        }
+        futures::future::ready(Some(item))
+    })
+}
@@ -0,0 +1,41 @@
+//! Streams of lines and frames read from any `AsyncRead`
+
+use tokio::io::AsyncRead;
+use tokio_stream::Stream;
+use tokio_util::codec::{Decoder, FramedRead, LinesCodec, LinesCodecError};
+
+/// Yields each line of `reader`, without its `\n` or `\r\n`
+///
+/// Lines may be arbitrarily long; for untrusted input use
+/// [`frames`] with `LinesCodec::new_with_max_length` instead.
+///
+/// ```
+/// # async fn example() -> std::io::Result<()> {
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::lines;
+///
+/// let file = tokio::fs::File::open("/etc/hosts").await?;
+/// let mut lines = lines(file);
+/// while let Some(line) = lines.next().await {
+///     println!("{}", line.expect("readable line"));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn lines<R: AsyncRead>(reader: R) -> impl Stream<Item = Result<String, LinesCodecError>> {
+    frames(reader, LinesCodec::new())
+}
+
+/// Yields each frame `codec` decodes from `reader`
+///
+/// Works with any `tokio_util` decoder, e.g. `LengthDelimitedCodec` for
+/// length-prefixed messages or a custom one for a wire protocol. The stream
+/// ends at a clean end of input; a partial frame left over is reported as
+/// an error by the codec.
+pub fn frames<R, D>(reader: R, codec: D) -> impl Stream<Item = Result<D::Item, D::Error>>
+where
+    R: AsyncRead,
+    D: Decoder,
+{
+    FramedRead::new(reader, codec)
+}
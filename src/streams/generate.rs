@@ -0,0 +1,61 @@
+//! Streams driven by an interval
+
+use super::stream_fn;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tokio_stream::Stream;
+
+/// Yields 0, 1, 2, ... once per `period`, the first immediately
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+pub fn ticker(period: Duration) -> impl Stream<Item = u64> {
+    let mut interval = tokio::time::interval(period);
+
+    stream_fn(|mut y| async move {
+        for tick in 0.. {
+            interval.tick().await;
+            y.emit(tick).await;
+        }
+    })
+}
+
+/// Calls `producer` once per `period` and yields what it returns
+///
+/// A producer that takes longer than `period` is simply called again as
+/// soon as it finishes; missed ticks don't pile up into a burst of calls.
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::generate;
+///
+/// let readings = generate(Duration::from_secs(1), || async { 21.5 });
+/// let first_three: Vec<f64> = readings.take(3).collect().await;
+/// # let _ = first_three;
+/// # }
+/// ```
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+pub fn generate<T, F, Fut>(period: Duration, producer: F) -> impl Stream<Item = T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = T>,
+{
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    futures::stream::unfold(
+        (interval, producer),
+        |(mut interval, mut producer)| async move {
+            interval.tick().await;
+            let item = producer().await;
+            Some((item, (interval, producer)))
+        },
+    )
+}
@@ -0,0 +1,102 @@
+//! Throughput and gap metrics for streams
+
+use crate::metrics::{Counter, Gauge, Registry};
+use crate::select::LatencyRecorder;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_stream::{Stream, StreamExt};
+
+/// How often the items-per-second gauge is recomputed
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+struct Instruments {
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    name: String,
+    items: Counter,
+    rate: Gauge,
+    gaps: LatencyRecorder,
+    last_item: Instant,
+    window_start: Instant,
+    window_items: u64,
+}
+
+impl Instruments {
+    fn record_item(&mut self) {
+        let now = Instant::now();
+        self.items.inc();
+        self.gaps.record(now - self.last_item);
+        self.last_item = now;
+        self.window_items += 1;
+
+        let window = now - self.window_start;
+        if window >= RATE_WINDOW {
+            let rate = self.window_items as f64 / window.as_secs_f64();
+            self.rate.set(rate);
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                stream = %self.name,
+                items = self.items.get(),
+                items_per_sec = rate,
+                "stream throughput"
+            );
+            self.window_start = now;
+            self.window_items = 0;
+        }
+    }
+}
+
+/// Records what flows through the stream in the global [`Registry`]
+///
+/// See [`instrument_with`] for the metrics recorded.
+pub fn instrument<S: Stream>(stream: S, name: &str) -> impl Stream<Item = S::Item> {
+    instrument_with(stream, name, Registry::global())
+}
+
+/// Records what flows through the stream in `registry`, under:
+///
+/// - `{name}.items`: items yielded so far
+/// - `{name}.items_per_sec`: throughput, recomputed about once a second
+///   while items keep arriving
+/// - `{name}.gap`: time between consecutive items, the first measured from
+///   when the stream was instrumented; long gaps point at a slow producer
+///
+/// With the `tracing` feature, throughput is also logged as a `DEBUG`
+/// event every time the rate is recomputed, and once more when the stream
+/// ends.
+pub fn instrument_with<S: Stream>(
+    stream: S,
+    name: &str,
+    registry: &Registry,
+) -> impl Stream<Item = S::Item> {
+    let now = Instant::now();
+    let instruments = Instruments {
+        name: name.to_string(),
+        items: registry.counter(&format!("{}.items", name)),
+        rate: registry.gauge(&format!("{}.items_per_sec", name)),
+        gaps: registry.latency(&format!("{}.gap", name)),
+        last_item: now,
+        window_start: now,
+        window_items: 0,
+    };
+
+    futures::stream::unfold(
+        (Box::pin(stream), instruments),
+        |(mut stream, mut instruments)| async move {
+            match stream.next().await {
+                Some(item) => {
+                    instruments.record_item();
+                    Some((item, (stream, instruments)))
+                }
+                None => {
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(
+                        stream = %instruments.name,
+                        items = instruments.items.get(),
+                        "stream ended"
+                    );
+                    None
+                }
+            }
+        },
+    )
+}
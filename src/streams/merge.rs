@@ -0,0 +1,110 @@
+//! Combining several streams into one
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio_stream::Stream;
+
+/// Yields items from all `streams` as they become ready, ending once every
+/// stream has ended
+///
+/// Streams are polled round-robin, so a busy stream can't starve the rest.
+pub fn merge<I>(streams: I) -> impl Stream<Item = <I::Item as Stream>::Item>
+where
+    I: IntoIterator,
+    I::Item: Stream,
+{
+    futures::stream::select_all(streams.into_iter().map(Box::pin))
+}
+
+/// Stream returned by [`merge_sorted_by_key`]
+pub struct MergeSortedByKey<S: Stream, F> {
+    sources: Vec<Source<S>>,
+    key_fn: F,
+}
+
+struct Source<S: Stream> {
+    stream: Pin<Box<S>>,
+    head: Option<S::Item>,
+    done: bool,
+}
+
+// Buffered heads are only ever moved, never pinned
+impl<S: Stream, F> Unpin for MergeSortedByKey<S, F> {}
+
+/// Merges streams that are each sorted by `key_fn` into one sorted stream
+///
+/// An item is only yielded once every unfinished stream has an item to
+/// compare it with, so one quiet source holds back the others. Items with
+/// equal keys come out in the order their streams were given.
+///
+/// ```
+/// # async fn example() {
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::merge_sorted_by_key;
+///
+/// let app = tokio_stream::iter(vec![(1, "app started"), (4, "request served")]);
+/// let db = tokio_stream::iter(vec![(2, "db connected"), (3, "query ran")]);
+///
+/// let log: Vec<_> = merge_sorted_by_key([app, db], |(timestamp, _)| *timestamp)
+///     .collect()
+///     .await;
+/// assert_eq!(log.len(), 4);
+/// # }
+/// ```
+pub fn merge_sorted_by_key<I, F, K>(streams: I, key_fn: F) -> MergeSortedByKey<I::Item, F>
+where
+    I: IntoIterator,
+    I::Item: Stream,
+    F: FnMut(&<I::Item as Stream>::Item) -> K,
+    K: Ord,
+{
+    let sources = streams
+        .into_iter()
+        .map(|stream| Source {
+            stream: Box::pin(stream),
+            head: None,
+            done: false,
+        })
+        .collect();
+    MergeSortedByKey { sources, key_fn }
+}
+
+impl<S, F, K> Stream for MergeSortedByKey<S, F>
+where
+    S: Stream,
+    F: FnMut(&S::Item) -> K,
+    K: Ord,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = self.get_mut();
+
+        let mut waiting = false;
+        for source in this
+            .sources
+            .iter_mut()
+            .filter(|s| s.head.is_none() && !s.done)
+        {
+            match source.stream.as_mut().poll_next(cx) {
+                Poll::Ready(Some(item)) => source.head = Some(item),
+                Poll::Ready(None) => source.done = true,
+                Poll::Pending => waiting = true,
+            }
+        }
+        if waiting {
+            return Poll::Pending;
+        }
+
+        let mut smallest: Option<(usize, K)> = None;
+        for (i, source) in this.sources.iter().enumerate() {
+            if let Some(item) = &source.head {
+                let key = (this.key_fn)(item);
+                if smallest.as_ref().is_none_or(|(_, min)| key < *min) {
+                    smallest = Some((i, key));
+                }
+            }
+        }
+        Poll::Ready(smallest.and_then(|(i, _)| this.sources[i].head.take()))
+    }
+}
@@ -0,0 +1,154 @@
+//! Flattening paged APIs into streams
+
+use crate::select::RetryPolicy;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::time::{Instant, Sleep};
+use tokio_stream::Stream;
+
+/// One page returned by the `fetch_page` function given to [`paginate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page<T, P> {
+    pub items: Vec<T>,
+    /// Token for the following page, `None` on the last one
+    pub next: Option<P>,
+}
+
+/// Stream returned by [`paginate`]
+pub struct Paginate<T, P, E, F, Fut> {
+    fetch_page: F,
+    /// `None` once the last page has been fetched or fetching failed
+    next: Option<P>,
+    buffered: VecDeque<T>,
+    fetching: Option<Pin<Box<Fut>>>,
+    retry: Option<RetryPolicy<E>>,
+    failures: u32,
+    failing_since: Instant,
+    backoff: Option<Pin<Box<Sleep>>>,
+    remaining: Option<usize>,
+}
+
+// Only the boxed futures are ever pinned
+impl<T, P, E, F, Fut> Unpin for Paginate<T, P, E, F, Fut> {}
+
+/// Yields the items of every page of a token-paged API, fetching each page
+/// once the items of the previous one have been consumed
+///
+/// `fetch_page` is called with `initial_token` and then with each page's
+/// `next` token until a page has none. A failed fetch ends the stream with
+/// the error, unless a [`retry`](Paginate::retry) policy says to try the
+/// same page again.
+///
+/// ```
+/// # async fn example() {
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::{paginate, Page};
+///
+/// async fn list_users(cursor: Option<u32>) -> Result<Page<String, Option<u32>>, std::io::Error> {
+///     let start = cursor.unwrap_or(0);
+///     let next = (start < 20).then_some(Some(start + 10));
+///     Ok(Page { items: (start..start + 10).map(|id| format!("user{}", id)).collect(), next })
+/// }
+///
+/// let mut users = paginate(None, list_users).max_items(25);
+/// while let Some(user) = users.next().await {
+///     println!("{}", user.unwrap());
+/// }
+/// # }
+/// ```
+pub fn paginate<T, P, E, F, Fut>(initial_token: P, fetch_page: F) -> Paginate<T, P, E, F, Fut>
+where
+    P: Clone,
+    F: FnMut(P) -> Fut,
+    Fut: Future<Output = Result<Page<T, P>, E>>,
+{
+    Paginate {
+        fetch_page,
+        next: Some(initial_token),
+        buffered: VecDeque::new(),
+        fetching: None,
+        retry: None,
+        failures: 0,
+        failing_since: Instant::now(),
+        backoff: None,
+        remaining: None,
+    }
+}
+
+impl<T, P, E, F, Fut> Paginate<T, P, E, F, Fut> {
+    /// Retries a failed page fetch as `policy` allows
+    pub fn retry(mut self, policy: RetryPolicy<E>) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Ends the stream after `limit` items, without fetching further pages
+    pub fn max_items(mut self, limit: usize) -> Self {
+        self.remaining = Some(limit);
+        self
+    }
+}
+
+impl<T, P, E, F, Fut> Stream for Paginate<T, P, E, F, Fut>
+where
+    P: Clone,
+    F: FnMut(P) -> Fut,
+    Fut: Future<Output = Result<Page<T, P>, E>>,
+{
+    type Item = Result<T, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<T, E>>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.remaining == Some(0) {
+                return Poll::Ready(None);
+            }
+            if let Some(item) = this.buffered.pop_front() {
+                if let Some(remaining) = &mut this.remaining {
+                    *remaining -= 1;
+                }
+                return Poll::Ready(Some(Ok(item)));
+            }
+
+            if let Some(backoff) = &mut this.backoff {
+                ready!(backoff.as_mut().poll(cx));
+                this.backoff = None;
+            }
+            if this.fetching.is_none() {
+                let Some(token) = this.next.clone() else {
+                    return Poll::Ready(None);
+                };
+                this.fetching = Some(Box::pin((this.fetch_page)(token)));
+            }
+
+            let result = ready!(this.fetching.as_mut().unwrap().as_mut().poll(cx));
+            this.fetching = None;
+            match result {
+                Ok(page) => {
+                    this.failures = 0;
+                    this.buffered.extend(page.items);
+                    this.next = page.next;
+                }
+                Err(e) => {
+                    if this.failures == 0 {
+                        this.failing_since = Instant::now();
+                    }
+                    this.failures += 1;
+                    let delay = this.retry.as_ref().and_then(|policy| {
+                        policy.next_delay(this.failures, this.failing_since, &e)
+                    });
+                    match delay {
+                        Some(delay) => this.backoff = Some(Box::pin(tokio::time::sleep(delay))),
+                        None => {
+                            this.next = None;
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Splitting a stream into one sub-stream per key
+
+use crate::spawning::spawn_traced;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+/// The items of one key, handed out by [`partition_by_key`]
+///
+/// Ends when the source ends, or earlier if the key goes idle.
+#[derive(Debug)]
+pub struct Partition<T> {
+    inner: ReceiverStream<T>,
+}
+
+impl<T> Stream for Partition<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+/// Stream returned by [`partition_by_key`]
+pub struct PartitionByKey<S, F, K, T> {
+    state: State<S, F, K, T>,
+}
+
+enum State<S, F, K, T> {
+    Idle {
+        stream: S,
+        key_fn: F,
+        per_key_buffer: usize,
+        idle_timeout: Option<Duration>,
+    },
+    Running(ReceiverStream<(K, Partition<T>)>),
+    Taken,
+}
+
+// The source is moved onto a task before it is ever polled
+impl<S, F, K, T> Unpin for PartitionByKey<S, F, K, T> {}
+
+/// Routes items into a separate [`Partition`] per key
+///
+/// Yields `(key, partition)` the first time a key is seen. Each partition
+/// buffers up to `per_key_buffer` items; once one is full the whole source
+/// waits for it, so partitions should be consumed concurrently, typically
+/// each on its own task. A partition that is dropped, or that goes
+/// [idle](PartitionByKey::idle_timeout), is closed, and a later item with
+/// its key starts a new one.
+///
+/// The source is driven by a spawned task from the first poll onwards.
+///
+/// ```
+/// # async fn example(events: impl tokio_stream::Stream<Item = (u32, String)> + Send + 'static) {
+/// use std::time::Duration;
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::partition_by_key;
+///
+/// let mut users = partition_by_key(events, |(user, _)| *user, 16)
+///     .idle_timeout(Duration::from_secs(60));
+/// while let Some((user, mut events)) = users.next().await {
+///     tokio::spawn(async move {
+///         while let Some((_, event)) = events.next().await {
+///             println!("user {}: {}", user, event);
+///         }
+///     });
+/// }
+/// # }
+/// ```
+///
+/// # Panics
+///
+/// Panics if `per_key_buffer` is zero.
+pub fn partition_by_key<S, F, K>(
+    stream: S,
+    key_fn: F,
+    per_key_buffer: usize,
+) -> PartitionByKey<S, F, K, S::Item>
+where
+    S: Stream,
+    F: FnMut(&S::Item) -> K,
+{
+    assert!(per_key_buffer > 0, "`per_key_buffer` must be non-zero");
+    PartitionByKey {
+        state: State::Idle {
+            stream,
+            key_fn,
+            per_key_buffer,
+            idle_timeout: None,
+        },
+    }
+}
+
+impl<S, F, K, T> PartitionByKey<S, F, K, T> {
+    /// Closes a partition once its key has seen no items for `timeout`,
+    /// instead of keeping it open until the source ends
+    ///
+    /// Has no effect once the stream has been polled.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        if let State::Idle { idle_timeout, .. } = &mut self.state {
+            *idle_timeout = Some(timeout);
+        }
+        self
+    }
+}
+
+impl<S, F, K> Stream for PartitionByKey<S, F, K, S::Item>
+where
+    S: Stream + Send + 'static,
+    S::Item: Send + 'static,
+    F: FnMut(&S::Item) -> K + Send + 'static,
+    K: Hash + Eq + Clone + Send + 'static,
+{
+    type Item = (K, Partition<S::Item>);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let State::Idle { .. } = this.state {
+            let State::Idle {
+                stream,
+                key_fn,
+                per_key_buffer,
+                idle_timeout,
+            } = std::mem::replace(&mut this.state, State::Taken)
+            else {
+                unreachable!()
+            };
+            let (tx, rx) = mpsc::channel(1);
+            spawn_traced(
+                "partition router",
+                route(stream, key_fn, per_key_buffer, idle_timeout, tx),
+            );
+            this.state = State::Running(ReceiverStream::new(rx));
+        }
+
+        match &mut this.state {
+            State::Running(partitions) => Pin::new(partitions).poll_next(cx),
+            _ => Poll::Ready(None),
+        }
+    }
+}
+
+async fn route<S, F, K>(
+    stream: S,
+    mut key_fn: F,
+    per_key_buffer: usize,
+    idle_timeout: Option<Duration>,
+    partitions: mpsc::Sender<(K, Partition<S::Item>)>,
+) where
+    S: Stream,
+    F: FnMut(&S::Item) -> K,
+    K: Hash + Eq + Clone,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut open: HashMap<K, (mpsc::Sender<S::Item>, Instant)> = HashMap::new();
+    let mut sweep = idle_timeout.map(tokio::time::interval);
+
+    loop {
+        let item = tokio::select! {
+            item = stream.next() => match item {
+                Some(item) => item,
+                None => return,
+            },
+            _ = async { sweep.as_mut().unwrap().tick().await }, if sweep.is_some() => {
+                let timeout = idle_timeout.unwrap_or_default();
+                open.retain(|_, (tx, last_item)| !tx.is_closed() && last_item.elapsed() < timeout);
+                continue;
+            }
+        };
+
+        let key = key_fn(&item);
+        let tx = match open.get(&key) {
+            Some((tx, _)) if !tx.is_closed() => tx.clone(),
+            _ => {
+                let (tx, rx) = mpsc::channel(per_key_buffer);
+                let partition = Partition {
+                    inner: ReceiverStream::new(rx),
+                };
+                if partitions.send((key.clone(), partition)).await.is_err() && open.is_empty() {
+                    // Nobody is listening for partitions, old or new
+                    return;
+                }
+                tx
+            }
+        };
+
+        // A partition dropped meanwhile just loses the item
+        let _ = tx.send(item).await;
+        open.insert(key, (tx, Instant::now()));
+    }
+}
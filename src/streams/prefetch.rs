@@ -0,0 +1,125 @@
+//! Reading ahead of a slow consumer
+
+use crate::spawning::spawn_traced;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio_stream::{Stream, StreamExt};
+
+/// How well a [`Prefetch`] buffer is keeping its consumer fed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PrefetchMetrics {
+    pub capacity: usize,
+    /// Items currently waiting in the buffer
+    pub buffered: usize,
+    /// The most items that were ever waiting at once
+    pub peak_buffered: usize,
+    /// Items pulled from the source so far
+    pub produced: u64,
+    /// Times the consumer found the buffer empty and had to wait for the
+    /// source; few waits mean prefetching is hiding the source's latency
+    pub consumer_waits: u64,
+    /// Times the source had to pause because the buffer was full
+    pub producer_waits: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    peak_buffered: AtomicUsize,
+    produced: AtomicU64,
+    consumer_waits: AtomicU64,
+    producer_waits: AtomicU64,
+}
+
+/// Stream returned by [`prefetch`]
+pub struct Prefetch<T> {
+    rx: mpsc::Receiver<T>,
+    capacity: usize,
+    counters: Arc<Counters>,
+    waiting: bool,
+    task: AbortHandle,
+}
+
+/// Pulls up to `n` items ahead of the consumer on a background task
+///
+/// The source keeps producing while the consumer is busy with earlier
+/// items, so their latencies overlap instead of adding up. Dropping the
+/// returned stream stops the task.
+///
+/// # Panics
+///
+/// Panics if `n` is zero.
+pub fn prefetch<S>(stream: S, n: usize) -> Prefetch<S::Item>
+where
+    S: Stream + Send + 'static,
+    S::Item: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(n);
+    let counters = Arc::new(Counters::default());
+
+    let task = spawn_traced("prefetch", {
+        let counters = counters.clone();
+        async move {
+            let mut stream = std::pin::pin!(stream);
+            while let Some(item) = stream.next().await {
+                if tx.capacity() == 0 {
+                    counters.producer_waits.fetch_add(1, Ordering::Relaxed);
+                }
+                if tx.send(item).await.is_err() {
+                    return;
+                }
+                counters.produced.fetch_add(1, Ordering::Relaxed);
+                let buffered = n - tx.capacity();
+                counters
+                    .peak_buffered
+                    .fetch_max(buffered, Ordering::Relaxed);
+            }
+        }
+    });
+
+    Prefetch {
+        rx,
+        capacity: n,
+        counters,
+        waiting: false,
+        task: task.abort_handle(),
+    }
+}
+
+impl<T> Prefetch<T> {
+    pub fn metrics(&self) -> PrefetchMetrics {
+        let counters = &self.counters;
+        PrefetchMetrics {
+            capacity: self.capacity,
+            buffered: self.rx.len(),
+            peak_buffered: counters.peak_buffered.load(Ordering::Relaxed),
+            produced: counters.produced.load(Ordering::Relaxed),
+            consumer_waits: counters.consumer_waits.load(Ordering::Relaxed),
+            producer_waits: counters.producer_waits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<T> Stream for Prefetch<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let poll = self.rx.poll_recv(cx);
+        if poll.is_pending() && !self.waiting {
+            self.waiting = true;
+            self.counters.consumer_waits.fetch_add(1, Ordering::Relaxed);
+        } else if poll.is_ready() {
+            self.waiting = false;
+        }
+        poll
+    }
+}
+
+impl<T> Drop for Prefetch<T> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,36 @@
+//! Pacing streams with a token bucket
+
+use crate::ratelimit::TokenBucket;
+use tokio_stream::{Stream, StreamExt};
+
+/// Yields at most `items_per_sec` items per second on average, letting up
+/// to `burst` through at once
+///
+/// Items are held back rather than dropped, so a fast source is simply
+/// slowed down to the rate. See [`throttle`](super::throttle) for dropping
+/// instead.
+///
+/// # Panics
+///
+/// Panics if `items_per_sec` isn't positive and finite, or `burst` is zero.
+pub fn rate_limit<S: Stream>(
+    stream: S,
+    items_per_sec: f64,
+    burst: u32,
+) -> impl Stream<Item = S::Item> {
+    rate_limit_with(stream, TokenBucket::new(items_per_sec, burst))
+}
+
+/// Like [`rate_limit`], taking tokens from an existing bucket
+///
+/// Streams sharing clones of one bucket share its rate, e.g. several
+/// producers writing to the same API.
+pub fn rate_limit_with<S: Stream>(stream: S, bucket: TokenBucket) -> impl Stream<Item = S::Item> {
+    stream.then(move |item| {
+        let bucket = bucket.clone();
+        async move {
+            bucket.acquire().await;
+            item
+        }
+    })
+}
@@ -0,0 +1,65 @@
+//! Restoring sequence order after out-of-order processing
+
+use std::collections::BTreeMap;
+use tokio_stream::{Stream, StreamExt};
+
+/// Re-sorts `(sequence, item)` pairs numbered from 0 into sequence order
+///
+/// Items that arrive early wait in a buffer until the gap before them is
+/// filled. If more than `max_out_of_order` are waiting, the missing numbers
+/// are given up on and the buffer moves on to the lowest number it holds;
+/// the same happens for any gaps left when the source ends. Sequence
+/// numbers are passed through so skipped ones can be noticed, and items
+/// behind the current position (duplicates or late arrivals after a skip)
+/// are dropped.
+///
+/// ```
+/// # async fn example() {
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::{map_concurrent_unordered, reorder_by_sequence};
+///
+/// async fn resize(image: u32) -> u32 {
+///     image
+/// }
+///
+/// let images = tokio_stream::iter((0..100u32).enumerate());
+/// let resized = map_concurrent_unordered(images, 8, |(seq, image)| async move {
+///     (seq as u64, resize(image).await)
+/// });
+/// let in_order: Vec<(u64, u32)> = reorder_by_sequence(resized, 16).collect().await;
+/// # let _ = in_order;
+/// # }
+/// ```
+pub fn reorder_by_sequence<S, T>(stream: S, max_out_of_order: usize) -> impl Stream<Item = (u64, T)>
+where
+    S: Stream<Item = (u64, T)>,
+{
+    let state = (Box::pin(stream), BTreeMap::new(), 0u64, false);
+    futures::stream::unfold(
+        state,
+        move |(mut stream, mut waiting, mut next, mut done)| async move {
+            loop {
+                let ready = match waiting.remove(&next) {
+                    Some(item) => Some((next, item)),
+                    None if done || waiting.len() > max_out_of_order => waiting.pop_first(),
+                    None => None,
+                };
+                if let Some((seq, item)) = ready {
+                    next = seq + 1;
+                    return Some(((seq, item), (stream, waiting, next, done)));
+                }
+                if done {
+                    return None;
+                }
+
+                match stream.next().await {
+                    Some((seq, item)) if seq >= next => {
+                        waiting.insert(seq, item);
+                    }
+                    Some(_) => {}
+                    None => done = true,
+                }
+            }
+        },
+    )
+}
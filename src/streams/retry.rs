@@ -0,0 +1,108 @@
+//! Resubscribing to streams that fail
+
+use crate::select::RetryPolicy;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::time::Instant;
+use tokio_stream::{Stream, StreamExt};
+
+struct State<F, S, E> {
+    factory: F,
+    policy: RetryPolicy<E>,
+    current: Option<Pin<Box<S>>>,
+    /// Failures since the last item, and when the first of them happened
+    failures: u32,
+    failing_since: Instant,
+    finished: bool,
+}
+
+/// Opens a stream with `factory` and opens a fresh one, after the delay
+/// `policy` asks for, whenever opening fails or the stream yields an error
+///
+/// Errors the policy retries never reach the consumer. Once it gives up the
+/// last error is yielded and the stream ends; it also ends, without an
+/// error, when the current source ends. Every item received resets the
+/// policy's attempt count, so only consecutive failures count towards its
+/// limits.
+///
+/// ```
+/// # async fn example() {
+/// use std::time::Duration;
+/// use tokio_stream::{Stream, StreamExt};
+/// use tokio_tutorial_patterns::select::RetryPolicy;
+/// use tokio_tutorial_patterns::streams::{from_receiver, retry_stream};
+///
+/// async fn subscribe() -> std::io::Result<impl Stream<Item = std::io::Result<String>>> {
+///     let (_tx, rx) = tokio::sync::mpsc::channel(16);
+///     Ok(from_receiver(rx))
+/// }
+///
+/// let policy = RetryPolicy::exponential(Duration::from_millis(100)).max_attempts(Some(10));
+/// let mut events = std::pin::pin!(retry_stream(subscribe, policy));
+/// while let Some(event) = events.next().await {
+///     println!("{:?}", event);
+/// }
+/// # }
+/// ```
+pub fn retry_stream<F, Fut, S, T, E>(
+    factory: F,
+    policy: RetryPolicy<E>,
+) -> impl Stream<Item = Result<T, E>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<S, E>>,
+    S: Stream<Item = Result<T, E>>,
+{
+    let state: State<F, S, E> = State {
+        factory,
+        policy,
+        current: None,
+        failures: 0,
+        failing_since: Instant::now(),
+        finished: false,
+    };
+
+    futures::stream::unfold(state, |mut state| async move {
+        if state.finished {
+            return None;
+        }
+
+        loop {
+            let error = match &mut state.current {
+                Some(stream) => match stream.next().await {
+                    Some(Ok(item)) => {
+                        state.failures = 0;
+                        return Some((Ok(item), state));
+                    }
+                    Some(Err(e)) => {
+                        state.current = None;
+                        e
+                    }
+                    None => return None,
+                },
+                None => match (state.factory)().await {
+                    Ok(stream) => {
+                        state.current = Some(Box::pin(stream));
+                        continue;
+                    }
+                    Err(e) => e,
+                },
+            };
+
+            if state.failures == 0 {
+                state.failing_since = Instant::now();
+            }
+            state.failures += 1;
+            match state
+                .policy
+                .next_delay(state.failures, state.failing_since, &error)
+            {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => {
+                    state.finished = true;
+                    return Some((Err(error), state));
+                }
+            }
+        }
+    })
+}
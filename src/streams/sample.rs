@@ -0,0 +1,60 @@
+//! Down-sampling streams
+
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_stream::{Stream, StreamExt};
+
+/// Yields the first item and every `n`th one after it
+///
+/// # Panics
+///
+/// Panics if `n` is zero.
+pub fn sample_every<S: Stream>(stream: S, n: usize) -> impl Stream<Item = S::Item> {
+    assert!(n > 0, "`n` must be non-zero");
+    futures::StreamExt::enumerate(stream).filter_map(move |(i, item)| (i % n == 0).then_some(item))
+}
+
+/// Keeps each item independently with probability `p`
+///
+/// # Panics
+///
+/// Panics unless `p` is between 0.0 and 1.0.
+pub fn sample_probabilistic<S: Stream>(stream: S, p: f64) -> impl Stream<Item = S::Item> {
+    assert!((0.0..=1.0).contains(&p), "`p` must be between 0.0 and 1.0");
+    stream.filter(move |_| rand::random::<f64>() < p)
+}
+
+/// Yields the most recent item at the end of every `period`
+///
+/// Older items in the same period are dropped, and periods without a new
+/// item yield nothing. When the source ends, an item not yet yielded is
+/// yielded straight away.
+///
+/// # Panics
+///
+/// Panics if `period` is zero.
+pub fn latest_every<S: Stream>(stream: S, period: Duration) -> impl Stream<Item = S::Item> {
+    let mut ticks = tokio::time::interval_at(Instant::now() + period, period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let state = Some((Box::pin(stream), ticks));
+    futures::stream::unfold(state, |state| async move {
+        let (mut stream, mut ticks) = state?;
+        let mut latest = None;
+
+        loop {
+            tokio::select! {
+                biased;
+                _ = ticks.tick() => {
+                    if let Some(item) = latest {
+                        return Some((item, Some((stream, ticks))));
+                    }
+                }
+                item = stream.next() => match item {
+                    Some(item) => latest = Some(item),
+                    None => return latest.map(|item| (item, None)),
+                },
+            }
+        }
+    })
+}
@@ -0,0 +1,59 @@
+//! Threading state through a stream with async steps
+//!
+//! Each step takes the state by value and hands it back when done, so state
+//! only ever exists in one place: inside the step that is running. Dropping
+//! a stream or future part way through therefore drops the state along with
+//! it instead of leaving a half-applied update behind for anyone to see.
+//! Callers that need the state to survive cancellation should persist it
+//! from within the step.
+
+use std::future::Future;
+use tokio_stream::{Stream, StreamExt};
+
+/// Yields one output per item, computed by an async step that also returns
+/// the updated state
+///
+/// ```
+/// # async fn example() {
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::scan_async;
+///
+/// // Running totals
+/// let totals: Vec<u64> = scan_async(tokio_stream::iter(vec![3, 4, 5]), 0, |sum, n| async move {
+///     let sum = sum + n;
+///     (sum, sum)
+/// })
+/// .collect()
+/// .await;
+/// assert_eq!(totals, [3, 7, 12]);
+/// # }
+/// ```
+pub fn scan_async<S, St, F, Fut, T>(stream: S, initial: St, step: F) -> impl Stream<Item = T>
+where
+    S: Stream,
+    F: FnMut(St, S::Item) -> Fut,
+    Fut: Future<Output = (St, T)>,
+{
+    let state = (Box::pin(stream), initial, step);
+    futures::stream::unfold(state, |(mut stream, acc, mut step)| async move {
+        let item = stream.next().await?;
+        let (acc, output) = step(acc, item).await;
+        Some((output, (stream, acc, step)))
+    })
+}
+
+/// Reduces the stream to a single value with an async step, e.g. one that
+/// writes each item to a database and returns how many it has written
+pub async fn fold_async<S, St, F, Fut>(stream: S, initial: St, mut step: F) -> St
+where
+    S: Stream,
+    F: FnMut(St, S::Item) -> Fut,
+    Fut: Future<Output = St>,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut acc = initial;
+    while let Some(item) = stream.next().await {
+        acc = step(acc, item).await;
+    }
+    acc
+}
@@ -0,0 +1,149 @@
+//! `Sink`s for the crate's common destinations, and [`forward`] to drain a
+//! stream into one
+//!
+//! A sink accepts items one at a time with backpressure: `poll_ready`
+//! waits for room, `start_send` buffers an item, and `poll_flush` waits
+//! until everything buffered has reached its destination.
+
+use crate::io::RotatingLog;
+use futures::future::BoxFuture;
+use futures::{FutureExt, Sink, SinkExt};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::codec::FramedWrite;
+use tokio_util::sync::PollSender;
+
+/// Sends items on an mpsc channel, waiting for capacity
+///
+/// Fails with the unsent item once the receiver is gone.
+pub fn sender<T: Send + 'static>(tx: mpsc::Sender<T>) -> PollSender<T> {
+    PollSender::new(tx)
+}
+
+/// Writes items to `writer` as frames encoded by `encoder`, buffering until
+/// flushed
+///
+/// A sink for every item type `encoder` implements `Encoder` for.
+pub fn framed<W: AsyncWrite, E>(writer: W, encoder: E) -> FramedWrite<W, E> {
+    FramedWrite::new(writer, encoder)
+}
+
+/// Appends each item as a record to a [`RotatingLog`]
+///
+/// Flushing waits for the log's writer task to write and fsync everything
+/// sent so far. Closing the sink only flushes: the log itself stays open
+/// for its other handles.
+pub fn rotating_log(log: RotatingLog) -> LogSink {
+    LogSink {
+        log,
+        appending: None,
+        flushing: None,
+    }
+}
+
+/// Sink returned by [`rotating_log`]
+pub struct LogSink {
+    log: RotatingLog,
+    appending: Option<BoxFuture<'static, io::Result<()>>>,
+    flushing: Option<BoxFuture<'static, io::Result<()>>>,
+}
+
+impl LogSink {
+    fn poll_appended(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(appending) = &mut self.appending {
+            let result = ready!(appending.as_mut().poll(cx));
+            self.appending = None;
+            result?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Into<Vec<u8>>> Sink<T> for LogSink {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_appended(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> io::Result<()> {
+        let log = self.log.clone();
+        let record = item.into();
+        self.get_mut().appending =
+            Some(async move { log.append(record).await.map_err(io::Error::from) }.boxed());
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_appended(cx))?;
+
+        let log = this.log.clone();
+        let flushing = this
+            .flushing
+            .get_or_insert_with(|| async move { log.flush().await }.boxed());
+        let result = ready!(flushing.as_mut().poll(cx));
+        this.flushing = None;
+        Poll::Ready(result)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        <Self as Sink<T>>::poll_flush(self, cx)
+    }
+}
+
+/// Sends every item of `stream` into `sink`, then closes it
+///
+/// Waits whenever the sink is full, so a slow destination slows the source
+/// down instead of items piling up. The sink is flushed whenever the stream
+/// has nothing ready, so items never linger in a buffer while the source is
+/// quiet, and closed (which flushes) once the stream ends. To flush and stop
+/// on shutdown, end the stream with
+/// [`take_until_cancelled`](super::take_until_cancelled).
+///
+/// Returns how many items were sent.
+///
+/// ```
+/// # async fn example(events: impl tokio_stream::Stream<Item = String>,
+/// #     log: tokio_tutorial_patterns::io::RotatingLog,
+/// #     token: tokio_util::sync::CancellationToken) -> std::io::Result<()> {
+/// use tokio_tutorial_patterns::streams::{sinks, take_until_cancelled};
+///
+/// let events = take_until_cancelled(events, token);
+/// let written = sinks::forward(events, sinks::rotating_log(log)).await?;
+/// println!("{} events written", written);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn forward<S, Si>(stream: S, sink: Si) -> Result<usize, Si::Error>
+where
+    S: Stream,
+    Si: Sink<S::Item>,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut sink = std::pin::pin!(sink);
+    let mut sent = 0;
+
+    loop {
+        let item = match stream.next().now_or_never() {
+            Some(Some(item)) => item,
+            Some(None) => break,
+            None => {
+                sink.flush().await?;
+                match stream.next().await {
+                    Some(item) => item,
+                    None => break,
+                }
+            }
+        };
+        sink.feed(item).await?;
+        sent += 1;
+    }
+
+    sink.close().await?;
+    Ok(sent)
+}
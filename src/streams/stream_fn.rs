@@ -0,0 +1,103 @@
+//! Writing streams as async blocks that emit values
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio_stream::Stream;
+
+/// Hands values from a [`stream_fn`] body to the stream's consumer
+pub struct Yielder<T> {
+    slot: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> Yielder<T> {
+    /// Yields `value` from the stream, resuming once the consumer asks for
+    /// the next item
+    pub async fn emit(&mut self, value: T) {
+        *self.slot.lock().unwrap() = Some(value);
+        Handoff { yielded: false }.await
+    }
+}
+
+/// Pending exactly once, suspending the body while the value is taken
+struct Handoff {
+    yielded: bool,
+}
+
+impl Future for Handoff {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        // No wake-up needed: the stream returns the value right away and
+        // polls the body again on the next `poll_next`
+        self.yielded = true;
+        Poll::Pending
+    }
+}
+
+/// Stream returned by [`stream_fn`]
+pub struct StreamFn<T, Fut> {
+    body: Option<Pin<Box<Fut>>>,
+    slot: Arc<Mutex<Option<T>>>,
+}
+
+// Only the boxed body is ever pinned
+impl<T, Fut> Unpin for StreamFn<T, Fut> {}
+
+/// Builds a stream from an async body that calls [`Yielder::emit`] for each
+/// item; the stream ends when the body returns
+///
+/// The body runs only while the stream is polled and is suspended at each
+/// `emit` until the consumer wants another item, so the state a
+/// hand-written `poll_next` would keep in fields lives in plain local
+/// variables and loops instead.
+///
+/// ```
+/// # async fn example() {
+/// use tokio_stream::StreamExt;
+/// use tokio_tutorial_patterns::streams::stream_fn;
+///
+/// let countdown = stream_fn(|mut y| async move {
+///     for n in (1..=3).rev() {
+///         y.emit(n).await;
+///     }
+/// });
+/// assert_eq!(countdown.collect::<Vec<u32>>().await, [3, 2, 1]);
+/// # }
+/// ```
+pub fn stream_fn<T, F, Fut>(body: F) -> StreamFn<T, Fut>
+where
+    F: FnOnce(Yielder<T>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let slot = Arc::new(Mutex::new(None));
+    let yielder = Yielder { slot: slot.clone() };
+    StreamFn {
+        body: Some(Box::pin(body(yielder))),
+        slot,
+    }
+}
+
+impl<T, Fut: Future<Output = ()>> Stream for StreamFn<T, Fut> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let Some(body) = self.body.as_mut() else {
+            return Poll::Ready(None);
+        };
+
+        let finished = body.as_mut().poll(cx).is_ready();
+        if finished {
+            self.body = None;
+        }
+        match self.slot.lock().unwrap().take() {
+            Some(value) => Poll::Ready(Some(value)),
+            None if finished => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Feeding one stream to several independent consumers
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use tokio_stream::Stream;
+
+/// What [`tee`] does when a consumer's buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumer {
+    /// Stop pulling from the source until the slowest consumer catches up
+    Block,
+    /// Keep going; the slow consumer misses the items it has no room for
+    Drop,
+}
+
+#[derive(Debug, Clone)]
+pub struct TeeConfig {
+    /// Items each consumer can fall behind the fastest one
+    pub buffer: usize,
+    pub slow_consumer: SlowConsumer,
+}
+
+impl Default for TeeConfig {
+    fn default() -> Self {
+        Self {
+            buffer: 16,
+            slow_consumer: SlowConsumer::Block,
+        }
+    }
+}
+
+/// One of the consumers returned by [`tee`]
+pub struct Tee<S: Stream> {
+    shared: Arc<Mutex<Shared<S>>>,
+    wakers: Arc<Wakers>,
+    index: usize,
+}
+
+struct Shared<S: Stream> {
+    source: Pin<Box<S>>,
+    done: bool,
+    /// `None` once the consumer has been dropped
+    buffers: Vec<Option<VecDeque<S::Item>>>,
+    config: TeeConfig,
+}
+
+/// Wakes every waiting consumer, whichever of them last polled the source
+struct Wakers(Mutex<Vec<Option<Waker>>>);
+
+impl Wakers {
+    fn register(&self, index: usize, waker: &Waker) {
+        self.0.lock().unwrap()[index] = Some(waker.clone());
+    }
+
+    fn wake_others(&self, index: usize) {
+        let mut wakers = self.0.lock().unwrap();
+        for (i, waker) in wakers.iter_mut().enumerate() {
+            if i != index {
+                if let Some(waker) = waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+impl Wake for Wakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_others(usize::MAX);
+    }
+}
+
+/// Splits `stream` into `n` consumers that each see every item, with the
+/// default [`TeeConfig`]
+///
+/// # Panics
+///
+/// Panics if `n` is zero.
+pub fn tee<S>(stream: S, n: usize) -> Vec<Tee<S>>
+where
+    S: Stream,
+    S::Item: Clone,
+{
+    tee_with_config(stream, n, TeeConfig::default())
+}
+
+/// Splits `stream` into `n` consumers that each see every item
+///
+/// The source is pulled by whichever consumer runs out of buffered items
+/// first. Consumers that fall `config.buffer` items behind either hold
+/// everyone back or miss items, depending on `config.slow_consumer`; a
+/// dropped consumer no longer counts.
+///
+/// # Panics
+///
+/// Panics if `n` or `config.buffer` is zero.
+pub fn tee_with_config<S>(stream: S, n: usize, config: TeeConfig) -> Vec<Tee<S>>
+where
+    S: Stream,
+    S::Item: Clone,
+{
+    assert!(n > 0, "`n` must be non-zero");
+    assert!(config.buffer > 0, "`buffer` must be non-zero");
+
+    let shared = Arc::new(Mutex::new(Shared {
+        source: Box::pin(stream),
+        done: false,
+        buffers: (0..n).map(|_| Some(VecDeque::new())).collect(),
+        config,
+    }));
+    let wakers = Arc::new(Wakers(Mutex::new(vec![None; n])));
+
+    (0..n)
+        .map(|index| Tee {
+            shared: shared.clone(),
+            wakers: wakers.clone(),
+            index,
+        })
+        .collect()
+}
+
+impl<S> Stream for Tee<S>
+where
+    S: Stream,
+    S::Item: Clone,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = self.get_mut();
+        let mut shared = this.shared.lock().unwrap();
+        let shared = &mut *shared;
+
+        if let Some(item) = shared.buffers[this.index]
+            .as_mut()
+            .and_then(VecDeque::pop_front)
+        {
+            if shared.config.slow_consumer == SlowConsumer::Block {
+                // There may be a faster consumer waiting for this room
+                this.wakers.wake_others(this.index);
+            }
+            return Poll::Ready(Some(item));
+        }
+        if shared.done {
+            return Poll::Ready(None);
+        }
+
+        this.wakers.register(this.index, cx.waker());
+        let capacity = shared.config.buffer;
+        let others_full = shared.buffers.iter().flatten().any(|b| b.len() >= capacity);
+        if shared.config.slow_consumer == SlowConsumer::Block && others_full {
+            return Poll::Pending;
+        }
+
+        let waker = Waker::from(this.wakers.clone());
+        match shared
+            .source
+            .as_mut()
+            .poll_next(&mut Context::from_waker(&waker))
+        {
+            Poll::Ready(Some(item)) => {
+                for (i, buffer) in shared.buffers.iter_mut().enumerate() {
+                    match buffer {
+                        Some(buffer) if i != this.index && buffer.len() < capacity => {
+                            buffer.push_back(item.clone())
+                        }
+                        _ => {}
+                    }
+                }
+                this.wakers.wake_others(this.index);
+                Poll::Ready(Some(item))
+            }
+            Poll::Ready(None) => {
+                shared.done = true;
+                this.wakers.wake_others(this.index);
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: Stream> Drop for Tee<S> {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.buffers[self.index] = None;
+        }
+        self.wakers.wake_others(self.index);
+    }
+}
@@ -0,0 +1,26 @@
+//! Time limits on streams
+
+use crate::select::TimeoutError;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_stream::{Stream, StreamExt};
+
+/// Yields `Err(TimeoutError)` whenever the source takes longer than
+/// `timeout` to produce its next item
+///
+/// The stream carries on after an error, with the timer restarted, so the
+/// consumer decides whether a slow source is fatal; `.take_while(Result::is_ok)`
+/// turns the first gap into the end of the stream.
+pub fn timeout_per_item<S: Stream>(
+    stream: S,
+    timeout: Duration,
+) -> impl Stream<Item = Result<S::Item, TimeoutError>> {
+    StreamExt::timeout(stream, timeout).map(move |item| item.map_err(|_| TimeoutError { timeout }))
+}
+
+/// Ends the stream at `deadline`, dropping the source
+///
+/// An item the source is still producing at that point is discarded.
+pub fn take_until_deadline<S: Stream>(stream: S, deadline: Instant) -> impl Stream<Item = S::Item> {
+    futures::StreamExt::take_until(stream, tokio::time::sleep_until(deadline))
+}
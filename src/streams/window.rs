@@ -0,0 +1,82 @@
+//! Count- and time-based windows over streams
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_stream::{Stream, StreamExt};
+
+/// Yields windows of `size` consecutive items, starting a new window every
+/// `step` items
+///
+/// With `step < size` windows overlap (a sliding window, e.g. for moving
+/// averages); with `step == size` they don't; with `step > size` items
+/// between windows are skipped. Trailing items that never fill a window are
+/// dropped.
+///
+/// # Panics
+///
+/// Panics if `size` or `step` is zero.
+pub fn windowed<S>(stream: S, size: usize, step: usize) -> impl Stream<Item = Vec<S::Item>>
+where
+    S: Stream,
+    S::Item: Clone,
+{
+    assert!(size > 0 && step > 0, "`size` and `step` must be non-zero");
+
+    let state = (Box::pin(stream), VecDeque::with_capacity(size), 0usize);
+    futures::stream::unfold(
+        state,
+        move |(mut stream, mut window, mut skip)| async move {
+            loop {
+                let item = stream.next().await?;
+                if skip > 0 {
+                    skip -= 1;
+                    continue;
+                }
+
+                window.push_back(item);
+                if window.len() == size {
+                    let full: Vec<_> = window.iter().cloned().collect();
+                    window.drain(..step.min(size));
+                    skip = step.saturating_sub(size);
+                    return Some((full, (stream, window, skip)));
+                }
+            }
+        },
+    )
+}
+
+/// Groups items into consecutive, non-overlapping windows of `duration`
+///
+/// Windows are aligned to when the stream is created and one is yielded
+/// at the end of every period, even if no items arrived, so the output can
+/// be used directly for per-period rates. When the source ends, the
+/// partial window is yielded if it holds anything.
+///
+/// # Panics
+///
+/// Panics if `duration` is zero.
+pub fn tumbling_window<S: Stream>(
+    stream: S,
+    duration: Duration,
+) -> impl Stream<Item = Vec<S::Item>> {
+    let boundaries = tokio::time::interval_at(Instant::now() + duration, duration);
+
+    let state = Some((Box::pin(stream), boundaries));
+    futures::stream::unfold(state, |state| async move {
+        let (mut stream, mut boundaries) = state?;
+        let mut window = Vec::new();
+
+        loop {
+            tokio::select! {
+                biased;
+                _ = boundaries.tick() => return Some((window, Some((stream, boundaries)))),
+                item = stream.next() => match item {
+                    Some(item) => window.push(item),
+                    None if window.is_empty() => return None,
+                    None => return Some((window, None)),
+                },
+            }
+        }
+    })
+}
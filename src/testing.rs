@@ -0,0 +1,268 @@
+//! Helpers for testing code built on this crate deterministically
+//!
+//! Everything here assumes Tokio's paused clock: time only moves when every
+//! task is idle, and then jumps straight to the next timer, so tests of
+//! timeouts, retries and rate limits run instantly and the same way every
+//! time. [`run_test`] sets that up for a plain `#[test]`.
+//!
+//! Faults are described by a [`Script`], a list of [`Step`]s applied to
+//! successive messages: pass them through, delay them, drop them, or end
+//! the stream or channel there. The same script drives [`mock_channel`]
+//! and [`scripted_stream`]; the in-memory [`test_transport`] covers I/O.
+//!
+//! Only available with the `testing` feature, which also turns on Tokio's
+//! `test-util` feature.
+//!
+//! ```
+//! use std::time::Duration;
+//! use tokio_tutorial_patterns::testing::{self, Script};
+//!
+//! testing::run_test(async {
+//!     let script = Script::new().pass(1).drop().delay(Duration::from_secs(30));
+//!     let (tx, mut rx) = testing::mock_channel(8, script);
+//!
+//!     tx.send("a").await.unwrap();
+//!     tx.send("lost").await.unwrap();
+//!     tx.send("late").await.unwrap(); // "sleeps" 30s, instantly
+//!
+//!     assert_eq!(rx.recv().await, Some("a"));
+//!     assert_eq!(rx.recv().await, Some("late"));
+//!     assert_eq!(tx.dropped(), 1);
+//! });
+//! ```
+
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tokio_stream::{Stream, StreamExt};
+
+pub use crate::io::{test_transport, test_transport_with, FaultConfig, FaultyStream};
+
+/// Runs `future` to completion on a fresh current-thread runtime with the
+/// clock paused
+///
+/// The clock advances automatically whenever the runtime has nothing else
+/// to do, like `#[tokio::test(start_paused = true)]`.
+///
+/// # Panics
+///
+/// Panics if called from within another runtime.
+pub fn run_test<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .expect("Failed to create test runtime")
+        .block_on(future)
+}
+
+/// Awaits `future`, panicking if it takes longer than `limit`
+pub async fn assert_completes_within<F: Future>(limit: Duration, future: F) -> F::Output {
+    match tokio::time::timeout(limit, future).await {
+        Ok(output) => output,
+        Err(_) => panic!("future did not complete within {:?}", limit),
+    }
+}
+
+/// Polls `future` for `duration`, panicking if it completes in that time
+///
+/// The future is borrowed, so it can be awaited afterwards to check what
+/// it does next.
+pub async fn assert_pending_for<F>(duration: Duration, future: &mut F)
+where
+    F: Future + Unpin,
+{
+    if tokio::time::timeout(duration, future).await.is_ok() {
+        panic!("future completed within {:?}", duration);
+    }
+}
+
+/// Lets other tasks run until they are all waiting on something, without
+/// moving the clock
+pub async fn settle() {
+    for _ in 0..64 {
+        tokio::task::yield_now().await;
+    }
+}
+
+/// What happens to one message passing through a [`Script`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Delivered as normal
+    Pass,
+    /// Delivered after this long
+    Delay(Duration),
+    /// Silently lost
+    Drop,
+    /// Lost, and nothing after it gets through either
+    End,
+}
+
+/// The fate of successive messages; once the steps run out, messages pass
+/// unless the script [cycles](Script::cycle)
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    steps: Vec<Step>,
+    cycle: bool,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn step(mut self, step: Step) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Lets the next `count` messages through
+    pub fn pass(mut self, count: usize) -> Self {
+        self.steps.extend(std::iter::repeat_n(Step::Pass, count));
+        self
+    }
+
+    pub fn delay(self, delay: Duration) -> Self {
+        self.step(Step::Delay(delay))
+    }
+
+    pub fn drop(self) -> Self {
+        self.step(Step::Drop)
+    }
+
+    pub fn end(self) -> Self {
+        self.step(Step::End)
+    }
+
+    /// Starts over from the first step after the last one
+    pub fn cycle(mut self) -> Self {
+        self.cycle = true;
+        self
+    }
+
+    fn cursor(self) -> Cursor {
+        Cursor {
+            script: self,
+            next: 0,
+        }
+    }
+}
+
+struct Cursor {
+    script: Script,
+    next: usize,
+}
+
+impl Cursor {
+    fn next_step(&mut self) -> Step {
+        let steps = &self.script.steps;
+        if self.next == steps.len() {
+            if !self.script.cycle || steps.is_empty() {
+                return Step::Pass;
+            }
+            self.next = 0;
+        }
+        self.next += 1;
+        steps[self.next - 1]
+    }
+}
+
+/// Applies `script` to the items of `stream`
+///
+/// Delays hold up the items behind them too, as on a slow connection.
+pub fn scripted_stream<S>(stream: S, script: Script) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+{
+    let stream = Box::pin(stream);
+    futures::stream::unfold(
+        (stream, script.cursor()),
+        |(mut stream, mut cursor)| async move {
+            loop {
+                let item = stream.next().await?;
+                match cursor.next_step() {
+                    Step::Pass => return Some((item, (stream, cursor))),
+                    Step::Delay(delay) => {
+                        tokio::time::sleep(delay).await;
+                        return Some((item, (stream, cursor)));
+                    }
+                    Step::Drop => continue,
+                    Step::End => return None,
+                }
+            }
+        },
+    )
+}
+
+/// Creates an mpsc channel whose sender follows `script`
+pub fn mock_channel<T>(buffer: usize, script: Script) -> (ScriptedSender<T>, mpsc::Receiver<T>) {
+    let (tx, rx) = mpsc::channel(buffer);
+    let sender = ScriptedSender {
+        inner: Arc::new(ScriptedInner {
+            tx: Mutex::new(Some(tx)),
+            cursor: Mutex::new(script.cursor()),
+            dropped: AtomicUsize::new(0),
+        }),
+    };
+    (sender, rx)
+}
+
+struct ScriptedInner<T> {
+    /// Taken at [`Step::End`], which closes the channel
+    tx: Mutex<Option<mpsc::Sender<T>>>,
+    cursor: Mutex<Cursor>,
+    dropped: AtomicUsize,
+}
+
+/// Sending half of a [`mock_channel`]; clones share the script
+pub struct ScriptedSender<T> {
+    inner: Arc<ScriptedInner<T>>,
+}
+
+impl<T> Clone for ScriptedSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> ScriptedSender<T> {
+    /// Sends `value` as the script says
+    ///
+    /// Dropped messages still count as sent. Fails like a real sender once
+    /// the receiver is gone, or from the [`Step::End`] onwards.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let step = self.inner.cursor.lock().unwrap().next_step();
+        match step {
+            Step::Pass => {}
+            Step::Delay(delay) => tokio::time::sleep(delay).await,
+            Step::Drop => {
+                let tx = self.inner.tx.lock().unwrap();
+                if tx.as_ref().is_none_or(|tx| tx.is_closed()) {
+                    return Err(SendError(value));
+                }
+                self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            Step::End => {
+                self.inner.tx.lock().unwrap().take();
+                return Err(SendError(value));
+            }
+        }
+
+        let tx = self.inner.tx.lock().unwrap().clone();
+        match tx {
+            Some(tx) => tx.send(value).await,
+            None => Err(SendError(value)),
+        }
+    }
+
+    /// Messages dropped by the script so far
+    pub fn dropped(&self) -> usize {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+}
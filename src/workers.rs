@@ -0,0 +1,288 @@
+//! A worker pool that grows and shrinks with its queue
+//!
+//! Jobs submitted to a [`WorkerPool`] wait in a bounded queue and are run by
+//! a number of worker tasks that all pull from it. A supervisor checks the
+//! queue depth periodically: a backlog above the scale-up threshold adds a
+//! worker, a queue at or below the scale-down threshold retires one, always
+//! within the policy's bounds and one worker per check so the pool doesn't
+//! oscillate. Retired workers finish the job they are running first. A job
+//! that panics is counted and its worker carries on.
+//!
+//! CPU-heavy work that would stall async workers belongs on a
+//! [`BlockingPool`] instead, which runs closures on threads of its own.
+
+use crate::spawning::spawn_traced;
+use futures::FutureExt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+
+mod blocking;
+
+pub use blocking::{BlockingError, BlockingPool, BlockingPoolConfig, Completed, Lane};
+
+/// Bounds and thresholds for a [`WorkerPool`]
+#[derive(Debug, Clone)]
+pub struct ScalingPolicy {
+    pub min_workers: usize,
+    pub max_workers: usize,
+    /// Add a worker while more jobs than this are queued
+    pub scale_up_depth: usize,
+    /// Retire a worker while this many jobs or fewer are queued
+    pub scale_down_depth: usize,
+    pub check_interval: Duration,
+    /// Jobs that can wait before [`WorkerPool::submit`] blocks
+    pub queue_capacity: usize,
+}
+
+impl Default for ScalingPolicy {
+    fn default() -> Self {
+        Self {
+            min_workers: 1,
+            max_workers: 8,
+            scale_up_depth: 16,
+            scale_down_depth: 0,
+            check_interval: Duration::from_secs(1),
+            queue_capacity: 256,
+        }
+    }
+}
+
+/// Returned by [`WorkerPool::submit`] with the rejected job once the pool is
+/// draining
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolClosed<J>(pub J);
+
+impl<J> std::fmt::Display for PoolClosed<J> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "worker pool is closed")
+    }
+}
+
+impl<J: std::fmt::Debug> std::error::Error for PoolClosed<J> {}
+
+/// A point-in-time view of a [`WorkerPool`], from [`WorkerPool::metrics`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolMetrics {
+    pub workers: usize,
+    /// Workers currently running a job
+    pub busy: usize,
+    pub queued: usize,
+    /// Jobs finished, including those that panicked
+    pub completed: u64,
+    pub panicked: u64,
+    /// `busy / workers`, or 0 with no workers
+    pub utilization: f64,
+}
+
+type Handler<J> = Arc<dyn Fn(J) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+struct Shared<J> {
+    sender: Mutex<Option<mpsc::Sender<J>>>,
+    jobs: tokio::sync::Mutex<mpsc::Receiver<J>>,
+    handler: Handler<J>,
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    /// One retirement token per active worker
+    workers: Mutex<Vec<CancellationToken>>,
+    tasks: Mutex<JoinSet<()>>,
+}
+
+impl<J: Send + 'static> Shared<J> {
+    fn spawn_worker(self: &Arc<Self>) {
+        let retire = CancellationToken::new();
+        self.workers.lock().unwrap().push(retire.clone());
+        self.tasks.lock().unwrap().spawn(work(self.clone(), retire));
+    }
+
+    fn retire_worker(&self) {
+        if let Some(retire) = self.workers.lock().unwrap().pop() {
+            retire.cancel();
+        }
+    }
+
+    fn worker_count(&self) -> usize {
+        self.workers.lock().unwrap().len()
+    }
+}
+
+async fn work<J>(shared: Arc<Shared<J>>, retire: CancellationToken) {
+    loop {
+        let job = tokio::select! {
+            biased;
+            _ = retire.cancelled() => return,
+            job = async { shared.jobs.lock().await.recv().await } => job,
+        };
+        // The queue is closed and empty
+        let Some(job) = job else { return };
+
+        shared.queued.fetch_sub(1, Ordering::Relaxed);
+        shared.busy.fetch_add(1, Ordering::Relaxed);
+        let run = AssertUnwindSafe(async { (shared.handler)(job).await });
+        if run.catch_unwind().await.is_err() {
+            shared.panicked.fetch_add(1, Ordering::Relaxed);
+        }
+        shared.busy.fetch_sub(1, Ordering::Relaxed);
+        shared.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+async fn supervise<J: Send + 'static>(shared: Arc<Shared<J>>, policy: ScalingPolicy) {
+    let mut checks = tokio::time::interval(policy.check_interval);
+    loop {
+        checks.tick().await;
+        // Reap retired workers so their results don't pile up in the set
+        while shared.tasks.lock().unwrap().try_join_next().is_some() {}
+
+        let queued = shared.queued.load(Ordering::Relaxed);
+        let workers = shared.worker_count();
+
+        if queued > policy.scale_up_depth && workers < policy.max_workers {
+            shared.spawn_worker();
+        } else if queued <= policy.scale_down_depth && workers > policy.min_workers {
+            shared.retire_worker();
+        }
+    }
+}
+
+/// Runs submitted jobs on a self-scaling set of worker tasks
+///
+/// ```
+/// # async fn example() {
+/// use tokio_tutorial_patterns::workers::{ScalingPolicy, WorkerPool};
+///
+/// let pool = WorkerPool::new(ScalingPolicy::default(), |url: String| async move {
+///     println!("fetching {}", url);
+/// });
+/// for page in 0..100 {
+///     pool.submit(format!("https://example.com/{}", page)).await.unwrap();
+/// }
+/// pool.drain_and_stop().await;
+/// # }
+/// ```
+pub struct WorkerPool<J> {
+    shared: Arc<Shared<J>>,
+    supervisor: JoinHandle<()>,
+}
+
+impl<J: Send + 'static> WorkerPool<J> {
+    /// Starts `policy.min_workers` workers running `handler`
+    ///
+    /// Must be called from within a Tokio runtime.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min_workers` is zero or exceeds `max_workers`, if
+    /// `scale_up_depth` isn't below `queue_capacity`, or if `check_interval`
+    /// is zero. Without a worker, a short queue would never run, and a full
+    /// one could never grow the pool past a threshold it can't reach.
+    pub fn new<F, Fut>(policy: ScalingPolicy, handler: F) -> Self
+    where
+        F: Fn(J) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        assert!(policy.min_workers > 0, "`min_workers` must be non-zero");
+        assert!(
+            policy.min_workers <= policy.max_workers,
+            "`min_workers` must not exceed `max_workers`"
+        );
+        assert!(
+            policy.scale_up_depth < policy.queue_capacity,
+            "`scale_up_depth` must be below `queue_capacity`"
+        );
+        assert!(
+            !policy.check_interval.is_zero(),
+            "`check_interval` must be non-zero"
+        );
+
+        let (sender, jobs) = mpsc::channel(policy.queue_capacity);
+        let shared = Arc::new(Shared {
+            sender: Mutex::new(Some(sender)),
+            jobs: tokio::sync::Mutex::new(jobs),
+            handler: Arc::new(move |job| Box::pin(handler(job))),
+            queued: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+            completed: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+            workers: Mutex::new(Vec::new()),
+            tasks: Mutex::new(JoinSet::new()),
+        });
+
+        for _ in 0..policy.min_workers {
+            shared.spawn_worker();
+        }
+        let supervisor = spawn_traced("worker pool supervisor", supervise(shared.clone(), policy));
+
+        Self { shared, supervisor }
+    }
+
+    /// Queues a job, waiting while the queue is full
+    pub async fn submit(&self, job: J) -> Result<(), PoolClosed<J>> {
+        let sender = self.shared.sender.lock().unwrap().clone();
+        let Some(sender) = sender else {
+            return Err(PoolClosed(job));
+        };
+
+        match sender.reserve_owned().await {
+            Ok(permit) => {
+                // Counted first so a worker can never take it before it is counted
+                self.shared.queued.fetch_add(1, Ordering::Relaxed);
+                permit.send(job);
+                Ok(())
+            }
+            Err(_) => Err(PoolClosed(job)),
+        }
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let workers = self.shared.worker_count();
+        let busy = self.shared.busy.load(Ordering::Relaxed);
+
+        PoolMetrics {
+            workers,
+            busy,
+            queued: self.shared.queued.load(Ordering::Relaxed),
+            completed: self.shared.completed.load(Ordering::Relaxed),
+            panicked: self.shared.panicked.load(Ordering::Relaxed),
+            utilization: if workers == 0 {
+                0.0
+            } else {
+                busy as f64 / workers as f64
+            },
+        }
+    }
+
+    /// Stops accepting jobs, runs everything already queued and waits for
+    /// every worker to exit
+    ///
+    /// Scaling stops at once, so the queue is drained by the workers running
+    /// at the time, or by a single one if there are none.
+    pub async fn drain_and_stop(&self) {
+        self.supervisor.abort();
+        self.shared.sender.lock().unwrap().take();
+        if self.shared.worker_count() == 0 {
+            self.shared.spawn_worker();
+        }
+
+        let mut tasks = std::mem::take(&mut *self.shared.tasks.lock().unwrap());
+        while tasks.join_next().await.is_some() {}
+        self.shared.workers.lock().unwrap().clear();
+    }
+}
+
+/// Dropping the pool closes the queue; workers already running finish the
+/// jobs in it in the background
+impl<J> Drop for WorkerPool<J> {
+    fn drop(&mut self) {
+        self.supervisor.abort();
+        self.shared.sender.lock().unwrap().take();
+    }
+}
@@ -0,0 +1,246 @@
+//! A dedicated thread pool for CPU-heavy closures
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Semaphore};
+
+/// Which queue a job waits in; a thread always takes the oldest job from
+/// the highest non-empty lane, so a steady stream of `High` jobs starves
+/// the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Lane {
+    High,
+    Normal,
+    Low,
+}
+
+/// Settings for a [`BlockingPool`]
+#[derive(Debug, Clone)]
+pub struct BlockingPoolConfig {
+    /// OS threads running jobs
+    pub threads: usize,
+    /// Jobs that can wait, over all lanes, before [`BlockingPool::run`]
+    /// waits for room
+    pub queue_capacity: usize,
+    pub thread_name: String,
+}
+
+impl Default for BlockingPoolConfig {
+    /// One thread per core and room for 256 waiting jobs
+    fn default() -> Self {
+        Self {
+            threads: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            queue_capacity: 256,
+            thread_name: "blocking-pool".to_string(),
+        }
+    }
+}
+
+/// A job's result and how long it spent queued and running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completed<T> {
+    pub output: T,
+    /// From submission until a thread picked it up, including any wait for
+    /// room in the queue
+    pub waited: Duration,
+    pub ran: Duration,
+}
+
+/// Why [`BlockingPool::run`] produced no result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockingError {
+    /// The pool was stopped before the job could be queued
+    Closed,
+    /// The job panicked; the thread running it carries on
+    Panicked,
+}
+
+impl fmt::Display for BlockingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockingError::Closed => write!(f, "blocking pool is closed"),
+            BlockingError::Panicked => write!(f, "blocking job panicked"),
+        }
+    }
+}
+
+impl std::error::Error for BlockingError {}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct State {
+    /// Indexed by `Lane as usize`
+    lanes: [VecDeque<Job>; 3],
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when a job is queued or the pool closes
+    available: Condvar,
+    /// One permit per free queue slot
+    room: Arc<Semaphore>,
+    /// One permit per thread that has exited
+    exited: Semaphore,
+}
+
+fn work(shared: Arc<Shared>) {
+    loop {
+        let job = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if let Some(job) = state.lanes.iter_mut().find_map(VecDeque::pop_front) {
+                    break job;
+                }
+                if state.closed {
+                    drop(state);
+                    shared.exited.add_permits(1);
+                    return;
+                }
+                state = shared.available.wait(state).unwrap();
+            }
+        };
+        job();
+    }
+}
+
+/// Runs CPU-heavy closures on its own OS threads, away from both the async
+/// workers and the runtime's blocking pool
+///
+/// Long computations on `spawn_blocking` compete with file and DNS work for
+/// the same threads; here they can only hold up each other. The queue is
+/// bounded, so producers slow down to the pool's pace instead of piling
+/// up jobs.
+///
+/// ```
+/// # async fn example() {
+/// use tokio_tutorial_patterns::workers::{BlockingPool, BlockingPoolConfig, Lane};
+///
+/// let pool = BlockingPool::new(BlockingPoolConfig::default());
+/// let done = pool
+///     .run(Lane::High, || (1..=20u64).product::<u64>())
+///     .await
+///     .unwrap();
+/// println!("{} after {:?} queued, {:?} running", done.output, done.waited, done.ran);
+/// # }
+/// ```
+pub struct BlockingPool {
+    shared: Arc<Shared>,
+    threads: usize,
+}
+
+impl BlockingPool {
+    /// Starts the pool's threads
+    ///
+    /// # Panics
+    ///
+    /// Panics if `threads` or `queue_capacity` is zero, or a thread can't
+    /// be spawned.
+    pub fn new(config: BlockingPoolConfig) -> Self {
+        assert!(config.threads > 0, "`threads` must be non-zero");
+        assert!(
+            config.queue_capacity > 0,
+            "`queue_capacity` must be non-zero"
+        );
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                lanes: Default::default(),
+                closed: false,
+            }),
+            available: Condvar::new(),
+            room: Arc::new(Semaphore::new(config.queue_capacity)),
+            exited: Semaphore::new(0),
+        });
+        for i in 0..config.threads {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name(format!("{}-{}", config.thread_name, i))
+                .spawn(move || work(shared))
+                .expect("failed to spawn blocking pool thread");
+        }
+
+        Self {
+            shared,
+            threads: config.threads,
+        }
+    }
+
+    /// Queues `job` in `lane`, waiting while the queue is full, and returns
+    /// its result once a thread has run it
+    ///
+    /// Once queued the job runs even if this future is dropped.
+    pub async fn run<F, T>(&self, lane: Lane, job: F) -> Result<Completed<T>, BlockingError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let submitted = Instant::now();
+        let room = self.shared.room.clone().acquire_owned().await;
+        let room = room.map_err(|_| BlockingError::Closed)?;
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // Leaving the queue frees its slot
+            drop(room);
+            let started = Instant::now();
+            let output = std::panic::catch_unwind(AssertUnwindSafe(job));
+            let _ = tx.send(output.map(|output| Completed {
+                output,
+                waited: started - submitted,
+                ran: started.elapsed(),
+            }));
+        });
+
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed {
+                return Err(BlockingError::Closed);
+            }
+            state.lanes[lane as usize].push_back(job);
+        }
+        self.shared.available.notify_one();
+
+        match rx.await {
+            Ok(Ok(completed)) => Ok(completed),
+            Ok(Err(_)) => Err(BlockingError::Panicked),
+            // Threads drain the queue before exiting, so this is only a
+            // job dropped unrun
+            Err(_) => Err(BlockingError::Closed),
+        }
+    }
+
+    /// Jobs waiting for a thread, over all lanes
+    pub fn queued(&self) -> usize {
+        let state = self.shared.state.lock().unwrap();
+        state.lanes.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Stops accepting jobs, runs everything already queued and waits for
+    /// every thread to exit
+    pub async fn drain_and_stop(&self) {
+        self.close();
+        // Dropping the permits right away lets later calls return too
+        let _ = self.shared.exited.acquire_many(self.threads as u32).await;
+    }
+
+    fn close(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.room.close();
+        self.shared.available.notify_all();
+    }
+}
+
+/// Dropping the pool closes the queue; its threads finish the jobs in it
+/// and exit in the background
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        self.close();
+    }
+}